@@ -0,0 +1,75 @@
+//throughput and socket buffer memory for the two ends of buffers.client_bytes:
+//cargo bench --bench buffer_size
+#[path = "../src/listener.rs"]
+mod listener;
+
+use listener::bind_listener;
+use std::time::Instant;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+const TRANSFER_BYTES: usize = 256 * 1024 * 1024;
+const IDLE_CONNECTIONS: usize = 256;
+
+//kernel buffer memory reserved for one accepted connection, as the kernel reports it.
+//the socket is returned so the connection stays open while the others are counted
+fn buffer_memory(stream: tokio::net::TcpStream) -> (u64, tokio::net::TcpSocket) {
+    let socket = tokio::net::TcpSocket::from_std_stream(stream.into_std().unwrap());
+    let bytes = socket.recv_buffer_size().unwrap() as u64 + socket.send_buffer_size().unwrap() as u64;
+    (bytes, socket)
+}
+
+//pushes TRANSFER_BYTES through one loopback connection and opens IDLE_CONNECTIONS more,
+//returning MiB/s and the buffer memory those connections hold
+async fn run(buffer_bytes: u32) -> (f64, u64) {
+    let listener = bind_listener("127.0.0.1:0", Some(buffer_bytes)).await.unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    let reader = tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut buf = vec![0u8; 64 * 1024];
+        let mut received = 0;
+        while received < TRANSFER_BYTES {
+            received += stream.read(&mut buf).await.unwrap();
+        }
+
+        let mut idle = Vec::with_capacity(IDLE_CONNECTIONS);
+        let mut memory = 0;
+        for _ in 0..IDLE_CONNECTIONS {
+            let (bytes, socket) = buffer_memory(listener.accept().await.unwrap().0);
+            memory += bytes;
+            idle.push(socket);
+        }
+        memory
+    });
+
+    let mut writer = tokio::net::TcpStream::connect(addr).await.unwrap();
+    let chunk = vec![0u8; 64 * 1024];
+    let start = Instant::now();
+    for _ in 0..TRANSFER_BYTES / chunk.len() {
+        writer.write_all(&chunk).await.unwrap();
+    }
+    writer.flush().await.unwrap();
+    let elapsed = start.elapsed().as_secs_f64();
+
+    let mut clients = Vec::with_capacity(IDLE_CONNECTIONS);
+    for _ in 0..IDLE_CONNECTIONS {
+        clients.push(tokio::net::TcpStream::connect(addr).await.unwrap());
+    }
+    let memory = reader.await.unwrap();
+
+    ((TRANSFER_BYTES / (1024 * 1024)) as f64 / elapsed, memory)
+}
+
+#[tokio::main]
+async fn main() {
+    for buffer_bytes in [16 * 1024, 256 * 1024] {
+        let (throughput, memory) = run(buffer_bytes).await;
+        println!(
+            "buffers.client_bytes = {:>6}: {:>8.0} MiB/s over one connection, {:>6} KiB of socket buffers for {} idle connections",
+            buffer_bytes,
+            throughput,
+            memory / 1024,
+            IDLE_CONNECTIONS
+        );
+    }
+}
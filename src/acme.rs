@@ -0,0 +1,239 @@
+use instant_acme::{
+    Account, AccountCredentials, AuthorizationStatus, ChallengeType, Identifier, NewAccount, NewOrder, Order,
+    OrderStatus,
+};
+use rcgen::{CertificateParams, DistinguishedName, KeyPair};
+use sqlx::SqlitePool;
+use std::{
+    collections::HashMap,
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+use tracing::{info, warn};
+use crate::database;
+use crate::routing::RouteTable;
+use crate::tls::CertStore;
+
+//the CA fetches HTTP-01 tokens from here, over plain http on port 80
+pub const CHALLENGE_PREFIX: &str = "/.well-known/acme-challenge/";
+const CHECK_INTERVAL: Duration = Duration::from_secs(60 * 60);
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+const MAX_POLLS: u32 = 30;
+
+#[derive(Debug, Clone)]
+pub struct AcmeConfig {
+    pub directory: String,
+    pub email: Option<String>,
+    //a certificate is renewed once it has less than this left
+    pub renew_before: Duration,
+}
+
+//key authorizations for the HTTP-01 challenges in progress, by token
+#[derive(Clone, Default)]
+pub struct Challenges {
+    tokens: Arc<RwLock<HashMap<String, String>>>,
+}
+
+impl Challenges {
+    //what the CA expects at `path`, None for anything but a pending challenge
+    pub fn key_authorization(&self, path: &str) -> Option<String> {
+        let token = path.strip_prefix(CHALLENGE_PREFIX)?;
+        self.tokens.read().unwrap().get(token).cloned()
+    }
+
+    fn add(&self, token: &str, key_authorization: &str) {
+        self.tokens.write().unwrap().insert(token.to_string(), key_authorization.to_string());
+    }
+
+    fn remove(&self, token: &str) {
+        self.tokens.write().unwrap().remove(token);
+    }
+}
+
+//obtains and renews a certificate for every enabled domain and alias. wildcards
+//would need a DNS challenge and are left to tls.cert_path
+#[derive(Clone)]
+pub struct Acme {
+    config: AcmeConfig,
+    db: SqlitePool,
+    routes: RouteTable,
+    certs: CertStore,
+    challenges: Challenges,
+}
+
+impl Acme {
+    pub fn new(config: AcmeConfig, db: SqlitePool, routes: RouteTable, certs: CertStore) -> Self {
+        Self {
+            config,
+            db,
+            routes,
+            certs,
+            challenges: Challenges::default(),
+        }
+    }
+
+    pub fn challenges(&self) -> Challenges {
+        self.challenges.clone()
+    }
+
+    //certificates from earlier runs, so a restart doesn't order them again
+    pub async fn load_stored(&self) {
+        let stored = match database::load_certificates(&self.db).await {
+            Ok(stored) => stored,
+            Err(e) => {
+                warn!("ACME: can't load stored certificates: {}", e);
+                return;
+            }
+        };
+        for (domain, cert_pem, key_pem, _) in stored {
+            if let Err(e) = self.certs.insert(&domain, &cert_pem, &key_pem) {
+                warn!("ACME: stored certificate for {} unusable: {}", domain, e);
+            }
+        }
+    }
+
+    //checks right away, then hourly
+    pub fn spawn(&self) {
+        let acme = self.clone();
+        tokio::spawn(async move {
+            loop {
+                acme.renew_due().await;
+                tokio::time::sleep(CHECK_INTERVAL).await;
+            }
+        });
+    }
+
+    async fn renew_due(&self) {
+        let renew_at = chrono::Utc::now() + chrono::Duration::seconds(self.config.renew_before.as_secs() as i64);
+        let mut due: Vec<String> = self
+            .routes
+            .read()
+            .await
+            .keys()
+            .filter(|name| !name.starts_with("*."))
+            .filter(|name| self.certs.expires_at(name).map(|expires| expires <= renew_at).unwrap_or(true))
+            .cloned()
+            .collect();
+        if due.is_empty() {
+            return;
+        }
+        due.sort();
+
+        let account = match self.account().await {
+            Ok(account) => account,
+            Err(e) => {
+                warn!("ACME: no account with {}: {}", self.config.directory, e);
+                return;
+            }
+        };
+        for name in due {
+            match self.issue(&account, &name).await {
+                Ok(expires_at) => info!("ACME: certificate for {} issued, expires {}", name, expires_at),
+                Err(e) => warn!("ACME: certificate for {} failed: {}", name, e),
+            }
+        }
+    }
+
+    //the account registered with this directory before, or a new one
+    async fn account(&self) -> Result<Account, String> {
+        let stored = database::get_acme_account(&self.db, &self.config.directory)
+            .await
+            .map_err(|e| e.to_string())?;
+        if let Some(stored) = stored {
+            let credentials: AccountCredentials = serde_json::from_str(&stored).map_err(|e| e.to_string())?;
+            return Account::from_credentials(credentials).await.map_err(|e| e.to_string());
+        }
+
+        let contact: Vec<String> = self.config.email.iter().map(|email| format!("mailto:{}", email)).collect();
+        let contact: Vec<&str> = contact.iter().map(String::as_str).collect();
+        let new_account = NewAccount {
+            contact: &contact,
+            terms_of_service_agreed: true,
+            only_return_existing: false,
+        };
+        let (account, credentials) = Account::create(&new_account, &self.config.directory, None)
+            .await
+            .map_err(|e| e.to_string())?;
+        let stored = serde_json::to_string(&credentials).map_err(|e| e.to_string())?;
+        database::save_acme_account(&self.db, &self.config.directory, &stored)
+            .await
+            .map_err(|e| e.to_string())?;
+        info!("ACME: registered an account with {}", self.config.directory);
+        Ok(account)
+    }
+
+    //orders a certificate for one name and starts serving it
+    async fn issue(&self, account: &Account, name: &str) -> Result<chrono::DateTime<chrono::Utc>, String> {
+        let identifiers = [Identifier::Dns(name.to_string())];
+        let mut order = account
+            .new_order(&NewOrder { identifiers: &identifiers })
+            .await
+            .map_err(|e| e.to_string())?;
+
+        let mut tokens = Vec::new();
+        let result = self.complete(&mut order, name, &mut tokens).await;
+        for token in &tokens {
+            self.challenges.remove(token);
+        }
+        let (cert_pem, key_pem) = result?;
+
+        let expires_at = self.certs.insert(name, &cert_pem, &key_pem)?;
+        database::save_certificate(&self.db, name, &cert_pem, &key_pem, expires_at.timestamp())
+            .await
+            .map_err(|e| e.to_string())?;
+        Ok(expires_at)
+    }
+
+    //answers the order's HTTP-01 challenges, then finalizes it with a fresh key.
+    //tokens put up are recorded so they're taken down whatever happens
+    async fn complete(&self, order: &mut Order, name: &str, tokens: &mut Vec<String>) -> Result<(String, String), String> {
+        let authorizations = order.authorizations().await.map_err(|e| e.to_string())?;
+        for authorization in &authorizations {
+            if matches!(authorization.status, AuthorizationStatus::Valid) {
+                continue;
+            }
+            let challenge = authorization
+                .challenges
+                .iter()
+                .find(|challenge| matches!(challenge.r#type, ChallengeType::Http01))
+                .ok_or_else(|| "the CA offered no http-01 challenge".to_string())?;
+            let key_authorization = order.key_authorization(challenge);
+            self.challenges.add(&challenge.token, key_authorization.as_str());
+            tokens.push(challenge.token.clone());
+            order.set_challenge_ready(&challenge.url).await.map_err(|e| e.to_string())?;
+        }
+
+        let mut polls = 0;
+        loop {
+            tokio::time::sleep(POLL_INTERVAL).await;
+            match order.refresh().await.map_err(|e| e.to_string())?.status {
+                OrderStatus::Ready => break,
+                OrderStatus::Invalid => return Err("the CA couldn't validate the challenge".to_string()),
+                _ => {}
+            }
+            polls += 1;
+            if polls >= MAX_POLLS {
+                return Err("timed out waiting for the challenge to be validated".to_string());
+            }
+        }
+
+        let key = KeyPair::generate().map_err(|e| e.to_string())?;
+        let mut params = CertificateParams::new(vec![name.to_string()]).map_err(|e| e.to_string())?;
+        params.distinguished_name = DistinguishedName::new();
+        let csr = params.serialize_request(&key).map_err(|e| e.to_string())?;
+        order.finalize(csr.der()).await.map_err(|e| e.to_string())?;
+
+        let mut polls = 0;
+        let cert_pem = loop {
+            if let Some(chain) = order.certificate().await.map_err(|e| e.to_string())? {
+                break chain;
+            }
+            polls += 1;
+            if polls >= MAX_POLLS {
+                return Err("timed out waiting for the certificate".to_string());
+            }
+            tokio::time::sleep(POLL_INTERVAL).await;
+        };
+        Ok((cert_pem, key.serialize_pem()))
+    }
+}
@@ -0,0 +1,108 @@
+use axum::{
+    body::Body,
+    extract::Request,
+    http::{header::HOST, HeaderValue, Response, Uri},
+};
+use hyper::{body::Incoming, client::conn::http1};
+use hyper_util::client::legacy::connect::HttpConnector;
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tower_service::Service;
+use tracing::{debug, warn};
+use crate::dns::CachingResolver;
+use crate::upstream_error::SendError;
+
+struct Pinned {
+    sender: http1::SendRequest<Body>,
+    last_used: Instant,
+}
+
+//one dedicated upstream connection per client connection and origin, for backends that
+//keep state per connection. a client connection is told apart by its peer address
+#[derive(Clone)]
+pub struct PinnedConnections {
+    connector: HttpConnector<CachingResolver>,
+    //a pinned connection unused this long is closed, the client has most likely gone
+    idle: Duration,
+    conns: Arc<Mutex<HashMap<(SocketAddr, String), Pinned>>>,
+}
+
+impl PinnedConnections {
+    pub fn new(connector: HttpConnector<CachingResolver>, idle: Duration) -> Self {
+        Self {
+            connector,
+            idle,
+            conns: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    pub async fn send(&self, client: SocketAddr, origin: &str, mut req: Request) -> Result<Response<Incoming>, SendError> {
+        let key = (client, origin.to_string());
+
+        //taken out while in use, a client connection only has one request in flight anyway
+        let existing = {
+            let mut conns = self.conns.lock().unwrap();
+            conns.retain(|_, pinned| !pinned.sender.is_closed() && pinned.last_used.elapsed() < self.idle);
+            conns.remove(&key)
+        };
+        let mut sender = match existing {
+            Some(pinned) => pinned.sender,
+            None => self.connect(req.uri().clone()).await?,
+        };
+
+        //a bare connection wants the origin-form target and an explicit Host
+        let uri = req.uri().clone();
+        if let Some(authority) = uri.authority().and_then(|a| HeaderValue::from_str(a.as_str()).ok()) {
+            req.headers_mut().insert(HOST, authority);
+        }
+        *req.uri_mut() = uri
+            .path_and_query()
+            .map(|pq| pq.as_str())
+            .unwrap_or("/")
+            .parse()
+            .unwrap_or_default();
+
+        let pinned_failure = |error: hyper::Error| SendError::Pinned {
+            connecting: false,
+            error: Box::new(error),
+        };
+        sender.ready().await.map_err(pinned_failure)?;
+        let response = sender.send_request(req).await.map_err(pinned_failure)?;
+
+        //the next request waits in ready() until this response's body has been read
+        self.conns.lock().unwrap().insert(
+            key,
+            Pinned {
+                sender,
+                last_used: Instant::now(),
+            },
+        );
+        Ok(response)
+    }
+
+    async fn connect(&self, uri: Uri) -> Result<http1::SendRequest<Body>, SendError> {
+        let connect_failure = |error: Box<dyn std::error::Error + Send + Sync>| SendError::Pinned {
+            connecting: true,
+            error,
+        };
+
+        let mut connector = self.connector.clone();
+        futures::future::poll_fn(|cx| connector.poll_ready(cx))
+            .await
+            .map_err(|e| connect_failure(Box::new(e)))?;
+        let io = connector.call(uri.clone()).await.map_err(|e| connect_failure(Box::new(e)))?;
+        let (sender, conn) = http1::handshake(io).await.map_err(|e| connect_failure(Box::new(e)))?;
+        tokio::spawn(async move {
+            if let Err(e) = conn.with_upgrades().await {
+                warn!("PINNED CONNECTION: {}", e);
+            }
+        });
+
+        debug!("PINNED CONNECTION: opened to {}", uri.authority().map(|a| a.as_str()).unwrap_or(""));
+        Ok(sender)
+    }
+}
@@ -1,24 +1,121 @@
 use axum::{
-    extract::{Path, State},
+    extract::{Path, Query, State},
     http::StatusCode,
     response::IntoResponse,
-    routing::get,
+    routing::{get, patch, post},
     Json, Router,
 };
 use serde::{Deserialize, Serialize};
 use sqlx::SqlitePool;
-use std::sync::Arc;
-use tokio::sync::RwLock;
 use std::collections::HashMap;
+use crate::cache::MemoryCache;
+use crate::log_writer::LogWriter;
+use crate::breaker::{BreakerSnapshot, BreakerState, CircuitBreakers};
+use crate::balancer;
 use crate::database;
+use crate::settings;
+use crate::snapshot;
+use crate::validation::{self, ConfigDocument};
+use crate::health::{HealthCheckConfig, HealthChecker};
+use crate::pacing::Pacing;
+use crate::stats::{LimitCounters, LimitCounts, OriginCounters, RequestMetrics, LATENCY_BUCKETS};
+use crate::tls::{CertStore, TlsStats};
+use crate::cidr::Cidr;
+use crate::ip_filter::IpFilter;
+use crate::routing::{self, AcceptRoute, BlueGreen, Color, PathRateLimit, PathRoute, RouteTable, TrailingSlash};
 use tracing::info;
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Debug, Default, Serialize, Deserialize, Clone)]
 pub struct DomainDto {
     pub id: Option<i64>,
     pub domain: String,
     pub origin: String,
     pub enabled: bool,
+    #[serde(default)]
+    pub origins: Vec<String>,
+    #[serde(default)]
+    pub upstream_timeout_ms: Option<u64>,
+    #[serde(default)]
+    pub health_check: Option<HealthCheckConfig>,
+    #[serde(default)]
+    pub priority: i64,
+    #[serde(default)]
+    pub aliases: Vec<String>,
+    #[serde(default)]
+    pub maintenance: bool,
+    //relative share of traffic per origin for weighted_round_robin, unlisted origins weigh 1
+    #[serde(default)]
+    pub origin_weights: HashMap<String, u32>,
+    //when set, only paths matching one of these prefixes or globs (e.g. /static/*) are cached
+    #[serde(default)]
+    pub cache_paths: Vec<String>,
+    #[serde(default)]
+    pub max_response_bytes: Option<u64>,
+    #[serde(default)]
+    pub trailing_slash: Option<TrailingSlash>,
+    //markup inserted before </head> in html responses
+    #[serde(default)]
+    pub html_inject: Option<String>,
+    //when set, replaces `origin`/`origins` with whichever color is active
+    #[serde(default)]
+    pub blue_green: Option<BlueGreen>,
+    //per path prefix limits, paths matching none use the global rate limit
+    #[serde(default)]
+    pub rate_limits: Vec<PathRateLimit>,
+    //replaces the global rate_limit_per_minute for this domain's paths without a rule above
+    #[serde(default)]
+    pub rate_limit_per_minute: Option<u32>,
+    #[serde(default)]
+    pub max_concurrency: Option<usize>,
+    //minimum spacing between dispatches to each origin
+    #[serde(default)]
+    pub pacing: Option<Pacing>,
+    //origins picked by the request's Accept header, other requests use the origins above
+    #[serde(default)]
+    pub accept_routes: Vec<AcceptRoute>,
+    //origins picked by path prefix, the longest matching prefix wins over everything above
+    #[serde(default)]
+    pub path_routes: Vec<PathRoute>,
+    //directory holding .br/.gz copies of static files, served without asking the origin
+    #[serde(default)]
+    pub precompressed_dir: Option<String>,
+    //groups this domain's traffic with others of the same team or service in the stats
+    #[serde(default)]
+    pub service: Option<String>,
+    //each client connection gets its own upstream connection instead of the shared pool
+    #[serde(default)]
+    pub connection_affinity: bool,
+}
+
+impl DomainDto {
+    //all origins serving this domain, falling back to the single `origin`
+    pub fn origin_list(&self) -> Vec<String> {
+        if let Some(blue_green) = &self.blue_green {
+            vec![blue_green.active_origin().to_string()]
+        } else if self.origins.is_empty() {
+            vec![self.origin.clone()]
+        } else {
+            self.origins.clone()
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct ApiState {
+    pub routes: RouteTable,
+    pub db: SqlitePool,
+    pub limit_counters: LimitCounters,
+    pub origin_counters: OriginCounters,
+    pub breakers: CircuitBreakers,
+    pub health: HealthChecker,
+    pub tls: TlsStats,
+    pub certs: CertStore,
+    pub ip_filter: IpFilter,
+    pub metrics: RequestMetrics,
+    pub log_writer: LogWriter,
+    pub cache: Option<MemoryCache>,
+    //the key listing endpoints are only served when cache.inspect is on
+    pub cache_inspect: bool,
 }
 
 #[derive(Debug, Serialize)]
@@ -53,23 +150,176 @@ pub struct StatsResponse {
     pub cache_hit_rate: f64,
     pub avg_response_time_ms: f64,
     pub total_bytes_sent: i64,
+    pub circuit_breakers: Vec<BreakerSnapshot>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DomainStats {
+    pub domain: String,
+    pub total_requests: i64,
+    pub error_count: i64,
+    pub avg_response_time_ms: f64,
+    pub total_bytes_sent: i64,
+    pub throttled: u64,
+    pub shed: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct OriginStats {
+    pub domain: String,
+    pub origin: String,
+    pub weight: u32,
+    pub requests: u64,
+    //percentage of the domain's proxied requests
+    pub share: f64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct LogDto {
+    pub request_id: Option<String>,
+    pub domain: String,
+    pub origin: Option<String>,
+    pub service: Option<String>,
+    pub path: String,
+    pub method: String,
+    pub status: i64,
+    pub response_time_ms: i64,
+    pub bytes_sent: i64,
+    pub ip_address: Option<String>,
+    pub denial: Option<String>,
+    pub error: Option<String>,
+    pub timestamp: i64,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct LogQuery {
+    pub request_id: Option<String>,
+    pub limit: Option<i64>,
+}
+
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StatusGroup {
+    #[default]
+    Domain,
+    Origin,
+    Service,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TimeseriesQuery {
+    //bucket width in seconds, default 60
+    pub bucket: Option<i64>,
+    #[serde(default)]
+    pub group_by: StatusGroup,
+    //how far back to look in seconds, default 3600
+    pub window: Option<i64>,
+    //only requests for domains with this service label
+    pub service: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DomainStatsQuery {
+    pub service: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ErrorBudgetQuery {
+    //rolling window in seconds, default slo.window_secs
+    pub window: Option<i64>,
+    //availability target in percent, default slo.target
+    pub target: Option<f64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ErrorBudget {
+    pub domain: String,
+    pub total_requests: i64,
+    pub error_count: i64,
+    //percent of requests that weren't 5xx
+    pub availability: f64,
+    //share of the allowed errors already used, over 1.0 once the budget is blown
+    pub budget_consumed: f64,
+    pub budget_remaining: f64,
+}
+
+impl ErrorBudget {
+    fn new(domain: String, total_requests: i64, error_count: i64, target: f64) -> Self {
+        let error_rate = if total_requests > 0 { error_count as f64 / total_requests as f64 } else { 0.0 };
+        let allowed = 1.0 - target / 100.0;
+        //a 100% target has no budget, any error spends all of it
+        let budget_consumed = if allowed > 0.0 {
+            error_rate / allowed
+        } else if error_count > 0 {
+            1.0
+        } else {
+            0.0
+        };
+        Self {
+            domain,
+            total_requests,
+            error_count,
+            availability: (1.0 - error_rate) * 100.0,
+            budget_consumed,
+            budget_remaining: 1.0 - budget_consumed,
+        }
+    }
+}
+
+//one group's requests in one bucket, by status class
+#[derive(Debug, Serialize)]
+pub struct StatusBucket {
+    pub bucket_start: i64,
+    pub group: String,
+    pub status_1xx: i64,
+    pub status_2xx: i64,
+    pub status_3xx: i64,
+    pub status_4xx: i64,
+    pub status_5xx: i64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DenialDto {
+    pub category: String,
+    //None for denials logged before the rule was stored
+    pub rule: Option<String>,
+    pub domain: String,
+    pub path: String,
+    pub method: String,
+    pub status: i64,
+    pub ip_address: Option<String>,
+    pub timestamp: i64,
 }
 
-pub fn api_router(
-    routes: Arc<RwLock<HashMap<String, String>>>,
-    db: SqlitePool,
-) -> Router {
+pub fn api_router(state: ApiState) -> Router {
     Router::new()
         .route("/domains", get(list_domains).post(create_domain))
         .route("/domains/{id}", get(get_domain).patch(update_domain).delete(delete_domain))
+        .route("/domains/{id}/priority", patch(update_domain_priority))
+        .route("/domains/{id}/cutover", post(cutover_domain))
         .route("/stats", get(get_stats))
+        .route("/stats/domains", get(get_domain_stats))
+        .route("/stats/origins", get(get_origin_stats))
+        .route("/stats/status-timeseries", get(get_status_timeseries))
+        .route("/stats/error-budget", get(get_error_budget))
+        .route("/health/upstreams", get(get_upstream_health))
+        .route("/certs", get(list_certs))
+        .route("/ip-filter", get(get_ip_filter))
+        .route("/ip-filter/deny", post(block_ip).delete(unblock_ip))
+        .route("/cache/keys", get(list_cache_keys))
+        .route("/cache/entry", get(get_cache_entry))
+        .route("/metrics", get(get_metrics))
+        .route("/denials", get(list_denials))
+        .route("/logs", get(list_logs))
         .route("/config", get(get_all_config_endpoint).post(set_config_endpoint))
+        .route("/config/validate", post(validate_config_endpoint))
+        .route("/config/snapshot", post(snapshot_config_endpoint))
         .route("/config/{key}", get(get_config_endpoint).patch(update_config_endpoint))
-        .with_state((routes, db))
+        .with_state(state)
 }
 
 async fn list_domains(
-    State((_routes, db)): State<(Arc<RwLock<HashMap<String, String>>>, SqlitePool)>,
+    State(ApiState { db, .. }): State<ApiState>,
 ) -> impl IntoResponse {
     match database::get_all_domains(&db).await {
         Ok(domains) => Json(ApiResponse::ok(domains)).into_response(),
@@ -82,17 +332,31 @@ async fn list_domains(
 }
 
 async fn create_domain(
-    State((routes, db)): State<(Arc<RwLock<HashMap<String, String>>>, SqlitePool)>,
+    State(ApiState { routes, db, .. }): State<ApiState>,
     Json(payload): Json<DomainDto>,
 ) -> impl IntoResponse {
-    match database::create_domain(&db, &payload.domain, &payload.origin).await {
+    let problems = validation::validate_domain(&payload);
+    if !problems.is_empty() {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(ApiResponse::<()>::err(problems.join("; "))),
+        )
+            .into_response();
+    }
+
+    match database::create_domain(&db, &payload).await {
         Ok(domain) => {
-            // Update in-memory routes immediately
-            let mut routes_map = routes.write().await;
-            routes_map.insert(domain.domain.clone(), domain.origin.clone());
-            drop(routes_map);
-            
-            info!("Domain created and added to routes: {} -> {}", domain.domain, domain.origin);
+            //disabled domains are persisted but stay unroutable until enabled
+            if domain.enabled {
+                let entries = routing::entries(&domain);
+                let mut routes_map = routes.write().await;
+                routing::insert(&mut routes_map, entries);
+                drop(routes_map);
+
+                info!("Domain created and added to routes: {} -> {:?}", domain.domain, domain.origin_list());
+            } else {
+                info!("Domain created disabled: {}", domain.domain);
+            }
             
             (StatusCode::CREATED, Json(ApiResponse::ok(domain))).into_response()
         }
@@ -105,7 +369,7 @@ async fn create_domain(
 }
 
 async fn get_domain(
-    State((_routes, db)): State<(Arc<RwLock<HashMap<String, String>>>, SqlitePool)>,
+    State(ApiState { db, .. }): State<ApiState>,
     Path(id): Path<i64>,
 ) -> impl IntoResponse {
     match database::get_domain_by_id(&db, id).await {
@@ -123,19 +387,71 @@ async fn get_domain(
     }
 }
 
+//rebuilds the entries for these names from every domain, loaded before taking the lock.
+//a name shared with another domain may change hands, so it can't just be set or removed
+async fn refresh_routes(
+    db: &SqlitePool,
+    routes: &RouteTable,
+    names: impl IntoIterator<Item = String>,
+) -> Result<(), sqlx::Error> {
+    let fresh = routing::table(&database::load_domains(db).await?);
+    let mut routes_map = routes.write().await;
+    for name in names {
+        match fresh.get(&name) {
+            Some(route) => routes_map.insert(name, route.clone()),
+            None => routes_map.remove(&name),
+        };
+    }
+    Ok(())
+}
+
 async fn update_domain(
-    State((routes, db)): State<(Arc<RwLock<HashMap<String, String>>>, SqlitePool)>,
+    State(ApiState { routes, db, .. }): State<ApiState>,
     Path(id): Path<i64>,
     Json(payload): Json<DomainDto>,
 ) -> impl IntoResponse {
-    match database::update_domain(&db, id, &payload.domain, &payload.origin).await {
+    let problems = validation::validate_domain(&payload);
+    if !problems.is_empty() {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(ApiResponse::<()>::err(problems.join("; "))),
+        )
+            .into_response();
+    }
+
+    //all db work happens before taking the routes write lock so proxying
+    //is only blocked for the map swap itself
+    let previous = match database::get_domain_by_id(&db, id).await {
+        Ok(Some(domain)) => domain,
+        Ok(None) => {
+            return (
+                StatusCode::NOT_FOUND,
+                Json(ApiResponse::<()>::err("Domain not found".to_string())),
+            )
+                .into_response()
+        }
+        Err(e) => {
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(ApiResponse::<()>::err(e.to_string())),
+            )
+                .into_response()
+        }
+    };
+
+    match database::update_domain(&db, id, &payload).await {
         Ok(domain) => {
-            // Update in-memory routes immediately
-            let mut routes_map = routes.write().await;
-            routes_map.insert(domain.domain.clone(), domain.origin.clone());
-            drop(routes_map);
+            //old names and aliases too, a dropped one may fall back to another domain
+            let names = routing::names(&previous).into_iter().chain(routing::names(&domain));
+            if let Err(e) = refresh_routes(&db, &routes, names).await {
+                return (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    Json(ApiResponse::<()>::err(e.to_string())),
+                )
+                    .into_response();
+            }
             
-            info!("Domain updated in routes: {} -> {}", domain.domain, domain.origin);
+            info!("Domain updated in routes: {} -> {:?}", domain.domain, domain.origin_list());
             
             Json(ApiResponse::ok(domain)).into_response()
         }
@@ -147,18 +463,135 @@ async fn update_domain(
     }
 }
 
+#[derive(Debug, Deserialize)]
+struct PriorityUpdate {
+    priority: i64,
+}
+
+async fn update_domain_priority(
+    State(ApiState { routes, db, .. }): State<ApiState>,
+    Path(id): Path<i64>,
+    Json(payload): Json<PriorityUpdate>,
+) -> impl IntoResponse {
+    match database::set_domain_priority(&db, id, payload.priority).await {
+        Ok(Some(domain)) => {
+            if let Err(e) = refresh_routes(&db, &routes, routing::names(&domain)).await {
+                return (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    Json(ApiResponse::<()>::err(e.to_string())),
+                )
+                    .into_response();
+            }
+
+            info!("Domain priority updated: {} -> {}", domain.domain, domain.priority);
+
+            Json(ApiResponse::ok(domain)).into_response()
+        }
+        Ok(None) => (
+            StatusCode::NOT_FOUND,
+            Json(ApiResponse::<()>::err("Domain not found".to_string())),
+        )
+            .into_response(),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(ApiResponse::<()>::err(e.to_string())),
+        )
+            .into_response(),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct CutoverQuery {
+    to: Color,
+}
+
+//switches a blue/green domain to the other color, the route swap happens under one write lock
+async fn cutover_domain(
+    State(ApiState { routes, db, .. }): State<ApiState>,
+    Path(id): Path<i64>,
+    Query(query): Query<CutoverQuery>,
+) -> impl IntoResponse {
+    match database::set_domain_color(&db, id, query.to).await {
+        Ok(Some(domain)) => {
+            let entries = if domain.enabled { routing::entries(&domain) } else { Vec::new() };
+            let mut routes_map = routes.write().await;
+            routing::insert(&mut routes_map, entries);
+            drop(routes_map);
+
+            info!("Domain cut over: {} -> {} ({:?})", domain.domain, query.to.as_str(), domain.origin_list());
+
+            Json(ApiResponse::ok(domain)).into_response()
+        }
+        Ok(None) => (
+            StatusCode::NOT_FOUND,
+            Json(ApiResponse::<()>::err("Domain not found or has no blue_green origins".to_string())),
+        )
+            .into_response(),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(ApiResponse::<()>::err(e.to_string())),
+        )
+            .into_response(),
+    }
+}
+
+fn cache_inspection_disabled() -> axum::response::Response {
+    (
+        StatusCode::NOT_FOUND,
+        Json(ApiResponse::<()>::err("Cache inspection is disabled (cache.inspect)".to_string())),
+    )
+        .into_response()
+}
+
+//keys with their remaining TTL and size, bodies are never exposed
+async fn list_cache_keys(
+    State(ApiState { cache, cache_inspect, .. }): State<ApiState>,
+) -> impl IntoResponse {
+    match cache.filter(|_| cache_inspect) {
+        Some(cache) => Json(ApiResponse::ok(cache.entries().await)).into_response(),
+        None => cache_inspection_disabled(),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct CacheEntryQuery {
+    key: String,
+}
+
+async fn get_cache_entry(
+    State(ApiState { cache, cache_inspect, .. }): State<ApiState>,
+    Query(query): Query<CacheEntryQuery>,
+) -> impl IntoResponse {
+    let cache = match cache.filter(|_| cache_inspect) {
+        Some(cache) => cache,
+        None => return cache_inspection_disabled(),
+    };
+    match cache.entry(&query.key).await {
+        Some(entry) => Json(ApiResponse::ok(entry)).into_response(),
+        None => (
+            StatusCode::NOT_FOUND,
+            Json(ApiResponse::<()>::err("Cache entry not found".to_string())),
+        )
+            .into_response(),
+    }
+}
+
 async fn delete_domain(
-    State((routes, db)): State<(Arc<RwLock<HashMap<String, String>>>, SqlitePool)>,
+    State(ApiState { routes, db, .. }): State<ApiState>,
     Path(id): Path<i64>,
 ) -> impl IntoResponse {
     match database::get_domain_by_id(&db, id).await {
         Ok(Some(domain)) => {
             match database::delete_domain(&db, id).await {
                 Ok(_) => {
-                    // Remove from in-memory routes immediately
-                    let mut routes_map = routes.write().await;
-                    routes_map.remove(&domain.domain);
-                    drop(routes_map);
+                    //a name another domain also claims passes to it rather than going away
+                    if let Err(e) = refresh_routes(&db, &routes, routing::names(&domain)).await {
+                        return (
+                            StatusCode::INTERNAL_SERVER_ERROR,
+                            Json(ApiResponse::<()>::err(e.to_string())),
+                        )
+                            .into_response();
+                    }
                     
                     info!("Domain deleted from routes: {}", domain.domain);
                     
@@ -185,7 +618,7 @@ async fn delete_domain(
 }
 
 async fn get_stats(
-    State((_routes, db)): State<(Arc<RwLock<HashMap<String, String>>>, SqlitePool)>,
+    State(ApiState { db, breakers, .. }): State<ApiState>,
 ) -> impl IntoResponse {
     let total = sqlx::query_scalar::<_, i64>("SELECT COUNT(*) FROM request_logs")
         .fetch_one(&db)
@@ -222,13 +655,408 @@ async fn get_stats(
         cache_hit_rate,
         avg_response_time_ms: avg_time.unwrap_or(0.0),
         total_bytes_sent: total_bytes,
+        circuit_breakers: breakers.snapshot(),
     };
 
     Json(ApiResponse::ok(stats))
 }
 
+//the health check state of every configured origin, unprobed ones count as healthy
+async fn get_upstream_health(
+    State(ApiState { routes, health, .. }): State<ApiState>,
+) -> impl IntoResponse {
+    let mut origins: Vec<String> = routes
+        .read()
+        .await
+        .values()
+        .flat_map(|route| route.origins.clone())
+        .collect();
+    origins.sort();
+    origins.dedup();
+
+    Json(ApiResponse::ok(health.snapshot(&origins).await))
+}
+
+//certificates the https listener serves, ACME ones first by domain, then the default
+async fn list_certs(State(ApiState { certs, .. }): State<ApiState>) -> impl IntoResponse {
+    Json(ApiResponse::ok(certs.list()))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct IpRange {
+    pub cidr: String,
+}
+
+async fn get_ip_filter(State(ApiState { ip_filter, .. }): State<ApiState>) -> impl IntoResponse {
+    Json(ApiResponse::ok(ip_filter.snapshot()))
+}
+
+//adds an address or range to the deny list, taking effect on the next request
+async fn block_ip(
+    State(ApiState { db, ip_filter, .. }): State<ApiState>,
+    Json(payload): Json<IpRange>,
+) -> impl IntoResponse {
+    let range = match Cidr::parse(&payload.cidr) {
+        Some(range) => range,
+        None => {
+            return (
+                StatusCode::BAD_REQUEST,
+                Json(ApiResponse::<()>::err(format!("'{}' is not an address or CIDR range", payload.cidr))),
+            )
+                .into_response()
+        }
+    };
+    match ip_filter.block(&db, range).await {
+        Ok(_) => {
+            info!("IP FILTER: blocked {}", range);
+            Json(ApiResponse::ok(ip_filter.snapshot())).into_response()
+        }
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(ApiResponse::<()>::err(e.to_string())),
+        )
+            .into_response(),
+    }
+}
+
+async fn unblock_ip(
+    State(ApiState { db, ip_filter, .. }): State<ApiState>,
+    Json(payload): Json<IpRange>,
+) -> impl IntoResponse {
+    let range = match Cidr::parse(&payload.cidr) {
+        Some(range) => range,
+        None => {
+            return (
+                StatusCode::BAD_REQUEST,
+                Json(ApiResponse::<()>::err(format!("'{}' is not an address or CIDR range", payload.cidr))),
+            )
+                .into_response()
+        }
+    };
+    match ip_filter.unblock(&db, range).await {
+        Ok(true) => {
+            info!("IP FILTER: unblocked {}", range);
+            Json(ApiResponse::ok(ip_filter.snapshot())).into_response()
+        }
+        Ok(false) => (
+            StatusCode::NOT_FOUND,
+            Json(ApiResponse::<()>::err(format!("{} is not on the deny list", range))),
+        )
+            .into_response(),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(ApiResponse::<()>::err(e.to_string())),
+        )
+            .into_response(),
+    }
+}
+
+async fn get_origin_stats(
+    State(ApiState { routes, origin_counters, .. }): State<ApiState>,
+) -> impl IntoResponse {
+    let counts = origin_counters.snapshot();
+    let routes = routes.read().await;
+
+    let mut domain_totals: HashMap<&str, u64> = HashMap::new();
+    for ((domain, _), requests) in &counts {
+        *domain_totals.entry(domain.as_str()).or_default() += requests;
+    }
+
+    let mut stats: Vec<OriginStats> = counts
+        .iter()
+        .map(|((domain, origin), requests)| {
+            let total = domain_totals.get(domain.as_str()).copied().unwrap_or(0);
+            OriginStats {
+                domain: domain.clone(),
+                origin: origin.clone(),
+                weight: routes
+                    .get(domain)
+                    .map(|route| balancer::weight_of(&route.weights, origin))
+                    .unwrap_or(1),
+                requests: *requests,
+                share: if total > 0 {
+                    (*requests as f64 / total as f64) * 100.0
+                } else {
+                    0.0
+                },
+            }
+        })
+        .collect();
+    stats.sort_by(|a, b| (&a.domain, &a.origin).cmp(&(&b.domain, &b.origin)));
+
+    Json(ApiResponse::ok(stats))
+}
+
+//prometheus text format
+//a label value in the text exposition format, with backslash, double quote and line feed escaped
+fn label_value(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+async fn get_metrics(
+    State(ApiState { breakers, tls, metrics, log_writer, cache, .. }): State<ApiState>,
+) -> impl IntoResponse {
+    let snapshot = breakers.snapshot();
+    let mut out = String::new();
+
+    let mut domains: Vec<_> = metrics.snapshot().into_iter().collect();
+    domains.sort_by(|a, b| a.0.cmp(&b.0));
+
+    out.push_str("# HELP proxynet_requests_total Requests answered, by domain and status class\n");
+    out.push_str("# TYPE proxynet_requests_total counter\n");
+    for (domain, counts) in &domains {
+        let mut classes: Vec<_> = counts.requests.iter().collect();
+        classes.sort();
+        for (class, count) in classes {
+            out.push_str(&format!(
+                "proxynet_requests_total{{domain=\"{}\",status=\"{}\"}} {}\n",
+                label_value(domain), class, count,
+            ));
+        }
+    }
+
+    out.push_str("# HELP proxynet_upstream_errors_total Requests that got no usable response from the origin\n");
+    out.push_str("# TYPE proxynet_upstream_errors_total counter\n");
+    for (domain, counts) in &domains {
+        out.push_str(&format!("proxynet_upstream_errors_total{{domain=\"{}\"}} {}\n", label_value(domain), counts.upstream_errors));
+    }
+
+    out.push_str("# HELP proxynet_cache_hits_total Requests answered from the cache\n");
+    out.push_str("# TYPE proxynet_cache_hits_total counter\n");
+    for (domain, counts) in &domains {
+        out.push_str(&format!("proxynet_cache_hits_total{{domain=\"{}\"}} {}\n", label_value(domain), counts.cache_hits));
+    }
+
+    out.push_str("# HELP proxynet_cache_misses_total Cacheable requests the cache had no entry for\n");
+    out.push_str("# TYPE proxynet_cache_misses_total counter\n");
+    for (domain, counts) in &domains {
+        out.push_str(&format!("proxynet_cache_misses_total{{domain=\"{}\"}} {}\n", label_value(domain), counts.cache_misses));
+    }
+
+    out.push_str("# HELP proxynet_response_time_seconds Time from receiving a request to answering it\n");
+    out.push_str("# TYPE proxynet_response_time_seconds histogram\n");
+    for (domain, counts) in &domains {
+        for (bound, count) in LATENCY_BUCKETS.iter().zip(counts.latency_buckets) {
+            out.push_str(&format!(
+                "proxynet_response_time_seconds_bucket{{domain=\"{}\",le=\"{}\"}} {}\n",
+                label_value(domain), bound, count,
+            ));
+        }
+        out.push_str(&format!(
+            "proxynet_response_time_seconds_bucket{{domain=\"{}\",le=\"+Inf\"}} {}\n",
+            label_value(domain), counts.latency_count,
+        ));
+        out.push_str(&format!("proxynet_response_time_seconds_sum{{domain=\"{}\"}} {}\n", label_value(domain), counts.latency_sum));
+        out.push_str(&format!("proxynet_response_time_seconds_count{{domain=\"{}\"}} {}\n", label_value(domain), counts.latency_count));
+    }
+
+    out.push_str("# HELP proxynet_circuit_breaker_state 1 for the state each origin's breaker is in\n");
+    out.push_str("# TYPE proxynet_circuit_breaker_state gauge\n");
+    for breaker in &snapshot {
+        for state in [BreakerState::Closed, BreakerState::Open, BreakerState::HalfOpen] {
+            out.push_str(&format!(
+                "proxynet_circuit_breaker_state{{origin=\"{}\",state=\"{}\"}} {}\n",
+                label_value(&breaker.origin),
+                state.as_str(),
+                (breaker.state == state) as u8,
+            ));
+        }
+    }
+
+    out.push_str("# HELP proxynet_circuit_breaker_trips_total Times each origin's breaker has opened\n");
+    out.push_str("# TYPE proxynet_circuit_breaker_trips_total counter\n");
+    for breaker in &snapshot {
+        out.push_str(&format!(
+            "proxynet_circuit_breaker_trips_total{{origin=\"{}\"}} {}\n",
+            label_value(&breaker.origin), breaker.trips,
+        ));
+    }
+
+    out.push_str("# HELP proxynet_circuit_breaker_last_trip_timestamp_seconds When each origin's breaker last opened\n");
+    out.push_str("# TYPE proxynet_circuit_breaker_last_trip_timestamp_seconds gauge\n");
+    for breaker in &snapshot {
+        if let Some(at) = breaker.last_trip_at {
+            out.push_str(&format!(
+                "proxynet_circuit_breaker_last_trip_timestamp_seconds{{origin=\"{}\"}} {}\n",
+                label_value(&breaker.origin), at,
+            ));
+        }
+    }
+
+    out.push_str("# HELP proxynet_tls_handshake_failures_total TLS handshakes that failed before a request was read\n");
+    out.push_str("# TYPE proxynet_tls_handshake_failures_total counter\n");
+    out.push_str(&format!("proxynet_tls_handshake_failures_total {}\n", tls.handshake_failures()));
+
+    out.push_str("# HELP proxynet_dropped_logs_total Request logs discarded because the log write queue was full\n");
+    out.push_str("# TYPE proxynet_dropped_logs_total counter\n");
+    out.push_str(&format!("proxynet_dropped_logs_total {}\n", log_writer.dropped_total()));
+
+    out.push_str("# HELP proxynet_failed_log_writes_total Request logs lost because the database insert failed\n");
+    out.push_str("# TYPE proxynet_failed_log_writes_total counter\n");
+    out.push_str(&format!("proxynet_failed_log_writes_total {}\n", log_writer.failed_total()));
+
+    if let Some(cache) = cache {
+        out.push_str("# HELP proxynet_cache_entries Responses currently held in the cache\n");
+        out.push_str("# TYPE proxynet_cache_entries gauge\n");
+        out.push_str(&format!("proxynet_cache_entries {}\n", cache.len().await));
+        if let Some(capacity) = cache.capacity() {
+            out.push_str("# HELP proxynet_cache_capacity_bytes Most bytes the cache may hold (cache.capacity_bytes)\n");
+            out.push_str("# TYPE proxynet_cache_capacity_bytes gauge\n");
+            out.push_str(&format!("proxynet_cache_capacity_bytes {}\n", capacity));
+        }
+        if let Some(max) = cache.max_entries() {
+            out.push_str("# HELP proxynet_cache_max_entries Most responses the cache may hold (cache.max_entries)\n");
+            out.push_str("# TYPE proxynet_cache_max_entries gauge\n");
+            out.push_str(&format!("proxynet_cache_max_entries {}\n", max));
+        }
+    }
+
+    ([("content-type", "text/plain; version=0.0.4")], out)
+}
+
+async fn get_domain_stats(
+    State(ApiState { db, limit_counters, .. }): State<ApiState>,
+    Query(query): Query<DomainStatsQuery>,
+) -> impl IntoResponse {
+    let mut limits = limit_counters.snapshot();
+
+    match database::get_domain_stats(&db, query.service.as_deref()).await {
+        Ok(rows) => {
+            let mut stats: Vec<DomainStats> = rows
+                .into_iter()
+                .map(|(domain, total_requests, error_count, avg_response_time_ms, total_bytes_sent)| {
+                    let counts = limits.remove(&domain).unwrap_or_default();
+                    DomainStats {
+                        domain,
+                        total_requests,
+                        error_count,
+                        avg_response_time_ms,
+                        total_bytes_sent,
+                        throttled: counts.throttled,
+                        shed: counts.shed,
+                    }
+                })
+                .collect();
+
+            //domains that were only ever refused have no log rows of their own,
+            //the counters carry no service label so they're left out of a filtered view
+            if query.service.is_some() {
+                limits.clear();
+            }
+            stats.extend(limits.into_iter().map(|(domain, counts): (String, LimitCounts)| DomainStats {
+                domain,
+                total_requests: 0,
+                error_count: 0,
+                avg_response_time_ms: 0.0,
+                total_bytes_sent: 0,
+                throttled: counts.throttled,
+                shed: counts.shed,
+            }));
+
+            Json(ApiResponse::ok(stats)).into_response()
+        }
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(ApiResponse::<()>::err(e.to_string())),
+        )
+            .into_response(),
+    }
+}
+
+async fn list_logs(
+    State(ApiState { db, .. }): State<ApiState>,
+    Query(query): Query<LogQuery>,
+) -> impl IntoResponse {
+    let limit = query.limit.unwrap_or(100).clamp(1, 1000);
+    match database::get_logs(&db, query.request_id.as_deref(), limit).await {
+        Ok(logs) => Json(ApiResponse::ok(logs)).into_response(),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(ApiResponse::<()>::err(e.to_string())),
+        )
+            .into_response(),
+    }
+}
+
+async fn get_status_timeseries(
+    State(ApiState { db, .. }): State<ApiState>,
+    Query(query): Query<TimeseriesQuery>,
+) -> impl IntoResponse {
+    let bucket = query.bucket.unwrap_or(60).clamp(1, 86400);
+    let since = chrono::Utc::now().timestamp() - query.window.unwrap_or(3600).max(bucket);
+    match database::get_status_timeseries(&db, bucket, since, query.group_by, query.service.as_deref()).await {
+        Ok(buckets) => Json(ApiResponse::ok(buckets)).into_response(),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(ApiResponse::<()>::err(e.to_string())),
+        )
+            .into_response(),
+    }
+}
+
+//how much of each domain's error budget the 5xx responses in the window have used
+async fn get_error_budget(
+    State(ApiState { db, .. }): State<ApiState>,
+    Query(query): Query<ErrorBudgetQuery>,
+) -> impl IntoResponse {
+    let target = match query.target {
+        Some(target) => target,
+        None => database::get_config(&db, "slo.target")
+            .await
+            .ok()
+            .flatten()
+            .and_then(|t| t.parse().ok())
+            .unwrap_or(99.9),
+    };
+    if !(0.0..=100.0).contains(&target) {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(ApiResponse::<()>::err("target must be a percentage between 0 and 100".to_string())),
+        )
+            .into_response();
+    }
+    let window = match query.window {
+        Some(window) => window,
+        None => database::get_config(&db, "slo.window_secs")
+            .await
+            .ok()
+            .flatten()
+            .and_then(|w| w.parse().ok())
+            .unwrap_or(30 * 86400),
+    };
+    let since = chrono::Utc::now().timestamp() - window.max(1);
+
+    match database::get_error_counts(&db, since).await {
+        Ok(rows) => {
+            let budgets: Vec<ErrorBudget> = rows
+                .into_iter()
+                .map(|(domain, total, errors)| ErrorBudget::new(domain, total, errors, target))
+                .collect();
+            Json(ApiResponse::ok(budgets)).into_response()
+        }
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(ApiResponse::<()>::err(e.to_string())),
+        )
+            .into_response(),
+    }
+}
+
+async fn list_denials(
+    State(ApiState { db, .. }): State<ApiState>,
+) -> impl IntoResponse {
+    match database::get_denied_requests(&db, 100).await {
+        Ok(denials) => Json(ApiResponse::ok(denials)).into_response(),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(ApiResponse::<()>::err(e.to_string())),
+        )
+            .into_response(),
+    }
+}
+
 async fn get_all_config_endpoint(
-    State((_routes, db)): State<(Arc<RwLock<HashMap<String, String>>>, SqlitePool)>,
+    State(ApiState { db, .. }): State<ApiState>,
 ) -> impl IntoResponse {
     match crate::database::get_all_config(&db).await {
         Ok(config) => Json(ApiResponse::ok(config)).into_response(),
@@ -241,7 +1069,7 @@ async fn get_all_config_endpoint(
 }
 
 async fn get_config_endpoint(
-    State((_routes, db)): State<(Arc<RwLock<HashMap<String, String>>>, SqlitePool)>,
+    State(ApiState { db, .. }): State<ApiState>,
     Path(key): Path<String>,
 ) -> impl IntoResponse {
     match crate::database::get_config(&db, &key).await {
@@ -265,10 +1093,18 @@ struct ConfigUpdate {
 }
 
 async fn update_config_endpoint(
-    State((_routes, db)): State<(Arc<RwLock<HashMap<String, String>>>, SqlitePool)>,
+    State(ApiState { db, .. }): State<ApiState>,
     Path(key): Path<String>,
     Json(payload): Json<ConfigUpdate>,
 ) -> impl IntoResponse {
+    if let Some(problem) = validation::validate_config_value(&key, &payload.value) {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(ApiResponse::<()>::err(problem)),
+        )
+            .into_response();
+    }
+
     match crate::database::set_config(&db, &key, &payload.value).await {
         Ok(_) => {
             info!("Config updated: {} = {}", key, payload.value);
@@ -283,13 +1119,625 @@ async fn update_config_endpoint(
 }
 
 async fn set_config_endpoint(
-    State((_routes, db)): State<(Arc<RwLock<HashMap<String, String>>>, SqlitePool)>,
+    State(ApiState { db, .. }): State<ApiState>,
     Json(payload): Json<std::collections::HashMap<String, String>>,
 ) -> impl IntoResponse {
+    //nothing is written unless every value is valid
+    let mut problems: Vec<String> = payload
+        .iter()
+        .filter_map(|(key, value)| validation::validate_config_value(key, value))
+        .collect();
+    if !problems.is_empty() {
+        problems.sort();
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(ApiResponse::<()>::err(problems.join("; "))),
+        )
+            .into_response();
+    }
+
     for (key, value) in payload.iter() {
         let _ = crate::database::set_config(&db, key, value).await;
         info!("Config set: {} = {}", key, value);
     }
 
     Json(ApiResponse::ok(payload)).into_response()
-}
\ No newline at end of file
+}
+
+//writes a snapshot of the domains and config table right away
+async fn snapshot_config_endpoint(
+    State(ApiState { db, .. }): State<ApiState>,
+) -> impl IntoResponse {
+    let dir = settings::snapshot_dir(&db).await;
+    match snapshot::write(&db, &dir).await {
+        Ok(file) => {
+            info!("SNAPSHOT: wrote {}", file.display());
+            Json(ApiResponse::ok(serde_json::json!({ "file": file.display().to_string() }))).into_response()
+        }
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(ApiResponse::<()>::err(e)),
+        )
+            .into_response(),
+    }
+}
+
+//checks a proposed config (TOML or JSON) without applying any of it
+async fn validate_config_endpoint(body: String) -> impl IntoResponse {
+    let problems = match ConfigDocument::parse(&body) {
+        Ok(doc) => validation::validate_document(&doc),
+        Err(e) => vec![e],
+    };
+
+    Json(ApiResponse::ok(serde_json::json!({
+        "valid": problems.is_empty(),
+        "problems": problems,
+    })))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{body::Body, http::{Method, Request}};
+    use http_body_util::BodyExt;
+    use hyper_util::{client::legacy::Client, rt::TokioExecutor};
+    use serde_json::{json, Value};
+    use std::{collections::HashMap, sync::Arc, time::Duration};
+    use tokio::sync::RwLock;
+    use crate::log_writer::Overflow;
+    use crate::ip_filter::DefaultAction;
+    use crate::logger::RequestLog;
+
+    async fn api_state() -> ApiState {
+        let db = database::test_db().await;
+        ApiState {
+            routes: Arc::new(RwLock::new(HashMap::new())),
+            db: db.clone(),
+            limit_counters: LimitCounters::new(),
+            breakers: CircuitBreakers::new(None),
+            origin_counters: OriginCounters::new(),
+            tls: TlsStats::new(false),
+            cache: None,
+            log_writer: LogWriter::spawn(db, 100, Overflow::Block),
+            cache_inspect: false,
+            health: HealthChecker::new(),
+            certs: CertStore::new(None),
+            ip_filter: IpFilter::new(DefaultAction::Allow, Vec::new(), Vec::new()),
+            metrics: RequestMetrics::new(),
+        }
+    }
+
+    //one request through the api router on a loopback port, the json body of the answer
+    async fn call(state: ApiState, method: Method, uri: &str, body: Option<Value>) -> (StatusCode, Value) {
+        let (status, bytes) = send(state, method, uri, body).await;
+        (status, serde_json::from_slice(&bytes).unwrap_or(Value::Null))
+    }
+
+    //a GET whose answer isn't json, like /metrics
+    async fn text(state: ApiState, uri: &str) -> String {
+        let (_, bytes) = send(state, Method::GET, uri, None).await;
+        String::from_utf8(bytes.to_vec()).unwrap()
+    }
+
+    async fn send(state: ApiState, method: Method, uri: &str, body: Option<Value>) -> (StatusCode, axum::body::Bytes) {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, api_router(state)).await });
+
+        let body = match body {
+            Some(body) => Body::from(body.to_string()),
+            None => Body::empty(),
+        };
+        let req = Request::builder()
+            .method(method)
+            .uri(format!("http://{}{}", addr, uri))
+            .header("content-type", "application/json")
+            .body(body)
+            .unwrap();
+        let client: Client<_, Body> = Client::builder(TokioExecutor::new()).build_http();
+        let response = client.request(req).await.unwrap();
+        let status = response.status();
+        (status, response.into_body().collect().await.unwrap().to_bytes())
+    }
+
+    fn origins(routes: &HashMap<String, routing::Route>, name: &str) -> Vec<String> {
+        routes.get(name).map(|route| route.origins.clone()).unwrap_or_default()
+    }
+
+    #[tokio::test]
+    async fn routes_stay_readable_while_an_update_waits_on_the_database() {
+        let state = api_state().await;
+        let created = json!({"domain": "app.test", "origin": "http://old.test", "enabled": true});
+        let (status, body) = call(state.clone(), Method::POST, "/domains", Some(created)).await;
+        assert_eq!(status, StatusCode::CREATED);
+        let id = body["data"]["id"].as_i64().unwrap();
+
+        //the test database has a single connection, holding it stalls every query of the update
+        let connection = state.db.acquire().await.unwrap();
+        let updated = json!({"domain": "app.test", "origin": "http://new.test", "enabled": true});
+        let update = tokio::spawn({
+            let state = state.clone();
+            async move { call(state, Method::PATCH, &format!("/domains/{}", id), Some(updated)).await }
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        let routes = tokio::time::timeout(Duration::from_millis(100), state.routes.read())
+            .await
+            .expect("routes were locked while the update waited on the database");
+        assert_eq!(origins(&routes, "app.test"), ["http://old.test"]);
+        drop(routes);
+
+        drop(connection);
+        let (status, _) = update.await.unwrap();
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(origins(&*state.routes.read().await, "app.test"), ["http://new.test"]);
+    }
+
+    #[tokio::test]
+    async fn raising_a_priority_takes_over_a_shared_name() {
+        let state = api_state().await;
+        let shop = json!({"domain": "shop.test", "origin": "http://shop.test", "enabled": true});
+        let (_, body) = call(state.clone(), Method::POST, "/domains", Some(shop)).await;
+        let shop_id = body["data"]["id"].as_i64().unwrap();
+        let legacy = json!({
+            "domain": "legacy.test",
+            "origin": "http://legacy.test",
+            "aliases": ["shop.test"],
+            "priority": -1,
+            "enabled": true
+        });
+        call(state.clone(), Method::POST, "/domains", Some(legacy)).await;
+        assert_eq!(origins(&*state.routes.read().await, "shop.test"), ["http://shop.test"]);
+
+        let shop_priority = format!("/domains/{}/priority", shop_id);
+        let (status, body) = call(state.clone(), Method::PATCH, &shop_priority, Some(json!({"priority": -5}))).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["data"]["priority"], -5);
+        assert_eq!(origins(&*state.routes.read().await, "shop.test"), ["http://legacy.test"]);
+        assert_eq!(origins(&*state.routes.read().await, "legacy.test"), ["http://legacy.test"]);
+
+        let (status, _) = call(state.clone(), Method::PATCH, "/domains/999/priority", Some(json!({"priority": 1}))).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    //shop.test at priority 0 and legacy.test at -1, which also claims shop.test as an alias
+    async fn shared_alias(state: &ApiState) -> (i64, i64) {
+        let shop = json!({"domain": "shop.test", "origin": "http://shop.test", "enabled": true});
+        let (_, body) = call(state.clone(), Method::POST, "/domains", Some(shop)).await;
+        let shop_id = body["data"]["id"].as_i64().unwrap();
+        let (_, body) = call(state.clone(), Method::POST, "/domains", Some(legacy("http://legacy.test"))).await;
+        (shop_id, body["data"]["id"].as_i64().unwrap())
+    }
+
+    fn legacy(origin: &str) -> Value {
+        json!({"domain": "legacy.test", "origin": origin, "aliases": ["shop.test"], "priority": -1, "enabled": true})
+    }
+
+    #[tokio::test]
+    async fn updating_or_deleting_the_lower_domain_leaves_a_shared_alias_alone() {
+        let state = api_state().await;
+        let (_, legacy_id) = shared_alias(&state).await;
+        let legacy_uri = format!("/domains/{}", legacy_id);
+
+        let (status, _) = call(state.clone(), Method::PATCH, &legacy_uri, Some(legacy("http://legacy-2.test"))).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(origins(&*state.routes.read().await, "shop.test"), ["http://shop.test"]);
+        assert_eq!(origins(&*state.routes.read().await, "legacy.test"), ["http://legacy-2.test"]);
+
+        let (status, _) = call(state.clone(), Method::DELETE, &legacy_uri, None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(origins(&*state.routes.read().await, "shop.test"), ["http://shop.test"]);
+        assert!(!state.routes.read().await.contains_key("legacy.test"));
+    }
+
+    #[tokio::test]
+    async fn a_shared_alias_falls_back_when_the_higher_domain_lets_it_go() {
+        let state = api_state().await;
+        let (shop_id, _) = shared_alias(&state).await;
+        let shop_uri = format!("/domains/{}", shop_id);
+
+        //renamed away, shop.test passes to legacy.test
+        let store = json!({"domain": "store.test", "origin": "http://shop.test", "enabled": true});
+        let (status, _) = call(state.clone(), Method::PATCH, &shop_uri, Some(store)).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(origins(&*state.routes.read().await, "shop.test"), ["http://legacy.test"]);
+        assert_eq!(origins(&*state.routes.read().await, "store.test"), ["http://shop.test"]);
+
+        //taken back, then deleted
+        let shop = json!({"domain": "shop.test", "origin": "http://shop.test", "enabled": true});
+        call(state.clone(), Method::PATCH, &shop_uri, Some(shop)).await;
+        assert_eq!(origins(&*state.routes.read().await, "shop.test"), ["http://shop.test"]);
+        assert!(!state.routes.read().await.contains_key("store.test"));
+
+        let (status, _) = call(state.clone(), Method::DELETE, &shop_uri, None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(origins(&*state.routes.read().await, "shop.test"), ["http://legacy.test"]);
+    }
+
+    #[tokio::test]
+    async fn a_disabled_domain_is_stored_but_not_routed_until_enabled() {
+        let state = api_state().await;
+        let disabled = json!({"domain": "staging.test", "origin": "http://staging.test", "enabled": false});
+        let (status, body) = call(state.clone(), Method::POST, "/domains", Some(disabled)).await;
+        assert_eq!(status, StatusCode::CREATED);
+        let id = body["data"]["id"].as_i64().unwrap();
+
+        let stored = database::get_domain_by_id(&state.db, id).await.unwrap().unwrap();
+        assert!(!stored.enabled);
+        assert!(database::load_domains(&state.db).await.unwrap().is_empty());
+        assert!(state.routes.read().await.is_empty());
+
+        let enabled = json!({"domain": "staging.test", "origin": "http://staging.test", "enabled": true});
+        let (status, _) = call(state.clone(), Method::PATCH, &format!("/domains/{}", id), Some(enabled)).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(origins(&*state.routes.read().await, "staging.test"), ["http://staging.test"]);
+    }
+
+    #[tokio::test]
+    async fn validate_reports_problems_only_for_a_broken_document() {
+        let state = api_state().await;
+        let valid = json!({
+            "config": {"port": 8080, "rate_limit_per_minute": "120"},
+            "domains": [{"domain": "app.test", "origin": "http://127.0.0.1:3000", "enabled": true}]
+        });
+        let (status, body) = call(state.clone(), Method::POST, "/config/validate", Some(valid)).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["data"]["valid"], true);
+        assert_eq!(body["data"]["problems"], json!([]));
+
+        let invalid = json!({"config": {"port": "eighty"}, "domains": []});
+        let (status, body) = call(state, Method::POST, "/config/validate", Some(invalid)).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["data"]["valid"], false);
+        assert_eq!(body["data"]["problems"].as_array().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn an_invalid_config_value_is_rejected_and_not_stored() {
+        let state = api_state().await;
+        let port = database::get_config(&state.db, "port").await.unwrap();
+        let limit = database::get_config(&state.db, "rate_limit_per_minute").await.unwrap();
+
+        let (status, _) = call(state.clone(), Method::PATCH, "/config/port", Some(json!({"value": "eighty"}))).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(database::get_config(&state.db, "port").await.unwrap(), port);
+
+        let batch = json!({"rate_limit_per_minute": "7", "port": "eighty"});
+        let (status, _) = call(state.clone(), Method::POST, "/config", Some(batch)).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(database::get_config(&state.db, "rate_limit_per_minute").await.unwrap(), limit);
+
+        let (status, _) = call(state.clone(), Method::PATCH, "/config/port", Some(json!({"value": "8081"}))).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(database::get_config(&state.db, "port").await.unwrap().as_deref(), Some("8081"));
+    }
+
+    #[tokio::test]
+    async fn a_tripped_breaker_shows_open_with_its_trip_count() {
+        let mut state = api_state().await;
+        state.breakers = CircuitBreakers::new(Some(crate::breaker::BreakerConfig {
+            failure_threshold: 2,
+            open_for: Duration::from_secs(60),
+            idle_for: None,
+            max_entries: None,
+        }));
+        state.breakers.record("http://flaky.test", true);
+        let (_, body) = call(state.clone(), Method::GET, "/stats", None).await;
+        assert_eq!(body["data"]["circuit_breakers"][0]["state"], "closed");
+        assert_eq!(body["data"]["circuit_breakers"][0]["trips"], 0);
+
+        state.breakers.record("http://flaky.test", false);
+        state.breakers.record("http://flaky.test", false);
+        let snapshot = state.breakers.snapshot();
+        assert_eq!((snapshot[0].state, snapshot[0].trips), (BreakerState::Open, 1));
+
+        let (_, body) = call(state.clone(), Method::GET, "/stats", None).await;
+        assert_eq!(body["data"]["circuit_breakers"][0]["origin"], "http://flaky.test");
+        assert_eq!(body["data"]["circuit_breakers"][0]["state"], "open");
+        assert_eq!(body["data"]["circuit_breakers"][0]["trips"], 1);
+
+        let metrics = text(state, "/metrics").await;
+        let lines: Vec<&str> = metrics.lines().collect();
+        assert!(lines.contains(&r#"proxynet_circuit_breaker_state{origin="http://flaky.test",state="open"} 1"#));
+        assert!(lines.contains(&r#"proxynet_circuit_breaker_state{origin="http://flaky.test",state="closed"} 0"#));
+        assert!(lines.contains(&r#"proxynet_circuit_breaker_trips_total{origin="http://flaky.test"} 1"#));
+    }
+
+    #[tokio::test]
+    async fn cutover_moves_traffic_and_survives_a_reload() {
+        let state = api_state().await;
+        let created = json!({
+            "domain": "app.test",
+            "origin": "http://blue.test",
+            "blue_green": {"blue": "http://blue.test", "green": "http://green.test"},
+            "enabled": true
+        });
+        let (status, body) = call(state.clone(), Method::POST, "/domains", Some(created)).await;
+        assert_eq!(status, StatusCode::CREATED);
+        let id = body["data"]["id"].as_i64().unwrap();
+        assert_eq!(origins(&*state.routes.read().await, "app.test"), ["http://blue.test"]);
+
+        let cutover = format!("/domains/{}/cutover?to=green", id);
+        let (status, body) = call(state.clone(), Method::POST, &cutover, None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["data"]["blue_green"]["active"], "green");
+        assert_eq!(origins(&*state.routes.read().await, "app.test"), ["http://green.test"]);
+
+        //a reload rebuilds the routes from the database, the color was stored there
+        let settings = Arc::new(settings::ProxySettings::load(&state.db).await);
+        let active = Arc::new(std::sync::RwLock::new(settings::ActiveConfig { settings, rate_limiter: None }));
+        crate::reload::reload(&state.db, &state.routes, &active).await.unwrap();
+        assert_eq!(origins(&*state.routes.read().await, "app.test"), ["http://green.test"]);
+    }
+
+    #[tokio::test]
+    async fn status_timeseries_counts_each_class_per_bucket_and_group() {
+        let state = api_state().await;
+        let start = chrono::Utc::now().timestamp() / 60 * 60 - 120;
+        let seed = [
+            ("a.test", "http://a1", 200, 5),
+            ("a.test", "http://a1", 204, 10),
+            ("a.test", "http://a2", 503, 20),
+            ("b.test", "http://b1", 404, 30),
+            ("a.test", "http://a1", 302, 65),
+            ("a.test", "http://a2", 500, 70),
+            //a denial logged without a status is not counted
+            ("a.test", "http://a1", 0, 75),
+        ];
+        for (domain, origin, status, offset) in seed {
+            let mut log = RequestLog::new(domain.into(), "/".into(), "GET".into(), status, std::time::Instant::now())
+                .with_origin(origin);
+            log.timestamp = chrono::DateTime::from_timestamp(start + offset, 0).unwrap();
+            database::save_log(&state.db, &log).await.unwrap();
+        }
+
+        let (status, body) = call(state.clone(), Method::GET, "/stats/status-timeseries?bucket=60", None).await;
+        assert_eq!(status, StatusCode::OK);
+        let counts = |row: &Value| {
+            let classes = ["status_1xx", "status_2xx", "status_3xx", "status_4xx", "status_5xx"];
+            (
+                row["bucket_start"].as_i64().unwrap() - start,
+                row["group"].as_str().unwrap().to_string(),
+                classes.map(|class| row[class].as_i64().unwrap()),
+            )
+        };
+        let rows: Vec<_> = body["data"].as_array().unwrap().iter().map(counts).collect();
+        assert_eq!(
+            rows,
+            [
+                (0, "a.test".to_string(), [0, 2, 0, 0, 1]),
+                (0, "b.test".to_string(), [0, 0, 0, 1, 0]),
+                (60, "a.test".to_string(), [0, 0, 1, 0, 1]),
+            ]
+        );
+
+        let (_, body) = call(state, Method::GET, "/stats/status-timeseries?bucket=60&group_by=origin", None).await;
+        let rows: Vec<_> = body["data"].as_array().unwrap().iter().map(counts).collect();
+        assert_eq!(
+            rows,
+            [
+                (0, "http://a1".to_string(), [0, 2, 0, 0, 0]),
+                (0, "http://a2".to_string(), [0, 0, 0, 0, 1]),
+                (0, "http://b1".to_string(), [0, 0, 0, 1, 0]),
+                (60, "http://a1".to_string(), [0, 0, 1, 0, 0]),
+                (60, "http://a2".to_string(), [0, 0, 0, 0, 1]),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn cache_keys_and_entries_report_their_ttl_and_size() {
+        let mut state = api_state().await;
+        let cache = MemoryCache::new();
+        let page = crate::cache::CachedResponse::new(200, &axum::http::HeaderMap::new(), "page body".into());
+        let moved = crate::cache::CachedResponse::new(301, &axum::http::HeaderMap::new(), "".into());
+        cache.set("cache:a.test:/page".to_string(), page, 60).await;
+        cache.set("cache:a.test:/old".to_string(), moved, 600).await;
+        state.cache = Some(cache);
+
+        //listing keys is off unless cache.inspect is set
+        let (status, _) = call(state.clone(), Method::GET, "/cache/keys", None).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        state.cache_inspect = true;
+
+        let (status, body) = call(state.clone(), Method::GET, "/cache/keys", None).await;
+        assert_eq!(status, StatusCode::OK);
+        let keys: Vec<_> = body["data"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|entry| (entry["key"].as_str().unwrap(), entry["status"].as_u64().unwrap()))
+            .collect();
+        assert_eq!(keys, [("cache:a.test:/old", 301), ("cache:a.test:/page", 200)]);
+        let ttls: Vec<_> = body["data"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|entry| entry["ttl_secs"].as_i64().unwrap())
+            .collect();
+        assert!((599..=600).contains(&ttls[0]) && (59..=60).contains(&ttls[1]), "{:?}", ttls);
+        assert!(body["data"][0].get("body").is_none());
+        let listed_size = body["data"][1]["size_bytes"].as_u64().unwrap();
+        assert!(listed_size >= ("cache:a.test:/page".len() + "page body".len()) as u64);
+
+        let (status, body) = call(state.clone(), Method::GET, "/cache/entry?key=cache:a.test:/page", None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["data"]["size_bytes"], listed_size);
+        assert!((59..=60).contains(&body["data"]["ttl_secs"].as_i64().unwrap()));
+
+        let (status, _) = call(state, Method::GET, "/cache/entry?key=cache:a.test:/missing", None).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn dropped_logs_are_exported_as_a_metric() {
+        let mut state = api_state().await;
+        state.log_writer = LogWriter::spawn(state.db.clone(), 1, Overflow::DropNewest);
+        for _ in 0..4 {
+            let log = RequestLog::new("app.test".into(), "/".into(), "GET".into(), 200, std::time::Instant::now());
+            state.log_writer.push(log).await;
+        }
+
+        let metrics = text(state, "/metrics").await;
+        assert!(metrics.lines().any(|line| line == "proxynet_dropped_logs_total 3"), "{}", metrics);
+    }
+
+    //a metric name, its labels and the value
+    type Sample = (String, Vec<(String, String)>, f64);
+
+    //one sample line of the text exposition format, label escapes undone. None if the
+    //line doesn't follow the format
+    fn parse_sample(line: &str) -> Option<Sample> {
+        let name_end = line.find(['{', ' '])?;
+        let (name, mut rest) = line.split_at(name_end);
+        let mut labels = Vec::new();
+        if let Some(mut chars) = rest.strip_prefix('{').map(|r| r.chars()) {
+            loop {
+                let label: String = chars.by_ref().take_while(|c| *c != '=').collect();
+                if chars.next()? != '"' {
+                    return None;
+                }
+                let mut value = String::new();
+                loop {
+                    match chars.next()? {
+                        '\\' => value.push(match chars.next()? {
+                            '\\' => '\\',
+                            '"' => '"',
+                            'n' => '\n',
+                            _ => return None,
+                        }),
+                        '"' => break,
+                        '\n' => return None,
+                        c => value.push(c),
+                    }
+                }
+                labels.push((label, value));
+                match chars.next()? {
+                    ',' => continue,
+                    '}' => break,
+                    _ => return None,
+                }
+            }
+            rest = chars.as_str();
+        }
+        let value = match rest.strip_prefix(' ')? {
+            "+Inf" => f64::INFINITY,
+            value => value.parse().ok()?,
+        };
+        Some((name.to_string(), labels, value))
+    }
+
+    #[tokio::test]
+    async fn metrics_parse_with_awkward_label_values() {
+        let state = api_state().await;
+        let host = "we\"ird\\host\nname";
+        state.metrics.record(host, 200, Duration::from_millis(30), false);
+        state.metrics.record(host, 502, Duration::from_millis(30), true);
+        state.metrics.cache_lookup(host, true);
+
+        let metrics = text(state, "/metrics").await;
+        let mut samples = Vec::new();
+        for line in metrics.lines().filter(|line| !line.starts_with('#')) {
+            samples.push(parse_sample(line).unwrap_or_else(|| panic!("unparseable line {:?} in\n{}", line, metrics)));
+        }
+
+        let value = |name: &str, labels: &[(&str, &str)]| {
+            samples
+                .iter()
+                .find(|(n, l, _)| n == name && l.iter().map(|(k, v)| (k.as_str(), v.as_str())).eq(labels.iter().copied()))
+                .map(|(_, _, value)| *value)
+        };
+        assert_eq!(value("proxynet_requests_total", &[("domain", host), ("status", "2xx")]), Some(1.0));
+        assert_eq!(value("proxynet_requests_total", &[("domain", host), ("status", "5xx")]), Some(1.0));
+        assert_eq!(value("proxynet_upstream_errors_total", &[("domain", host)]), Some(1.0));
+        assert_eq!(value("proxynet_cache_hits_total", &[("domain", host)]), Some(1.0));
+        assert_eq!(value("proxynet_response_time_seconds_bucket", &[("domain", host), ("le", "+Inf")]), Some(2.0));
+    }
+
+    #[tokio::test]
+    async fn stats_are_grouped_and_filtered_by_service() {
+        let state = api_state().await;
+        let start = chrono::Utc::now().timestamp() / 60 * 60 - 60;
+        let seed = [
+            ("shop.test", Some("checkout"), 200),
+            ("pay.test", Some("checkout"), 502),
+            ("pay.test", Some("checkout"), 200),
+            ("find.test", Some("search"), 200),
+            ("misc.test", None, 200),
+        ];
+        for (domain, service, status) in seed {
+            let mut log = RequestLog::new(domain.into(), "/".into(), "GET".into(), status, std::time::Instant::now())
+                .with_service(service);
+            log.timestamp = chrono::DateTime::from_timestamp(start + 10, 0).unwrap();
+            database::save_log(&state.db, &log).await.unwrap();
+        }
+
+        let (_, body) = call(state.clone(), Method::GET, "/stats/domains?service=checkout", None).await;
+        let domains: Vec<_> = body["data"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|row| (row["domain"].as_str().unwrap(), row["total_requests"].as_i64().unwrap()))
+            .collect();
+        assert_eq!(domains, [("pay.test", 2), ("shop.test", 1)]);
+
+        let (_, body) = call(state.clone(), Method::GET, "/stats/domains", None).await;
+        assert_eq!(body["data"].as_array().unwrap().len(), 4);
+
+        let (_, body) = call(state.clone(), Method::GET, "/stats/status-timeseries?group_by=service", None).await;
+        let groups: Vec<_> = body["data"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|row| {
+                (
+                    row["group"].as_str().unwrap(),
+                    row["status_2xx"].as_i64().unwrap(),
+                    row["status_5xx"].as_i64().unwrap(),
+                )
+            })
+            .collect();
+        //unlabelled requests belong to no service
+        assert_eq!(groups, [("checkout", 2, 1), ("search", 1, 0)]);
+
+        let (_, body) = call(state, Method::GET, "/stats/status-timeseries?service=search", None).await;
+        let rows = body["data"].as_array().unwrap();
+        assert_eq!(rows.len(), 1);
+        assert_eq!((rows[0]["group"].as_str(), rows[0]["status_2xx"].as_i64()), (Some("find.test"), Some(1)));
+    }
+
+    #[tokio::test]
+    async fn error_budget_reflects_a_known_error_rate() {
+        let state = api_state().await;
+        let now = chrono::Utc::now().timestamp();
+        let mut seed = Vec::new();
+        //0.5% errors against a 1% budget, and 2% errors that blow it
+        seed.extend((0..200).map(|i| ("a.test", if i == 0 { 500 } else { 200 }, now - 60)));
+        seed.extend((0..50).map(|i| ("b.test", if i == 0 { 503 } else { 404 }, now - 60)));
+        //outside the window, and a denial logged without a status
+        seed.push(("a.test", 500, now - 7200));
+        seed.push(("a.test", 0, now - 60));
+        for (domain, status, timestamp) in seed {
+            let mut log = RequestLog::new(domain.into(), "/".into(), "GET".into(), status, std::time::Instant::now());
+            log.timestamp = chrono::DateTime::from_timestamp(timestamp, 0).unwrap();
+            database::save_log(&state.db, &log).await.unwrap();
+        }
+
+        let (status, body) = call(state.clone(), Method::GET, "/stats/error-budget?window=3600&target=99", None).await;
+        assert_eq!(status, StatusCode::OK);
+        let close = |value: &Value, expected: f64| (value.as_f64().unwrap() - expected).abs() < 1e-9;
+        let a = &body["data"][0];
+        assert_eq!(
+            (a["domain"].as_str(), a["total_requests"].as_i64(), a["error_count"].as_i64()),
+            (Some("a.test"), Some(200), Some(1))
+        );
+        assert!(close(&a["availability"], 99.5), "{}", a);
+        assert!(close(&a["budget_consumed"], 0.5) && close(&a["budget_remaining"], 0.5), "{}", a);
+        let b = &body["data"][1];
+        assert_eq!((b["domain"].as_str(), b["error_count"].as_i64()), (Some("b.test"), Some(1)));
+        assert!(close(&b["budget_consumed"], 2.0) && close(&b["budget_remaining"], -1.0), "{}", b);
+
+        let (status, _) = call(state, Method::GET, "/stats/error-budget?target=101", None).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+}
@@ -0,0 +1,143 @@
+use std::time::Duration;
+use tracing::debug;
+use crate::cache::MemoryCache;
+use crate::rate_limiter::{PathLimiters, RateLimiter};
+use crate::settings::ActiveConfigHandle;
+
+//how often the budget is checked besides after every cache store, this is what
+//bounds rate limiter growth between stores
+const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+//one memory budget shared by the response cache and the rate limiter maps.
+//sizes are estimates: payloads and keys plus a fixed per-entry overhead
+#[derive(Clone)]
+pub struct AuxMemory {
+    budget: usize,
+    cache: Option<MemoryCache>,
+    //the global limiter is whichever one the active config has
+    active: ActiveConfigHandle,
+    path_limiters: PathLimiters,
+}
+
+impl AuxMemory {
+    pub fn new(budget: usize, cache: Option<MemoryCache>, active: ActiveConfigHandle, path_limiters: PathLimiters) -> Self {
+        Self {
+            budget,
+            cache,
+            active,
+            path_limiters,
+        }
+    }
+
+    fn limiters(&self) -> Vec<RateLimiter> {
+        let rate_limiter = self.active.read().unwrap().rate_limiter.clone();
+        rate_limiter.into_iter().chain(self.path_limiters.all()).collect()
+    }
+
+    //trims both back under the budget, each giving up its share of the excess.
+    //the cache drops expired and then soonest-expiring entries, the limiters the
+    //clients seen longest ago; whatever one can't free is taken from the other
+    pub async fn enforce(&self) {
+        let cache_bytes = match &self.cache {
+            Some(cache) => cache.estimated_bytes().await,
+            None => 0,
+        };
+        let mut limiters = Vec::new();
+        for limiter in self.limiters() {
+            let bytes = limiter.estimated_bytes().await;
+            limiters.push((bytes, limiter));
+        }
+        let limiter_bytes: usize = limiters.iter().map(|(bytes, _)| bytes).sum();
+
+        let total = cache_bytes + limiter_bytes;
+        if total <= self.budget {
+            return;
+        }
+        let excess = total - self.budget;
+        let mut remaining = excess;
+
+        if let Some(cache) = &self.cache {
+            let share = (excess as u128 * cache_bytes as u128).div_ceil(total as u128) as usize;
+            remaining = remaining.saturating_sub(cache.shrink(share).await);
+        }
+
+        //the biggest limiters give up their keys first
+        limiters.sort_by_key(|(bytes, _)| std::cmp::Reverse(*bytes));
+        for (_, limiter) in &limiters {
+            if remaining == 0 {
+                break;
+            }
+            remaining = remaining.saturating_sub(limiter.shrink(remaining).await);
+        }
+
+        if let (Some(cache), true) = (&self.cache, remaining > 0) {
+            remaining = remaining.saturating_sub(cache.shrink(remaining).await);
+        }
+
+        debug!(
+            "AUX MEMORY: ~{} bytes over the {} byte budget, freed ~{}",
+            excess,
+            self.budget,
+            excess - remaining
+        );
+    }
+
+    pub fn spawn(&self) {
+        let memory = self.clone();
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(CHECK_INTERVAL).await;
+                memory.enforce().await;
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::HeaderMap;
+    use bytes::Bytes;
+    use std::sync::{Arc, RwLock};
+    use crate::cache::CachedResponse;
+    use crate::database;
+    use crate::routing::PathRateLimit;
+    use crate::settings::{ActiveConfig, ProxySettings};
+
+    #[tokio::test]
+    async fn a_full_cache_and_limiters_are_trimmed_under_the_budget_together() {
+        let db = database::test_db().await;
+        let settings = Arc::new(ProxySettings::load(&db).await);
+        let rate_limiter = RateLimiter::new(100, 60);
+        let active = Arc::new(RwLock::new(ActiveConfig { settings, rate_limiter: Some(rate_limiter.clone()) }));
+        let path_limiters = PathLimiters::new();
+        let rule = PathRateLimit { prefix: "/api".to_string(), limit: 10, window_secs: 60 };
+        let path_limiter = path_limiters.limiter("app.test", &rule);
+        let cache = MemoryCache::new();
+
+        for i in 0..200u64 {
+            let response = CachedResponse::new(200, &HeaderMap::new(), Bytes::from(vec![b'x'; 1024]));
+            cache.set(format!("cache:app.test:/{}", i), response, 60 + i).await;
+        }
+        for i in 0..2000 {
+            rate_limiter.check(&format!("10.0.{}.{}", i / 256, i % 256)).await;
+            path_limiter.check(&format!("10.1.{}.{}", i / 256, i % 256)).await;
+        }
+        let estimate = || async {
+            cache.estimated_bytes().await + rate_limiter.estimated_bytes().await + path_limiter.estimated_bytes().await
+        };
+        let budget = 128 * 1024;
+        assert!(estimate().await > 2 * budget);
+
+        AuxMemory::new(budget, Some(cache.clone()), active, path_limiters).enforce().await;
+
+        let after = estimate().await;
+        assert!(after <= budget, "~{} bytes held against a {} byte budget", after, budget);
+        //the cache and the limiters each gave up a share rather than one being emptied for the other
+        assert!(cache.len().await > 0);
+        assert!(rate_limiter.estimated_bytes().await + path_limiter.estimated_bytes().await > 0);
+        //the cache let go of the entries closest to expiring
+        assert!(cache.get("cache:app.test:/199").await.is_some());
+        assert!(cache.get("cache:app.test:/0").await.is_none());
+    }
+}
@@ -0,0 +1,399 @@
+use axum::http::HeaderMap;
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap},
+    hash::{Hash, Hasher},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+};
+use crate::stats::InFlight;
+
+//picks which origin of a domain serves a request. origins missing from
+//`weights` have weight 1, strategies that don't weigh origins ignore it
+pub trait OriginSelector: Send + Sync {
+    fn select<'a>(
+        &self,
+        domain: &str,
+        origins: &'a [String],
+        weights: &HashMap<String, u32>,
+        key: &str,
+    ) -> Option<&'a str>;
+
+    //whether `weights` affects the pick, slow start ramps weights for these
+    fn weighs_origins(&self) -> bool {
+        false
+    }
+}
+
+pub fn from_config(strategy: &str, in_flight: &InFlight) -> Arc<dyn OriginSelector> {
+    match strategy {
+        "first" => Arc::new(First),
+        "consistent_hash" => Arc::new(ConsistentHash::new(100)),
+        "weighted_round_robin" => Arc::new(WeightedRoundRobin::new()),
+        "least_connections" => Arc::new(LeastConnections::new(in_flight.clone())),
+        _ => Arc::new(RoundRobin::new()),
+    }
+}
+
+pub fn weight_of(weights: &HashMap<String, u32>, origin: &str) -> u32 {
+    weights.get(origin).copied().unwrap_or(1)
+}
+
+//request attribute used as the key for hashing strategies
+#[derive(Debug, Clone)]
+pub enum HashKey {
+    Path,
+    ClientIp,
+    Header(String),
+}
+
+impl HashKey {
+    //accepts "path", "ip" or "header:<name>"
+    pub fn parse(value: &str) -> Self {
+        match value {
+            "ip" => HashKey::ClientIp,
+            v if v.starts_with("header:") => HashKey::Header(v["header:".len()..].to_lowercase()),
+            _ => HashKey::Path,
+        }
+    }
+
+    pub fn extract(&self, path: &str, headers: &HeaderMap, client_ip: &str) -> String {
+        match self {
+            HashKey::Path => path.to_string(),
+            HashKey::ClientIp => client_ip.to_string(),
+            HashKey::Header(name) => headers
+                .get(name.as_str())
+                .and_then(|h| h.to_str().ok())
+                .unwrap_or("")
+                .to_string(),
+        }
+    }
+}
+
+//always uses the first origin
+pub struct First;
+
+impl OriginSelector for First {
+    fn select<'a>(
+        &self,
+        _domain: &str,
+        origins: &'a [String],
+        _weights: &HashMap<String, u32>,
+        _key: &str,
+    ) -> Option<&'a str> {
+        origins.first().map(String::as_str)
+    }
+}
+
+//takes each origin in turn, one counter per domain. weights are ignored
+pub struct RoundRobin {
+    next: Mutex<HashMap<String, Arc<AtomicUsize>>>,
+}
+
+impl RoundRobin {
+    pub fn new() -> Self {
+        Self {
+            next: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+impl OriginSelector for RoundRobin {
+    fn select<'a>(
+        &self,
+        domain: &str,
+        origins: &'a [String],
+        _weights: &HashMap<String, u32>,
+        _key: &str,
+    ) -> Option<&'a str> {
+        if origins.len() <= 1 {
+            return origins.first().map(String::as_str);
+        }
+
+        let counter = self
+            .next
+            .lock()
+            .unwrap()
+            .entry(domain.to_string())
+            .or_default()
+            .clone();
+        let idx = counter.fetch_add(1, Ordering::Relaxed) % origins.len();
+        origins.get(idx).map(String::as_str)
+    }
+}
+
+//the origins last seen for a domain and their running weights
+type RunningWeights = (Vec<String>, Vec<i64>);
+
+//smooth weighted round-robin: each origin gets requests in proportion to its
+//weight, interleaved rather than in runs. weight 0 takes no traffic
+pub struct WeightedRoundRobin {
+    state: Mutex<HashMap<String, RunningWeights>>,
+}
+
+impl WeightedRoundRobin {
+    pub fn new() -> Self {
+        Self {
+            state: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+impl OriginSelector for WeightedRoundRobin {
+    fn select<'a>(
+        &self,
+        domain: &str,
+        origins: &'a [String],
+        weights: &HashMap<String, u32>,
+        _key: &str,
+    ) -> Option<&'a str> {
+        if origins.len() <= 1 {
+            return origins.first().map(String::as_str);
+        }
+
+        let mut state = self.state.lock().unwrap();
+        let (seen, current) = state
+            .entry(domain.to_string())
+            .or_insert_with(|| (Vec::new(), Vec::new()));
+
+        //origin set changed (or first use), start over
+        if seen.as_slice() != origins {
+            *seen = origins.to_vec();
+            *current = vec![0; origins.len()];
+        }
+
+        let mut total = 0;
+        let mut best: Option<usize> = None;
+        for (idx, origin) in origins.iter().enumerate() {
+            let weight = weight_of(weights, origin) as i64;
+            if weight == 0 {
+                continue;
+            }
+            current[idx] += weight;
+            total += weight;
+            if best.map(|b| current[idx] > current[b]).unwrap_or(true) {
+                best = Some(idx);
+            }
+        }
+
+        let idx = best?;
+        current[idx] -= total;
+        origins.get(idx).map(String::as_str)
+    }
+
+    fn weighs_origins(&self) -> bool {
+        true
+    }
+}
+
+//sends each request to the origin with the fewest requests in flight, ties
+//are broken by rotating the starting point so idle origins share the load
+pub struct LeastConnections {
+    in_flight: InFlight,
+    next: AtomicUsize,
+}
+
+impl LeastConnections {
+    pub fn new(in_flight: InFlight) -> Self {
+        Self {
+            in_flight,
+            next: AtomicUsize::new(0),
+        }
+    }
+}
+
+impl OriginSelector for LeastConnections {
+    fn select<'a>(
+        &self,
+        _domain: &str,
+        origins: &'a [String],
+        _weights: &HashMap<String, u32>,
+        _key: &str,
+    ) -> Option<&'a str> {
+        if origins.len() <= 1 {
+            return origins.first().map(String::as_str);
+        }
+
+        let start = self.next.fetch_add(1, Ordering::Relaxed) % origins.len();
+        (0..origins.len())
+            .map(|offset| &origins[(start + offset) % origins.len()])
+            .min_by_key(|origin| self.in_flight.count(origin))
+            .map(String::as_str)
+    }
+}
+
+//(hash, origin index) points sorted by hash
+type Ring = Arc<Vec<(u64, usize)>>;
+
+//maps keys onto a hash ring so adding or removing an origin only remaps
+//the keys that fell on its segments
+pub struct ConsistentHash {
+    replicas: u32,
+    rings: Mutex<HashMap<String, (Vec<String>, Ring)>>,
+}
+
+impl ConsistentHash {
+    pub fn new(replicas: u32) -> Self {
+        Self {
+            replicas: replicas.max(1),
+            rings: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn build_ring(&self, origins: &[String]) -> Vec<(u64, usize)> {
+        let mut ring = Vec::with_capacity(origins.len() * self.replicas as usize);
+        for (idx, origin) in origins.iter().enumerate() {
+            for replica in 0..self.replicas {
+                ring.push((hash_of(&format!("{}#{}", origin, replica)), idx));
+            }
+        }
+        ring.sort_unstable();
+        ring
+    }
+
+    fn ring_for(&self, domain: &str, origins: &[String]) -> Ring {
+        let mut rings = self.rings.lock().unwrap();
+        if let Some((cached_origins, ring)) = rings.get(domain)
+            && cached_origins.as_slice() == origins
+        {
+            return ring.clone();
+        }
+
+        //origin set changed (or first use), rebuild the ring
+        let ring = Arc::new(self.build_ring(origins));
+        rings.insert(domain.to_string(), (origins.to_vec(), ring.clone()));
+        ring
+    }
+}
+
+impl OriginSelector for ConsistentHash {
+    fn select<'a>(
+        &self,
+        domain: &str,
+        origins: &'a [String],
+        _weights: &HashMap<String, u32>,
+        key: &str,
+    ) -> Option<&'a str> {
+        if origins.len() <= 1 {
+            return origins.first().map(String::as_str);
+        }
+
+        let ring = self.ring_for(domain, origins);
+        let point = hash_of(key);
+        let pos = ring.partition_point(|(h, _)| *h < point);
+        let (_, idx) = ring.get(pos).or_else(|| ring.first())?;
+        origins.get(*idx).map(String::as_str)
+    }
+}
+
+fn hash_of(value: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    value.hash(&mut hasher);
+    hasher.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn origins(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    #[test]
+    fn consistent_hash_moves_few_keys_when_origins_change() {
+        let balancer = ConsistentHash::new(100);
+        let three = origins(&["http://a", "http://b", "http://c"]);
+        let four = origins(&["http://a", "http://b", "http://c", "http://d"]);
+        let keys: Vec<String> = (0..1000).map(|i| format!("/item/{}", i)).collect();
+        let pick = |origins: &[String], key: &str| {
+            balancer.select("app.local", origins, &HashMap::new(), key).unwrap().to_string()
+        };
+
+        //adding an origin only moves keys onto it, about a quarter of them
+        let mut moved = 0;
+        for key in &keys {
+            let before = pick(&three, key);
+            let after = pick(&four, key);
+            if before != after {
+                assert_eq!(after, "http://d");
+                moved += 1;
+            }
+        }
+        assert!(moved > 0 && moved < 400, "{} of 1000 keys moved", moved);
+
+        //removing an origin only moves the keys it had, the rest stay where they were
+        let two = origins(&["http://a", "http://b"]);
+        for key in &keys {
+            let before = pick(&three, key);
+            if before != "http://c" {
+                assert_eq!(pick(&two, key), before);
+            }
+        }
+    }
+
+    #[test]
+    fn round_robin_alternates_between_two_origins() {
+        let balancer = from_config("round_robin", &InFlight::new());
+        let origins = origins(&["http://a", "http://b"]);
+        let picks: Vec<&str> = (0..4)
+            .filter_map(|_| balancer.select("app.local", &origins, &HashMap::new(), ""))
+            .collect();
+        assert_eq!(picks, vec!["http://a", "http://b", "http://a", "http://b"]);
+    }
+
+    #[test]
+    fn weighted_round_robin_splits_traffic_by_weight() {
+        let balancer = from_config("weighted_round_robin", &InFlight::new());
+        let origins = origins(&["http://a", "http://b", "http://c", "http://d"]);
+        let weights: HashMap<String, u32> = [("http://a", 5), ("http://b", 3), ("http://d", 0)]
+            .into_iter()
+            .map(|(origin, weight)| (origin.to_string(), weight))
+            .collect();
+
+        let mut counts: HashMap<&str, usize> = HashMap::new();
+        let mut longest_run = 0;
+        let mut run = 0;
+        let mut previous = "";
+        for _ in 0..900 {
+            let pick = balancer.select("app.local", &origins, &weights, "").unwrap();
+            *counts.entry(pick).or_default() += 1;
+            run = if pick == previous { run + 1 } else { 1 };
+            longest_run = longest_run.max(run);
+            previous = pick;
+        }
+
+        //c has the default weight of 1, d's weight of 0 keeps it out entirely
+        assert_eq!(counts.get("http://a"), Some(&500));
+        assert_eq!(counts.get("http://b"), Some(&300));
+        assert_eq!(counts.get("http://c"), Some(&100));
+        assert_eq!(counts.get("http://d"), None);
+        //interleaved, never a's five turns in a row
+        assert!(longest_run < 5, "{} picks of one origin in a row", longest_run);
+    }
+
+    #[test]
+    fn least_connections_favours_the_origin_that_finishes_sooner() {
+        let in_flight = InFlight::new();
+        let balancer = from_config("least_connections", &in_flight);
+        let origins = origins(&["http://slow", "http://fast"]);
+        let takes = |origin: &str| if origin == "http://slow" { 6 } else { 1 };
+
+        //two requests arrive per tick, each holds its origin until its own completion tick
+        let mut pending = Vec::new();
+        let mut counts: HashMap<&str, usize> = HashMap::new();
+        for tick in 0..100 {
+            pending.retain(|(done, _)| *done > tick);
+            for _ in 0..2 {
+                let origin = balancer.select("app.local", &origins, &HashMap::new(), "").unwrap();
+                *counts.entry(origin).or_default() += 1;
+                pending.push((tick + takes(origin), in_flight.start(origin)));
+            }
+        }
+
+        let (slow, fast) = (counts["http://slow"], counts["http://fast"]);
+        assert!(fast > slow * 2, "fast {} vs slow {}", fast, slow);
+        assert!(slow > 0);
+    }
+}
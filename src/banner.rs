@@ -0,0 +1,47 @@
+use tracing::info;
+
+//config keys whose values never show up in logs
+const SENSITIVE: [&str; 7] = ["password", "secret", "token", "api_key", "apikey", "credential", "private_key"];
+
+const REDACTED: &str = "[redacted]";
+
+fn is_sensitive(key: &str) -> bool {
+    let key = key.to_ascii_lowercase();
+    SENSITIVE.iter().any(|word| key.contains(word))
+}
+
+fn redact<'a>(key: &str, value: &'a str) -> &'a str {
+    if is_sensitive(key) {
+        REDACTED
+    } else {
+        value
+    }
+}
+
+//what the proxy resolved at startup, next to everything stored in the config table
+pub struct Banner {
+    pub resolved: Vec<(&'static str, String)>,
+    pub stored: Vec<(String, String)>,
+}
+
+impl Banner {
+    //one block, so the effective config can be read (or grepped) as a whole
+    pub fn render(&self) -> String {
+        let mut out = String::from("EFFECTIVE CONFIG");
+        for (key, value) in &self.resolved {
+            out.push_str(&format!("\n  {} = {}", key, redact(key, value)));
+        }
+
+        let mut stored: Vec<&(String, String)> = self.stored.iter().collect();
+        stored.sort();
+        out.push_str("\n  [config table]");
+        for (key, value) in stored {
+            out.push_str(&format!("\n  {} = {}", key, redact(key, value)));
+        }
+        out
+    }
+
+    pub fn log(&self) {
+        info!("{}", self.render());
+    }
+}
@@ -0,0 +1,255 @@
+use serde::Serialize;
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tracing::{info, warn};
+use crate::slow_start::SlowStart;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BreakerState {
+    //requests flow normally
+    Closed,
+    //the origin is skipped until open_for has passed
+    Open,
+    //the origin gets traffic again, the next failure reopens it
+    HalfOpen,
+}
+
+impl BreakerState {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            BreakerState::Closed => "closed",
+            BreakerState::Open => "open",
+            BreakerState::HalfOpen => "half_open",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct BreakerConfig {
+    //consecutive failures that open the circuit
+    pub failure_threshold: u32,
+    pub open_for: Duration,
+    //breakers for origins not used this long are forgotten
+    pub idle_for: Option<Duration>,
+    //the most origins tracked at once, the least recently used goes first
+    pub max_entries: Option<usize>,
+}
+
+struct Breaker {
+    state: BreakerState,
+    failures: u32,
+    opened_at: Option<Instant>,
+    trips: u64,
+    last_trip_at: Option<i64>,
+    last_seen: Instant,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct BreakerSnapshot {
+    pub origin: String,
+    pub state: BreakerState,
+    pub trips: u64,
+    //unix seconds of the most recent trip
+    pub last_trip_at: Option<i64>,
+}
+
+//one breaker per origin, disabled entirely when there's no config
+#[derive(Clone)]
+pub struct CircuitBreakers {
+    config: Option<BreakerConfig>,
+    inner: Arc<Mutex<HashMap<String, Breaker>>>,
+    slow_start: SlowStart,
+}
+
+impl CircuitBreakers {
+    pub fn new(config: Option<BreakerConfig>) -> Self {
+        Self {
+            config,
+            inner: Arc::new(Mutex::new(HashMap::new())),
+            slow_start: SlowStart::new(None),
+        }
+    }
+
+    pub fn with_slow_start(mut self, slow_start: SlowStart) -> Self {
+        self.slow_start = slow_start;
+        self
+    }
+
+    pub fn allow(&self, origin: &str) -> bool {
+        let config = match &self.config {
+            Some(config) => config,
+            None => return true,
+        };
+
+        let mut breakers = self.inner.lock().unwrap();
+        let breaker = match breakers.get_mut(origin) {
+            Some(breaker) => breaker,
+            None => return true,
+        };
+        breaker.last_seen = Instant::now();
+
+        if breaker.state == BreakerState::Open {
+            let cooled_down = breaker
+                .opened_at
+                .map(|at| at.elapsed() >= config.open_for)
+                .unwrap_or(true);
+            if !cooled_down {
+                return false;
+            }
+            breaker.state = BreakerState::HalfOpen;
+            info!("BREAKER: {} is half open", origin);
+        }
+
+        true
+    }
+
+    pub fn record(&self, origin: &str, success: bool) {
+        self.update(origin, success, false);
+    }
+
+    //failures that say the origin is unreachable open the circuit without waiting for the threshold
+    pub fn trip(&self, origin: &str) {
+        self.update(origin, false, true);
+    }
+
+    fn update(&self, origin: &str, success: bool, immediate: bool) {
+        let config = match &self.config {
+            Some(config) => config,
+            None => return,
+        };
+
+        let mut breakers = self.inner.lock().unwrap();
+        let now = Instant::now();
+        if !breakers.contains_key(origin) {
+            prune(&mut breakers, config, now);
+        }
+        let breaker = breakers.entry(origin.to_string()).or_insert(Breaker {
+            state: BreakerState::Closed,
+            failures: 0,
+            opened_at: None,
+            trips: 0,
+            last_trip_at: None,
+            last_seen: now,
+        });
+        breaker.last_seen = now;
+
+        if success {
+            if breaker.state != BreakerState::Closed {
+                info!("BREAKER: {} is closed", origin);
+                self.slow_start.recovered(origin);
+            }
+            breaker.state = BreakerState::Closed;
+            breaker.failures = 0;
+            return;
+        }
+
+        breaker.failures += 1;
+        let trip = match breaker.state {
+            BreakerState::HalfOpen => true,
+            BreakerState::Closed => immediate || breaker.failures >= config.failure_threshold,
+            BreakerState::Open => false,
+        };
+
+        if trip {
+            breaker.state = BreakerState::Open;
+            breaker.opened_at = Some(Instant::now());
+            breaker.trips += 1;
+            breaker.last_trip_at = Some(chrono::Utc::now().timestamp());
+            breaker.failures = 0;
+            warn!("BREAKER: {} is open (trip #{})", origin, breaker.trips);
+        }
+    }
+
+    pub fn snapshot(&self) -> Vec<BreakerSnapshot> {
+        let breakers = self.inner.lock().unwrap();
+        let mut snapshot: Vec<BreakerSnapshot> = breakers
+            .iter()
+            .map(|(origin, breaker)| BreakerSnapshot {
+                origin: origin.clone(),
+                state: breaker.state,
+                trips: breaker.trips,
+                last_trip_at: breaker.last_trip_at,
+            })
+            .collect();
+        snapshot.sort_by(|a, b| a.origin.cmp(&b.origin));
+        snapshot
+    }
+}
+
+//makes room before a new origin is tracked: idle breakers go first, then the least recently used
+fn prune(breakers: &mut HashMap<String, Breaker>, config: &BreakerConfig, now: Instant) {
+    if let Some(idle_for) = config.idle_for {
+        breakers.retain(|_, breaker| now.duration_since(breaker.last_seen) < idle_for);
+    }
+
+    if let Some(max) = config.max_entries {
+        while breakers.len() >= max.max(1) {
+            let oldest = breakers
+                .iter()
+                .min_by_key(|(_, breaker)| breaker.last_seen)
+                .map(|(origin, _)| origin.clone());
+            match oldest {
+                Some(origin) => breakers.remove(&origin),
+                None => break,
+            };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn breakers(idle_for: Option<Duration>, max_entries: Option<usize>) -> CircuitBreakers {
+        CircuitBreakers::new(Some(BreakerConfig {
+            failure_threshold: 3,
+            open_for: Duration::from_secs(30),
+            idle_for,
+            max_entries,
+        }))
+    }
+
+    fn origins(breakers: &CircuitBreakers) -> Vec<String> {
+        breakers.snapshot().into_iter().map(|b| b.origin).collect()
+    }
+
+    #[tokio::test]
+    async fn breakers_for_origins_gone_idle_are_pruned() {
+        let breakers = breakers(Some(Duration::from_millis(200)), None);
+        breakers.record("http://active", false);
+        for i in 0..50 {
+            breakers.record(&format!("http://transient-{}", i), false);
+        }
+        assert_eq!(breakers.snapshot().len(), 51);
+
+        //the active origin keeps being used while the transient ones go quiet
+        for _ in 0..5 {
+            tokio::time::sleep(Duration::from_millis(60)).await;
+            assert!(breakers.allow("http://active"));
+        }
+
+        breakers.record("http://new", true);
+        assert_eq!(origins(&breakers), ["http://active", "http://new"]);
+    }
+
+    #[test]
+    fn the_least_recently_used_breakers_make_room_for_new_ones() {
+        let breakers = breakers(None, Some(10));
+        breakers.trip("http://active");
+        for i in 0..100 {
+            breakers.record(&format!("http://transient-{:03}", i), false);
+            breakers.allow("http://active");
+        }
+
+        let tracked = origins(&breakers);
+        assert_eq!(tracked.len(), 10);
+        assert!(tracked.contains(&"http://active".to_string()));
+        assert!(tracked.contains(&"http://transient-099".to_string()));
+        //the open circuit survived the churn
+        assert!(!breakers.allow("http://active"));
+    }
+}
@@ -1,26 +1,258 @@
-use std::collections::HashMap;
-use std::sync::Arc;
+use axum::http::{header, HeaderMap, HeaderName};
+use bytes::Bytes;
+use serde::Serialize;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, HashMap};
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
 use std::time::{Duration, Instant};
 use tokio::sync::RwLock;
+use tracing::debug;
+use crate::headers;
+
+//upstream headers a hit replays: the ones describing the body, plus Location for redirects.
+//anything about the original exchange or one user (Set-Cookie in particular) is dropped
+const KEPT_HEADERS: &[HeaderName] = &[
+    header::CONTENT_TYPE,
+    header::CONTENT_ENCODING,
+    header::CONTENT_LANGUAGE,
+    header::CONTENT_DISPOSITION,
+    header::CACHE_CONTROL,
+    header::EXPIRES,
+    header::ETAG,
+    header::LAST_MODIFIED,
+    header::VARY,
+    header::LOCATION,
+];
+
+//a stored response with the upstream headers in KEPT_HEADERS
+#[derive(Debug, Clone)]
+pub struct CachedResponse {
+    pub status: u16,
+    pub headers: HeaderMap,
+    pub body: Bytes,
+}
+
+impl CachedResponse {
+    pub fn new(status: u16, upstream: &HeaderMap, body: Bytes) -> Self {
+        let mut headers = HeaderMap::new();
+        for name in KEPT_HEADERS {
+            for value in upstream.get_all(name) {
+                headers.append(name, value.clone());
+            }
+        }
+        Self { status, headers, body }
+    }
+
+    fn size(&self) -> usize {
+        let headers: usize = self.headers.iter().map(|(name, value)| name.as_str().len() + value.len()).sum();
+        self.body.len() + headers
+    }
+}
+
+//what the admin api shows about an entry, never the body itself
+#[derive(Debug, Clone, Serialize)]
+pub struct EntryInfo {
+    pub key: String,
+    pub status: u16,
+    pub size_bytes: usize,
+    //negative once expired and only kept for stale-if-error
+    pub ttl_secs: i64,
+    pub shared_body: bool,
+}
+
+struct Entry {
+    response: CachedResponse,
+    expires_at: Instant,
+    //hash of the body when it lives in the shared body pool
+    shared: Option<u64>,
+    //tick of the store's clock when the entry was last stored or read, its place in the
+    //store's recency index. atomic so a hit under the read lock can still bump it
+    last_used: AtomicU64,
+}
+
+impl Entry {
+    fn info(&self, key: &str, now: Instant) -> EntryInfo {
+        let ttl_secs = if self.expires_at >= now {
+            (self.expires_at - now).as_secs() as i64
+        } else {
+            -((now - self.expires_at).as_secs() as i64)
+        };
+        EntryInfo {
+            key: key.to_string(),
+            status: self.response.status,
+            size_bytes: key.len() + self.response.size(),
+            ttl_secs,
+            shared_body: self.shared.is_some(),
+        }
+    }
+
+    //bytes held by this entry alone, a shared body is counted once in the pool
+    fn size(&self, key: &str) -> usize {
+        match self.shared {
+            Some(_) => key.len() + self.response.size() - self.response.body.len(),
+            None => key.len() + self.response.size(),
+        }
+    }
+}
+
+#[derive(Default)]
+struct Store {
+    entries: HashMap<String, Entry>,
+    //deduplicated bodies by content hash, with how many entries use each
+    bodies: HashMap<u64, (Bytes, usize)>,
+    //key, response and pooled body bytes held, checked against the capacity
+    bytes: usize,
+    //orders entries by use, ticks once per store or read
+    clock: AtomicU64,
+    //last_used tick -> key, least recently used first. behind its own lock so a hit
+    //under the read lock can still move its key to the back
+    recency: Mutex<BTreeMap<u64, String>>,
+}
+
+impl Store {
+    //tracked bytes plus what each entry costs on top of its key and body
+    fn estimated_bytes(&self) -> usize {
+        let overhead = std::mem::size_of::<String>() + std::mem::size_of::<Entry>();
+        self.bytes + self.entries.len() * overhead
+    }
+
+    //the old tick is read and replaced under the index lock, so two hits on one key at
+    //once can't leave it in the index twice
+    fn touch(&self, key: &str, entry: &Entry) {
+        let mut recency = self.recency.lock().unwrap();
+        let tick = self.clock.fetch_add(1, Ordering::Relaxed) + 1;
+        recency.remove(&entry.last_used.swap(tick, Ordering::Relaxed));
+        recency.insert(tick, key.to_string());
+    }
+
+    //drops the entry read or stored longest ago, false when there is none
+    fn evict_least_recent(&mut self) -> bool {
+        match self.recency.get_mut().unwrap().pop_first() {
+            Some((_, key)) => {
+                self.remove(&key);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn remove(&mut self, key: &str) {
+        let entry = match self.entries.remove(key) {
+            Some(entry) => entry,
+            None => return,
+        };
+        self.bytes -= entry.size(key);
+        self.recency.get_mut().unwrap().remove(&entry.last_used.load(Ordering::Relaxed));
+
+        if let Some(hash) = entry.shared
+            && let Some((body, refs)) = self.bodies.get_mut(&hash)
+        {
+            *refs -= 1;
+            if *refs == 0 {
+                self.bytes -= body.len();
+                self.bodies.remove(&hash);
+            }
+        }
+    }
+
+    fn insert(&mut self, key: String, mut response: CachedResponse, expires_at: Instant, dedupe: bool) {
+        let mut shared = None;
+        if dedupe {
+            let hash = hash_of(&response.body);
+            match self.bodies.get_mut(&hash) {
+                Some((body, refs)) if *body == response.body => {
+                    *refs += 1;
+                    response.body = body.clone();
+                    shared = Some(hash);
+                }
+                //a hash collision keeps its own copy
+                Some(_) => {}
+                None => {
+                    self.bytes += response.body.len();
+                    self.bodies.insert(hash, (response.body.clone(), 1));
+                    shared = Some(hash);
+                }
+            }
+        }
+
+        let entry = Entry {
+            response,
+            expires_at,
+            shared,
+            last_used: AtomicU64::new(0),
+        };
+        self.touch(&key, &entry);
+        self.bytes += entry.size(&key);
+        self.entries.insert(key, entry);
+    }
+}
 
 #[derive(Clone)]
 pub struct MemoryCache {
-    data: Arc<RwLock<HashMap<String, (String, Instant)>>>,
+    data: Arc<RwLock<Store>>,
+    capacity: Option<usize>,
+    max_entries: Option<usize>,
+    //how long expired entries are kept to answer for a failing origin
+    stale_window: Duration,
+    dedupe: bool,
 }
 
 impl MemoryCache {
     pub fn new() -> Self {
         Self {
-            data: Arc::new(RwLock::new(HashMap::new())),
+            data: Arc::new(RwLock::new(Store::default())),
+            capacity: None,
+            max_entries: None,
+            stale_window: Duration::ZERO,
+            dedupe: false,
         }
     }
 
-    pub async fn get(&self, key: &str) -> Option<String> {
+    //total bytes of keys and responses the cache may hold
+    pub fn with_capacity(mut self, bytes: usize) -> Self {
+        self.capacity = Some(bytes);
+        self
+    }
+
+    //most entries held, the least recently used one makes room for a new key
+    pub fn with_max_entries(mut self, max_entries: usize) -> Self {
+        self.max_entries = Some(max_entries.max(1));
+        self
+    }
+
+    pub fn capacity(&self) -> Option<usize> {
+        self.capacity
+    }
+
+    pub fn max_entries(&self) -> Option<usize> {
+        self.max_entries
+    }
+
+    pub async fn len(&self) -> usize {
+        self.data.read().await.entries.len()
+    }
+
+    pub fn with_stale_window(mut self, window: Duration) -> Self {
+        self.stale_window = window;
+        self
+    }
+
+    //identical bodies under different keys are stored once
+    pub fn with_dedupe(mut self) -> Self {
+        self.dedupe = true;
+        self
+    }
+
+    pub async fn get(&self, key: &str) -> Option<CachedResponse> {
         let cache = self.data.read().await;
-        if let Some((value, expires_at)) = cache.get(key) {
-            if Instant::now() < *expires_at {
-                return Some(value.clone());
-            } else {
+        if let Some(entry) = cache.entries.get(key) {
+            let now = Instant::now();
+            if now < entry.expires_at {
+                cache.touch(key, entry);
+                return Some(entry.response.clone());
+            } else if now >= entry.expires_at + self.stale_window {
                 //remove expired entry
                 drop(cache);
                 let mut cache = self.data.write().await;
@@ -30,14 +262,365 @@ impl MemoryCache {
         None
     }
 
-    pub async fn set(&self, key: String, value: String, ttl_seconds: u64) {
-        let expires_at = Instant::now() + Duration::from_secs(ttl_seconds);
+    //an entry that expired less than the stale window ago, for when the origin can't answer
+    pub async fn get_stale(&self, key: &str) -> Option<CachedResponse> {
+        let cache = self.data.read().await;
+        let entry = cache
+            .entries
+            .get(key)
+            .filter(|entry| Instant::now() < entry.expires_at + self.stale_window)?;
+        cache.touch(key, entry);
+        Some(entry.response.clone())
+    }
+
+    //every entry still held, expired ones included until they're evicted
+    pub async fn entries(&self) -> Vec<EntryInfo> {
+        let cache = self.data.read().await;
+        let now = Instant::now();
+        let mut entries: Vec<EntryInfo> = cache
+            .entries
+            .iter()
+            .map(|(key, entry)| entry.info(key, now))
+            .collect();
+        entries.sort_by(|a, b| a.key.cmp(&b.key));
+        entries
+    }
+
+    pub async fn entry(&self, key: &str) -> Option<EntryInfo> {
+        let cache = self.data.read().await;
+        cache.entries.get(key).map(|entry| entry.info(key, Instant::now()))
+    }
+
+    pub async fn set(&self, key: String, value: CachedResponse, ttl_seconds: u64) {
+        let size = key.len() + value.size();
+
+        //something bigger than the whole cache would only flush everything else out
+        if let Some(capacity) = self.capacity.filter(|capacity| size > *capacity) {
+            debug!("CACHE SKIP: {} is {} bytes, over the {} byte capacity", key, size, capacity);
+            return;
+        }
+
+        let now = Instant::now();
+        let expires_at = now + Duration::from_secs(ttl_seconds);
         let mut cache = self.data.write().await;
-        cache.insert(key, (value, expires_at));
+        cache.remove(&key);
+
+        if let Some(capacity) = self.capacity {
+            if cache.bytes + size > capacity {
+                let expired: Vec<String> = cache
+                    .entries
+                    .iter()
+                    .filter(|(_, entry)| entry.expires_at <= now)
+                    .map(|(k, _)| k.clone())
+                    .collect();
+                for k in expired {
+                    cache.remove(&k);
+                }
+            }
+
+            //still short on room, drop the entries closest to expiring first
+            while cache.bytes + size > capacity {
+                let soonest = cache
+                    .entries
+                    .iter()
+                    .min_by_key(|(_, entry)| entry.expires_at)
+                    .map(|(k, _)| k.clone());
+                match soonest {
+                    Some(k) => cache.remove(&k),
+                    None => break,
+                }
+            }
+        }
+
+        if let Some(max) = self.max_entries {
+            while cache.entries.len() >= max && cache.evict_least_recent() {}
+        }
+
+        cache.insert(key, value, expires_at, self.dedupe);
+    }
+
+    pub async fn estimated_bytes(&self) -> usize {
+        self.data.read().await.estimated_bytes()
+    }
+
+    //drops expired and then soonest-expiring entries until about `bytes` are freed,
+    //returns the estimated bytes actually freed
+    pub async fn shrink(&self, bytes: usize) -> usize {
+        let mut cache = self.data.write().await;
+        let before = cache.estimated_bytes();
+
+        let mut keys: Vec<(Instant, String)> = cache
+            .entries
+            .iter()
+            .map(|(key, entry)| (entry.expires_at, key.clone()))
+            .collect();
+        keys.sort();
+        for (_, key) in keys {
+            if before - cache.estimated_bytes() >= bytes {
+                break;
+            }
+            cache.remove(&key);
+        }
+
+        before - cache.estimated_bytes()
+    }
+
+    //drops entries past their TTL and stale window every `interval`, so keys nobody asks
+    //for again don't stay around. expired keys are found under the read lock and removed
+    //in one batch, keeping the write lock short
+    pub fn spawn_cleanup(&self, interval: Duration) {
+        let cache = self.clone();
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(interval).await;
+                let now = Instant::now();
+                let expired: Vec<String> = cache
+                    .data
+                    .read()
+                    .await
+                    .entries
+                    .iter()
+                    .filter(|(_, entry)| now >= entry.expires_at + cache.stale_window)
+                    .map(|(key, _)| key.clone())
+                    .collect();
+                if expired.is_empty() {
+                    continue;
+                }
+
+                let mut store = cache.data.write().await;
+                for key in &expired {
+                    //stored again since it was found expired
+                    if store.entries.get(key).is_some_and(|entry| now >= entry.expires_at + cache.stale_window) {
+                        store.remove(key);
+                    }
+                }
+                drop(store);
+                debug!("CACHE SWEEP: removed {} expired entries", expired.len());
+            }
+        });
+    }
+
+    //how long the origin says a response may be kept by a shared cache, None when it doesn't say.
+    //Duration::ZERO means it must not be cached: no-store, no-cache, private, max-age=0 or an Expires
+    //in the past. s-maxage wins over max-age, either wins over Expires; malformed values are ignored
+    pub fn ttl_from_headers(headers: &HeaderMap) -> Option<Duration> {
+        let directives = headers::cache_control(headers);
+        let forbids = |d: &String| {
+            let name = d.split('=').next().unwrap_or("").trim();
+            name == "no-store" || name == "no-cache" || name == "private"
+        };
+        if directives.iter().any(forbids) {
+            return Some(Duration::ZERO);
+        }
+
+        let seconds = |name: &str| {
+            directives.iter().find_map(|d| {
+                let (key, value) = d.split_once('=')?;
+                (key.trim() == name).then(|| value.trim().trim_matches('"').parse::<u64>().ok())?
+            })
+        };
+        if let Some(secs) = seconds("s-maxage").or_else(|| seconds("max-age")) {
+            return Some(Duration::from_secs(secs));
+        }
+
+        //Expires counts from the origin's Date so clock skew between the two doesn't matter.
+        //an Expires that isn't a date (often "0" or "-1") means already expired
+        let expires = headers.get(header::EXPIRES)?.to_str().ok()?;
+        let expires = match chrono::DateTime::parse_from_rfc2822(expires.trim()) {
+            Ok(expires) => expires.timestamp(),
+            Err(_) => return Some(Duration::ZERO),
+        };
+        let date = headers
+            .get(header::DATE)
+            .and_then(|date| date.to_str().ok())
+            .and_then(|date| chrono::DateTime::parse_from_rfc2822(date.trim()).ok())
+            .map(|date| date.timestamp())
+            .unwrap_or_else(|| chrono::Utc::now().timestamp());
+        Some(Duration::from_secs(expires.saturating_sub(date).max(0) as u64))
     }
 
     pub fn generate_cache_key(domain: &str, path: &str, query: Option<&str>) -> String {
         let query_part = query.map(|q| format!("?{}", q)).unwrap_or_default();
         format!("cache:{}:{}{}", domain, path, query_part)
     }
-}
\ No newline at end of file
+}
+
+fn hash_of(body: &[u8]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    body.hash(&mut hasher);
+    hasher.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn response(body: &'static [u8]) -> CachedResponse {
+        CachedResponse::new(200, &HeaderMap::new(), Bytes::from_static(body))
+    }
+
+    #[tokio::test]
+    async fn evicts_the_least_recently_used_key() {
+        let cache = MemoryCache::new().with_max_entries(2);
+        cache.set("cache:a:/old".to_string(), response(b"old"), 60).await;
+        cache.set("cache:a:/used".to_string(), response(b"used"), 60).await;
+        assert!(cache.get("cache:a:/old").await.is_some());
+
+        //"/used" was stored after "/old" but "/old" was read since
+        cache.set("cache:a:/new".to_string(), response(b"new"), 60).await;
+        assert_eq!(cache.len().await, 2);
+        assert!(cache.get("cache:a:/used").await.is_none());
+        assert!(cache.get("cache:a:/old").await.is_some());
+        assert!(cache.get("cache:a:/new").await.is_some());
+    }
+
+    #[tokio::test]
+    async fn the_recency_index_follows_every_store_read_and_removal() {
+        let cache = MemoryCache::new().with_max_entries(3).with_capacity(4096);
+        assert_eq!((cache.capacity(), cache.max_entries()), (Some(4096), Some(3)));
+        for key in ["cache:a:/1", "cache:a:/2", "cache:a:/3"] {
+            cache.set(key.to_string(), response(b"body"), 60).await;
+        }
+        //read twice and stored again, each key is still indexed once
+        cache.get("cache:a:/1").await;
+        cache.get("cache:a:/1").await;
+        cache.set("cache:a:/2".to_string(), response(b"again"), 60).await;
+        {
+            let store = cache.data.read().await;
+            let indexed: Vec<String> = store.recency.lock().unwrap().values().cloned().collect();
+            assert_eq!(indexed, ["cache:a:/3", "cache:a:/1", "cache:a:/2"]);
+        }
+
+        cache.set("cache:a:/4".to_string(), response(b"body"), 60).await;
+        assert!(cache.get("cache:a:/3").await.is_none());
+        cache.data.write().await.remove("cache:a:/1");
+        let store = cache.data.read().await;
+        let indexed: Vec<String> = store.recency.lock().unwrap().values().cloned().collect();
+        assert_eq!(indexed, ["cache:a:/2", "cache:a:/4"]);
+    }
+
+    #[tokio::test]
+    async fn identical_bodies_are_stored_once() {
+        let cache = MemoryCache::new().with_dedupe();
+        let body: &[u8] = &[0x89, b'P', b'N', b'G', 0xff, 0x00];
+        cache.set("cache:a:/one.png".to_string(), response(body), 60).await;
+        cache.set("cache:a:/two.png".to_string(), response(body), 60).await;
+
+        {
+            let store = cache.data.read().await;
+            assert_eq!(store.bodies.len(), 1);
+            assert_eq!(store.bodies.values().next().map(|(_, refs)| *refs), Some(2));
+        }
+        assert_eq!(cache.get("cache:a:/two.png").await.map(|r| r.body), Some(Bytes::from_static(body)));
+
+        let mut store = cache.data.write().await;
+        store.remove("cache:a:/one.png");
+        assert_eq!(store.bodies.len(), 1);
+        store.remove("cache:a:/two.png");
+        assert!(store.bodies.is_empty());
+        assert_eq!(store.bytes, 0);
+    }
+
+    #[tokio::test]
+    async fn a_value_larger_than_the_capacity_is_skipped() {
+        let cache = MemoryCache::new().with_capacity(1024);
+        cache.set("cache:a:/small".to_string(), response(b"small"), 60).await;
+        let held = cache.estimated_bytes().await;
+
+        let huge = CachedResponse::new(200, &HeaderMap::new(), Bytes::from(vec![b'x'; 4096]));
+        cache.set("cache:a:/huge".to_string(), huge, 60).await;
+
+        //nothing was evicted to make room for a value that could never fit
+        assert!(cache.get("cache:a:/huge").await.is_none());
+        assert_eq!(cache.get("cache:a:/small").await.map(|r| r.body), Some(Bytes::from_static(b"small")));
+        assert_eq!(cache.len().await, 1);
+        assert_eq!(cache.estimated_bytes().await, held);
+    }
+
+    #[tokio::test]
+    async fn the_sweeper_drops_expired_entries_nobody_reads() {
+        let cache = MemoryCache::new();
+        cache.set("cache:a:/short".to_string(), response(b"short"), 1).await;
+        cache.set("cache:a:/long".to_string(), response(b"long"), 60).await;
+        cache.spawn_cleanup(Duration::from_millis(50));
+
+        tokio::time::sleep(Duration::from_millis(1300)).await;
+
+        //no get ran, so only the sweeper could have removed it
+        assert_eq!(cache.len().await, 1);
+        let store = cache.data.read().await;
+        assert!(!store.entries.contains_key("cache:a:/short"));
+        assert!(store.entries.contains_key("cache:a:/long"));
+    }
+
+    #[test]
+    fn keeps_only_body_headers() {
+        let mut upstream = HeaderMap::new();
+        upstream.insert(header::CONTENT_TYPE, "image/png".parse().unwrap());
+        upstream.insert(header::ETAG, "\"v1\"".parse().unwrap());
+        upstream.insert(header::SET_COOKIE, "session=abc".parse().unwrap());
+        upstream.insert(header::CONNECTION, "keep-alive".parse().unwrap());
+
+        let cached = CachedResponse::new(200, &upstream, Bytes::from_static(b"png"));
+        assert_eq!(cached.headers.get(header::CONTENT_TYPE).unwrap(), "image/png");
+        assert_eq!(cached.headers.get(header::ETAG).unwrap(), "\"v1\"");
+        assert!(cached.headers.get(header::SET_COOKIE).is_none());
+        assert!(cached.headers.get(header::CONNECTION).is_none());
+    }
+
+    fn ttl(headers: &[(&'static str, &str)]) -> Option<Duration> {
+        let mut map = HeaderMap::new();
+        for (name, value) in headers {
+            map.append(*name, value.parse().unwrap());
+        }
+        MemoryCache::ttl_from_headers(&map)
+    }
+
+    #[test]
+    fn ttl_from_headers_reads_cache_control() {
+        assert_eq!(ttl(&[]), None);
+        assert_eq!(ttl(&[("cache-control", "max-age=0")]), Some(Duration::ZERO));
+        assert_eq!(ttl(&[("cache-control", "public, max-age=120, must-revalidate")]), Some(Duration::from_secs(120)));
+        assert_eq!(ttl(&[("cache-control", "public"), ("cache-control", "max-age=30")]), Some(Duration::from_secs(30)));
+        assert_eq!(ttl(&[("cache-control", "max-age=\"45\"")]), Some(Duration::from_secs(45)));
+        //a forbidding directive wins wherever it appears
+        assert_eq!(ttl(&[("cache-control", "max-age=600, no-store")]), Some(Duration::ZERO));
+        assert_eq!(ttl(&[("cache-control", "private, max-age=600")]), Some(Duration::ZERO));
+        assert_eq!(ttl(&[("cache-control", "no-cache=\"set-cookie\"")]), Some(Duration::ZERO));
+    }
+
+    #[test]
+    fn s_maxage_wins_over_max_age() {
+        assert_eq!(ttl(&[("cache-control", "max-age=60, s-maxage=300")]), Some(Duration::from_secs(300)));
+        assert_eq!(ttl(&[("cache-control", "s-maxage=0, max-age=60")]), Some(Duration::ZERO));
+        //a malformed s-maxage falls back to max-age
+        assert_eq!(ttl(&[("cache-control", "s-maxage=soon, max-age=60")]), Some(Duration::from_secs(60)));
+    }
+
+    #[test]
+    fn malformed_values_are_ignored() {
+        assert_eq!(ttl(&[("cache-control", "max-age=abc")]), None);
+        assert_eq!(ttl(&[("cache-control", "max-age=-5")]), None);
+        assert_eq!(ttl(&[("cache-control", "max-age=")]), None);
+        assert_eq!(ttl(&[("cache-control", "max-age")]), None);
+        //Expires still applies when max-age can't be read
+        let headers = [
+            ("cache-control", "max-age=1.5"),
+            ("date", "Wed, 01 Jan 2025 00:00:00 GMT"),
+            ("expires", "Wed, 01 Jan 2025 00:10:00 GMT"),
+        ];
+        assert_eq!(ttl(&headers), Some(Duration::from_secs(600)));
+    }
+
+    #[test]
+    fn expires_counts_from_the_origin_date() {
+        //both far in the past, only the difference matters
+        let date = ("date", "Wed, 01 Jan 2025 00:00:00 GMT");
+        assert_eq!(ttl(&[date, ("expires", "Wed, 01 Jan 2025 01:00:00 GMT")]), Some(Duration::from_secs(3600)));
+        assert_eq!(ttl(&[date, ("expires", "Tue, 31 Dec 2024 23:00:00 GMT")]), Some(Duration::ZERO));
+        assert_eq!(ttl(&[date, ("expires", "0")]), Some(Duration::ZERO));
+        //max-age wins over Expires
+        let headers = [date, ("expires", "Wed, 01 Jan 2025 01:00:00 GMT"), ("cache-control", "max-age=10")];
+        assert_eq!(ttl(&headers), Some(Duration::from_secs(10)));
+    }
+}
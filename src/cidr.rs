@@ -0,0 +1,66 @@
+use std::{fmt, net::IpAddr};
+use tracing::warn;
+
+//an address range in CIDR notation, a bare address is a /32 or /128
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    pub fn parse(value: &str) -> Option<Self> {
+        let value = value.trim();
+        let (addr, prefix) = match value.split_once('/') {
+            Some((addr, prefix)) => (addr.parse::<IpAddr>().ok()?, Some(prefix.parse::<u8>().ok()?)),
+            None => (value.parse::<IpAddr>().ok()?, None),
+        };
+
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = prefix.unwrap_or(max);
+        if prefix > max {
+            return None;
+        }
+
+        Some(Self { addr, prefix })
+    }
+
+    //comma separated list, invalid entries are logged and skipped
+    pub fn parse_list(value: &str) -> Vec<Self> {
+        value
+            .split(',')
+            .filter(|entry| !entry.trim().is_empty())
+            .filter_map(|entry| {
+                let cidr = Self::parse(entry);
+                if cidr.is_none() {
+                    warn!("Ignoring invalid CIDR: {}", entry.trim());
+                }
+                cidr
+            })
+            .collect()
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = if self.prefix == 0 { 0 } else { u32::MAX << (32 - self.prefix) };
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = if self.prefix == 0 { 0 } else { u128::MAX << (128 - self.prefix) };
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+pub fn any_contains(ranges: &[Cidr], ip: IpAddr) -> bool {
+    ranges.iter().any(|range| range.contains(ip))
+}
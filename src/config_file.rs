@@ -0,0 +1,200 @@
+use notify::{EventKind, RecursiveMode, Watcher};
+use sqlx::SqlitePool;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tracing::{info, warn};
+use crate::database;
+use crate::reload;
+use crate::routing::RouteTable;
+use crate::settings::ActiveConfigHandle;
+use crate::validation::{self, ConfigDocument};
+
+//editors save in several steps (truncate, write, rename), events this close together
+//are taken as one change
+const SETTLE: Duration = Duration::from_millis(200);
+
+//writes the `config_file` (the shape /config/validate takes, TOML or JSON) into the
+//database. the file owns the domains table: its domains are created or updated by name,
+//any other domain is removed. config keys it sets are written, the rest are left alone.
+//nothing is written unless the whole file is valid
+pub async fn apply(db: &SqlitePool, path: &Path) -> Result<(), String> {
+    let body = tokio::fs::read_to_string(path)
+        .await
+        .map_err(|e| format!("can't read {}: {}", path.display(), e))?;
+    let doc = ConfigDocument::parse(&body)?;
+    let problems = validation::validate_document(&doc);
+    if !problems.is_empty() {
+        return Err(problems.join("; "));
+    }
+
+    //all or nothing, a database error halfway leaves the previous contents in place
+    let mut tx = db.begin().await.map_err(|e| e.to_string())?;
+    for (key, value) in doc.entries() {
+        database::set_config_on(&mut tx, &key, &value).await.map_err(|e| e.to_string())?;
+    }
+
+    let existing = database::get_all_domains_on(&mut tx).await.map_err(|e| e.to_string())?;
+    let id_of = |name: &str| {
+        existing
+            .iter()
+            .find(|domain| domain.domain.eq_ignore_ascii_case(name))
+            .and_then(|domain| domain.id)
+    };
+    for domain in &doc.domains {
+        match id_of(&domain.domain) {
+            Some(id) => database::update_domain_on(&mut tx, id, domain).await.map(|_| ()),
+            None => database::create_domain_on(&mut tx, domain).await.map(|_| ()),
+        }
+        .map_err(|e| e.to_string())?;
+    }
+    for stale in existing
+        .iter()
+        .filter(|old| !doc.domains.iter().any(|domain| domain.domain.eq_ignore_ascii_case(&old.domain)))
+    {
+        if let Some(id) = stale.id {
+            database::delete_domain_on(&mut tx, id).await.map_err(|e| e.to_string())?;
+        }
+    }
+
+    tx.commit().await.map_err(|e| e.to_string())
+}
+
+//applies the config file again and reloads whenever it changes. the directory is watched
+//rather than the file so a save that replaces the file is still seen. an invalid file is
+//logged and skipped, the running routes and config stay as they were
+pub fn spawn_watcher(path: PathBuf, db: SqlitePool, routes: RouteTable, active: ActiveConfigHandle) {
+    let Some(name) = path.file_name().map(|name| name.to_os_string()) else {
+        warn!("config_file {} names no file, not watching it", path.display());
+        return;
+    };
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+        _ => PathBuf::from("."),
+    };
+
+    let (changed_tx, mut changed) = mpsc::unbounded_channel();
+    let handler = move |event: notify::Result<notify::Event>| {
+        if let Ok(event) = event
+            && matches!(event.kind, EventKind::Create(_) | EventKind::Modify(_))
+            && event.paths.iter().any(|changed| changed.file_name() == Some(name.as_os_str()))
+        {
+            let _ = changed_tx.send(());
+        }
+    };
+    let mut watcher = match notify::recommended_watcher(handler) {
+        Ok(watcher) => watcher,
+        Err(e) => {
+            warn!("Can't watch config_file, changes to it need a SIGHUP or a restart: {}", e);
+            return;
+        }
+    };
+    if let Err(e) = watcher.watch(&dir, RecursiveMode::NonRecursive) {
+        warn!("Can't watch {}, changes to config_file need a SIGHUP or a restart: {}", dir.display(), e);
+        return;
+    }
+    info!("Watching {} for config changes", path.display());
+
+    tokio::spawn(async move {
+        //the watcher stops delivering events once dropped
+        let _watcher = watcher;
+        while changed.recv().await.is_some() {
+            tokio::time::sleep(SETTLE).await;
+            while changed.try_recv().is_ok() {}
+
+            info!("{} changed, reloading domains and config", path.display());
+            reload::log_outcome(reload::reload_from(&db, Some(&path), &routes, &active).await);
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::DomainDto;
+    use crate::routing;
+    use crate::settings::{ActiveConfig, ProxySettings};
+    use std::sync::Arc;
+    use tokio::sync::RwLock;
+
+    fn config_path(test: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("proxynet-config-file-{}-{}", test, std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir.join("config.toml")
+    }
+
+    fn document(origin: &str, upstream_timeout_ms: &str) -> String {
+        format!(
+            "[config]\nupstream_timeout_ms = \"{}\"\n\n\
+             [[domains]]\ndomain = \"app.test\"\norigin = \"{}\"\nenabled = true\n",
+            upstream_timeout_ms, origin
+        )
+    }
+
+    #[tokio::test]
+    async fn the_file_owns_the_domains_and_an_invalid_one_changes_nothing() {
+        let db = database::test_db().await;
+        let manual = DomainDto {
+            domain: "manual.test".to_string(),
+            origin: "http://manual".to_string(),
+            enabled: true,
+            ..Default::default()
+        };
+        database::create_domain(&db, &manual).await.unwrap();
+        let path = config_path("apply");
+
+        std::fs::write(&path, document("http://one", "1500")).unwrap();
+        apply(&db, &path).await.unwrap();
+        let domains = database::get_all_domains(&db).await.unwrap();
+        let names: Vec<&str> = domains.iter().map(|d| d.domain.as_str()).collect();
+        assert_eq!(names, ["app.test"]);
+        let id = domains[0].id;
+        assert_eq!(database::get_config(&db, "upstream_timeout_ms").await.unwrap().as_deref(), Some("1500"));
+
+        //an edit updates the domain in place
+        std::fs::write(&path, document("http://two", "1500")).unwrap();
+        apply(&db, &path).await.unwrap();
+        let domains = database::get_all_domains(&db).await.unwrap();
+        assert_eq!((domains[0].id, domains[0].origin.as_str()), (id, "http://two"));
+
+        //one bad value and none of the file is written
+        std::fs::write(&path, document("http://three", "soon")).unwrap();
+        assert!(apply(&db, &path).await.unwrap_err().contains("upstream_timeout_ms"));
+        let domains = database::get_all_domains(&db).await.unwrap();
+        assert_eq!(domains[0].origin, "http://two");
+        assert_eq!(database::get_config(&db, "upstream_timeout_ms").await.unwrap().as_deref(), Some("1500"));
+
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[tokio::test]
+    async fn saving_the_file_swaps_the_live_routes() {
+        let db = database::test_db().await;
+        let path = config_path("watch");
+        std::fs::write(&path, document("http://before", "1500")).unwrap();
+        apply(&db, &path).await.unwrap();
+
+        let routes: RouteTable = Arc::new(RwLock::new(routing::table(&database::load_domains(&db).await.unwrap())));
+        let settings = Arc::new(ProxySettings::load(&db).await);
+        let active = Arc::new(std::sync::RwLock::new(ActiveConfig { settings, rate_limiter: None }));
+        spawn_watcher(path.clone(), db.clone(), routes.clone(), active.clone());
+
+        let origin = || async { routes.read().await.get("app.test").map(|route| route.origins[0].clone()) };
+        //an invalid save is skipped, the next valid one is applied
+        std::fs::write(&path, document("http://broken", "soon")).unwrap();
+        tokio::time::sleep(SETTLE * 3).await;
+        assert_eq!(origin().await.as_deref(), Some("http://before"));
+
+        std::fs::write(&path, document("http://after", "2500")).unwrap();
+        for _ in 0..100 {
+            if origin().await.as_deref() == Some("http://after") {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        assert_eq!(origin().await.as_deref(), Some("http://after"));
+        assert_eq!(active.read().unwrap().settings.upstream_timeout_ms, Some(2500));
+
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+}
@@ -1,11 +1,32 @@
-use sqlx::{sqlite::SqlitePool, Row};
+use sqlx::{sqlite::{SqliteConnection, SqlitePool, SqliteRow}, Row};
 use crate::logger::RequestLog;
-use crate::api::DomainDto;
+use crate::api::{DenialDto, DomainDto, LogDto, StatusBucket, StatusGroup};
+use crate::routing::{Color, TrailingSlash};
 
 pub async fn init_db() -> Result<SqlitePool, sqlx::Error> {
     let database_url = "sqlite:proxynet.db";
     let pool = SqlitePool::connect(database_url).await?;
-    
+    create_schema(&pool).await?;
+    Ok(pool)
+}
+
+//an empty database for each test. one connection that is never recycled, every
+//connection to sqlite::memory: would open a database of its own
+#[cfg(test)]
+pub async fn test_db() -> SqlitePool {
+    let pool = sqlx::sqlite::SqlitePoolOptions::new()
+        .max_connections(1)
+        .idle_timeout(None)
+        .max_lifetime(None)
+        .connect("sqlite::memory:")
+        .await
+        .unwrap();
+    create_schema(&pool).await.unwrap();
+    pool
+}
+
+async fn create_schema(pool: &SqlitePool) -> Result<(), sqlx::Error> {
+
     sqlx::query(
         "CREATE TABLE IF NOT EXISTS request_logs (
             id INTEGER PRIMARY KEY AUTOINCREMENT,
@@ -21,7 +42,7 @@ pub async fn init_db() -> Result<SqlitePool, sqlx::Error> {
             timestamp INTEGER NOT NULL
         )"
     )
-    .execute(&pool)
+    .execute(pool)
     .await?;
     
     sqlx::query(
@@ -34,7 +55,127 @@ pub async fn init_db() -> Result<SqlitePool, sqlx::Error> {
             updated_at INTEGER NOT NULL
         )"
     )
-    .execute(&pool)
+    .execute(pool)
+    .await?;
+
+    //columns added after the initial schema, ignore "duplicate column" on existing dbs
+    let _ = sqlx::query("ALTER TABLE domains ADD COLUMN origins TEXT")
+        .execute(pool)
+        .await;
+
+    let _ = sqlx::query("ALTER TABLE domains ADD COLUMN upstream_timeout_ms INTEGER")
+        .execute(pool)
+        .await;
+
+    let _ = sqlx::query("ALTER TABLE domains ADD COLUMN health_check TEXT")
+        .execute(pool)
+        .await;
+
+    let _ = sqlx::query("ALTER TABLE domains ADD COLUMN priority INTEGER NOT NULL DEFAULT 0")
+        .execute(pool)
+        .await;
+
+    let _ = sqlx::query("ALTER TABLE domains ADD COLUMN aliases TEXT")
+        .execute(pool)
+        .await;
+
+    let _ = sqlx::query("ALTER TABLE domains ADD COLUMN maintenance BOOLEAN NOT NULL DEFAULT 0")
+        .execute(pool)
+        .await;
+
+    let _ = sqlx::query("ALTER TABLE domains ADD COLUMN origin_weights TEXT")
+        .execute(pool)
+        .await;
+
+    let _ = sqlx::query("ALTER TABLE domains ADD COLUMN cache_paths TEXT")
+        .execute(pool)
+        .await;
+
+    let _ = sqlx::query("ALTER TABLE request_logs ADD COLUMN denial TEXT")
+        .execute(pool)
+        .await;
+
+    let _ = sqlx::query("ALTER TABLE request_logs ADD COLUMN denial_rule TEXT")
+        .execute(pool)
+        .await;
+
+    let _ = sqlx::query("ALTER TABLE request_logs ADD COLUMN request_id TEXT")
+        .execute(pool)
+        .await;
+
+    let _ = sqlx::query("ALTER TABLE request_logs ADD COLUMN error TEXT")
+        .execute(pool)
+        .await;
+
+    let _ = sqlx::query("ALTER TABLE request_logs ADD COLUMN origin TEXT")
+        .execute(pool)
+        .await;
+
+    let _ = sqlx::query("ALTER TABLE request_logs ADD COLUMN service TEXT")
+        .execute(pool)
+        .await;
+
+    let _ = sqlx::query("ALTER TABLE domains ADD COLUMN max_response_bytes INTEGER")
+        .execute(pool)
+        .await;
+
+    let _ = sqlx::query("ALTER TABLE domains ADD COLUMN trailing_slash TEXT")
+        .execute(pool)
+        .await;
+
+    let _ = sqlx::query("ALTER TABLE domains ADD COLUMN html_inject TEXT")
+        .execute(pool)
+        .await;
+
+    let _ = sqlx::query("ALTER TABLE domains ADD COLUMN blue_green TEXT")
+        .execute(pool)
+        .await;
+
+    let _ = sqlx::query("ALTER TABLE domains ADD COLUMN rate_limits TEXT")
+        .execute(pool)
+        .await;
+
+    let _ = sqlx::query("ALTER TABLE domains ADD COLUMN max_concurrency INTEGER")
+        .execute(pool)
+        .await;
+
+    let _ = sqlx::query("ALTER TABLE domains ADD COLUMN pacing TEXT")
+        .execute(pool)
+        .await;
+
+    let _ = sqlx::query("ALTER TABLE domains ADD COLUMN accept_routes TEXT")
+        .execute(pool)
+        .await;
+
+    let _ = sqlx::query("ALTER TABLE domains ADD COLUMN precompressed_dir TEXT")
+        .execute(pool)
+        .await;
+
+    let _ = sqlx::query("ALTER TABLE domains ADD COLUMN service TEXT")
+        .execute(pool)
+        .await;
+
+    let _ = sqlx::query("ALTER TABLE domains ADD COLUMN connection_affinity BOOLEAN NOT NULL DEFAULT 0")
+        .execute(pool)
+        .await;
+
+    let _ = sqlx::query("ALTER TABLE domains ADD COLUMN path_routes TEXT")
+        .execute(pool)
+        .await;
+
+    let _ = sqlx::query("ALTER TABLE domains ADD COLUMN rate_limit_per_minute INTEGER")
+        .execute(pool)
+        .await;
+
+    sqlx::query("CREATE INDEX IF NOT EXISTS idx_request_logs_request_id ON request_logs (request_id)")
+        .execute(pool)
+        .await?;
+
+    sqlx::query(
+        "CREATE VIEW IF NOT EXISTS denied_requests AS
+         SELECT * FROM request_logs WHERE denial IS NOT NULL"
+    )
+    .execute(pool)
     .await?;
     
     sqlx::query(
@@ -44,152 +185,378 @@ pub async fn init_db() -> Result<SqlitePool, sqlx::Error> {
             updated_at INTEGER NOT NULL
         )"
     )
-    .execute(&pool)
+    .execute(pool)
+    .await?;
+
+    //certificates obtained over ACME, and the account that obtained them. kept out of
+    //the config table so they never show up in the config API
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS certificates (
+            domain TEXT PRIMARY KEY,
+            cert_pem TEXT NOT NULL,
+            key_pem TEXT NOT NULL,
+            not_after INTEGER NOT NULL,
+            updated_at INTEGER NOT NULL
+        )"
+    )
+    .execute(pool)
+    .await?;
+
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS acme_accounts (
+            directory TEXT PRIMARY KEY,
+            credentials TEXT NOT NULL
+        )"
+    )
+    .execute(pool)
     .await?;
 
     sqlx::query("INSERT OR IGNORE INTO config (key, value, updated_at) VALUES (?, ?, ?)")
         .bind("host")
         .bind("0.0.0.0")
         .bind(chrono::Utc::now().timestamp())
-        .execute(&pool)
+        .execute(pool)
         .await?;
 
     sqlx::query("INSERT OR IGNORE INTO config (key, value, updated_at) VALUES (?, ?, ?)")
         .bind("port")
         .bind("8080")
         .bind(chrono::Utc::now().timestamp())
-        .execute(&pool)
+        .execute(pool)
         .await?;
 
     sqlx::query("INSERT OR IGNORE INTO config (key, value, updated_at) VALUES (?, ?, ?)")
         .bind("rate_limit_per_minute")
         .bind("10")
         .bind(chrono::Utc::now().timestamp())
-        .execute(&pool)
+        .execute(pool)
         .await?;
 
-    Ok(pool)
+    Ok(())
 }
 
-pub async fn load_domains(db: &SqlitePool) -> Result<Vec<(String, String)>, sqlx::Error> {
-    sqlx::query_as::<_, (String, String)>(
-        "SELECT domain, origin FROM domains WHERE enabled = 1"
-    )
-    .fetch_all(db)
-    .await
+const DOMAIN_COLUMNS: &str = "id, domain, origin, enabled, origins, upstream_timeout_ms, health_check, priority, aliases, maintenance, origin_weights, cache_paths, max_response_bytes, trailing_slash, html_inject, blue_green, rate_limits, max_concurrency, pacing, accept_routes, precompressed_dir, service, connection_affinity, path_routes, rate_limit_per_minute";
+
+fn domain_from_row(row: &SqliteRow) -> DomainDto {
+    let origins: Option<String> = row.get("origins");
+    let upstream_timeout_ms: Option<i64> = row.get("upstream_timeout_ms");
+    let health_check: Option<String> = row.get("health_check");
+    let aliases: Option<String> = row.get("aliases");
+    let origin_weights: Option<String> = row.get("origin_weights");
+    let cache_paths: Option<String> = row.get("cache_paths");
+    let max_response_bytes: Option<i64> = row.get("max_response_bytes");
+    let trailing_slash: Option<String> = row.get("trailing_slash");
+    let blue_green: Option<String> = row.get("blue_green");
+    let rate_limits: Option<String> = row.get("rate_limits");
+    let max_concurrency: Option<i64> = row.get("max_concurrency");
+    let pacing: Option<String> = row.get("pacing");
+    let accept_routes: Option<String> = row.get("accept_routes");
+    let path_routes: Option<String> = row.get("path_routes");
+    let rate_limit_per_minute: Option<i64> = row.get("rate_limit_per_minute");
+    DomainDto {
+        id: Some(row.get("id")),
+        domain: row.get("domain"),
+        origin: row.get("origin"),
+        enabled: row.get("enabled"),
+        origins: origins
+            .and_then(|o| serde_json::from_str(&o).ok())
+            .unwrap_or_default(),
+        upstream_timeout_ms: upstream_timeout_ms.map(|t| t as u64),
+        health_check: health_check.and_then(|h| serde_json::from_str(&h).ok()),
+        priority: row.get("priority"),
+        aliases: aliases
+            .and_then(|a| serde_json::from_str(&a).ok())
+            .unwrap_or_default(),
+        maintenance: row.get("maintenance"),
+        origin_weights: origin_weights
+            .and_then(|w| serde_json::from_str(&w).ok())
+            .unwrap_or_default(),
+        cache_paths: cache_paths
+            .and_then(|c| serde_json::from_str(&c).ok())
+            .unwrap_or_default(),
+        max_response_bytes: max_response_bytes.map(|b| b as u64),
+        trailing_slash: trailing_slash.as_deref().and_then(TrailingSlash::parse),
+        html_inject: row.get("html_inject"),
+        blue_green: blue_green.and_then(|b| serde_json::from_str(&b).ok()),
+        rate_limits: rate_limits
+            .and_then(|r| serde_json::from_str(&r).ok())
+            .unwrap_or_default(),
+        max_concurrency: max_concurrency.map(|c| c as usize),
+        pacing: pacing.and_then(|p| serde_json::from_str(&p).ok()),
+        accept_routes: accept_routes
+            .and_then(|a| serde_json::from_str(&a).ok())
+            .unwrap_or_default(),
+        path_routes: path_routes
+            .and_then(|p| serde_json::from_str(&p).ok())
+            .unwrap_or_default(),
+        rate_limit_per_minute: rate_limit_per_minute.map(|r| r as u32),
+        precompressed_dir: row.get("precompressed_dir"),
+        service: row.get("service"),
+        connection_affinity: row.get("connection_affinity"),
+    }
+}
+
+//stores a list or map column as JSON, empty ones as NULL
+fn rules_json<T: serde::Serialize>(rules: &T) -> Option<String> {
+    serde_json::to_string(rules)
+        .ok()
+        .filter(|json| json != "[]" && json != "{}")
+}
+
+pub async fn load_domains(db: &SqlitePool) -> Result<Vec<DomainDto>, sqlx::Error> {
+    let rows = sqlx::query(&format!("SELECT {} FROM domains WHERE enabled = 1", DOMAIN_COLUMNS))
+        .fetch_all(db)
+        .await?;
+
+    Ok(rows.iter().map(domain_from_row).collect())
 }
 
 pub async fn create_domain(
     db: &SqlitePool,
-    domain: &str,
-    origin: &str,
+    domain: &DomainDto,
+) -> Result<DomainDto, sqlx::Error> {
+    create_domain_on(&mut *db.acquire().await?, domain).await
+}
+
+//the _on variants run on one connection so config_file can write inside a transaction
+pub async fn create_domain_on(
+    conn: &mut SqliteConnection,
+    domain: &DomainDto,
 ) -> Result<DomainDto, sqlx::Error> {
     let now = chrono::Utc::now().timestamp();
     
-    sqlx::query("INSERT INTO domains (domain, origin, enabled, created_at, updated_at) VALUES (?, ?, ?, ?, ?)")
-        .bind(domain)
-        .bind(origin)
-        .bind(true)
-        .bind(now)
-        .bind(now)
-        .execute(db)
-        .await?;
-
-    let result = sqlx::query_as::<_, (i64, String, String, bool)>(
-        "SELECT id, domain, origin, enabled FROM domains WHERE domain = ? ORDER BY id DESC LIMIT 1"
+    sqlx::query(
+        "INSERT INTO domains
+         (domain, origin, origins, upstream_timeout_ms, health_check, priority, aliases, maintenance, origin_weights, cache_paths, max_response_bytes, trailing_slash, html_inject, blue_green, rate_limits, max_concurrency, pacing, accept_routes, precompressed_dir, service, connection_affinity, path_routes, rate_limit_per_minute, enabled, created_at, updated_at)
+         VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)"
     )
-    .bind(domain)
-    .fetch_one(db)
+    .bind(&domain.domain)
+    .bind(&domain.origin)
+    .bind(rules_json(&domain.origins))
+    .bind(domain.upstream_timeout_ms.map(|t| t as i64))
+    .bind(domain.health_check.as_ref().and_then(|h| serde_json::to_string(h).ok()))
+    .bind(domain.priority)
+    .bind(rules_json(&domain.aliases))
+    .bind(domain.maintenance)
+    .bind(rules_json(&domain.origin_weights))
+    .bind(rules_json(&domain.cache_paths))
+    .bind(domain.max_response_bytes.map(|b| b as i64))
+    .bind(domain.trailing_slash.map(|t| t.as_str()))
+    .bind(&domain.html_inject)
+    .bind(domain.blue_green.as_ref().and_then(|b| serde_json::to_string(b).ok()))
+    .bind(rules_json(&domain.rate_limits))
+    .bind(domain.max_concurrency.map(|c| c as i64))
+    .bind(domain.pacing.as_ref().and_then(|p| serde_json::to_string(p).ok()))
+    .bind(rules_json(&domain.accept_routes))
+    .bind(&domain.precompressed_dir)
+    .bind(&domain.service)
+    .bind(domain.connection_affinity)
+    .bind(rules_json(&domain.path_routes))
+    .bind(domain.rate_limit_per_minute.map(|r| r as i64))
+    .bind(domain.enabled)
+    .bind(now)
+    .bind(now)
+    .execute(&mut *conn)
+    .await?;
+
+    let row = sqlx::query(&format!(
+        "SELECT {} FROM domains WHERE domain = ? ORDER BY id DESC LIMIT 1",
+        DOMAIN_COLUMNS
+    ))
+    .bind(&domain.domain)
+    .fetch_one(&mut *conn)
     .await?;
 
-    Ok(DomainDto {
-        id: Some(result.0),
-        domain: result.1,
-        origin: result.2,
-        enabled: result.3,
-    })
+    Ok(domain_from_row(&row))
 }
 
 pub async fn update_domain(
     db: &SqlitePool,
     id: i64,
-    domain: &str,
-    origin: &str,
+    domain: &DomainDto,
+) -> Result<DomainDto, sqlx::Error> {
+    update_domain_on(&mut *db.acquire().await?, id, domain).await
+}
+
+pub async fn update_domain_on(
+    conn: &mut SqliteConnection,
+    id: i64,
+    domain: &DomainDto,
 ) -> Result<DomainDto, sqlx::Error> {
     let now = chrono::Utc::now().timestamp();
     
-    sqlx::query("UPDATE domains SET domain = ?, origin = ?, updated_at = ? WHERE id = ?")
-        .bind(domain)
-        .bind(origin)
+    sqlx::query(
+        "UPDATE domains SET domain = ?, origin = ?, origins = ?, upstream_timeout_ms = ?, health_check = ?,
+         priority = ?, aliases = ?, maintenance = ?, origin_weights = ?, cache_paths = ?, max_response_bytes = ?, trailing_slash = ?, html_inject = ?, blue_green = ?, rate_limits = ?, max_concurrency = ?, pacing = ?, accept_routes = ?, precompressed_dir = ?, service = ?, connection_affinity = ?, path_routes = ?, rate_limit_per_minute = ?, enabled = ?, updated_at = ? WHERE id = ?"
+    )
+    .bind(&domain.domain)
+    .bind(&domain.origin)
+    .bind(rules_json(&domain.origins))
+    .bind(domain.upstream_timeout_ms.map(|t| t as i64))
+    .bind(domain.health_check.as_ref().and_then(|h| serde_json::to_string(h).ok()))
+    .bind(domain.priority)
+    .bind(rules_json(&domain.aliases))
+    .bind(domain.maintenance)
+    .bind(rules_json(&domain.origin_weights))
+    .bind(rules_json(&domain.cache_paths))
+    .bind(domain.max_response_bytes.map(|b| b as i64))
+    .bind(domain.trailing_slash.map(|t| t.as_str()))
+    .bind(&domain.html_inject)
+    .bind(domain.blue_green.as_ref().and_then(|b| serde_json::to_string(b).ok()))
+    .bind(rules_json(&domain.rate_limits))
+    .bind(domain.max_concurrency.map(|c| c as i64))
+    .bind(domain.pacing.as_ref().and_then(|p| serde_json::to_string(p).ok()))
+    .bind(rules_json(&domain.accept_routes))
+    .bind(&domain.precompressed_dir)
+    .bind(&domain.service)
+    .bind(domain.connection_affinity)
+    .bind(rules_json(&domain.path_routes))
+    .bind(domain.rate_limit_per_minute.map(|r| r as i64))
+    .bind(domain.enabled)
+    .bind(now)
+    .bind(id)
+    .execute(&mut *conn)
+    .await?;
+
+    let row = sqlx::query(&format!("SELECT {} FROM domains WHERE id = ?", DOMAIN_COLUMNS))
+        .bind(id)
+        .fetch_one(&mut *conn)
+        .await?;
+
+    Ok(domain_from_row(&row))
+}
+
+pub async fn set_domain_priority(
+    db: &SqlitePool,
+    id: i64,
+    priority: i64,
+) -> Result<Option<DomainDto>, sqlx::Error> {
+    let now = chrono::Utc::now().timestamp();
+
+    sqlx::query("UPDATE domains SET priority = ?, updated_at = ? WHERE id = ?")
+        .bind(priority)
         .bind(now)
         .bind(id)
         .execute(db)
         .await?;
 
-    let result = sqlx::query_as::<_, (i64, String, String, bool)>(
-        "SELECT id, domain, origin, enabled FROM domains WHERE id = ?"
+    get_domain_by_id(db, id).await
+}
+
+//None when the domain doesn't exist or has no blue_green origins to switch between
+pub async fn set_domain_color(
+    db: &SqlitePool,
+    id: i64,
+    color: Color,
+) -> Result<Option<DomainDto>, sqlx::Error> {
+    let now = chrono::Utc::now().timestamp();
+
+    let result = sqlx::query(
+        "UPDATE domains SET blue_green = json_set(blue_green, '$.active', ?), updated_at = ?
+         WHERE id = ? AND blue_green IS NOT NULL"
     )
+    .bind(color.as_str())
+    .bind(now)
     .bind(id)
-    .fetch_one(db)
+    .execute(db)
     .await?;
 
-    Ok(DomainDto {
-        id: Some(result.0),
-        domain: result.1,
-        origin: result.2,
-        enabled: result.3,
-    })
+    if result.rows_affected() == 0 {
+        return Ok(None);
+    }
+
+    get_domain_by_id(db, id).await
 }
 
 pub async fn delete_domain(db: &SqlitePool, id: i64) -> Result<(), sqlx::Error> {
+    delete_domain_on(&mut *db.acquire().await?, id).await
+}
+
+pub async fn delete_domain_on(conn: &mut SqliteConnection, id: i64) -> Result<(), sqlx::Error> {
     sqlx::query("DELETE FROM domains WHERE id = ?")
         .bind(id)
-        .execute(db)
+        .execute(&mut *conn)
         .await?;
 
     Ok(())
 }
 
 pub async fn get_domain_by_id(db: &SqlitePool, id: i64) -> Result<Option<DomainDto>, sqlx::Error> {
-    sqlx::query_as::<_, (i64, String, String, bool)>(
-        "SELECT id, domain, origin, enabled FROM domains WHERE id = ?"
-    )
-    .bind(id)
-    .fetch_optional(db)
-    .await
-    .map(|opt| {
-        opt.map(|(id, domain, origin, enabled)| DomainDto {
-            id: Some(id),
-            domain,
-            origin,
-            enabled,
-        })
-    })
+    let row = sqlx::query(&format!("SELECT {} FROM domains WHERE id = ?", DOMAIN_COLUMNS))
+        .bind(id)
+        .fetch_optional(db)
+        .await?;
+
+    Ok(row.as_ref().map(domain_from_row))
 }
 
 pub async fn get_all_domains(db: &SqlitePool) -> Result<Vec<DomainDto>, sqlx::Error> {
-    sqlx::query_as::<_, (i64, String, String, bool)>(
-        "SELECT id, domain, origin, enabled FROM domains"
+    get_all_domains_on(&mut *db.acquire().await?).await
+}
+
+pub async fn get_all_domains_on(conn: &mut SqliteConnection) -> Result<Vec<DomainDto>, sqlx::Error> {
+    let rows = sqlx::query(&format!("SELECT {} FROM domains", DOMAIN_COLUMNS))
+        .fetch_all(&mut *conn)
+        .await?;
+
+    Ok(rows.iter().map(domain_from_row).collect())
+}
+
+//(domain, certificate chain PEM, private key PEM, not_after unix time)
+pub async fn load_certificates(db: &SqlitePool) -> Result<Vec<(String, String, String, i64)>, sqlx::Error> {
+    sqlx::query_as::<_, (String, String, String, i64)>(
+        "SELECT domain, cert_pem, key_pem, not_after FROM certificates ORDER BY domain"
     )
     .fetch_all(db)
     .await
-    .map(|rows| {
-        rows.into_iter()
-            .map(|(id, domain, origin, enabled)| DomainDto {
-                id: Some(id),
-                domain,
-                origin,
-                enabled,
-            })
-            .collect()
-    })
+}
+
+pub async fn save_certificate(
+    db: &SqlitePool,
+    domain: &str,
+    cert_pem: &str,
+    key_pem: &str,
+    not_after: i64,
+) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        "INSERT INTO certificates (domain, cert_pem, key_pem, not_after, updated_at) VALUES (?, ?, ?, ?, ?)
+         ON CONFLICT(domain) DO UPDATE SET cert_pem = excluded.cert_pem, key_pem = excluded.key_pem,
+         not_after = excluded.not_after, updated_at = excluded.updated_at"
+    )
+    .bind(domain)
+    .bind(cert_pem)
+    .bind(key_pem)
+    .bind(not_after)
+    .bind(chrono::Utc::now().timestamp())
+    .execute(db)
+    .await?;
+
+    Ok(())
+}
+
+//serialized account credentials for an ACME directory url
+pub async fn get_acme_account(db: &SqlitePool, directory: &str) -> Result<Option<String>, sqlx::Error> {
+    sqlx::query_scalar("SELECT credentials FROM acme_accounts WHERE directory = ?")
+        .bind(directory)
+        .fetch_optional(db)
+        .await
+}
+
+pub async fn save_acme_account(db: &SqlitePool, directory: &str, credentials: &str) -> Result<(), sqlx::Error> {
+    sqlx::query("INSERT OR REPLACE INTO acme_accounts (directory, credentials) VALUES (?, ?)")
+        .bind(directory)
+        .bind(credentials)
+        .execute(db)
+        .await?;
+
+    Ok(())
 }
 
 pub async fn save_log(pool: &SqlitePool, log: &RequestLog) -> Result<(), sqlx::Error> {
     sqlx::query(
         "INSERT INTO request_logs 
-         (domain, path, method, status, response_time_ms, bytes_sent, ip_address, user_agent, referer, timestamp)
-         VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?)"
+         (domain, path, method, status, response_time_ms, bytes_sent, ip_address, user_agent, referer, denial, denial_rule, request_id, error, origin, service, timestamp)
+         VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)"
     )
     .bind(&log.domain)
     .bind(&log.path)
@@ -200,6 +567,12 @@ pub async fn save_log(pool: &SqlitePool, log: &RequestLog) -> Result<(), sqlx::E
     .bind(&log.ip_address)
     .bind(&log.user_agent)
     .bind(&log.referer)
+    .bind(&log.denial)
+    .bind(&log.denial_rule)
+    .bind(&log.request_id)
+    .bind(&log.error)
+    .bind(&log.origin)
+    .bind(&log.service)
     .bind(log.timestamp.timestamp())
     .execute(pool)
     .await?;
@@ -207,6 +580,144 @@ pub async fn save_log(pool: &SqlitePool, log: &RequestLog) -> Result<(), sqlx::E
     Ok(())
 }
 
+//per-domain request count, 5xx count, average response time and bytes sent,
+//optionally only for requests labelled with `service`
+pub async fn get_domain_stats(
+    pool: &SqlitePool,
+    service: Option<&str>,
+) -> Result<Vec<(String, i64, i64, f64, i64)>, sqlx::Error> {
+    sqlx::query_as::<_, (String, i64, i64, f64, i64)>(
+        "SELECT domain,
+                COUNT(*),
+                COALESCE(SUM(CASE WHEN status >= 500 THEN 1 ELSE 0 END), 0),
+                COALESCE(AVG(response_time_ms), 0.0),
+                COALESCE(SUM(bytes_sent), 0)
+         FROM request_logs WHERE (?1 IS NULL OR service = ?1) GROUP BY domain ORDER BY domain"
+    )
+    .bind(service)
+    .fetch_all(pool)
+    .await
+}
+
+//per-domain answered requests and 5xx responses since a unix time,
+//denials logged without a status don't count either way
+pub async fn get_error_counts(pool: &SqlitePool, since: i64) -> Result<Vec<(String, i64, i64)>, sqlx::Error> {
+    sqlx::query_as::<_, (String, i64, i64)>(
+        "SELECT domain,
+                COUNT(*),
+                COALESCE(SUM(CASE WHEN status >= 500 THEN 1 ELSE 0 END), 0)
+         FROM request_logs WHERE timestamp >= ? AND status > 0
+         GROUP BY domain ORDER BY domain"
+    )
+    .bind(since)
+    .fetch_all(pool)
+    .await
+}
+
+//requests per time bucket and status class, grouped by domain, origin or service.
+//rows without a status (denials logged before one is set) or without the group are left out
+pub async fn get_status_timeseries(
+    pool: &SqlitePool,
+    bucket_secs: i64,
+    since: i64,
+    group_by: StatusGroup,
+    service: Option<&str>,
+) -> Result<Vec<StatusBucket>, sqlx::Error> {
+    let column = match group_by {
+        StatusGroup::Domain => "domain",
+        StatusGroup::Origin => "origin",
+        StatusGroup::Service => "service",
+    };
+    let rows = sqlx::query(&format!(
+        "SELECT (timestamp / ?1) * ?1 AS bucket, {column} AS grp,
+                SUM(CASE WHEN status BETWEEN 100 AND 199 THEN 1 ELSE 0 END) AS status_1xx,
+                SUM(CASE WHEN status BETWEEN 200 AND 299 THEN 1 ELSE 0 END) AS status_2xx,
+                SUM(CASE WHEN status BETWEEN 300 AND 399 THEN 1 ELSE 0 END) AS status_3xx,
+                SUM(CASE WHEN status BETWEEN 400 AND 499 THEN 1 ELSE 0 END) AS status_4xx,
+                SUM(CASE WHEN status BETWEEN 500 AND 599 THEN 1 ELSE 0 END) AS status_5xx
+         FROM request_logs
+         WHERE timestamp >= ?2 AND status > 0 AND {column} IS NOT NULL AND (?3 IS NULL OR service = ?3)
+         GROUP BY bucket, grp ORDER BY bucket, grp"
+    ))
+    .bind(bucket_secs)
+    .bind(since)
+    .bind(service)
+    .fetch_all(pool)
+    .await?;
+
+    Ok(rows
+        .into_iter()
+        .map(|row| StatusBucket {
+            bucket_start: row.get("bucket"),
+            group: row.get("grp"),
+            status_1xx: row.get("status_1xx"),
+            status_2xx: row.get("status_2xx"),
+            status_3xx: row.get("status_3xx"),
+            status_4xx: row.get("status_4xx"),
+            status_5xx: row.get("status_5xx"),
+        })
+        .collect())
+}
+
+pub async fn get_denied_requests(pool: &SqlitePool, limit: i64) -> Result<Vec<DenialDto>, sqlx::Error> {
+    let rows = sqlx::query(
+        "SELECT denial, denial_rule, domain, path, method, status, ip_address, timestamp
+         FROM denied_requests ORDER BY id DESC LIMIT ?"
+    )
+    .bind(limit)
+    .fetch_all(pool)
+    .await?;
+
+    Ok(rows
+        .into_iter()
+        .map(|row| DenialDto {
+            category: row.get("denial"),
+            rule: row.get("denial_rule"),
+            domain: row.get("domain"),
+            path: row.get("path"),
+            method: row.get("method"),
+            status: row.get("status"),
+            ip_address: row.get("ip_address"),
+            timestamp: row.get("timestamp"),
+        })
+        .collect())
+}
+
+//most recent request logs, optionally only those carrying a request id
+pub async fn get_logs(
+    pool: &SqlitePool,
+    request_id: Option<&str>,
+    limit: i64,
+) -> Result<Vec<LogDto>, sqlx::Error> {
+    let rows = sqlx::query(
+        "SELECT request_id, domain, origin, service, path, method, status, response_time_ms, bytes_sent, ip_address, denial, error, timestamp
+         FROM request_logs WHERE (?1 IS NULL OR request_id = ?1) ORDER BY id DESC LIMIT ?2"
+    )
+    .bind(request_id)
+    .bind(limit)
+    .fetch_all(pool)
+    .await?;
+
+    Ok(rows
+        .into_iter()
+        .map(|row| LogDto {
+            request_id: row.get("request_id"),
+            domain: row.get("domain"),
+            origin: row.get("origin"),
+            service: row.get("service"),
+            path: row.get("path"),
+            method: row.get("method"),
+            status: row.get("status"),
+            response_time_ms: row.get("response_time_ms"),
+            bytes_sent: row.get("bytes_sent"),
+            ip_address: row.get("ip_address"),
+            denial: row.get("denial"),
+            error: row.get("error"),
+            timestamp: row.get("timestamp"),
+        })
+        .collect())
+}
+
 pub async fn get_config(pool: &SqlitePool, key: &str) -> Result<Option<String>, sqlx::Error> {
     let value: Option<String> = sqlx::query_scalar("SELECT value FROM config WHERE key = ?")
         .bind(key)
@@ -216,12 +727,16 @@ pub async fn get_config(pool: &SqlitePool, key: &str) -> Result<Option<String>,
 }
 
 pub async fn set_config(pool: &SqlitePool, key: &str, value: &str) -> Result<(), sqlx::Error> {
+    set_config_on(&mut *pool.acquire().await?, key, value).await
+}
+
+pub async fn set_config_on(conn: &mut SqliteConnection, key: &str, value: &str) -> Result<(), sqlx::Error> {
     let now = chrono::Utc::now().timestamp();
     sqlx::query("INSERT OR REPLACE INTO config (key, value, updated_at) VALUES (?, ?, ?)")
         .bind(key)
         .bind(value)
         .bind(now)
-        .execute(pool)
+        .execute(&mut *conn)
         .await?;
     Ok(())
 }
@@ -0,0 +1,77 @@
+use axum::http::StatusCode;
+use crate::settings::ProxySettings;
+
+//why a request was refused before reaching an origin
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DenialCategory {
+    RateLimit,
+    IpDeny,
+    OriginBlock,
+}
+
+impl DenialCategory {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DenialCategory::RateLimit => "rate_limit",
+            DenialCategory::IpDeny => "ip_deny",
+            DenialCategory::OriginBlock => "origin_block",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Denial {
+    pub category: DenialCategory,
+    pub rule: String,
+}
+
+impl Denial {
+    pub fn new(category: DenialCategory, rule: impl Into<String>) -> Self {
+        Self {
+            category,
+            rule: rule.into(),
+        }
+    }
+
+    //rate limiting keeps its own 429 so clients know to back off and a blocked
+    //origin is always a 403, every other category uses the configured denial response
+    pub fn status(&self, settings: &ProxySettings) -> StatusCode {
+        match self.category {
+            DenialCategory::RateLimit => StatusCode::TOO_MANY_REQUESTS,
+            DenialCategory::OriginBlock => StatusCode::FORBIDDEN,
+            _ => settings.deny_status,
+        }
+    }
+
+    pub fn body(&self, settings: &ProxySettings) -> String {
+        match self.category {
+            DenialCategory::RateLimit => "Too many requests".to_string(),
+            _ => settings.deny_body.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database;
+
+    #[tokio::test]
+    async fn only_rate_limits_and_blocked_origins_skip_the_configured_response() {
+        let db = database::test_db().await;
+        database::set_config(&db, "deny_status", "451").await.unwrap();
+        database::set_config(&db, "deny_body", "Blocked").await.unwrap();
+        let settings = ProxySettings::load(&db).await;
+
+        let cases = [
+            (DenialCategory::RateLimit, 429, "Too many requests"),
+            (DenialCategory::OriginBlock, 403, "Blocked"),
+            (DenialCategory::IpDeny, 451, "Blocked"),
+        ];
+        for (category, status, body) in cases {
+            let denial = Denial::new(category, "rule");
+            assert_eq!(denial.status(&settings).as_u16(), status, "{}", category.as_str());
+            assert_eq!(denial.body(&settings), body, "{}", category.as_str());
+        }
+    }
+}
@@ -0,0 +1,191 @@
+use hyper_util::client::legacy::connect::dns::{GaiResolver, Name};
+use std::{
+    collections::HashMap,
+    future::Future,
+    net::SocketAddr,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+use tower_service::Service;
+use tracing::warn;
+
+type Addrs = std::vec::IntoIter<SocketAddr>;
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+#[derive(Debug, Clone, Copy)]
+pub struct DnsCacheConfig {
+    pub ttl: Duration,
+    //serve an expired entry while it is re-resolved in the background
+    pub refresh: bool,
+}
+
+struct CachedAddrs {
+    addrs: Vec<SocketAddr>,
+    expires_at: Instant,
+    refreshing: bool,
+}
+
+//resolver for the upstream connector that remembers lookups for a TTL,
+//without a config every connection resolves as before
+#[derive(Clone)]
+pub struct CachingResolver<R = GaiResolver> {
+    inner: R,
+    config: Option<DnsCacheConfig>,
+    cache: Arc<Mutex<HashMap<String, CachedAddrs>>>,
+}
+
+impl CachingResolver {
+    pub fn new(config: Option<DnsCacheConfig>) -> Self {
+        Self {
+            inner: GaiResolver::new(),
+            config,
+            cache: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+}
+
+async fn lookup<R>(mut resolver: R, name: Name) -> Result<Vec<SocketAddr>, BoxError>
+where
+    R: Service<Name>,
+    R::Response: Iterator<Item = SocketAddr>,
+    R::Error: Into<BoxError>,
+{
+    Ok(resolver.call(name).await.map_err(Into::into)?.collect())
+}
+
+fn store(cache: &Mutex<HashMap<String, CachedAddrs>>, host: String, addrs: Vec<SocketAddr>, ttl: Duration) {
+    cache.lock().unwrap().insert(
+        host,
+        CachedAddrs {
+            addrs,
+            expires_at: Instant::now() + ttl,
+            refreshing: false,
+        },
+    );
+}
+
+impl<R> Service<Name> for CachingResolver<R>
+where
+    R: Service<Name> + Clone + Send + 'static,
+    R::Response: Iterator<Item = SocketAddr>,
+    R::Error: Into<BoxError>,
+    R::Future: Send,
+{
+    type Response = Addrs;
+    type Error = BoxError;
+    type Future = Pin<Box<dyn Future<Output = Result<Addrs, BoxError>> + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, name: Name) -> Self::Future {
+        let resolver = self.inner.clone();
+        let config = match self.config {
+            Some(config) => config,
+            None => return Box::pin(async move { Ok(lookup(resolver, name).await?.into_iter()) }),
+        };
+
+        let host = name.as_str().to_string();
+        let cached = {
+            let mut cache = self.cache.lock().unwrap();
+            match cache.get_mut(&host) {
+                Some(entry) if Instant::now() < entry.expires_at => Some(entry.addrs.clone()),
+                //only one background refresh per host at a time
+                Some(entry) if config.refresh => {
+                    let start_refresh = !entry.refreshing;
+                    entry.refreshing = true;
+                    if start_refresh {
+                        let cache = self.cache.clone();
+                        let resolver = resolver.clone();
+                        let name = name.clone();
+                        let host = host.clone();
+                        tokio::spawn(async move {
+                            match lookup(resolver, name).await {
+                                Ok(addrs) => store(&cache, host, addrs, config.ttl),
+                                Err(e) => {
+                                    warn!("DNS refresh failed for {}: {}", host, e);
+                                    if let Some(entry) = cache.lock().unwrap().get_mut(&host) {
+                                        entry.refreshing = false;
+                                    }
+                                }
+                            }
+                        });
+                    }
+                    Some(entry.addrs.clone())
+                }
+                _ => None,
+            }
+        };
+
+        if let Some(addrs) = cached {
+            return Box::pin(async move { Ok(addrs.into_iter()) });
+        }
+
+        let cache = self.cache.clone();
+        Box::pin(async move {
+            let addrs = lookup(resolver, name).await?;
+            store(&cache, host, addrs.clone(), config.ttl);
+            Ok(addrs.into_iter())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    //answers every lookup with the same address, counting how often it was asked
+    #[derive(Clone, Default)]
+    struct StubResolver {
+        lookups: Arc<AtomicUsize>,
+    }
+
+    impl Service<Name> for StubResolver {
+        type Response = Addrs;
+        type Error = BoxError;
+        type Future = Pin<Box<dyn Future<Output = Result<Addrs, BoxError>> + Send>>;
+
+        fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn call(&mut self, _name: Name) -> Self::Future {
+            self.lookups.fetch_add(1, Ordering::SeqCst);
+            Box::pin(async { Ok(vec![SocketAddr::from(([127, 0, 0, 1], 80))].into_iter()) })
+        }
+    }
+
+    fn resolver(ttl: Duration) -> (CachingResolver<StubResolver>, Arc<AtomicUsize>) {
+        let stub = StubResolver::default();
+        let lookups = stub.lookups.clone();
+        let resolver = CachingResolver {
+            inner: stub,
+            config: Some(DnsCacheConfig { ttl, refresh: false }),
+            cache: Arc::new(Mutex::new(HashMap::new())),
+        };
+        (resolver, lookups)
+    }
+
+    #[tokio::test]
+    async fn lookups_within_the_ttl_reuse_the_answer() {
+        let name = Name::from_str("origin.test").unwrap();
+
+        let (mut cached, lookups) = resolver(Duration::from_secs(60));
+        for _ in 0..3 {
+            let addrs: Vec<_> = cached.call(name.clone()).await.unwrap().collect();
+            assert_eq!(addrs, [SocketAddr::from(([127, 0, 0, 1], 80))]);
+        }
+        assert_eq!(lookups.load(Ordering::SeqCst), 1);
+
+        let (mut expired, lookups) = resolver(Duration::ZERO);
+        for _ in 0..3 {
+            expired.call(name.clone()).await.unwrap();
+        }
+        assert_eq!(lookups.load(Ordering::SeqCst), 3);
+    }
+}
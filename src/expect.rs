@@ -0,0 +1,40 @@
+use axum::http::HeaderMap;
+
+//what to do with a request carrying `Expect: 100-continue`. the interim 100 is sent by
+//the proxy once it starts reading the body, which only happens after the upstream request
+//is under way, so a request refused earlier (rate limit, 404, ...) never uploads its body
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExpectContinue {
+    //pass the header on so the origin can refuse the body too
+    Forward,
+    //drop the header, for origins that mishandle it
+    Strip,
+    //answer 417 without reading the body
+    Reject,
+}
+
+impl ExpectContinue {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "forward" => Some(ExpectContinue::Forward),
+            "strip" => Some(ExpectContinue::Strip),
+            "reject" => Some(ExpectContinue::Reject),
+            _ => None,
+        }
+    }
+}
+
+pub enum Expectation {
+    None,
+    Continue,
+    //anything but 100-continue, which nobody can meet
+    Unsupported(String),
+}
+
+pub fn of(headers: &HeaderMap) -> Expectation {
+    match headers.get("expect").map(|h| h.to_str().unwrap_or("").trim()) {
+        None => Expectation::None,
+        Some(value) if value.eq_ignore_ascii_case("100-continue") => Expectation::Continue,
+        Some(value) => Expectation::Unsupported(value.to_string()),
+    }
+}
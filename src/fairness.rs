@@ -0,0 +1,86 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+#[derive(Default)]
+struct Usage {
+    total: usize,
+    per_client: HashMap<String, usize>,
+}
+
+//requests each domain has in flight, split by client so one client can be
+//kept to a share of the domain's max_concurrency
+#[derive(Clone, Default)]
+pub struct DomainConcurrency {
+    inner: Arc<Mutex<HashMap<String, Usage>>>,
+}
+
+pub enum Refusal {
+    //the domain is at max_concurrency
+    DomainFull,
+    //this client already holds its share
+    ClientShare,
+}
+
+pub struct ConcurrencyGuard {
+    concurrency: DomainConcurrency,
+    domain: String,
+    client: String,
+}
+
+impl DomainConcurrency {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn try_acquire(
+        &self,
+        domain: &str,
+        client: &str,
+        capacity: usize,
+        client_cap: Option<usize>,
+    ) -> Result<ConcurrencyGuard, Refusal> {
+        let mut usage = self.inner.lock().unwrap();
+        let entry = usage.entry(domain.to_string()).or_default();
+
+        if entry.total >= capacity {
+            return Err(Refusal::DomainFull);
+        }
+        let held = entry.per_client.get(client).copied().unwrap_or(0);
+        if client_cap.map(|cap| held >= cap).unwrap_or(false) {
+            return Err(Refusal::ClientShare);
+        }
+
+        entry.total += 1;
+        *entry.per_client.entry(client.to_string()).or_default() += 1;
+        Ok(ConcurrencyGuard {
+            concurrency: self.clone(),
+            domain: domain.to_string(),
+            client: client.to_string(),
+        })
+    }
+}
+
+impl Drop for ConcurrencyGuard {
+    fn drop(&mut self) {
+        let mut usage = self.concurrency.inner.lock().unwrap();
+        if let Some(entry) = usage.get_mut(&self.domain) {
+            entry.total = entry.total.saturating_sub(1);
+            if let Some(held) = entry.per_client.get_mut(&self.client) {
+                *held = held.saturating_sub(1);
+                if *held == 0 {
+                    entry.per_client.remove(&self.client);
+                }
+            }
+            if entry.total == 0 {
+                usage.remove(&self.domain);
+            }
+        }
+    }
+}
+
+//the most requests one client may have in flight against a domain, at least one
+pub fn client_cap(capacity: usize, share: f64) -> usize {
+    ((capacity as f64 * share).floor() as usize).max(1)
+}
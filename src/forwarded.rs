@@ -0,0 +1,127 @@
+use axum::http::{HeaderMap, HeaderValue};
+use std::net::IpAddr;
+use crate::cidr::{self, Cidr};
+
+//resolves the real client address behind trusted proxies.
+//X-Forwarded-For is only honored when the peer itself is a trusted proxy,
+//and entries are read from the right so anything a client prepends is ignored
+pub fn client_ip(peer: IpAddr, headers: &HeaderMap, trusted: &[Cidr], hops: usize) -> IpAddr {
+    if !cidr::any_contains(trusted, peer) {
+        return peer;
+    }
+
+    let chain: Vec<IpAddr> = headers
+        .get_all("x-forwarded-for")
+        .iter()
+        .filter_map(|h| h.to_str().ok())
+        .flat_map(|h| h.split(','))
+        .filter_map(|entry| entry.trim().parse::<IpAddr>().ok())
+        .collect();
+
+    if chain.is_empty() {
+        return peer;
+    }
+
+    //a fixed number of trusted hops appended entries, the client is the one before them
+    if hops > 0 {
+        return match chain.len().checked_sub(hops) {
+            Some(idx) => chain[idx],
+            None => peer,
+        };
+    }
+
+    //otherwise skip trusted proxies from the right, the first untrusted entry is the client
+    chain
+        .iter()
+        .rev()
+        .find(|ip| !cidr::any_contains(trusted, **ip))
+        .or(chain.first())
+        .copied()
+        .unwrap_or(peer)
+}
+
+//tells the origin who the request came from and what it was addressed to. a trusted
+//proxy's X-Forwarded-* values are extended or kept, anything else a client sent is
+//replaced so it can't pass itself off as someone else
+pub fn add_headers(headers: &mut HeaderMap, peer: IpAddr, host: &str, proto: &str, trusted: &[Cidr]) {
+    let from_trusted = cidr::any_contains(trusted, peer);
+
+    let chain: Vec<String> = headers
+        .get_all("x-forwarded-for")
+        .iter()
+        .filter_map(|h| h.to_str().ok())
+        .map(|h| h.trim().to_string())
+        .filter(|h| !h.is_empty())
+        .collect();
+    let forwarded_for = match (from_trusted, chain.is_empty()) {
+        (true, false) => format!("{}, {}", chain.join(", "), peer),
+        _ => peer.to_string(),
+    };
+    set(headers, "x-forwarded-for", &forwarded_for);
+
+    //a trusted proxy in front may have terminated tls itself
+    if !from_trusted || !headers.contains_key("x-forwarded-proto") {
+        set(headers, "x-forwarded-proto", proto);
+    }
+    if !from_trusted || !headers.contains_key("x-forwarded-host") {
+        set(headers, "x-forwarded-host", host);
+    }
+
+    let via = match headers.get("via").and_then(|h| h.to_str().ok()) {
+        Some(existing) => format!("{}, 1.1 proxynet", existing),
+        None => "1.1 proxynet".to_string(),
+    };
+    set(headers, "via", &via);
+}
+
+fn set(headers: &mut HeaderMap, name: &'static str, value: &str) {
+    match HeaderValue::from_str(value) {
+        Ok(value) => {
+            headers.insert(name, value);
+        }
+        Err(_) => {
+            headers.remove(name);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn forwarded_for(chain: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert("x-forwarded-for", HeaderValue::from_str(chain).unwrap());
+        headers
+    }
+
+    #[test]
+    fn hops_pick_the_client_from_a_known_chain() {
+        let trusted = Cidr::parse_list("10.0.0.0/8");
+        let peer: IpAddr = "10.0.0.1".parse().unwrap();
+
+        //the cdn's edge and its shield each appended the address they saw
+        let headers = forwarded_for("203.0.113.7, 10.1.1.1");
+        assert_eq!(client_ip(peer, &headers, &trusted, 2), "203.0.113.7".parse::<IpAddr>().unwrap());
+        assert_eq!(client_ip(peer, &headers, &trusted, 1), "10.1.1.1".parse::<IpAddr>().unwrap());
+        //fewer entries than hops means the chain can't be trusted at all
+        assert_eq!(client_ip(peer, &headers, &trusted, 3), peer);
+    }
+
+    #[test]
+    fn entries_prepended_by_the_client_are_ignored() {
+        let trusted = Cidr::parse_list("10.0.0.0/8");
+        let peer: IpAddr = "10.0.0.1".parse().unwrap();
+        let client: IpAddr = "203.0.113.7".parse().unwrap();
+
+        //the client sent three addresses of its own, the cdn then appended the real one
+        let headers = forwarded_for("1.1.1.1, 8.8.8.8, 192.0.2.1, 203.0.113.7, 10.1.1.1");
+        assert_eq!(client_ip(peer, &headers, &trusted, 2), client);
+        //without hops, trusted addresses are skipped from the right instead
+        assert_eq!(client_ip(peer, &headers, &trusted, 0), client);
+
+        //a peer that isn't a trusted proxy is the client whatever it claims
+        let untrusted: IpAddr = "198.51.100.9".parse().unwrap();
+        assert_eq!(client_ip(untrusted, &headers, &trusted, 2), untrusted);
+    }
+}
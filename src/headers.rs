@@ -0,0 +1,79 @@
+use axum::http::{
+    header::{CACHE_CONTROL, CONNECTION, SET_COOKIE, UPGRADE},
+    HeaderMap, HeaderName, HeaderValue,
+};
+
+//headers describing a single connection, they mean nothing on the other side of the proxy.
+//transfer-encoding is left to hyper, which frames each side's body itself
+const HOP_BY_HOP: [&str; 6] = ["connection", "keep-alive", "proxy-connection", "te", "trailer", "upgrade"];
+
+//joins repeated values of each named header into one comma separated value.
+//Set-Cookie is never touched, its values can contain commas and must stay separate
+pub fn merge_values(headers: &mut HeaderMap, names: &[HeaderName]) {
+    for name in names.iter().filter(|name| **name != SET_COOKIE) {
+        let values: Vec<&str> = headers
+            .get_all(name)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .map(str::trim)
+            .filter(|value| !value.is_empty())
+            .collect();
+        if values.len() < 2 {
+            continue;
+        }
+
+        if let Ok(joined) = HeaderValue::from_str(&values.join(", ")) {
+            headers.insert(name.clone(), joined);
+        }
+    }
+}
+
+//tokens of every Connection header, lowercased
+fn connection_tokens(headers: &HeaderMap) -> Vec<String> {
+    headers
+        .get_all(CONNECTION)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|token| token.trim().to_ascii_lowercase())
+        .filter(|token| !token.is_empty())
+        .collect()
+}
+
+//directives of every Cache-Control header, lowercased, e.g. "no-store" or "max-age=60"
+pub fn cache_control(headers: &HeaderMap) -> Vec<String> {
+    headers
+        .get_all(CACHE_CONTROL)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|directive| directive.trim().to_ascii_lowercase())
+        .filter(|directive| !directive.is_empty())
+        .collect()
+}
+
+//whether the sender asked for its connection to be closed after this message
+pub fn wants_close(headers: &HeaderMap) -> bool {
+    connection_tokens(headers).iter().any(|token| token == "close")
+}
+
+//the protocol a request asks to switch to (e.g. websocket), only when its Connection
+//header says so, as an Upgrade header on its own doesn't apply
+pub fn upgrade(headers: &HeaderMap) -> Option<HeaderValue> {
+    if !connection_tokens(headers).iter().any(|token| token == "upgrade") {
+        return None;
+    }
+    headers.get(UPGRADE).cloned()
+}
+
+//removes the standard hop-by-hop headers and any other header the Connection header names
+pub fn strip_hop_by_hop(headers: &mut HeaderMap) {
+    for token in connection_tokens(headers) {
+        if let Ok(name) = HeaderName::from_bytes(token.as_bytes()) {
+            headers.remove(name);
+        }
+    }
+    for name in HOP_BY_HOP {
+        headers.remove(name);
+    }
+}
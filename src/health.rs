@@ -0,0 +1,342 @@
+use axum::{body::Body, http::Request};
+use http_body_util::BodyExt;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::sync::RwLock;
+use tracing::{info, warn};
+use crate::{routing::RouteTable, slow_start::SlowStart, HyperClient};
+
+const PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+
+//how often the checker looks for origins due a probe, per-domain intervals are rounded up to it
+const TICK: Duration = Duration::from_secs(1);
+
+//what a probe response must look like for an origin to count as up,
+//unset fields fall back to the global health_check.* config
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct HealthCheckConfig {
+    #[serde(default)]
+    pub expected_status: Option<String>,
+    #[serde(default)]
+    pub expect_body: Option<String>,
+    #[serde(default)]
+    pub path: Option<String>,
+    #[serde(default)]
+    pub interval_secs: Option<u64>,
+    //consecutive passing probes before a down origin takes traffic again
+    #[serde(default)]
+    pub healthy_threshold: Option<u32>,
+    //consecutive failing probes before an up origin stops taking traffic
+    #[serde(default)]
+    pub unhealthy_threshold: Option<u32>,
+}
+
+impl HealthCheckConfig {
+    pub fn merged(&self, defaults: &HealthCheckConfig) -> HealthCheckConfig {
+        HealthCheckConfig {
+            expected_status: self.expected_status.clone().or(defaults.expected_status.clone()),
+            expect_body: self.expect_body.clone().or(defaults.expect_body.clone()),
+            path: self.path.clone().or(defaults.path.clone()),
+            interval_secs: self.interval_secs.or(defaults.interval_secs),
+            healthy_threshold: self.healthy_threshold.or(defaults.healthy_threshold),
+            unhealthy_threshold: self.unhealthy_threshold.or(defaults.unhealthy_threshold),
+        }
+    }
+
+    pub fn status_ok(&self, status: u16) -> bool {
+        status_matches(self.expected_status.as_deref().unwrap_or("200-399"), status)
+    }
+
+    pub fn body_ok(&self, body: &str) -> bool {
+        self.expect_body
+            .as_deref()
+            .map(|needle| body.contains(needle))
+            .unwrap_or(true)
+    }
+}
+
+//accepts a range ("200-299") or a comma separated list ("200,204")
+pub fn status_matches(expected: &str, status: u16) -> bool {
+    match expected.split_once('-') {
+        Some((low, high)) => match (low.trim().parse::<u16>(), high.trim().parse::<u16>()) {
+            (Ok(low), Ok(high)) => (low..=high).contains(&status),
+            _ => false,
+        },
+        None => expected
+            .split(',')
+            .any(|code| code.trim().parse::<u16>() == Ok(status)),
+    }
+}
+
+//probe results of one origin
+#[derive(Debug, Clone, Copy)]
+struct OriginHealth {
+    healthy: bool,
+    //consecutive probes with the same outcome as the last one
+    passes: u32,
+    failures: u32,
+    last_checked_at: i64,
+}
+
+//what the admin api shows for an origin
+#[derive(Debug, Clone, Serialize)]
+pub struct UpstreamHealth {
+    pub origin: String,
+    pub healthy: bool,
+    pub consecutive_passes: u32,
+    pub consecutive_failures: u32,
+    //None until the origin has been probed
+    pub last_checked_at: Option<i64>,
+}
+
+#[derive(Clone)]
+pub struct HealthChecker {
+    status: Arc<RwLock<HashMap<String, OriginHealth>>>,
+    slow_start: SlowStart,
+}
+
+impl HealthChecker {
+    pub fn new() -> Self {
+        Self {
+            status: Arc::new(RwLock::new(HashMap::new())),
+            slow_start: SlowStart::new(None),
+        }
+    }
+
+    pub fn with_slow_start(mut self, slow_start: SlowStart) -> Self {
+        self.slow_start = slow_start;
+        self
+    }
+
+    //origins that were never probed count as healthy
+    pub async fn healthy_origins(&self, origins: &[String]) -> Vec<String> {
+        let status = self.status.read().await;
+        origins
+            .iter()
+            .filter(|origin| status.get(*origin).map(|health| health.healthy).unwrap_or(true))
+            .cloned()
+            .collect()
+    }
+
+    pub async fn snapshot(&self, origins: &[String]) -> Vec<UpstreamHealth> {
+        let status = self.status.read().await;
+        origins
+            .iter()
+            .map(|origin| {
+                let health = status.get(origin);
+                UpstreamHealth {
+                    origin: origin.clone(),
+                    healthy: health.map(|h| h.healthy).unwrap_or(true),
+                    consecutive_passes: health.map(|h| h.passes).unwrap_or(0),
+                    consecutive_failures: health.map(|h| h.failures).unwrap_or(0),
+                    last_checked_at: health.map(|h| h.last_checked_at),
+                }
+            })
+            .collect()
+    }
+
+    //an origin flips only after its threshold of probes in a row disagree with its state.
+    //origins start out up, like unprobed ones
+    async fn mark(&self, origin: &str, passed: bool, expectation: &HealthCheckConfig) {
+        let mut status = self.status.write().await;
+        let health = status.entry(origin.to_string()).or_insert(OriginHealth {
+            healthy: true,
+            passes: 0,
+            failures: 0,
+            last_checked_at: 0,
+        });
+        if passed {
+            health.passes += 1;
+            health.failures = 0;
+        } else {
+            health.failures += 1;
+            health.passes = 0;
+        }
+        health.last_checked_at = chrono::Utc::now().timestamp();
+
+        let flip = if passed {
+            !health.healthy && health.passes >= expectation.healthy_threshold.unwrap_or(1)
+        } else {
+            health.healthy && health.failures >= expectation.unhealthy_threshold.unwrap_or(1)
+        };
+        if !flip {
+            return;
+        }
+        health.healthy = passed;
+
+        if passed {
+            info!("HEALTH: {} is up", origin);
+            self.slow_start.recovered(origin);
+        } else {
+            warn!("HEALTH: {} is down", origin);
+        }
+    }
+
+    //probes every origin of a domain with a check interval, its own or the global one.
+    //an origin shared by several domains is probed once per interval
+    pub fn spawn(&self, routes: RouteTable, client: HyperClient, defaults: HealthCheckConfig) {
+        let checker = self.clone();
+        tokio::spawn(async move {
+            let mut next_probe: HashMap<String, Instant> = HashMap::new();
+            loop {
+                let targets: Vec<(Vec<String>, HealthCheckConfig)> = routes
+                    .read()
+                    .await
+                    .values()
+                    .map(|route| {
+                        let expectation = route
+                            .health_check
+                            .as_ref()
+                            .map(|hc| hc.merged(&defaults))
+                            .unwrap_or(defaults.clone());
+                        (route.origins.clone(), expectation)
+                    })
+                    .collect();
+
+                let now = Instant::now();
+                for (origins, expectation) in targets {
+                    let interval = match expectation.interval_secs.filter(|secs| *secs > 0) {
+                        Some(secs) => Duration::from_secs(secs),
+                        None => continue,
+                    };
+                    let path = expectation.path.as_deref().unwrap_or("/");
+                    for origin in origins {
+                        if next_probe.get(&origin).is_some_and(|due| *due > now) {
+                            continue;
+                        }
+                        next_probe.insert(origin.clone(), now + interval);
+
+                        let url = format!("{}{}", origin, path);
+                        let passed = probe(&client, &url, &expectation).await;
+                        checker.mark(&origin, passed, &expectation).await;
+                    }
+                }
+
+                tokio::time::sleep(TICK).await;
+            }
+        });
+    }
+}
+
+async fn probe(client: &HyperClient, url: &str, expectation: &HealthCheckConfig) -> bool {
+    let req = match Request::get(url).body(Body::empty()) {
+        Ok(req) => req,
+        Err(_) => return false,
+    };
+
+    let response = match tokio::time::timeout(PROBE_TIMEOUT, client.request(req)).await {
+        Ok(Ok(response)) => response,
+        _ => return false,
+    };
+
+    if !expectation.status_ok(response.status().as_u16()) {
+        return false;
+    }
+
+    //only read the body when something has to match in it
+    if expectation.expect_body.is_none() {
+        return true;
+    }
+
+    match response.into_body().collect().await {
+        Ok(collected) => expectation.body_ok(&String::from_utf8_lossy(&collected.to_bytes())),
+        Err(_) => false,
+    }
+}
+
+//origins that don't answer at all within the timeout, any http response counts as reachable
+pub async fn unreachable_origins(client: &HyperClient, origins: &[String], timeout: Duration) -> Vec<String> {
+    let mut unreachable = Vec::new();
+    for origin in origins {
+        let reachable = match Request::get(origin.as_str()).body(Body::empty()) {
+            Ok(req) => matches!(tokio::time::timeout(timeout, client.request(req)).await, Ok(Ok(_))),
+            Err(_) => false,
+        };
+        if !reachable {
+            unreachable.push(origin.clone());
+        }
+    }
+    unreachable
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{http::StatusCode, Router};
+    use hyper_util::{
+        client::legacy::{connect::HttpConnector, Client},
+        rt::TokioExecutor,
+    };
+    use crate::dns::CachingResolver;
+
+    fn client() -> HyperClient {
+        Client::builder(TokioExecutor::new()).build(HttpConnector::new_with_resolver(CachingResolver::new(None)))
+    }
+
+    //an origin answering /health with `status` and `body` on a loopback port
+    async fn origin(status: StatusCode, body: &'static str) -> String {
+        let app = Router::new().route("/health", axum::routing::get(move || async move { (status, body) }));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        format!("http://{}", addr)
+    }
+
+    //one probe of `origin`, marked like the background checker does
+    async fn check(checker: &HealthChecker, origin: &str, expectation: &HealthCheckConfig) -> bool {
+        let passed = probe(&client(), &format!("{}/health", origin), expectation).await;
+        checker.mark(origin, passed, expectation).await;
+        checker.healthy_origins(&[origin.to_string()]).await.len() == 1
+    }
+
+    #[tokio::test]
+    async fn an_unexpected_status_marks_the_origin_down() {
+        let checker = HealthChecker::new();
+        let expectation = HealthCheckConfig {
+            expected_status: Some("200,204".to_string()),
+            ..Default::default()
+        };
+
+        let up = origin(StatusCode::NO_CONTENT, "").await;
+        assert!(check(&checker, &up, &expectation).await);
+
+        //a 500 would fail the default 200-399 too, a 302 only fails the configured list
+        let redirecting = origin(StatusCode::FOUND, "").await;
+        assert!(check(&checker, &redirecting, &HealthCheckConfig::default()).await);
+        assert!(!check(&checker, &redirecting, &expectation).await);
+    }
+
+    #[tokio::test]
+    async fn a_body_without_the_expected_text_marks_the_origin_down() {
+        let checker = HealthChecker::new();
+        let expectation = HealthCheckConfig {
+            expect_body: Some("\"status\":\"ok\"".to_string()),
+            ..Default::default()
+        };
+
+        let up = origin(StatusCode::OK, "{\"status\":\"ok\"}").await;
+        assert!(check(&checker, &up, &expectation).await);
+
+        let degraded = origin(StatusCode::OK, "{\"status\":\"degraded\"}").await;
+        assert!(!check(&checker, &degraded, &expectation).await);
+    }
+
+    #[tokio::test]
+    async fn startup_probe_fails_only_on_origins_that_are_down() {
+        let up = origin(StatusCode::OK, "ok").await;
+        //an origin answering with an error is still reachable
+        let failing = origin(StatusCode::INTERNAL_SERVER_ERROR, "").await;
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let down = format!("http://{}", listener.local_addr().unwrap());
+        drop(listener);
+
+        let timeout = Duration::from_secs(1);
+        assert!(unreachable_origins(&client(), &[up.clone(), failing.clone()], timeout).await.is_empty());
+        let origins = [up, down.clone(), failing];
+        assert_eq!(unreachable_origins(&client(), &origins, timeout).await, vec![down]);
+    }
+}
@@ -0,0 +1,65 @@
+use axum::http::HeaderMap;
+use bytes::Bytes;
+
+//html sent as-is, an encoded body would have to be decompressed before it could be edited
+pub fn is_plain_html(headers: &HeaderMap) -> bool {
+    let html = headers
+        .get("content-type")
+        .and_then(|h| h.to_str().ok())
+        .map(|ct| ct.trim_start().to_ascii_lowercase().starts_with("text/html"))
+        .unwrap_or(false);
+    let encoded = headers
+        .get("content-encoding")
+        .and_then(|h| h.to_str().ok())
+        .map(|ce| !ce.trim().eq_ignore_ascii_case("identity"))
+        .unwrap_or(false);
+    html && !encoded
+}
+
+//inserts `snippet` right before the first </head>, None when the page has none
+pub fn inject_before_head(body: &[u8], snippet: &str) -> Option<Bytes> {
+    const CLOSE_HEAD: &[u8] = b"</head>";
+    let at = body
+        .windows(CLOSE_HEAD.len())
+        .position(|window| window.eq_ignore_ascii_case(CLOSE_HEAD))?;
+
+    let mut out = Vec::with_capacity(body.len() + snippet.len());
+    out.extend_from_slice(&body[..at]);
+    out.extend_from_slice(snippet.as_bytes());
+    out.extend_from_slice(&body[at..]);
+    Some(Bytes::from(out))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SNIPPET: &str = "<script src=\"/rum.js\"></script>";
+
+    fn headers(pairs: &[(&'static str, &'static str)]) -> HeaderMap {
+        pairs.iter().map(|(name, value)| (name.parse().unwrap(), value.parse().unwrap())).collect()
+    }
+
+    #[test]
+    fn snippet_goes_in_once_right_before_the_first_head_close() {
+        let page = b"<html><HEAD><title>t</title></HEAD><body></head></body></html>";
+        let injected = inject_before_head(page, SNIPPET).unwrap();
+        let injected = std::str::from_utf8(&injected).unwrap();
+        assert_eq!(
+            injected,
+            "<html><HEAD><title>t</title><script src=\"/rum.js\"></script></HEAD><body></head></body></html>"
+        );
+        assert_eq!(injected.matches(SNIPPET).count(), 1);
+
+        assert_eq!(inject_before_head(b"<html><body>no head</body></html>", SNIPPET), None);
+    }
+
+    #[test]
+    fn only_unencoded_html_is_edited() {
+        assert!(is_plain_html(&headers(&[("content-type", "text/html; charset=utf-8")])));
+        assert!(is_plain_html(&headers(&[("content-type", "text/html"), ("content-encoding", "identity")])));
+        assert!(!is_plain_html(&headers(&[("content-type", "text/html"), ("content-encoding", "gzip")])));
+        assert!(!is_plain_html(&headers(&[("content-type", "application/json")])));
+        assert!(!is_plain_html(&HeaderMap::new()));
+    }
+}
@@ -0,0 +1,104 @@
+use serde::Serialize;
+use sqlx::SqlitePool;
+use std::{
+    net::IpAddr,
+    sync::{Arc, RwLock},
+};
+use crate::cidr::{self, Cidr};
+use crate::database;
+
+//what happens to addresses on neither list
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DefaultAction {
+    Allow,
+    Deny,
+}
+
+impl DefaultAction {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "allow" => Some(DefaultAction::Allow),
+            "deny" => Some(DefaultAction::Deny),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct FilterSnapshot {
+    pub default: DefaultAction,
+    pub allow: Vec<String>,
+    pub deny: Vec<String>,
+}
+
+//client address allow/deny lists checked before anything else, a deny entry wins over
+//an allow entry. the deny list can change at runtime and is kept in ip_filter.deny
+#[derive(Clone)]
+pub struct IpFilter {
+    default: DefaultAction,
+    allow: Vec<Cidr>,
+    deny: Arc<RwLock<Vec<Cidr>>>,
+}
+
+impl IpFilter {
+    pub fn new(default: DefaultAction, allow: Vec<Cidr>, deny: Vec<Cidr>) -> Self {
+        Self {
+            default,
+            allow,
+            deny: Arc::new(RwLock::new(deny)),
+        }
+    }
+
+    //the list entry that refuses `ip`, or "ip_filter.default" when nothing allows it
+    pub fn check(&self, ip: IpAddr) -> Option<String> {
+        if let Some(range) = self.deny.read().unwrap().iter().find(|range| range.contains(ip)) {
+            return Some(format!("ip_filter.deny:{}", range));
+        }
+        if self.default == DefaultAction::Deny && !cidr::any_contains(&self.allow, ip) {
+            return Some("ip_filter.default".to_string());
+        }
+        None
+    }
+
+    pub fn snapshot(&self) -> FilterSnapshot {
+        FilterSnapshot {
+            default: self.default,
+            allow: self.allow.iter().map(Cidr::to_string).collect(),
+            deny: self.deny.read().unwrap().iter().map(Cidr::to_string).collect(),
+        }
+    }
+
+    //returns false when the range was already listed
+    pub async fn block(&self, db: &SqlitePool, range: Cidr) -> Result<bool, sqlx::Error> {
+        let list = {
+            let mut deny = self.deny.write().unwrap();
+            if deny.contains(&range) {
+                return Ok(false);
+            }
+            deny.push(range);
+            join(&deny)
+        };
+        database::set_config(db, "ip_filter.deny", &list).await?;
+        Ok(true)
+    }
+
+    //returns false when the range wasn't listed
+    pub async fn unblock(&self, db: &SqlitePool, range: Cidr) -> Result<bool, sqlx::Error> {
+        let list = {
+            let mut deny = self.deny.write().unwrap();
+            let before = deny.len();
+            deny.retain(|listed| *listed != range);
+            if deny.len() == before {
+                return Ok(false);
+            }
+            join(&deny)
+        };
+        database::set_config(db, "ip_filter.deny", &list).await?;
+        Ok(true)
+    }
+}
+
+fn join(ranges: &[Cidr]) -> String {
+    ranges.iter().map(Cidr::to_string).collect::<Vec<_>>().join(",")
+}
@@ -0,0 +1,27 @@
+use tracing::info;
+
+//accepted connections inherit the listener's socket buffer sizes, so they're set before listening
+pub async fn bind_listener(addr: &str, buffer_bytes: Option<u32>) -> std::io::Result<tokio::net::TcpListener> {
+    let bytes = match buffer_bytes {
+        Some(bytes) => bytes,
+        None => return tokio::net::TcpListener::bind(addr).await,
+    };
+
+    let addr = tokio::net::lookup_host(addr)
+        .await?
+        .next()
+        .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidInput, "no address to bind"))?;
+    let socket = if addr.is_ipv4() {
+        tokio::net::TcpSocket::new_v4()?
+    } else {
+        tokio::net::TcpSocket::new_v6()?
+    };
+    //same as TcpListener::bind, which leaves it off on windows where it allows port stealing
+    #[cfg(not(windows))]
+    socket.set_reuseaddr(true)?;
+    socket.set_recv_buffer_size(bytes)?;
+    socket.set_send_buffer_size(bytes)?;
+    socket.bind(addr)?;
+    info!("Client socket buffers: {} bytes", bytes);
+    socket.listen(1024)
+}
@@ -0,0 +1,200 @@
+use sqlx::sqlite::SqlitePool;
+use std::{
+    collections::VecDeque,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+use tokio::sync::Notify;
+use tracing::warn;
+use crate::database::save_log;
+use crate::logger::RequestLog;
+
+//what to do with a request log when the write queue is full
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Overflow {
+    //make room by discarding the longest-waiting log
+    DropOldest,
+    //discard the log being added
+    DropNewest,
+    //hold the request until the writer has caught up
+    Block,
+}
+
+impl Overflow {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "drop_oldest" => Some(Overflow::DropOldest),
+            "drop_newest" => Some(Overflow::DropNewest),
+            "block" => Some(Overflow::Block),
+            _ => None,
+        }
+    }
+}
+
+struct Queue {
+    logs: Mutex<VecDeque<RequestLog>>,
+    capacity: usize,
+    overflow: Overflow,
+    //wakes the writer when a log is queued
+    queued: Notify,
+    //wakes blocked requests when the writer takes logs off the queue
+    drained: Notify,
+    dropped: AtomicU64,
+    failed: AtomicU64,
+}
+
+//request logs waiting to be written by a single background task, so a burst
+//of traffic can't pile up unbounded inserts against the database
+#[derive(Clone)]
+pub struct LogWriter {
+    queue: Arc<Queue>,
+}
+
+impl LogWriter {
+    pub fn spawn(db: SqlitePool, capacity: usize, overflow: Overflow) -> Self {
+        let queue = Arc::new(Queue {
+            logs: Mutex::new(VecDeque::with_capacity(capacity.min(4096))),
+            capacity: capacity.max(1),
+            overflow,
+            queued: Notify::new(),
+            drained: Notify::new(),
+            dropped: AtomicU64::new(0),
+            failed: AtomicU64::new(0),
+        });
+
+        let writer = queue.clone();
+        tokio::spawn(async move {
+            loop {
+                let next = writer.logs.lock().unwrap().pop_front();
+                match next {
+                    Some(log) => {
+                        writer.drained.notify_waiters();
+                        //a failed insert loses that log and nothing else, the request was answered long ago
+                        if let Err(e) = save_log(&db, &log).await {
+                            let total = writer.failed.fetch_add(1, Ordering::Relaxed) + 1;
+                            if total % 1000 == 1 {
+                                warn!("LOG WRITE FAILED: {} ({} request logs lost so far)", e, total);
+                            }
+                        }
+                    }
+                    None => writer.queued.notified().await,
+                }
+            }
+        });
+
+        Self { queue }
+    }
+
+    pub async fn push(&self, log: RequestLog) {
+        let mut log = Some(log);
+        loop {
+            //registered before checking for room so a drain in between isn't missed
+            let drained = self.queue.drained.notified();
+            {
+                let mut logs = self.queue.logs.lock().unwrap();
+                if logs.len() < self.queue.capacity {
+                    logs.extend(log.take());
+                    drop(logs);
+                    self.queue.queued.notify_one();
+                    return;
+                }
+
+                match self.queue.overflow {
+                    Overflow::DropOldest => {
+                        logs.pop_front();
+                        logs.extend(log.take());
+                        drop(logs);
+                        self.dropped();
+                        self.queue.queued.notify_one();
+                        return;
+                    }
+                    Overflow::DropNewest => {
+                        drop(logs);
+                        self.dropped();
+                        return;
+                    }
+                    Overflow::Block => {}
+                }
+            }
+            drained.await;
+        }
+    }
+
+    fn dropped(&self) {
+        let total = self.queue.dropped.fetch_add(1, Ordering::Relaxed) + 1;
+        //one line per thousand so a flood doesn't also flood the log
+        if total % 1000 == 1 {
+            warn!("LOG QUEUE FULL: {} request logs dropped so far", total);
+        }
+    }
+
+    pub fn dropped_total(&self) -> u64 {
+        self.queue.dropped.load(Ordering::Relaxed)
+    }
+
+    pub fn failed_total(&self) -> u64 {
+        self.queue.failed.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database;
+    use std::time::{Duration, Instant};
+
+    //ten logs pushed into a writer holding three, without yielding to it in between
+    async fn flood(overflow: Overflow) -> (LogWriter, SqlitePool) {
+        let db = database::test_db().await;
+        let writer = LogWriter::spawn(db.clone(), 3, overflow);
+        for i in 0..10 {
+            let log = RequestLog::new("app.test".into(), format!("/{}", i), "GET".into(), 200, Instant::now());
+            writer.push(log).await;
+        }
+        (writer, db)
+    }
+
+    //the paths written once `count` logs are stored, oldest first
+    async fn written(db: &SqlitePool, count: usize) -> Vec<String> {
+        for _ in 0..100 {
+            let logs = database::get_logs(db, None, 100).await.unwrap();
+            if logs.len() >= count {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+                let mut paths: Vec<String> = database::get_logs(db, None, 100)
+                    .await
+                    .unwrap()
+                    .into_iter()
+                    .map(|log| log.path)
+                    .collect();
+                paths.reverse();
+                return paths;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        panic!("fewer than {} logs written", count);
+    }
+
+    #[tokio::test]
+    async fn drop_newest_keeps_the_first_logs_and_counts_the_rest() {
+        let (writer, db) = flood(Overflow::DropNewest).await;
+        assert_eq!(writer.dropped_total(), 7);
+        assert_eq!(written(&db, 3).await, ["/0", "/1", "/2"]);
+    }
+
+    #[tokio::test]
+    async fn drop_oldest_keeps_the_latest_logs_and_counts_the_rest() {
+        let (writer, db) = flood(Overflow::DropOldest).await;
+        assert_eq!(writer.dropped_total(), 7);
+        assert_eq!(written(&db, 3).await, ["/7", "/8", "/9"]);
+    }
+
+    #[tokio::test]
+    async fn block_waits_for_room_and_loses_nothing() {
+        let (writer, db) = flood(Overflow::Block).await;
+        assert_eq!(writer.dropped_total(), 0);
+        let expected: Vec<String> = (0..10).map(|i| format!("/{}", i)).collect();
+        assert_eq!(written(&db, 10).await, expected);
+    }
+}
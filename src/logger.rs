@@ -13,6 +13,12 @@ pub struct RequestLog {
     pub ip_address: Option<String>,
     pub user_agent: Option<String>,
     pub referer: Option<String>,
+    pub denial: Option<String>,
+    pub denial_rule: Option<String>,
+    pub request_id: Option<String>,
+    pub error: Option<String>,
+    pub origin: Option<String>,
+    pub service: Option<String>,
     pub timestamp: chrono::DateTime<Utc>,
 }
 
@@ -34,6 +40,12 @@ impl RequestLog {
             ip_address: None,
             user_agent: None,
             referer: None,
+            denial: None,
+            denial_rule: None,
+            request_id: None,
+            error: None,
+            origin: None,
+            service: None,
             timestamp: Utc::now(),
         }
     }
@@ -53,6 +65,35 @@ impl RequestLog {
         self
     }
 
+    pub fn with_denial(mut self, category: &str, rule: &str) -> Self {
+        self.denial = Some(category.to_string());
+        self.denial_rule = Some(rule.to_string());
+        self
+    }
+
+    pub fn with_request_id(mut self, request_id: String) -> Self {
+        self.request_id = Some(request_id);
+        self
+    }
+
+    //why the proxy failed the request, e.g. "response_too_large"
+    pub fn with_error(mut self, reason: &str) -> Self {
+        self.error = Some(reason.to_string());
+        self
+    }
+
+    //the origin the request was sent to
+    pub fn with_origin(mut self, origin: &str) -> Self {
+        self.origin = Some(origin.to_string());
+        self
+    }
+
+    //the service label of the domain the request was for
+    pub fn with_service(mut self, service: Option<&str>) -> Self {
+        self.service = service.map(String::from);
+        self
+    }
+
     pub fn with_bytes(mut self, bytes: u64) -> Self {
         self.bytes_sent = bytes;
         self
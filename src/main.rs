@@ -3,35 +3,130 @@ mod database;
 mod cache;
 mod rate_limiter;
 mod api;
+mod balancer;
+mod routing;
+mod settings;
+mod denial;
+mod health;
+mod cidr;
+mod forwarded;
+mod streaming;
+mod stats;
+mod validation;
+mod redirect;
+mod breaker;
+mod maintenance;
+mod upstream_error;
+mod dns;
+mod request_id;
+mod proxy_protocol;
+mod snapshot;
+mod retry;
+mod tls;
+mod slow_start;
+mod html;
+mod expect;
+mod sampling;
+mod selftest;
+mod headers;
+mod fairness;
+mod pacing;
+mod log_writer;
+mod precompressed;
+mod ssrf;
+mod affinity;
+mod banner;
+mod status_page;
+mod aux_memory;
+mod listener;
+mod acme;
+mod ip_filter;
+mod reload;
+mod config_file;
 
 use axum::{
     body::Body,
     extract::{ConnectInfo, Request, State},
-    http::{HeaderMap, StatusCode},
-    response::{Response},
+    Extension,
+    http::{header, HeaderMap, HeaderName, HeaderValue, Method, StatusCode},
+    middleware,
+    response::{IntoResponse, Response},
     Router, 
 };
-use http_body_util::BodyExt;
-use hyper_util::{client::legacy::Client, rt::TokioExecutor};
-use sqlx::sqlite::SqlitePool;
-use std::{collections::HashMap, net::SocketAddr, sync::Arc, time::Instant};
-use tokio::sync::RwLock;
-use tracing::{info, warn};
+use http_body_util::{BodyExt, LengthLimitError, Limited};
+use hyper::body::Body as HttpBody;
+use hyper_util::{
+    client::legacy::{connect::HttpConnector, Client},
+    rt::{TokioExecutor, TokioIo},
+};
+use sqlx::SqlitePool;
+use std::{collections::HashMap, net::SocketAddr, sync::Arc, time::{Duration, Instant}};
+use tokio::{
+    sync::{watch, RwLock, Semaphore},
+    task::JoinSet,
+};
+use tracing::{error, info, info_span, warn, Instrument, Span};
 use logger::RequestLog;
-use database::{init_db, save_log};
-use cache::MemoryCache;
-use rate_limiter::RateLimiter;
-use api::api_router;
+use database::init_db;
+use cache::{CachedResponse, MemoryCache};
+use rate_limiter::{PathLimiters, RateLimitDecision, RateLimiter};
+use api::{api_router, ApiState};
+use balancer::{HashKey, OriginSelector};
+use routing::{Route, RouteTable, TrailingSlash};
+use settings::{ActiveConfig, ActiveConfigHandle, ProxySettings};
+use denial::{Denial, DenialCategory};
+use health::HealthChecker;
+use breaker::CircuitBreakers;
+use upstream_error::{ErrorBody, SendError, UpstreamFailure};
+use dns::CachingResolver;
+use expect::{ExpectContinue, Expectation};
+use fairness::{DomainConcurrency, Refusal};
+use pacing::OriginPacer;
+use log_writer::LogWriter;
+use affinity::PinnedConnections;
+use status_page::Status;
+use aux_memory::AuxMemory;
+use proxy_protocol::{ProxiedAddr, ProxyProtocolListener};
+use tls::{CertStore, TlsConnection, TlsListener, TlsStats};
+use acme::{Acme, Challenges};
+use ip_filter::IpFilter;
+use slow_start::SlowStart;
+use sampling::Sampler;
+use listener::bind_listener;
+use stats::{InFlight, LimitCounters, LiveCounters, OriginCounters, RequestMetrics};
 
-type HyperClient = Client<hyper_util::client::legacy::connect::HttpConnector, Body>;
+type HyperClient = Client<HttpConnector<CachingResolver>, Body>;
 
 #[derive(Clone)]
 struct AppState {
-    routes: Arc<RwLock<HashMap<String, String>>>,
+    routes: RouteTable,
     client: HyperClient,
-    db: SqlitePool,
-    cache: MemoryCache,
+    pinned: PinnedConnections,
+    log_writer: LogWriter,
+    cache: Option<MemoryCache>,
     rate_limiter: Option<RateLimiter>,
+    path_limiters: PathLimiters,
+    aux_memory: Option<AuxMemory>,
+    balancer: Arc<dyn OriginSelector>,
+    hash_key: HashKey,
+    settings: Arc<ProxySettings>,
+    //where settings and rate_limiter come from for each request, replaced by a reload
+    active: ActiveConfigHandle,
+    health: HealthChecker,
+    breakers: CircuitBreakers,
+    slow_start: SlowStart,
+    upstream_permits: Option<Arc<Semaphore>>,
+    limit_counters: LimitCounters,
+    origin_counters: OriginCounters,
+    in_flight: InFlight,
+    concurrency: DomainConcurrency,
+    pacer: OriginPacer,
+    live: LiveCounters,
+    metrics: RequestMetrics,
+    tls: TlsStats,
+    acme_challenges: Option<Challenges>,
+    ip_filter: IpFilter,
+    sampler: Option<Sampler>,
 }
 
 #[tokio::main]
@@ -46,6 +141,16 @@ async fn main() {
     let db = init_db().await.expect("Failed to initialize database");
     info!("Database initialized");
 
+    //a config file is written into the database before anything reads the config from it
+    let config_file = database::get_config(&db, "config_file").await.ok().flatten().map(std::path::PathBuf::from);
+    if let Some(path) = &config_file {
+        if let Err(e) = config_file::apply(&db, path).await {
+            error!("Invalid config file {}: {}", path.display(), e);
+            std::process::exit(1);
+        }
+        info!("Loaded config file {}", path.display());
+    }
+
     //load config from database
     let host = database::get_config(&db, "host")
         .await
@@ -61,44 +166,31 @@ async fn main() {
         .parse::<u16>()
         .unwrap_or(8080);
 
-    let api_port = database::get_config(&db, "api_port")
-        .await
-        .ok()
-        .flatten()
-        .unwrap_or("8081".to_string())
-        .parse::<u16>()
-        .unwrap_or(8081);
-
-    let rate_limit = database::get_config(&db, "rate_limit_per_minute")
-        .await
-        .ok()
-        .flatten()
-        .and_then(|s| s.parse::<u32>().ok());
-
-    info!("Loaded config from database: {}:{}, api_port: {}, rate_limit: {:?}", host, port, api_port, rate_limit);
+    let admin_addr = admin_addr(&db, &host).await;
 
-    //init in-memory cache
-    let cache = MemoryCache::new();
-    info!("In-memory cache initialized");
+    let (lb_strategy, hash_key) = lb_config(&db).await;
 
-    //init rate limiter
-    let rate_limiter = rate_limit.map(|limit| {
-        let rl = RateLimiter::new(limit, 60);
-        rl.spawn_cleanup();
-        info!("Rate limiter initialized: {} requests/minute", limit);
-        rl
-    });
+    let settings = ProxySettings::load(&db).await;
 
-    //create http client
-    let client = Client::builder(TokioExecutor::new()).build_http();
+    let rate_limit = settings.rate_limit.map(|config| config.limit);
+    info!(
+        "Loaded config from database: {}:{}, admin: {}, rate_limit: {:?}",
+        host,
+        port,
+        admin_addr.as_deref().unwrap_or("disabled"),
+        rate_limit
+    );
+    info!("Load balancing: {} (hash key: {:?})", lb_strategy, hash_key);
 
     //build routes from database
     let mut routes = HashMap::new();
+    let mut domain_count = 0;
     match database::load_domains(&db).await {
         Ok(domains) => {
-            for (domain, origin) in domains {
-                routes.insert(domain.clone(), origin.clone());
-                info!("Loaded from DB: {} -> {}", domain, origin);
+            domain_count = domains.len();
+            for domain in domains {
+                info!("Loaded from DB: {} -> {:?}", routing::names(&domain).join(", "), domain.origin_list());
+                routing::insert(&mut routes, routing::entries(&domain));
             }
         }
         Err(e) => {
@@ -106,62 +198,561 @@ async fn main() {
         }
     }
 
-    let app_state = AppState {
-        routes: Arc::new(RwLock::new(routes)),
-        client,
-        db: db.clone(),
-        cache,
-        rate_limiter,
-    };
+    let mut app_state = build_state(&db, settings, routes, &lb_strategy, hash_key);
+    let settings = app_state.settings.clone();
+
+    //refuse to start if any enabled origin can't be reached
+    if settings.startup_probe {
+        let origins: Vec<String> = app_state.routes.read().await.values().flat_map(|r| r.origins.clone()).collect();
+        let timeout = Duration::from_millis(settings.upstream_timeout_ms.unwrap_or(5000));
+        let unreachable = health::unreachable_origins(&app_state.client, &origins, timeout).await;
+        if !unreachable.is_empty() {
+            error!("Startup probe failed, unreachable origins: {}", unreachable.join(", "));
+            std::process::exit(1);
+        }
+        info!("Startup probe passed for {} origins", origins.len());
+    }
+
+    //active health checks run for domains with a check interval, their own or the global one
+    app_state.health.spawn(app_state.routes.clone(), app_state.client.clone(), settings.health_check.clone());
+    if let Some(interval) = settings.health_check.interval_secs {
+        let path = settings.health_check.path.as_deref().unwrap_or("/");
+        info!("Health checks enabled: every {}s on {}", interval, path);
+    }
+
+    //periodically write the domains and config to disk for disaster recovery
+    if let Some(interval) = settings.snapshot_interval_secs.filter(|secs| *secs > 0) {
+        snapshot::spawn(db.clone(), settings.snapshot_dir.clone(), Duration::from_secs(interval));
+        info!("Config snapshots enabled: every {}s to {}", interval, settings.snapshot_dir.display());
+    }
+
+    //certificates for the https listener: tls.cert_path as the default, plus any obtained over ACME
+    let certs = CertStore::new(settings.tls_unknown_sni.clone());
+    if let (Some(cert), Some(key)) = (&settings.tls_cert_path, &settings.tls_key_path)
+        && let Err(e) = certs.load_default(cert, key)
+    {
+        error!("TLS setup failed: {}", e);
+        std::process::exit(1);
+    }
+    if let Some(config) = &settings.acme {
+        let acme = Acme::new(config.clone(), db.clone(), app_state.routes.clone(), certs.clone());
+        acme.load_stored().await;
+        acme.spawn();
+        info!("ACME enabled: certificates from {}", config.directory);
+        app_state.acme_challenges = Some(acme.challenges());
+    }
+
+    //the cache and the rate limiter maps share one memory budget when configured
+    if let Some(budget) = settings.max_aux_memory_bytes {
+        info!("Cache and rate limiters limited to ~{} bytes together", budget);
+        let aux_memory = AuxMemory::new(
+            budget,
+            app_state.cache.clone(),
+            app_state.active.clone(),
+            app_state.path_limiters.clone(),
+        );
+        aux_memory.spawn();
+        app_state.aux_memory = Some(aux_memory);
+    }
 
     //build proxy router
     let proxy_app = Router::new()
         .fallback(proxy_handler)
         .with_state(app_state.clone());
 
+    //validate the build and config end to end, then exit without serving
+    if std::env::args().any(|arg| arg == "--selftest") {
+        match selftest::run(app_state.clone(), proxy_app).await {
+            Ok(()) => {
+                info!("SELFTEST PASSED");
+                std::process::exit(0);
+            }
+            Err(e) => {
+                error!("SELFTEST FAILED: {}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+
     //build API router
-    let api_app = api_router(app_state.routes.clone(), db.clone());
+    let api_app = api_router(admin_state(&app_state, db.clone(), certs.clone()));
+
+    if app_state.settings.startup_banner {
+        let listen = format!("{}:{}", host, port);
+        startup_banner(&db, &app_state.settings, listen, admin_addr.clone(), domain_count, &lb_strategy)
+            .await
+            .log();
+    }
+
+    //SIGHUP reloads the domains and config from the database
+    let startup_config = database::get_all_config(&db).await.unwrap_or_default();
+    reload::spawn_on_sighup(
+        db.clone(),
+        config_file.clone(),
+        app_state.routes.clone(),
+        app_state.active.clone(),
+        startup_config,
+    );
+    if let Some(path) = config_file {
+        config_file::spawn_watcher(path, db.clone(), app_state.routes.clone(), app_state.active.clone());
+    }
 
     //start proxy server
     let proxy_addr = format!("{}:{}", host, port);
     info!("Proxy server started on http://{}", proxy_addr);
 
-    let proxy_listener = tokio::net::TcpListener::bind(&proxy_addr).await.unwrap();
-    let proxy_server = axum::serve(
-        proxy_listener, 
-        proxy_app.into_make_service_with_connect_info::<SocketAddr>()
-    );
+    let proxy_listener = bind_listener(&proxy_addr, app_state.settings.client_buffer_bytes).await.unwrap();
+    let proxy_protocol = app_state.settings.proxy_protocol;
+    let tls_app = proxy_app.clone().layer(Extension(TlsConnection));
 
-    //start API server
-    let api_addr = format!("{}:{}", host, api_port);
-    info!("API server started on http://{}", api_addr);
+    //flipped once to stop every server accepting, each then finishes its open connections
+    let (shutdown_tx, shutdown_rx) = watch::channel(false);
 
-    let api_listener = tokio::net::TcpListener::bind(&api_addr).await.unwrap();
-    let api_server = axum::serve(
-        api_listener,
-        api_app.into_make_service()
-    );
+    let shutdown = shutdown_rx.clone();
+    let proxy_server = async move {
+        if proxy_protocol {
+            //the client address comes from the PROXY header sent by the load balancer
+            info!("PROXY protocol enabled on the proxy listener");
+            let listener = ProxyProtocolListener::new(proxy_listener)?;
+            let service = proxy_app
+                .layer(middleware::map_request(proxy_protocol::restore_connect_info))
+                .into_make_service_with_connect_info::<ProxiedAddr>();
+            axum::serve(listener, service).with_graceful_shutdown(shutdown_requested(shutdown)).await
+        } else {
+            let service = proxy_app.into_make_service_with_connect_info::<SocketAddr>();
+            axum::serve(proxy_listener, service).with_graceful_shutdown(shutdown_requested(shutdown)).await
+        }
+    };
+
+    //serve https alongside http when a certificate is configured or ACME will get them
+    let tls_listener = if !certs.is_empty() || app_state.settings.acme.is_some() {
+        let config = match tls::server_config(certs) {
+            Ok(config) => config,
+            Err(e) => {
+                error!("TLS setup failed: {}", e);
+                std::process::exit(1);
+            }
+        };
+        let tls_addr = format!("{}:{}", host, app_state.settings.tls_port);
+        info!("Proxy server started on https://{}", tls_addr);
+        let listener = bind_listener(&tls_addr, app_state.settings.client_buffer_bytes).await.unwrap();
+        Some(TlsListener::new(listener, config, app_state.tls.clone()).unwrap())
+    } else {
+        None
+    };
+    let shutdown = shutdown_rx.clone();
+    let tls_server = async move {
+        match tls_listener {
+            Some(listener) => {
+                let service = tls_app
+                    .layer(middleware::map_request(proxy_protocol::restore_connect_info))
+                    .into_make_service_with_connect_info::<ProxiedAddr>();
+                axum::serve(listener, service).with_graceful_shutdown(shutdown_requested(shutdown)).await
+            }
+            None => {
+                shutdown_requested(shutdown).await;
+                Ok(())
+            }
+        }
+    };
+
+    //start API server unless it is turned off
+    let api_listener = match &admin_addr {
+        Some(api_addr) => {
+            info!("API server started on http://{}", api_addr);
+            Some(tokio::net::TcpListener::bind(api_addr).await.unwrap())
+        }
+        None => {
+            info!("API server disabled");
+            None
+        }
+    };
+    let shutdown = shutdown_rx.clone();
+    let api_server = async move {
+        match api_listener {
+            Some(listener) => {
+                axum::serve(listener, api_app.into_make_service())
+                    .with_graceful_shutdown(shutdown_requested(shutdown))
+                    .await
+            }
+            None => {
+                shutdown_requested(shutdown).await;
+                Ok(())
+            }
+        }
+    };
+
+    let mut servers = JoinSet::new();
+    servers.spawn(async move {
+        if let Err(e) = proxy_server.await {
+            warn!("Proxy server error: {}", e);
+        }
+    });
+    servers.spawn(async move {
+        if let Err(e) = tls_server.await {
+            warn!("TLS proxy server error: {}", e);
+        }
+    });
+    servers.spawn(async move {
+        if let Err(e) = api_server.await {
+            warn!("API server error: {}", e);
+        }
+    });
 
-    //run both servers concurrently
+    //run all servers until one stops or a shutdown signal, then stop accepting and give
+    //requests already in flight up to shutdown.grace_secs to finish
     tokio::select! {
-        result = proxy_server => {
-            if let Err(e) = result {
-                warn!("Proxy server error: {}", e);
+        _ = servers.join_next() => {
+            warn!("A server stopped, shutting down");
+        }
+        _ = shutdown_signal() => {
+            info!("Shutdown signal received, {} requests in flight", app_state.in_flight.total());
+        }
+    }
+    let _ = shutdown_tx.send(true);
+
+    let grace = app_state.settings.shutdown_grace;
+    let drain = async { while servers.join_next().await.is_some() {} };
+    match tokio::time::timeout(grace, drain).await {
+        Ok(()) => info!("All connections finished"),
+        Err(_) => warn!(
+            "Grace period of {}s over, dropping {} requests still in flight",
+            grace.as_secs(),
+            app_state.in_flight.total()
+        ),
+    }
+
+    info!("SHUTDOWN REPORT: {}", app_state.live.snapshot());
+}
+
+//load balancing strategy and hash key from the config, round robin on the path by default
+async fn lb_config(db: &SqlitePool) -> (String, HashKey) {
+    let lb_strategy = database::get_config(db, "lb_strategy")
+        .await
+        .ok()
+        .flatten()
+        .unwrap_or("round_robin".to_string());
+
+    let hash_key = database::get_config(db, "lb_hash_key")
+        .await
+        .ok()
+        .flatten()
+        .map(|k| HashKey::parse(&k))
+        .unwrap_or(HashKey::Path);
+
+    (lb_strategy, hash_key)
+}
+
+//the state the proxy runs on, built from the settings and routes. main and the tests
+//both start here; background tasks, ACME and the shared memory budget are left to main
+fn build_state(
+    db: &SqlitePool,
+    settings: ProxySettings,
+    routes: HashMap<String, Route>,
+    lb_strategy: &str,
+    hash_key: HashKey,
+) -> AppState {
+    //init in-memory cache
+    let cache = response_cache(&settings);
+
+    //init rate limiter
+    let rate_limiter = settings.rate_limit.as_ref().map(|config| {
+        if let Some(penalty) = &config.penalty {
+            info!(
+                "Rate limit penalty: {:?} doubling up to {:?}, reset after {:?} quiet",
+                penalty.base, penalty.max, penalty.reset_after
+            );
+        }
+        if let Some(max) = config.max_entries {
+            info!("Rate limiter tracks at most {} clients", max);
+        }
+        info!("Rate limiter initialized: {} requests/minute ({:?})", config.limit, config.algorithm);
+        RateLimiter::from_config(config)
+    });
+
+    //create http client, keeping the idle pool within the upstream connection ceiling
+    let mut client_builder = Client::builder(TokioExecutor::new());
+    if let Some(max) = settings.max_upstream_connections {
+        client_builder.pool_max_idle_per_host(max);
+    }
+    if let Some(dns) = &settings.dns_cache {
+        info!("DNS cache enabled: {:?} ttl (background refresh: {})", dns.ttl, dns.refresh);
+    }
+    let mut connector = HttpConnector::new_with_resolver(CachingResolver::new(settings.dns_cache));
+    if let Some(ms) = settings.upstream_connect_timeout_ms {
+        connector.set_connect_timeout(Some(Duration::from_millis(ms)));
+    }
+    //socket buffers for upstream connections, the kernel default when unset
+    if let Some(bytes) = settings.upstream_buffer_bytes {
+        connector.set_recv_buffer_size(Some(bytes));
+        connector.set_send_buffer_size(Some(bytes));
+        info!("Upstream socket buffers: {} bytes", bytes);
+    }
+    let pinned = PinnedConnections::new(connector.clone(), Duration::from_secs(settings.connection_affinity_idle_secs));
+    let client = client_builder.build(connector);
+
+    let upstream_permits = settings.max_upstream_connections.map(|max| {
+        info!("Upstream connections limited to {}", max);
+        Arc::new(Semaphore::new(max))
+    });
+
+    //origins coming back up get their share of traffic ramped in
+    let slow_start = SlowStart::new(settings.slow_start);
+    if let Some(window) = settings.slow_start {
+        info!("Slow start enabled: recovering origins ramp up over {:?}", window);
+    }
+
+    if let Some(breaker) = &settings.circuit_breaker {
+        info!(
+            "Circuit breakers enabled: open after {} failures for {:?}",
+            breaker.failure_threshold, breaker.open_for
+        );
+    }
+    let breakers = CircuitBreakers::new(settings.circuit_breaker).with_slow_start(slow_start.clone());
+
+    let tls = TlsStats::new(settings.tls_log_handshake_failures);
+    let log_writer = LogWriter::spawn(db.clone(), settings.log_queue_capacity, settings.log_queue_overflow);
+    let in_flight = InFlight::new();
+
+    let sampler = settings.trace_sample_rate.map(Sampler::new);
+    if let Some(rate) = settings.trace_sample_rate {
+        info!("Tracing {:.1}% of requests (plus those sampled upstream)", rate * 100.0);
+    }
+
+    let settings = Arc::new(settings);
+    let active = Arc::new(std::sync::RwLock::new(ActiveConfig {
+        settings: settings.clone(),
+        rate_limiter: rate_limiter.clone(),
+    }));
+
+    let path_limiters = PathLimiters::new();
+    path_limiters.spawn_cleanup(Duration::from_secs(60));
+
+    let ip_filter = IpFilter::new(
+        settings.ip_filter_default,
+        settings.ip_filter_allow.clone(),
+        settings.ip_filter_deny.clone(),
+    );
+
+    AppState {
+        routes: Arc::new(RwLock::new(routes)),
+        client,
+        pinned,
+        log_writer,
+        cache,
+        rate_limiter,
+        path_limiters,
+        aux_memory: None,
+        balancer: balancer::from_config(lb_strategy, &in_flight),
+        hash_key,
+        settings,
+        active,
+        health: HealthChecker::new().with_slow_start(slow_start.clone()),
+        breakers,
+        slow_start,
+        upstream_permits,
+        limit_counters: LimitCounters::new(),
+        origin_counters: OriginCounters::new(),
+        in_flight,
+        concurrency: DomainConcurrency::new(),
+        pacer: OriginPacer::new(),
+        live: LiveCounters::new(),
+        metrics: RequestMetrics::new(),
+        tls,
+        acme_challenges: None,
+        ip_filter,
+        sampler,
+    }
+}
+
+//the resolved settings that matter most when diagnosing a deployment, next to the raw config rows
+async fn startup_banner(
+    db: &SqlitePool,
+    settings: &ProxySettings,
+    listen: String,
+    admin_listen: Option<String>,
+    domain_count: usize,
+    lb_strategy: &str,
+) -> banner::Banner {
+    let optional = |value: Option<String>| value.unwrap_or_else(|| "none".to_string());
+    banner::Banner {
+        resolved: vec![
+            ("listen", listen),
+            ("admin_listen", admin_listen.unwrap_or_else(|| "disabled".to_string())),
+            ("domains", domain_count.to_string()),
+            ("rate_limit_per_minute", optional(settings.rate_limit.map(|r| r.limit.to_string()))),
+            ("rate_limit_algorithm", optional(settings.rate_limit.map(|r| r.algorithm.name().to_string()))),
+            ("lb_strategy", lb_strategy.to_string()),
+            ("max_upstream_connections", optional(settings.max_upstream_connections.map(|m| m.to_string()))),
+            ("upstream_timeout_ms", optional(settings.upstream_timeout_ms.map(|t| t.to_string()))),
+            ("cache", if settings.cache_enabled { "enabled".to_string() } else { "disabled".to_string() }),
+            ("cache.capacity_bytes", optional(settings.cache_capacity_bytes.map(|c| c.to_string()))),
+        ],
+        stored: database::get_all_config(db).await.unwrap_or_default(),
+    }
+}
+
+//the in-memory response cache as configured, None when cache.enabled is false
+fn response_cache(settings: &ProxySettings) -> Option<MemoryCache> {
+    if settings.cache_enabled {
+        let mut cache = match settings.cache_capacity_bytes {
+            Some(capacity) => {
+                info!("In-memory cache initialized ({} bytes)", capacity);
+                MemoryCache::new().with_capacity(capacity)
+            }
+            None => {
+                info!("In-memory cache initialized");
+                MemoryCache::new()
             }
+        };
+        if let Some(secs) = settings.cache_stale_if_error_secs {
+            info!("Serving stale cache entries up to {}s old when origins fail", secs);
+            cache = cache.with_stale_window(Duration::from_secs(secs));
+        }
+        if let Some(max) = settings.cache_max_entries {
+            info!("Cache holds at most {} entries (least recently used evicted first)", max);
+            cache = cache.with_max_entries(max);
+        }
+        if settings.cache_dedupe_bodies {
+            info!("Identical cached bodies are stored once");
+            cache = cache.with_dedupe();
         }
-        result = api_server => {
-            if let Err(e) = result {
-                warn!("API server error: {}", e);
+        if settings.cache_sweep_interval_secs > 0 {
+            cache.spawn_cleanup(Duration::from_secs(settings.cache_sweep_interval_secs));
+        }
+        Some(cache)
+    } else {
+        info!("In-memory cache disabled");
+        None
+    }
+}
+
+//where the admin api listens, None when admin.enabled is false. it can listen on its
+//own (e.g. private) interface, admin.port wins over api_port
+async fn admin_addr(db: &SqlitePool, proxy_host: &str) -> Option<String> {
+    if !settings::config_value::<bool>(db, "admin.enabled").await.unwrap_or(true) {
+        return None;
+    }
+    let host = settings::config_value::<String>(db, "admin.host")
+        .await
+        .unwrap_or_else(|| proxy_host.to_string());
+    let port = match settings::config_value::<u16>(db, "admin.port").await {
+        Some(port) => port,
+        None => settings::config_value::<u16>(db, "api_port").await.unwrap_or(8081),
+    };
+    Some(format!("{}:{}", host, port))
+}
+
+//the admin api's view of the proxy, sharing its routes, counters and cache
+fn admin_state(app_state: &AppState, db: SqlitePool, certs: CertStore) -> ApiState {
+    ApiState {
+        routes: app_state.routes.clone(),
+        db,
+        limit_counters: app_state.limit_counters.clone(),
+        origin_counters: app_state.origin_counters.clone(),
+        breakers: app_state.breakers.clone(),
+        health: app_state.health.clone(),
+        tls: app_state.tls.clone(),
+        certs,
+        ip_filter: app_state.ip_filter.clone(),
+        metrics: app_state.metrics.clone(),
+        log_writer: app_state.log_writer.clone(),
+        cache: app_state.cache.clone(),
+        cache_inspect: app_state.settings.cache_inspect,
+    }
+}
+
+//ctrl-c, or SIGTERM from a service manager or orchestrator
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                tokio::select! {
+                    _ = tokio::signal::ctrl_c() => {}
+                    _ = terminate.recv() => {}
+                }
+            }
+            Err(_) => {
+                let _ = tokio::signal::ctrl_c().await;
             }
         }
     }
+    #[cfg(not(unix))]
+    {
+        let _ = tokio::signal::ctrl_c().await;
+    }
+}
+
+async fn shutdown_requested(mut shutdown: watch::Receiver<bool>) {
+    let _ = shutdown.wait_for(|stop| *stop).await;
 }
 
+//tags every request with an id that is sent upstream, returned to the client and stored with its log
 async fn proxy_handler(
-    State(state): State<AppState>,
+    State(mut state): State<AppState>,
     ConnectInfo(addr): ConnectInfo<SocketAddr>,
     headers: HeaderMap,
     mut req: Request,
+) -> Response {
+    //the config current when the request arrived, a reload midway doesn't change it
+    let active = state.active.read().unwrap().clone();
+    state.settings = active.settings;
+    state.rate_limiter = active.rate_limiter;
+
+    let request_id = request_id::for_request(&headers);
+    //only header-safe characters make it into an id
+    let header_value = HeaderValue::from_str(&request_id).unwrap();
+    req.headers_mut().insert(request_id::HEADER, header_value.clone());
+
+    //mirrored back whatever the origin does with them
+    let echoed: Vec<(HeaderName, HeaderValue)> = state
+        .settings
+        .echo_headers
+        .iter()
+        .filter_map(|name| Some((name.clone(), headers.get(name)?.clone())))
+        .collect();
+
+    //sampled requests run inside a span that tracing layers can export, the rest skip the overhead
+    let sampled = state.sampler.map(|sampler| sampler.sample(&headers)).unwrap_or(false);
+    let span = if sampled {
+        info_span!("proxy_request", request_id = %request_id, method = %req.method(), uri = %req.uri())
+    } else {
+        Span::none()
+    };
+
+    //the client's connection ends after this response, its Connection header never goes upstream
+    let close = headers::wants_close(&headers);
+
+    let mut rate_limit = None;
+    let mut response = match proxy_request(state, addr, headers, req, request_id, &mut rate_limit)
+        .instrument(span)
+        .await
+    {
+        Ok(response) => response,
+        Err(status) => status.into_response(),
+    };
+    if let Some(decision) = rate_limit {
+        decision.add_headers(response.headers_mut());
+    }
+    response.headers_mut().insert(request_id::HEADER, header_value);
+    for (name, value) in echoed {
+        response.headers_mut().insert(name, value);
+    }
+    if close {
+        response.headers_mut().insert(header::CONNECTION, HeaderValue::from_static("close"));
+    }
+    response
+}
+
+async fn proxy_request(
+    state: AppState,
+    addr: SocketAddr,
+    headers: HeaderMap,
+    mut req: Request,
+    request_id: String,
+    rate_limit: &mut Option<RateLimitDecision>,
 ) -> Result<Response, StatusCode> {
     let start_time = Instant::now();
 
@@ -187,27 +778,211 @@ async fn proxy_handler(
 
     let method = req.method().to_string();
     let path = req.uri().path().to_string();
-    let query = req.uri().query();
-    let client_ip = addr.ip().to_string();
+    let query = req.uri().query().map(String::from);
+    let tls = req.extensions().get::<TlsConnection>().is_some();
+    let client_addr = forwarded::client_ip(
+        addr.ip(),
+        &headers,
+        &state.settings.trusted_proxies,
+        state.settings.forwarded_hops,
+    );
+    let client_ip = client_addr.to_string();
+
+    //blocked addresses are turned away before anything else is looked at
+    if let Some(rule) = state.ip_filter.check(client_addr) {
+        let denial = Denial::new(DenialCategory::IpDeny, rule);
+        let log = RequestLog::new(host.to_string(), path, method, 0, start_time)
+            .with_ip(client_ip)
+            .with_request_id(request_id.clone());
+        return Ok(deny(&state, denial, log).await);
+    }
+
+    //several Host headers make the target ambiguous, refuse instead of guessing
+    if state.settings.reject_duplicate_host && headers.get_all("host").iter().count() > 1 {
+        let hosts: Vec<&str> = headers
+            .get_all("host")
+            .iter()
+            .map(|h| h.to_str().unwrap_or("<invalid>"))
+            .collect();
+        warn!("DUPLICATE HOST: {} sent Host headers {:?}", client_ip, hosts);
+        let body = "Multiple Host headers";
+
+        let log = RequestLog::new(host.to_string(), path, method, 400, start_time)
+            .with_ip(client_ip)
+            .with_request_id(request_id.clone())
+            .with_bytes(body.len() as u64);
+
+        finish_log(&state, log).await;
+
+        return Ok(Response::builder()
+            .status(StatusCode::BAD_REQUEST)
+            .body(Body::from(body))
+            .unwrap());
+    }
+
+    //bound the whole path+query before anything else looks at it
+    let uri_length = req.uri().path_and_query().map(|pq| pq.as_str().len()).unwrap_or(0);
+    if let Some(max) = state.settings.max_uri_length.filter(|max| uri_length > *max) {
+        warn!("URI TOO LONG: {} sent {} bytes to {} (max {})", client_ip, uri_length, host, max);
+        let body = "URI Too Long";
+
+        let log = RequestLog::new(host.to_string(), path, method, 414, start_time)
+            .with_ip(client_ip)
+            .with_request_id(request_id.clone())
+            .with_bytes(body.len() as u64);
+
+        finish_log(&state, log).await;
+
+        return Ok(Response::builder()
+            .status(StatusCode::URI_TOO_LONG)
+            .body(Body::from(body))
+            .unwrap());
+    }
+
+    //an oversized header block is refused before routing; hyper's own read buffer limit stays the hard cap
+    if let Some(max) = state.settings.max_request_header_bytes {
+        //each header line is "name: value\r\n"
+        let header_bytes: usize = headers.iter().map(|(name, value)| name.as_str().len() + value.len() + 4).sum();
+        if header_bytes > max {
+            warn!("HEADERS TOO LARGE: {} sent {} header bytes to {} (max {})", client_ip, header_bytes, host, max);
+            let body = "Request Header Fields Too Large";
+
+            let log = RequestLog::new(host.to_string(), path, method, 431, start_time)
+                .with_ip(client_ip)
+                .with_request_id(request_id.clone())
+                .with_bytes(body.len() as u64);
+
+            finish_log(&state, log).await;
 
-    //enforce rate limiting if enabled
-    if let Some(rl) = &state.rate_limiter {
-        if !rl.allow(&client_ip).await {
-            warn!("Rate limit exceeded for {}", client_ip);
             return Ok(Response::builder()
-                .status(StatusCode::TOO_MANY_REQUESTS)
-                .body(Body::from("Too many requests"))
+                .status(StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE)
+                .body(Body::from(body))
                 .unwrap());
         }
     }
 
+    //a client waiting for 100 Continue gets it when the body is first read, so an
+    //expectation the proxy won't meet has to be refused before that
+    let refuse_expectation = match expect::of(&headers) {
+        Expectation::None => false,
+        Expectation::Continue => match state.settings.expect_continue {
+            ExpectContinue::Forward => false,
+            ExpectContinue::Strip => {
+                req.headers_mut().remove("expect");
+                false
+            }
+            ExpectContinue::Reject => true,
+        },
+        Expectation::Unsupported(value) => {
+            warn!("UNSUPPORTED EXPECT: {} sent '{}' to {}", client_ip, value, host);
+            true
+        }
+    };
+    if refuse_expectation {
+        let log = RequestLog::new(host.to_string(), path, method, 417, start_time)
+            .with_ip(client_ip)
+            .with_request_id(request_id.clone());
+
+        finish_log(&state, log).await;
+
+        return Ok(Response::builder()
+            .status(StatusCode::EXPECTATION_FAILED)
+            .body(Body::empty())
+            .unwrap());
+    }
+
+    //a domain's path rule, or else its own per-minute limit, replaces the global limit
+    let domain_rule = routing::resolve(&*state.routes.read().await, host).and_then(|route| {
+        match route.rate_limit_for(&path) {
+            Some(rule) => Some((rule.clone(), format!("rate_limits:{}", rule.prefix))),
+            None => route
+                .domain_rate_limit()
+                .map(|rule| (rule, "domain.rate_limit_per_minute".to_string())),
+        }
+    });
+    //the decision is handed back so every response carries the client's rate limit headers
+    if let Some((rule, name)) = domain_rule {
+        let limiter = state.path_limiters.limiter(host, &rule);
+        let decision = limiter.check(&client_ip).await;
+        *rate_limit = Some(decision);
+        if !decision.allowed {
+            state.limit_counters.throttled(host);
+            let denial = Denial::new(DenialCategory::RateLimit, name);
+            let log = RequestLog::new(host.to_string(), path, method, 0, start_time)
+                .with_ip(client_ip.clone())
+                .with_request_id(request_id.clone());
+            return Ok(deny(&state, denial, log).await);
+        }
+    } else if let Some(rl) = &state.rate_limiter {
+        //each domain gets its own budget per client
+        let key = format!("{}|{}", client_ip, host);
+        let decision = rl.check(&key).await;
+        *rate_limit = Some(decision);
+        if !decision.allowed {
+            state.limit_counters.throttled(host);
+            let rule = match rl.blocked_for(&key).await {
+                Some(_) => "rate_limit_penalty_secs",
+                None => "rate_limit_per_minute",
+            };
+            let denial = Denial::new(DenialCategory::RateLimit, rule);
+            let log = RequestLog::new(host.to_string(), path, method, 0, start_time)
+                .with_ip(client_ip)
+                .with_request_id(request_id.clone());
+            return Ok(deny(&state, denial, log).await);
+        }
+    }
+
+    //the CA checks ownership of a domain over plain http, before any routing
+    if let Some(key_authorization) = state.acme_challenges.as_ref().and_then(|c| c.key_authorization(&path)) {
+        let log = RequestLog::new(host.to_string(), path, method, 200, start_time)
+            .with_ip(client_ip)
+            .with_request_id(request_id.clone())
+            .with_bytes(key_authorization.len() as u64);
+
+        finish_log(&state, log).await;
+
+        return Ok(Response::builder()
+            .status(StatusCode::OK)
+            .header(header::CONTENT_TYPE, "application/octet-stream")
+            .body(Body::from(key_authorization))
+            .unwrap());
+    }
+
+    //the status page's path is reserved on every host
+    if let Some(page) = state.settings.status_page.as_ref().filter(|page| page.path == path) {
+        let (response, bytes) = status(&state).await.respond(req.method() == "HEAD");
+        let log = RequestLog::new(host.to_string(), page.path.clone(), method, response.status().as_u16(), start_time)
+            .with_ip(client_ip)
+            .with_request_id(request_id.clone())
+            .with_bytes(bytes);
+
+        finish_log(&state, log).await;
+
+        return Ok(response);
+    }
+
     //look up origin for domain
     let routes = state.routes.read().await;
-    let origin = match routes.get(host) {
-        Some(o) => o.clone(),
+    let route = match routing::resolve(&routes, host) {
+        Some(r) => r.clone(),
         None => {
+            drop(routes);
             warn!("Unknown domain: {}", host);
 
+            if state.settings.status_page.as_ref().is_some_and(|page| page.unknown_domain) {
+                let (response, bytes) = status(&state).await.respond(req.method() == "HEAD");
+                let log = RequestLog::new(host.to_string(), path, method, response.status().as_u16(), start_time)
+                    .with_ip(client_ip)
+                    .with_request_id(request_id.clone())
+                    .with_bytes(bytes);
+
+                finish_log(&state, log).await;
+
+                return Ok(response);
+            }
+
+            let body = format!("Domain '{}' not configured", host);
+
             //log failed request
             let log = RequestLog::new(
                 host.to_string(),
@@ -217,114 +992,654 @@ async fn proxy_handler(
                 start_time,
             )
             .with_ip(client_ip)
-            .with_bytes(0);
+            .with_request_id(request_id.clone())
+            .with_bytes(body.len() as u64);
 
-            log.log();
-            let _ = save_log(&state.db, &log).await;
+            finish_log(&state, log).await;
 
             return Ok(Response::builder()
                 .status(StatusCode::NOT_FOUND)
-                .body(Body::from(format!("Domain '{}' not configured", host)))
+                .body(Body::from(body))
                 .unwrap());
         }
     };
     drop(routes);
 
-    //check cache for GET requests
-    let cache_key = MemoryCache::generate_cache_key(host, &path, query);
-    if req.method() == "GET" {
-        if let Some(cached_response) = state.cache.get(&cache_key).await {
+    //settle "/path" vs "/path/" before the cache, the balancer and the origin see the path
+    let normalized = route
+        .trailing_slash
+        .and_then(|policy| Some((policy, policy.normalize(&path)?)));
+    let path = match normalized {
+        Some((TrailingSlash::Redirect, canonical)) => {
+            let location = match query.as_deref() {
+                Some(q) => format!("{}?{}", canonical, q),
+                None => canonical,
+            };
+
+            let log = RequestLog::new(host.to_string(), path, method, 308, start_time)
+                .with_ip(client_ip)
+                .with_request_id(request_id.clone());
+
+            finish_log(&state, log).await;
+
+            return Ok(Response::builder()
+                .status(StatusCode::PERMANENT_REDIRECT)
+                .header("Location", location)
+                .body(Body::empty())
+                .unwrap());
+        }
+        Some((_, normalized)) => normalized,
+        None => path,
+    };
+
+    //domains in maintenance never reach their origins or the cache
+    if state.settings.maintenance || route.maintenance {
+        let (response, bytes) =
+            maintenance::respond(state.settings.maintenance_static_dir.as_deref(), &path).await;
+
+        let log = RequestLog::new(
+            host.to_string(),
+            path,
+            method,
+            response.status().as_u16(),
+            start_time,
+        )
+        .with_ip(client_ip)
+        .with_request_id(request_id.clone())
+        .with_bytes(bytes);
+
+        finish_log(&state, log).await;
+
+        return Ok(response);
+    }
+
+    //static assets with a pre-compressed copy on disk never reach the origin
+    if let (Some(dir), true) = (&route.precompressed_dir, req.method() == "GET" || req.method() == "HEAD") {
+        let accept_encoding = headers.get("accept-encoding").and_then(|h| h.to_str().ok());
+        let is_head = req.method() == "HEAD";
+        if let Some((response, bytes)) = precompressed::respond(dir, &path, accept_encoding, is_head).await {
+            let log = RequestLog::new(host.to_string(), path, method, 200, start_time)
+                .with_ip(client_ip)
+                .with_request_id(request_id.clone())
+                .with_bytes(bytes);
+
+            finish_log(&state, log).await;
+
+            return Ok(response);
+        }
+    }
+
+    //content negotiation: an Accept header matching one of the domain's accept routes
+    //goes to that route's origin, and gets its own cache entry
+    let accept = headers.get("accept").and_then(|h| h.to_str().ok());
+    let accept_route = route.accept_route_for(accept);
+    //a path route overrides both, and may forward the path without its prefix
+    let path_route = route.path_route_for(&path);
+    let origins = match (path_route, accept_route) {
+        (Some(rule), _) => std::slice::from_ref(&rule.origin),
+        (None, Some(rule)) => std::slice::from_ref(&rule.origin),
+        (None, None) => route.origins.as_slice(),
+    };
+    let upstream_path = match path_route {
+        Some(rule) => rule.upstream_path(&path),
+        None => path.clone(),
+    };
+
+    let mut cache_key = MemoryCache::generate_cache_key(host, &path, query.as_deref());
+    if let (Some(rule), None) = (accept_route, path_route) {
+        cache_key = format!("{}#{}", cache_key, rule.media_type.to_lowercase());
+    }
+    let is_head = req.method() == "HEAD";
+    //an authorized request may get a response meant only for that user, it neither reads nor fills the cache,
+    //and neither does a path outside the domain's cache_paths
+    let uses_cache = (req.method() == "GET" || is_head)
+        && !headers.contains_key("authorization")
+        && route.caches_path(&path);
+
+    //check cache for GET requests, HEAD can be answered from a GET entry but never gets its body.
+    //a hit needs no origin, so it's served even when none is up and leaves the balancer alone
+    if let (Some(cache), true) = (&state.cache, uses_cache) {
+        let cached = cache.get(&cache_key).await;
+        state.metrics.cache_lookup(host, cached.is_some());
+        if let Some(cached_response) = cached {
             info!("CACHE HIT: {}", cache_key);
             
-            let bytes = cached_response.len() as u64;
+            let content_length = cached_response.body.len();
+            let bytes = if is_head { 0 } else { content_length as u64 };
 
             //log cached request
             let log = RequestLog::new(
                 host.to_string(),
                 path,
                 method,
-                200,
+                cached_response.status,
                 start_time,
             )
             .with_ip(client_ip)
+            .with_request_id(request_id.clone())
             .with_bytes(bytes);
 
-            log.log();
-            let db = state.db.clone();
-            tokio::spawn(async move {
-                let _ = save_log(&db, &log).await;
-            });
+            finish_log(&state, log).await;
 
-            return Ok(Response::builder()
-                .status(StatusCode::OK)
-                .header("X-Cache", "HIT")
-                .body(Body::from(cached_response))
-                .unwrap());
+            return Ok(cached_reply(cached_response, is_head, "HIT"));
+        }
+    }
+
+    //pick an origin for this request, skipping ones failing health checks or with an open circuit
+    let candidates: Vec<String> = state
+        .health
+        .healthy_origins(origins)
+        .await
+        .into_iter()
+        .filter(|origin| state.breakers.allow(origin))
+        .collect();
+    let lb_key = state.hash_key.extract(&path, &headers, &client_ip);
+    let origin = match select_origin(&state, host, &candidates, &route.weights, &lb_key) {
+        Some(o) => o,
+        None => {
+            warn!("No healthy origins for domain: {}", host);
+            if let Some(stale) = stale_if_error(&state, &cache_key, uses_cache).await {
+                let log = RequestLog::new(host.to_string(), path, method, 0, start_time)
+                    .with_ip(client_ip)
+                    .with_request_id(request_id.clone())
+                    .with_error("no_healthy_origin");
+                return Ok(reply_stale(&state, stale, is_head, log).await);
+            }
+            let log = RequestLog::new(host.to_string(), path, method, 502, start_time)
+                .with_ip(client_ip)
+                .with_request_id(request_id.clone())
+                .with_error("no_healthy_origin");
+            return Ok(upstream_error(&state, StatusCode::BAD_GATEWAY, "no_healthy_origin", log).await);
         }
+    };
+
+    //every origin today comes from the domain config, per-request origins would pass `false`
+    if let Some(guard) = &state.settings.origin_guard
+        && let Some(reason) = guard.check(&origin, true).await
+    {
+        let denial = Denial::new(DenialCategory::OriginBlock, format!("ssrf_guard:{}", reason));
+        let log = RequestLog::new(host.to_string(), path, method, 0, start_time)
+            .with_ip(client_ip)
+            .with_request_id(request_id.clone())
+            .with_origin(&origin);
+        return Ok(deny(&state, denial, log).await);
     }
 
     //build upstream url
-    let query_part = query.map(|q| format!("?{}", q)).unwrap_or_default();
-    let upstream_uri = format!("{}{}{}", origin, path, query_part);
+    let query_part = query.as_deref().map(|q| format!("?{}", q)).unwrap_or_default();
+    let upstream_uri = format!("{}{}{}", origin, upstream_path, query_part);
 
     info!("PROXYING: {} -> {}", host, upstream_uri);
+    state.origin_counters.record(host, &origin);
 
     //update req uri
     *req.uri_mut() = upstream_uri.parse().map_err(|_| StatusCode::BAD_REQUEST)?;
     req.headers_mut().remove("host");
+    if state.settings.strip_hop_by_hop {
+        headers::strip_hop_by_hop(req.headers_mut());
+    }
+    if state.settings.forwarded_headers {
+        let proto = if tls { "https" } else { "http" };
+        forwarded::add_headers(req.headers_mut(), addr.ip(), hostname, proto, &state.settings.trusted_proxies);
+    }
 
-    //forward req
-    match state.client.request(req).await {
-        Ok(response) => {
-            let status = response.status().as_u16();
-            info!("SUCCESS: {} responded with {}", origin, status);
+    //a protocol upgrade (websockets) keeps its Connection/Upgrade headers, and the client's
+    //side of the connection is taken over once the origin agrees to switch
+    let client_upgrade = match headers::upgrade(&headers) {
+        Some(protocol) => {
+            req.headers_mut().insert(header::CONNECTION, HeaderValue::from_static("upgrade"));
+            req.headers_mut().insert(header::UPGRADE, protocol);
+            Some(hyper::upgrade::on(&mut req))
+        }
+        None => None,
+    };
+
+    //a domain's max_concurrency is shared out so one client can't take all of it
+    let concurrency = match route.max_concurrency {
+        Some(capacity) => {
+            let client_cap = state
+                .settings
+                .max_client_share
+                .map(|share| fairness::client_cap(capacity, share));
+            match state.concurrency.try_acquire(host, &client_ip, capacity, client_cap) {
+                Ok(guard) => Some(guard),
+                Err(Refusal::ClientShare) => {
+                    let denial = Denial::new(DenialCategory::RateLimit, "concurrency.max_client_share");
+                    let log = RequestLog::new(host.to_string(), path, method, 0, start_time)
+                        .with_ip(client_ip)
+                        .with_request_id(request_id.clone());
+                    return Ok(deny(&state, denial, log).await);
+                }
+                Err(Refusal::DomainFull) => {
+                    warn!("Domain concurrency limit reached, shedding {} {}", host, path);
+                    state.limit_counters.shed(host);
+                    let body = "Domain concurrency limit reached";
 
-            let collected = response.into_body().collect().await.map_err(|_| StatusCode::BAD_GATEWAY)?;
-            let bytes = collected.to_bytes();
-            let bytes_len = bytes.len() as u64;
-
-            //cache successful GET responses
-            if method == "GET" && status == 200 {
-                if let Ok(body_str) = String::from_utf8(bytes.to_vec()) {
-                    let cache = state.cache.clone();
-                    let cache_key = cache_key.clone();
-                    tokio::spawn(async move {
-                        cache.set(cache_key, body_str, 300).await;
-                    });
+                    let log = RequestLog::new(host.to_string(), path, method, 503, start_time)
+                        .with_ip(client_ip)
+                        .with_request_id(request_id.clone())
+                        .with_bytes(body.len() as u64);
+
+                    finish_log(&state, log).await;
+
+                    return Ok(Response::builder()
+                        .status(StatusCode::SERVICE_UNAVAILABLE)
+                        .body(Body::from(body))
+                        .unwrap());
                 }
             }
+        }
+        None => None,
+    };
 
-            //log successful request
-            let mut log = RequestLog::new(
-                host.to_string(),
-                path,
-                method,
-                status,
-                start_time,
-            )
-            .with_ip(client_ip)
-            .with_bytes(bytes_len);
+    //space out dispatches to origins that don't take bursts well
+    if let Some(pacing) = &route.pacing {
+        match state.pacer.reserve(&origin, pacing) {
+            Some(turn) => turn.wait().await,
+            None => {
+                warn!("Pacing queue full for {}, shedding {} {}", origin, host, path);
+                state.limit_counters.shed(host);
+                let body = "Origin pacing queue full";
+
+                let log = RequestLog::new(host.to_string(), path, method, 503, start_time)
+                    .with_ip(client_ip)
+                    .with_request_id(request_id.clone())
+                    .with_origin(&origin)
+                    .with_bytes(body.len() as u64);
 
-            if let Some(ua) = user_agent {
-                log = log.with_user_agent(ua);
+                finish_log(&state, log).await;
+
+                return Ok(Response::builder()
+                    .status(StatusCode::SERVICE_UNAVAILABLE)
+                    .body(Body::from(body))
+                    .unwrap());
             }
+        }
+    }
+
+    //wait briefly for a free upstream slot when the global ceiling is reached,
+    //the permit is held until the response body has been read
+    let permit = match &state.upstream_permits {
+        Some(permits) => {
+            let wait = Duration::from_millis(state.settings.upstream_queue_timeout_ms);
+            match tokio::time::timeout(wait, permits.clone().acquire_owned()).await {
+                Ok(Ok(permit)) => Some(permit),
+                _ => {
+                    warn!("Upstream connection limit reached, shedding {} {}", host, path);
+                    state.limit_counters.shed(host);
+                    let body = "Upstream connection limit reached";
 
-            if let Some(ref_url) = referer {
-                log = log.with_referer(ref_url);
+                    let log = RequestLog::new(
+                        host.to_string(),
+                        path,
+                        method,
+                        503,
+                        start_time,
+                    )
+                    .with_ip(client_ip)
+                    .with_request_id(request_id.clone())
+                    .with_bytes(body.len() as u64);
+
+                    finish_log(&state, log).await;
+
+                    return Ok(Response::builder()
+                        .status(StatusCode::SERVICE_UNAVAILABLE)
+                        .body(Body::from(body))
+                        .unwrap());
+                }
             }
+        }
+        None => None,
+    };
 
-            log.log();
+    //counts against the origin for least_connections until the response is done
+    let mut in_flight = state.in_flight.start(&origin);
 
-            //save to database async
-            let db = state.db.clone();
-            tokio::spawn(async move {
-                let _ = save_log(&db, &log).await;
-            });
+    //an idempotent request without a body can be sent again as it is when the origin
+    //resets the connection before answering
+    let replay = state
+        .settings
+        .redispatch_on_reset
+        .filter(|_| client_upgrade.is_none())
+        .filter(|_| is_idempotent(req.method()) && HttpBody::size_hint(req.body()).exact() == Some(0))
+        .map(|attempts| (attempts, req.method().clone(), req.headers().clone()));
+
+    //forward req, bounded by the domain's timeout or the global default
+    let upstream_timeout = route
+        .upstream_timeout_ms
+        .or(state.settings.upstream_timeout_ms)
+        .map(Duration::from_millis);
 
-            Ok(Response::new(Body::from(bytes)))
+    //domains with connection affinity keep one upstream connection per client connection
+    let dispatch = async {
+        if route.connection_affinity {
+            state.pinned.send(addr, &origin, req).await
+        } else {
+            send_upstream(&state, req).await
+        }
+    };
+
+    let result = match upstream_timeout {
+        Some(limit) => match tokio::time::timeout(limit, dispatch).await {
+            Ok(result) => result,
+            Err(_) => {
+                warn!("TIMEOUT: {} did not respond within {:?}", origin, limit);
+                state.breakers.record(&origin, false);
+
+                if let Some(stale) = stale_if_error(&state, &cache_key, uses_cache).await {
+                    let log = RequestLog::new(host.to_string(), path, method, 0, start_time)
+                        .with_ip(client_ip)
+                        .with_request_id(request_id.clone())
+                        .with_origin(&origin)
+                        .with_error("upstream_timeout");
+                    return Ok(reply_stale(&state, stale, is_head, log).await);
+                }
+
+                let log = RequestLog::new(
+                    host.to_string(),
+                    path,
+                    method,
+                    504,
+                    start_time,
+                )
+                .with_ip(client_ip)
+                .with_request_id(request_id.clone())
+                .with_origin(&origin)
+                .with_error("upstream_timeout");
+
+                return Ok(upstream_error(&state, StatusCode::GATEWAY_TIMEOUT, "upstream_timeout", log).await);
+            }
+        },
+        None => dispatch.await,
+    };
+
+    //an origin going away mid-request (a rolling restart, say) gets the request sent again,
+    //to another origin when the balancer has one. a timeout while redispatching keeps the reset
+    let mut origin = origin;
+    let mut result = result;
+    if let Some((attempts, replay_method, replay_headers)) = replay {
+        for attempt in 1..=attempts {
+            match &result {
+                Err(e) if UpstreamFailure::classify(e).is_reset() => {}
+                _ => break,
+            }
+            state.breakers.record(&origin, false);
+
+            let others: Vec<String> = candidates.iter().filter(|c| **c != origin).cloned().collect();
+            let next = select_origin(&state, host, &others, &route.weights, &lb_key).unwrap_or_else(|| origin.clone());
+            if let Some(guard) = &state.settings.origin_guard
+                && guard.check(&next, true).await.is_some()
+            {
+                break;
+            }
+            warn!(
+                "REDISPATCH: {} reset the connection, sending {} {} to {} (attempt {}/{})",
+                origin, replay_method, path, next, attempt, attempts
+            );
+
+            let uri = match format!("{}{}{}", next, upstream_path, query_part).parse() {
+                Ok(uri) => uri,
+                Err(_) => break,
+            };
+            let mut retry_req = Request::new(Body::empty());
+            *retry_req.method_mut() = replay_method.clone();
+            *retry_req.uri_mut() = uri;
+            *retry_req.headers_mut() = replay_headers.clone();
+
+            in_flight = state.in_flight.start(&next);
+            origin = next;
+            state.origin_counters.record(host, &origin);
+
+            let send = async {
+                if route.connection_affinity {
+                    state.pinned.send(addr, &origin, retry_req).await
+                } else {
+                    send_upstream(&state, retry_req).await
+                }
+            };
+            result = match upstream_timeout {
+                Some(limit) => match tokio::time::timeout(limit, send).await {
+                    Ok(result) => result,
+                    Err(_) => {
+                        warn!("TIMEOUT: {} did not respond within {:?}", origin, limit);
+                        break;
+                    }
+                },
+                None => send.await,
+            };
+        }
+    }
+
+    match result {
+        Ok(mut response) => {
+            let status = response.status().as_u16();
+            info!("SUCCESS: {} responded with {}", origin, status);
+            state.breakers.record(&origin, status < 500);
+
+            //the origin switched protocols: relay bytes both ways until either side closes.
+            //the origin's slots stay taken for as long as the tunnel is open
+            if let (Some(client_upgrade), StatusCode::SWITCHING_PROTOCOLS) = (client_upgrade, response.status()) {
+                let upstream_upgrade = hyper::upgrade::on(&mut response);
+                let held = (in_flight, concurrency, permit);
+                let tunnel = format!("{}{} <-> {}", host, path, origin);
+                tokio::spawn(async move {
+                    let _held = held;
+                    match tokio::try_join!(client_upgrade, upstream_upgrade) {
+                        Ok((client, upstream)) => {
+                            let mut client = TokioIo::new(client);
+                            let mut upstream = TokioIo::new(upstream);
+                            match tokio::io::copy_bidirectional(&mut client, &mut upstream).await {
+                                Ok((sent, received)) => {
+                                    info!("UPGRADE CLOSED: {} ({} bytes up, {} bytes down)", tunnel, sent, received);
+                                }
+                                Err(e) => warn!("UPGRADE ERROR: {}: {}", tunnel, e),
+                            }
+                        }
+                        Err(e) => warn!("UPGRADE FAILED: {}: {}", tunnel, e),
+                    }
+                });
+
+                let log = RequestLog::new(host.to_string(), path, method, status, start_time)
+                    .with_ip(client_ip)
+                    .with_request_id(request_id.clone())
+                    .with_origin(&origin);
+
+                finish_log(&state, log).await;
+
+                let (parts, _) = response.into_parts();
+                return Ok(Response::from_parts(parts, Body::empty()));
+            }
+
+            let (mut parts, body) = response.into_parts();
+
+            //keep redirects to the origin's own host going through the proxy
+            if state.settings.rewrite_redirects && parts.status.is_redirection() {
+                let scheme = headers
+                    .get("x-forwarded-proto")
+                    .and_then(|h| h.to_str().ok())
+                    .unwrap_or("http");
+                redirect::rewrite_headers(&mut parts.headers, &origin, scheme, hostname);
+            }
+
+            if state.settings.strip_hop_by_hop {
+                headers::strip_hop_by_hop(&mut parts.headers);
+            }
+            if !state.settings.merge_response_headers.is_empty() {
+                headers::merge_values(&mut parts.headers, &state.settings.merge_response_headers);
+            }
+
+            let declared_length = parts
+                .headers
+                .get("content-length")
+                .and_then(|h| h.to_str().ok())
+                .and_then(|v| v.parse::<u64>().ok());
+
+            //only complete GET bodies with a cache TTL for their status are buffered for the cache,
+            //everything else streams through. a declared length above max_cache_bytes streams right away
+            //a response setting a cookie would hand that cookie to everyone served from the cache,
+            //and one marked no-store asked not to be kept at all
+            let sets_cookie = parts.headers.contains_key("set-cookie");
+            let content_type = parts.headers.get("content-type").and_then(|h| h.to_str().ok());
+            //the origin's own freshness, when it states one, replaces the configured TTL of a cached status
+            let origin_ttl = MemoryCache::ttl_from_headers(&parts.headers).filter(|_| state.settings.cache_respect_origin_ttl);
+            let cache_ttl = state
+                .settings
+                .cache_ttl(status, content_type)
+                .and_then(|ttl| match origin_ttl {
+                    Some(origin_ttl) => Some(origin_ttl.as_secs()).filter(|secs| *secs > 0),
+                    None => Some(ttl),
+                });
+            let no_store = headers::cache_control(&parts.headers).iter().any(|d| d == "no-store");
+            let cacheable = method == "GET"
+                && uses_cache
+                && !no_store
+                && state.cache.is_some()
+                && cache_ttl.is_some()
+                && (!sets_cookie || state.settings.strips_set_cookie(&path))
+                && !streaming::is_event_stream(&parts.headers)
+                && declared_length
+                    .zip(state.settings.max_cache_bytes)
+                    .map(|(len, max)| len <= max)
+                    .unwrap_or(true);
+
+            //html pages of domains with a snippet to inject are buffered so it can be inserted
+            let inject = route
+                .html_inject
+                .as_deref()
+                .filter(|_| !is_head && html::is_plain_html(&parts.headers));
+
+            let max_response_bytes = route.max_response_bytes;
+            let cap_context = max_response_bytes.map(|_| format!("{}{} from {}", host, path, origin));
+
+            let build_log = move |bytes: u64| {
+                let mut log = RequestLog::new(
+                    host.to_string(),
+                    path,
+                    method,
+                    status,
+                    start_time,
+                )
+                .with_ip(client_ip)
+                .with_request_id(request_id.clone())
+                .with_origin(&origin)
+                .with_bytes(bytes);
+
+                if let Some(ua) = user_agent {
+                    log = log.with_user_agent(ua);
+                }
+
+                if let Some(ref_url) = referer {
+                    log = log.with_referer(ref_url);
+                }
+
+                log
+            };
+
+            //a body declared bigger than the domain allows fails before anything is sent
+            let declared_too_large = |max: &u64| !is_head && declared_length.map(|len| len > *max).unwrap_or(false);
+            if let Some(max) = max_response_bytes.filter(declared_too_large) {
+                return Ok(response_too_large(&state, build_log(0), max).await);
+            }
+
+            let (log, body) = if cacheable || inject.is_some() {
+                let collected = match max_response_bytes {
+                    Some(max) => match Limited::new(body, max as usize).collect().await {
+                        Ok(collected) => collected,
+                        Err(e) if e.downcast_ref::<LengthLimitError>().is_some() => {
+                            return Ok(response_too_large(&state, build_log(0), max).await);
+                        }
+                        Err(_) => return Err(StatusCode::BAD_GATEWAY),
+                    },
+                    None => body.collect().await.map_err(|_| StatusCode::BAD_GATEWAY)?,
+                };
+                let mut bytes = collected.to_bytes();
+                if let Some(injected) = inject.and_then(|snippet| html::inject_before_head(&bytes, snippet)) {
+                    bytes = injected;
+                    parts.headers.insert("content-length", HeaderValue::from(bytes.len()));
+                }
+                let bytes_len = bytes.len() as u64;
+
+                //cache GET responses whose size is within the configured bounds, for their status's TTL
+                let within_bounds = bytes_len >= state.settings.min_cache_bytes
+                    && state.settings.max_cache_bytes.map(|max| bytes_len <= max).unwrap_or(true);
+
+                match (cache_ttl, &state.cache) {
+                    (Some(ttl), Some(cache)) if cacheable && within_bounds => {
+                        let cache = cache.clone();
+                        let aux_memory = state.aux_memory.clone();
+                        let cache_key = cache_key.clone();
+                        let cached = CachedResponse::new(status, &parts.headers, bytes.clone());
+                        tokio::spawn(async move {
+                            cache.set(cache_key, cached, ttl).await;
+                            if let Some(aux_memory) = aux_memory {
+                                aux_memory.enforce().await;
+                            }
+                        });
+                        parts.headers.insert("X-Cache", HeaderValue::from_static("MISS"));
+                    }
+                    //with the cache turned off there's nothing to report
+                    (_, Some(_)) => {
+                        parts.headers.insert("X-Cache", HeaderValue::from_static("UNCACHEABLE"));
+                    }
+                    (_, None) => {}
+                }
+
+                (Some(build_log(bytes_len)), Body::from(bytes))
+            } else {
+                let policy = streaming::FlushPolicy::for_response(&parts.headers, state.settings.stream_flush);
+
+                //headers go out before the body, so an oversized stream can only be cut short
+                let body = match max_response_bytes.zip(cap_context) {
+                    Some((max, context)) => streaming::coalesce(streaming::cap(body, max, context), policy),
+                    None => streaming::coalesce(body, policy),
+                };
+                //the bytes relayed are only known once the body is done, a declared content-length
+                //says nothing about a stream cut short by the cap or a client going away.
+                //a HEAD response's content-length describes the GET body, nothing is sent
+                let log = build_log(0);
+                let (log, mut streamed) = if is_head {
+                    (Some(log), None)
+                } else {
+                    (None, Some(StreamedLog { state: state.clone(), log: Some(log), bytes: 0 }))
+                };
+
+                //the origin, the domain's concurrency slot and the upstream permit stay taken
+                //until the last chunk has been relayed
+                let body = Body::new(body.map_frame(move |frame| {
+                    let _ = (&in_flight, &concurrency, &permit);
+                    if let (Some(streamed), Some(data)) = (streamed.as_mut(), frame.data_ref()) {
+                        streamed.bytes += data.len() as u64;
+                    }
+                    frame
+                }));
+
+                (log, body)
+            };
+
+            if let Some(log) = log {
+                finish_log(&state, log).await;
+            }
+
+            Ok(Response::from_parts(parts, body))
         }
         Err(e) => {
-            warn!("ERROR: {}", e);
+            //refused connections and unresolvable hosts fail fast and open the circuit right away
+            let failure = UpstreamFailure::classify(&e);
+            warn!("ERROR [{}] {}: {}", failure.as_str(), origin, e);
+            if failure.is_unreachable() {
+                state.breakers.trip(&origin);
+            } else {
+                state.breakers.record(&origin, false);
+            }
+
+            if let Some(stale) = stale_if_error(&state, &cache_key, uses_cache).await {
+                let log = RequestLog::new(host.to_string(), path, method, 0, start_time)
+                    .with_ip(client_ip)
+                    .with_request_id(request_id.clone())
+                    .with_origin(&origin)
+                    .with_error(failure.as_str());
+                return Ok(reply_stale(&state, stale, is_head, log).await);
+            }
 
             //log error
             let log = RequestLog::new(
@@ -335,12 +1650,1781 @@ async fn proxy_handler(
                 start_time,
             )
             .with_ip(client_ip)
-            .with_bytes(0);
+            .with_request_id(request_id.clone())
+            .with_origin(&origin)
+            .with_error(failure.as_str());
+
+            Ok(upstream_error(&state, StatusCode::BAD_GATEWAY, failure.as_str(), log).await)
+        }
+    }
+}
+
+//methods whose effect is the same however often they're sent
+fn is_idempotent(method: &Method) -> bool {
+    matches!(
+        *method,
+        Method::GET | Method::HEAD | Method::OPTIONS | Method::TRACE | Method::PUT | Method::DELETE
+    )
+}
+
+//sends a request upstream, retrying bodiless GET/HEAD/OPTIONS requests whose connection
+//failed in a way that may not repeat. unreachable origins are never retried. the body
+//isn't kept, so a request with one is only ever sent once
+async fn send_upstream(
+    state: &AppState,
+    req: Request,
+) -> Result<Response<hyper::body::Incoming>, SendError> {
+    let retry = state
+        .settings
+        .retry
+        .filter(|_| matches!(*req.method(), Method::GET | Method::HEAD | Method::OPTIONS))
+        .filter(|_| HttpBody::size_hint(req.body()).exact() == Some(0))
+        .map(|policy| (policy, req.method().clone(), req.uri().clone(), req.headers().clone()));
+
+    let mut result = state.client.request(req).await.map_err(SendError::Pooled);
+
+    if let Some((policy, method, uri, headers)) = retry {
+        let mut delay = Duration::ZERO;
+        for attempt in 1..=policy.attempts {
+            match &result {
+                Err(e) if UpstreamFailure::classify(e).is_retryable() => {}
+                _ => break,
+            }
+
+            delay = policy.delay(attempt, delay);
+            warn!("RETRY: {} {} in {:?} (attempt {}/{})", method, uri, delay, attempt, policy.attempts);
+            tokio::time::sleep(delay).await;
+
+            let mut retry_req = Request::new(Body::empty());
+            *retry_req.method_mut() = method.clone();
+            *retry_req.uri_mut() = uri.clone();
+            *retry_req.headers_mut() = headers.clone();
+            result = state.client.request(retry_req).await.map_err(SendError::Pooled);
+        }
+    }
+
+    result
+}
+
+//the proxy's own status for the status page. unhealthy while in maintenance or when
+//every configured origin is failing its health checks
+async fn status(state: &AppState) -> Status {
+    let routes = state.routes.read().await;
+    let domains = routes.len();
+    let origins: Vec<String> = routes.values().flat_map(|route| route.origins.clone()).collect();
+    drop(routes);
+
+    let reachable = origins.is_empty() || !state.health.healthy_origins(&origins).await.is_empty();
+    Status {
+        version: env!("CARGO_PKG_VERSION"),
+        uptime_secs: state.live.snapshot().uptime.as_secs(),
+        domains,
+        healthy: !state.settings.maintenance && reachable,
+    }
+}
+
+//the log of a streamed response, written with the bytes actually relayed once the
+//body is done or the client goes away
+struct StreamedLog {
+    state: AppState,
+    log: Option<RequestLog>,
+    bytes: u64,
+}
+
+impl Drop for StreamedLog {
+    fn drop(&mut self) {
+        if let Some(log) = self.log.take() {
+            let state = self.state.clone();
+            let log = log.with_bytes(self.bytes);
+            tokio::spawn(async move { finish_log(&state, log).await });
+        }
+    }
+}
+
+//picks an origin with slow start applied: strategies that weigh origins get the ramping
+//origin's weight scaled down, with the others a pick of it may be turned away and made
+//again among the remaining candidates
+fn select_origin(
+    state: &AppState,
+    host: &str,
+    candidates: &[String],
+    weights: &HashMap<String, u32>,
+    key: &str,
+) -> Option<String> {
+    if state.balancer.weighs_origins() {
+        let ramped = state.slow_start.weights(candidates, weights);
+        let weights = ramped.as_ref().unwrap_or(weights);
+        return state.balancer.select(host, candidates, weights, key).map(String::from);
+    }
+
+    let origin = state.balancer.select(host, candidates, weights, key)?;
+    if state.slow_start.admits(origin) {
+        return Some(origin.to_string());
+    }
+    let others: Vec<String> = candidates.iter().filter(|c| *c != origin).cloned().collect();
+    let other = state.balancer.select(host, &others, weights, key).map(String::from);
+    other.or_else(|| Some(origin.to_string()))
+}
+
+//a cached response as sent to the client, `x_cache` says where it came from
+fn cached_reply(cached: CachedResponse, is_head: bool, x_cache: &'static str) -> Response {
+    let mut response = Response::builder()
+        .status(cached.status)
+        .header("X-Cache", x_cache)
+        .header("Content-Length", cached.body.len());
+    if let Some(headers) = response.headers_mut() {
+        headers.extend(cached.headers);
+    }
+
+    let body = if is_head { Body::empty() } else { Body::from(cached.body) };
+
+    response.body(body).unwrap()
+}
+
+//the expired entry kept for cache.stale_if_error_secs, if the request could be served from cache
+async fn stale_if_error(state: &AppState, cache_key: &str, uses_cache: bool) -> Option<CachedResponse> {
+    if !uses_cache || state.settings.cache_stale_if_error_secs.is_none() {
+        return None;
+    }
+    state.cache.as_ref()?.get_stale(cache_key).await
+}
+
+//answers a failed upstream request from the stale entry, the log keeps why the origin failed
+async fn reply_stale(state: &AppState, stale: CachedResponse, is_head: bool, mut log: RequestLog) -> Response {
+    warn!(
+        "STALE-IF-ERROR: serving cached {}{} after {}",
+        log.domain,
+        log.path,
+        log.error.as_deref().unwrap_or("error")
+    );
+    log.status = stale.status;
+    log.bytes_sent = if is_head { 0 } else { stale.body.len() as u64 };
+    finish_log(state, log).await;
+
+    cached_reply(stale, is_head, "STALE-ERROR")
+}
+
+//no response came from the origin: the bare status, or with upstream_error_body = json
+//a body saying why, e.g. {"error":"bad_gateway","code":"connection_refused",...}.
+//the log gets the status and the size of that body
+async fn upstream_error(state: &AppState, status: StatusCode, code: &str, log: RequestLog) -> Response {
+    let request_id = log.request_id.as_deref().unwrap_or("");
+    let response = match state.settings.upstream_error_body {
+        ErrorBody::Opaque => status.into_response(),
+        ErrorBody::Json => {
+            let error = status
+                .canonical_reason()
+                .unwrap_or("upstream_error")
+                .to_lowercase()
+                .replace(' ', "_");
+            let body = serde_json::json!({
+                "error": error,
+                "code": code,
+                "status": status.as_u16(),
+                "request_id": request_id,
+            });
+            (status, axum::Json(body)).into_response()
+        }
+    };
+
+    let mut log = log.with_bytes(HttpBody::size_hint(response.body()).exact().unwrap_or(0));
+    log.status = status.as_u16();
+    finish_log(state, log).await;
+
+    response
+}
+
+//a response over the domain's max_response_bytes that can still be replaced by a 502
+async fn response_too_large(state: &AppState, log: RequestLog, max: u64) -> Response {
+    warn!("RESPONSE TOO LARGE: {}{} exceeded {} bytes", log.domain, log.path, max);
+    let body = "Upstream response too large";
+
+    let mut log = log.with_error("response_too_large").with_bytes(body.len() as u64);
+    log.status = 502;
+    finish_log(state, log).await;
+
+    Response::builder()
+        .status(StatusCode::BAD_GATEWAY)
+        .body(Body::from(body))
+        .unwrap()
+}
+
+//single exit for refused requests: builds the configured response and
+//records a denial entry next to the normal request logs
+async fn deny(state: &AppState, denial: Denial, log: RequestLog) -> Response {
+    let status = denial.status(&state.settings);
+    let body = denial.body(&state.settings);
+
+    warn!(
+        "DENIED [{}] rule={} ip={} {} {}{}",
+        denial.category.as_str(),
+        denial.rule,
+        log.ip_address.as_deref().unwrap_or("unknown"),
+        log.method,
+        log.domain,
+        log.path,
+    );
+
+    let mut log = log.with_denial(denial.category.as_str(), &denial.rule);
+    log.status = status.as_u16();
+    log.bytes_sent = body.len() as u64;
+
+    finish_log(state, log).await;
+
+    Response::builder()
+        .status(status)
+        .body(Body::from(body))
+        .unwrap()
+}
+
+//emits the log line, updates the live counters and metrics and saves the log in the background
+async fn finish_log(state: &AppState, log: RequestLog) {
+    //the service label is looked up here so no code path has to carry it to the log
+    let routes = state.routes.read().await;
+    let route = routing::resolve(&routes, &log.domain);
+    let routed = route.is_some();
+    let service = route.and_then(|route| route.service.clone());
+    drop(routes);
+    let log = log.with_service(service.as_deref());
+    log.log();
+    state.live.record(log.status, log.bytes_sent);
+    //unknown hosts share one label so arbitrary Host headers can't grow the registry
+    let domain = if routed { log.domain.as_str() } else { "unmatched" };
+    state.metrics.record(
+        domain,
+        log.status,
+        Duration::from_millis(log.response_time_ms as u64),
+        log.error.is_some(),
+    );
+
+    state.log_writer.push(log).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use api::DomainDto;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Instant;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    //a proxy and its admin api on loopback ports, wired like main but with its own in-memory database
+    struct TestProxy {
+        addr: SocketAddr,
+        api: SocketAddr,
+        db: SqlitePool,
+    }
+
+    fn domain(name: &str, origin: &str) -> DomainDto {
+        DomainDto {
+            domain: name.to_string(),
+            origin: origin.to_string(),
+            enabled: true,
+            ..Default::default()
+        }
+    }
+
+    //an origin serving `app` on a loopback port
+    async fn origin(app: Router) -> String {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        format!("http://{}", addr)
+    }
+
+    //config rows are stored before the settings are loaded, the seeded global rate limit
+    //is dropped unless a test sets one. both listeners take a free loopback port unless
+    //a test configures their addresses
+    async fn start_proxy(config: &[(&str, &str)], domains: &[DomainDto]) -> TestProxy {
+        let db = database::test_db().await;
+        sqlx::query("DELETE FROM config WHERE key = 'rate_limit_per_minute'")
+            .execute(&db)
+            .await
+            .unwrap();
+        for (key, value) in [("host", "127.0.0.1"), ("port", "0"), ("admin.port", "0")] {
+            database::set_config(&db, key, value).await.unwrap();
+        }
+        for (key, value) in config {
+            database::set_config(&db, key, value).await.unwrap();
+        }
+        for domain in domains {
+            database::create_domain(&db, domain).await.unwrap();
+        }
+        let state = test_state(&db).await;
+
+        let host = settings::config_value::<String>(&db, "host").await.unwrap();
+        let api_addr = admin_addr(&db, &host).await.unwrap();
+        let listener = tokio::net::TcpListener::bind(api_addr).await.unwrap();
+        let api = listener.local_addr().unwrap();
+        let api_app = api_router(admin_state(&state, db.clone(), CertStore::new(None)));
+        tokio::spawn(async move { axum::serve(listener, api_app).await });
+
+        let port = settings::config_value::<u16>(&db, "port").await.unwrap();
+        let listener = tokio::net::TcpListener::bind((host.as_str(), port)).await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let app = Router::new().fallback(proxy_handler).with_state(state);
+        tokio::spawn(async move {
+            axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>()).await
+        });
+
+        TestProxy { addr, api, db }
+    }
+
+    //the proxy state start_proxy serves, for tests that drive the router themselves
+    async fn test_state(db: &SqlitePool) -> AppState {
+        let routes = routing::table(&database::load_domains(db).await.unwrap());
+        let (lb_strategy, hash_key) = lb_config(db).await;
+        build_state(db, ProxySettings::load(db).await, routes, &lb_strategy, hash_key)
+    }
+
+    impl TestProxy {
+        async fn get(&self, host: &str, path: &str) -> (StatusCode, String) {
+            let (status, _, body) = self.send(request(Method::GET, host, path)).await;
+            (status, body)
+        }
+
+        //sends `req` through the proxy, its uri only needs the path
+        async fn send(&self, mut req: axum::http::Request<Body>) -> (StatusCode, HeaderMap, String) {
+            let path = req.uri().path_and_query().map(|pq| pq.as_str().to_string()).unwrap_or_default();
+            *req.uri_mut() = format!("http://{}{}", self.addr, path).parse().unwrap();
+            let client: Client<HttpConnector, Body> = Client::builder(TokioExecutor::new()).build_http();
+            let response = client.request(req).await.unwrap();
+            let (parts, body) = response.into_parts();
+            let body = body.collect().await.unwrap().to_bytes();
+            (parts.status, parts.headers, String::from_utf8_lossy(&body).into_owned())
+        }
+
+        //a GET on the admin api, the json body of the answer
+        async fn admin(&self, path: &str) -> serde_json::Value {
+            let client: Client<HttpConnector, Body> = Client::builder(TokioExecutor::new()).build_http();
+            let uri = format!("http://{}{}", self.api, path).parse().unwrap();
+            let response = client.get(uri).await.unwrap();
+            let body = response.into_body().collect().await.unwrap().to_bytes();
+            serde_json::from_slice(&body).unwrap()
+        }
+
+        //the log rows for a request id from /logs, once the log writer has stored them
+        async fn logs(&self, request_id: &str) -> Vec<serde_json::Value> {
+            for _ in 0..100 {
+                let logs = self.admin(&format!("/logs?request_id={}", request_id)).await;
+                let rows = logs["data"].as_array().cloned().unwrap_or_default();
+                if !rows.is_empty() {
+                    return rows;
+                }
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+            panic!("no log row for request {}", request_id);
+        }
+
+        //the stored denials once at least `count` have been written
+        async fn denials(&self, count: usize) -> Vec<api::DenialDto> {
+            for _ in 0..100 {
+                let denials = database::get_denied_requests(&self.db, 100).await.unwrap();
+                if denials.len() >= count {
+                    return denials;
+                }
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+            panic!("fewer than {} denials were written", count);
+        }
+    }
+
+    fn request(method: Method, host: &str, path: &str) -> axum::http::Request<Body> {
+        axum::http::Request::builder()
+            .method(method)
+            .uri(path)
+            .header("host", host)
+            .body(Body::empty())
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn each_denial_category_is_recorded() {
+        let backend = origin(Router::new().fallback(|| async { "ok" })).await;
+
+        let blocked = start_proxy(&[("ip_filter.deny", "127.0.0.1/32")], &[domain("app.test", &backend)]).await;
+        assert_eq!(blocked.get("app.test", "/").await.0, StatusCode::FORBIDDEN);
+
+        let limited = start_proxy(&[("rate_limit_per_minute", "1")], &[domain("app.test", &backend)]).await;
+        assert_eq!(limited.get("app.test", "/").await.0, StatusCode::OK);
+        assert_eq!(limited.get("app.test", "/").await.0, StatusCode::TOO_MANY_REQUESTS);
+
+        let guard = [("ssrf_guard.enabled", "true"), ("ssrf_guard.exempt_configured", "false")];
+        let guarded = start_proxy(&guard, &[domain("app.test", &backend)]).await;
+        assert_eq!(guarded.get("app.test", "/").await.0, StatusCode::FORBIDDEN);
+
+        for (proxy, category, rule, status) in [
+            (&blocked, "ip_deny", "ip_filter.deny:127.0.0.1/32", 403),
+            (&limited, "rate_limit", "rate_limit_per_minute", 429),
+            (&guarded, "origin_block", "ssrf_guard:", 403),
+        ] {
+            let denials = proxy.denials(1).await;
+            assert_eq!(denials.len(), 1, "{}", category);
+            assert_eq!(denials[0].category, category);
+            let stored = denials[0].rule.as_deref().unwrap_or_default();
+            assert!(stored.starts_with(rule), "{}: {}", category, stored);
+            assert_eq!(denials[0].status, status);
+            assert_eq!(denials[0].ip_address.as_deref(), Some("127.0.0.1"));
+        }
+    }
+
+    #[tokio::test]
+    async fn each_domain_enforces_its_own_upstream_timeout() {
+        let backend = origin(Router::new().fallback(|| async {
+            tokio::time::sleep(Duration::from_millis(300)).await;
+            "slow"
+        }))
+        .await;
+        let strict = DomainDto {
+            upstream_timeout_ms: Some(100),
+            ..domain("strict.test", &backend)
+        };
+        let patient = DomainDto {
+            upstream_timeout_ms: Some(2000),
+            ..domain("patient.test", &backend)
+        };
+        let proxy = start_proxy(&[("upstream_timeout_ms", "200")], &[strict, patient]).await;
+
+        assert_eq!(proxy.get("strict.test", "/").await.0, StatusCode::GATEWAY_TIMEOUT);
+        let (status, body) = proxy.get("patient.test", "/").await;
+        assert_eq!((status, body.as_str()), (StatusCode::OK, "slow"));
+    }
+
+    #[tokio::test]
+    async fn not_found_log_counts_the_error_body() {
+        let proxy = start_proxy(&[], &[]).await;
+
+        let (status, body) = proxy.get("unknown.test", "/").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(body, "Domain 'unknown.test' not configured");
+
+        let (status, bytes_sent): (i64, i64) = sqlx::query_as("SELECT status, bytes_sent FROM request_logs")
+            .fetch_one(&proxy.db)
+            .await
+            .unwrap();
+        assert_eq!((status, bytes_sent), (404, body.len() as i64));
+    }
+
+    #[tokio::test]
+    async fn upstream_requests_stay_within_the_global_ceiling() {
+        //the origin counts how many requests it is serving at once
+        let current = Arc::new(AtomicUsize::new(0));
+        let peak = Arc::new(AtomicUsize::new(0));
+        let app = Router::new().fallback({
+            let (current, peak) = (current.clone(), peak.clone());
+            move || async move {
+                let now = current.fetch_add(1, Ordering::SeqCst) + 1;
+                peak.fetch_max(now, Ordering::SeqCst);
+                tokio::time::sleep(Duration::from_millis(50)).await;
+                current.fetch_sub(1, Ordering::SeqCst);
+                "ok"
+            }
+        });
+        let backend = origin(app).await;
+        let config = [("max_upstream_connections", "2"), ("upstream_queue_timeout_ms", "5000")];
+        let proxy = Arc::new(start_proxy(&config, &[domain("app.test", &backend)]).await);
+
+        let mut requests = JoinSet::new();
+        for i in 0..8 {
+            let proxy = proxy.clone();
+            requests.spawn(async move { proxy.get("app.test", &format!("/{}", i)).await.0 });
+        }
+        while let Some(status) = requests.join_next().await {
+            assert_eq!(status.unwrap(), StatusCode::OK);
+        }
+        assert_eq!(peak.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn throttled_requests_are_counted_per_domain() {
+        let backend = origin(Router::new().fallback(|| async { "ok" })).await;
+        let limited = DomainDto {
+            rate_limit_per_minute: Some(2),
+            ..domain("limited.test", &backend)
+        };
+        let proxy = start_proxy(&[], &[limited, domain("open.test", &backend)]).await;
+
+        for _ in 0..2 {
+            assert_eq!(proxy.get("limited.test", "/").await.0, StatusCode::OK);
+        }
+        for _ in 0..3 {
+            assert_eq!(proxy.get("limited.test", "/").await.0, StatusCode::TOO_MANY_REQUESTS);
+        }
+        assert_eq!(proxy.get("open.test", "/").await.0, StatusCode::OK);
+
+        let stats = proxy.admin("/stats/domains").await;
+        let throttled = |name: &str| {
+            stats["data"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .find(|entry| entry["domain"] == name)
+                .map(|entry| entry["throttled"].clone())
+        };
+        assert_eq!(throttled("limited.test"), Some(serde_json::json!(3)));
+        assert!(throttled("open.test").is_none_or(|count| count == 0));
+    }
+
+    #[tokio::test]
+    async fn head_gets_headers_only_and_leaves_the_get_entry_intact() {
+        let hits = Arc::new(AtomicUsize::new(0));
+        let app = Router::new().fallback({
+            let hits = hits.clone();
+            move || async move {
+                hits.fetch_add(1, Ordering::SeqCst);
+                "hello"
+            }
+        });
+        let backend = origin(app).await;
+        let proxy = start_proxy(&[], &[domain("app.test", &backend)]).await;
+
+        //an uncached HEAD goes upstream and must not store its empty body under the GET key
+        let (status, headers, body) = proxy.send(request(Method::HEAD, "app.test", "/page")).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(headers["content-length"], "5");
+        assert_eq!(body, "");
+
+        assert_eq!(proxy.get("app.test", "/page").await, (StatusCode::OK, "hello".to_string()));
+        assert_eq!(hits.load(Ordering::SeqCst), 2);
+
+        let (status, headers, body) = proxy.send(request(Method::HEAD, "app.test", "/page")).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(headers["x-cache"], "HIT");
+        assert_eq!(headers["content-length"], "5");
+        assert_eq!(body, "");
+
+        assert_eq!(proxy.get("app.test", "/page").await, (StatusCode::OK, "hello".to_string()));
+        assert_eq!(hits.load(Ordering::SeqCst), 2);
+    }
+
+    //an origin answering `/{size}` with that many bytes, counting the requests per path
+    async fn sized_origin() -> (String, Arc<std::sync::Mutex<Vec<String>>>) {
+        let seen = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let app = Router::new().fallback({
+            let seen = seen.clone();
+            move |uri: axum::http::Uri| async move {
+                seen.lock().unwrap().push(uri.path().to_string());
+                "x".repeat(uri.path()[1..].parse().unwrap_or(0))
+            }
+        });
+        (origin(app).await, seen)
+    }
 
-            log.log();
-            let _ = save_log(&state.db, &log).await;
+    #[tokio::test]
+    async fn only_bodies_within_the_size_bounds_are_cached() {
+        let (backend, seen) = sized_origin().await;
+        let config = [("min_cache_bytes", "10"), ("max_cache_bytes", "100")];
+        let proxy = start_proxy(&config, &[domain("app.test", &backend)]).await;
 
-            Err(StatusCode::BAD_GATEWAY)
+        for (path, first, second, upstream) in [
+            ("/3", Some("UNCACHEABLE"), Some("UNCACHEABLE"), 2),
+            ("/50", Some("MISS"), Some("HIT"), 1),
+            ("/500", None, None, 2),
+        ] {
+            let mut x_cache = Vec::new();
+            for _ in 0..2 {
+                let (status, headers, body) = proxy.send(request(Method::GET, "app.test", path)).await;
+                assert_eq!(status, StatusCode::OK);
+                assert_eq!(body.len().to_string(), path[1..]);
+                x_cache.push(headers.get("x-cache").map(|v| v.to_str().unwrap().to_string()));
+                //the cache is filled in the background
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+            assert_eq!(x_cache, [first.map(String::from), second.map(String::from)], "{}", path);
+            let hits = seen.lock().unwrap().iter().filter(|p| *p == path).count();
+            assert_eq!(hits, upstream, "{}", path);
+        }
+    }
+
+    #[tokio::test]
+    async fn an_absolute_backend_redirect_points_back_at_the_proxy() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let backend = format!("http://{}", listener.local_addr().unwrap());
+        let app = Router::new().fallback({
+            let location = format!("{}/next?step=2", backend);
+            move || async move { (StatusCode::FOUND, [("location", location)]) }
+        });
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        let proxy = start_proxy(&[("rewrite_redirects", "true")], &[domain("app.test", &backend)]).await;
+
+        let (status, headers, _) = proxy.send(request(Method::GET, "app.test", "/start")).await;
+        assert_eq!(status, StatusCode::FOUND);
+        assert_eq!(headers["location"], "http://app.test/next?step=2");
+    }
+
+    //a 502 carrying `code` well within the connect timeout, with the origin's breaker opened by that one failure
+    async fn assert_fails_fast(backend: &str, code: &str) {
+        let config = [
+            ("upstream_error_body", "json"),
+            ("upstream_connect_timeout_ms", "5000"),
+            ("circuit_breaker.failure_threshold", "5"),
+        ];
+        let proxy = start_proxy(&config, &[domain("app.test", backend)]).await;
+
+        let started = Instant::now();
+        let (status, _, body) = proxy.send(request(Method::GET, "app.test", "/")).await;
+        assert!(started.elapsed() < Duration::from_secs(2), "{} took {:?}", code, started.elapsed());
+        assert_eq!(status, StatusCode::BAD_GATEWAY);
+        let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(body["code"], code);
+
+        let stats = proxy.admin("/stats").await;
+        assert_eq!(stats["data"]["circuit_breakers"][0]["state"], "open", "{}", code);
+    }
+
+    #[tokio::test]
+    async fn a_refused_connection_is_a_fast_502() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let closed = format!("http://{}", listener.local_addr().unwrap());
+        drop(listener);
+        assert_fails_fast(&closed, "connection_refused").await;
+    }
+
+    #[tokio::test]
+    async fn an_unresolvable_origin_is_a_fast_502() {
+        assert_fails_fast("http://origin.invalid", "dns_failure").await;
+    }
+
+    //an origin answering each request with its path, recording the paths it was asked for
+    async fn echo_origin() -> (String, Arc<std::sync::Mutex<Vec<String>>>) {
+        let seen = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let app = Router::new().fallback({
+            let seen = seen.clone();
+            move |uri: axum::http::Uri| async move {
+                seen.lock().unwrap().push(uri.path().to_string());
+                uri.path().to_string()
+            }
+        });
+        (origin(app).await, seen)
+    }
+
+    #[tokio::test]
+    async fn only_paths_under_cache_paths_are_cached() {
+        let (backend, seen) = echo_origin().await;
+        let site = DomainDto {
+            cache_paths: vec!["/static".to_string(), "/images/*.png".to_string()],
+            ..domain("app.test", &backend)
+        };
+        let proxy = start_proxy(&[], &[site]).await;
+
+        for (path, cached) in [
+            ("/static/app.js", true),
+            ("/images/logo.png", true),
+            ("/staticky", false),
+            ("/images/logo.gif", false),
+            ("/account", false),
+        ] {
+            for _ in 0..2 {
+                assert_eq!(proxy.get("app.test", path).await, (StatusCode::OK, path.to_string()));
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+            let hits = seen.lock().unwrap().iter().filter(|p| *p == path).count();
+            assert_eq!(hits, if cached { 1 } else { 2 }, "{}", path);
+        }
+    }
+
+    #[tokio::test]
+    async fn set_cookie_responses_are_cached_only_where_the_cookie_is_stripped() {
+        let hits = Arc::new(AtomicUsize::new(0));
+        let app = Router::new().fallback({
+            let hits = hits.clone();
+            move || async move {
+                hits.fetch_add(1, Ordering::SeqCst);
+                ([("set-cookie", "session=abc")], "asset")
+            }
+        });
+        let backend = origin(app).await;
+        let fetch_twice = |proxy: TestProxy, path: &'static str| async move {
+            let mut responses = Vec::new();
+            for _ in 0..2 {
+                let (status, headers, body) = proxy.send(request(Method::GET, "app.test", path)).await;
+                assert_eq!((status, body.as_str()), (StatusCode::OK, "asset"));
+                responses.push(headers);
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+            responses
+        };
+
+        //by default a response setting a cookie is never stored
+        let proxy = start_proxy(&[], &[domain("app.test", &backend)]).await;
+        let responses = fetch_twice(proxy, "/static/app.js").await;
+        assert_eq!(hits.swap(0, Ordering::SeqCst), 2);
+        assert!(responses.iter().all(|headers| headers["set-cookie"] == "session=abc"));
+
+        let config = [("cache.strip_set_cookie_paths", "/static")];
+        let proxy = start_proxy(&config, &[domain("app.test", &backend)]).await;
+        let responses = fetch_twice(proxy, "/static/app.js").await;
+        assert_eq!(hits.swap(0, Ordering::SeqCst), 1);
+        assert_eq!(responses[1]["x-cache"], "HIT");
+        assert!(responses[1].get("set-cookie").is_none());
+
+        //the prefix covers whole segments only
+        let proxy = start_proxy(&config, &[domain("app.test", &backend)]).await;
+        fetch_twice(proxy, "/staticky").await;
+        assert_eq!(hits.swap(0, Ordering::SeqCst), 2);
+    }
+
+    //a port nothing listens on right now
+    async fn free_port() -> u16 {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        listener.local_addr().unwrap().port()
+    }
+
+    #[tokio::test]
+    async fn admin_api_and_proxy_listen_on_their_own_addresses() {
+        let backend = origin(Router::new().fallback(|| async { "proxied" })).await;
+        let (proxy_port, admin_port) = (free_port().await, free_port().await);
+        let config = [
+            ("host", "0.0.0.0".to_string()),
+            ("port", proxy_port.to_string()),
+            ("admin.host", "127.0.0.1".to_string()),
+            ("admin.port", admin_port.to_string()),
+        ];
+        let config: Vec<(&str, &str)> = config.iter().map(|(key, value)| (*key, value.as_str())).collect();
+        let proxy = start_proxy(&config, &[domain("app.test", &backend)]).await;
+
+        assert_eq!(proxy.api, SocketAddr::from(([127, 0, 0, 1], admin_port)));
+        assert_eq!(proxy.addr.port(), proxy_port);
+        assert!(proxy.addr.ip().is_unspecified());
+
+        let domains = proxy.admin("/domains").await;
+        assert_eq!(domains["data"][0]["domain"], "app.test");
+        //the proxy port only proxies, the admin routes aren't there
+        assert_eq!(proxy.get("app.test", "/domains").await, (StatusCode::OK, "proxied".to_string()));
+
+        database::set_config(&proxy.db, "admin.enabled", "false").await.unwrap();
+        assert_eq!(admin_addr(&proxy.db, "0.0.0.0").await, None);
+    }
+
+    #[tokio::test]
+    async fn one_request_id_links_upstream_response_and_log() {
+        let received = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let app = Router::new().fallback({
+            let received = received.clone();
+            move |headers: HeaderMap| async move {
+                let id = headers.get(request_id::HEADER).map(|v| v.to_str().unwrap().to_string());
+                received.lock().unwrap().push(id);
+                "ok"
+            }
+        });
+        let backend = origin(app).await;
+        let proxy = start_proxy(&[], &[domain("app.test", &backend)]).await;
+
+        let (_, headers, _) = proxy.send(request(Method::GET, "app.test", "/generated")).await;
+        let generated = headers[request_id::HEADER].to_str().unwrap().to_string();
+
+        let mut req = request(Method::GET, "app.test", "/supplied");
+        req.headers_mut().insert(request_id::HEADER, HeaderValue::from_static("client-id-42"));
+        let (_, headers, _) = proxy.send(req).await;
+        assert_eq!(headers[request_id::HEADER], "client-id-42");
+
+        let upstream = received.lock().unwrap().clone();
+        assert_eq!(upstream, [Some(generated.clone()), Some("client-id-42".to_string())]);
+
+        for (id, path) in [(generated.as_str(), "/generated"), ("client-id-42", "/supplied")] {
+            let rows = proxy.logs(id).await;
+            assert_eq!(rows.len(), 1, "{}", id);
+            assert_eq!(rows[0]["request_id"], id);
+            assert_eq!(rows[0]["path"], path);
+        }
+    }
+
+    #[tokio::test]
+    async fn a_disabled_cache_sends_every_get_to_the_origin() {
+        let hits = Arc::new(AtomicUsize::new(0));
+        let app = Router::new().fallback({
+            let hits = hits.clone();
+            move || async move {
+                hits.fetch_add(1, Ordering::SeqCst);
+                axum::response::Html("<html><head></head></html>")
+            }
+        });
+        let backend = origin(app).await;
+        //plain pages stream through, pages getting a snippet are buffered, neither touches a cache
+        let injected = DomainDto {
+            html_inject: Some("<script></script>".to_string()),
+            ..domain("injected.test", &backend)
+        };
+        let proxy = start_proxy(&[("cache.enabled", "false")], &[domain("app.test", &backend), injected]).await;
+
+        for host in ["app.test", "injected.test"] {
+            for _ in 0..3 {
+                let (status, headers, _) = proxy.send(request(Method::GET, host, "/page")).await;
+                assert_eq!(status, StatusCode::OK);
+                assert!(headers.get("x-cache").is_none(), "{}", host);
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+        }
+        assert_eq!(hits.load(Ordering::SeqCst), 6);
+    }
+
+    #[tokio::test]
+    async fn two_host_headers_are_refused() {
+        let backend = origin(Router::new().fallback(|| async { "ok" })).await;
+        let proxy = start_proxy(&[], &[domain("app.test", &backend), domain("other.test", &backend)]).await;
+
+        let mut req = request(Method::GET, "app.test", "/");
+        req.headers_mut().append("host", HeaderValue::from_static("other.test"));
+        let (status, _, body) = proxy.send(req).await;
+        assert_eq!((status, body.as_str()), (StatusCode::BAD_REQUEST, "Multiple Host headers"));
+
+        assert_eq!(proxy.get("app.test", "/").await.0, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn an_over_long_uri_gets_414() {
+        let (backend, seen) = echo_origin().await;
+        let proxy = start_proxy(&[("max_uri_length", "64")], &[domain("app.test", &backend)]).await;
+
+        //the limit covers the path and query together
+        let at_limit = format!("/{}?q={}", "a".repeat(40), "b".repeat(20));
+        assert_eq!(at_limit.len(), 64);
+        assert_eq!(proxy.get("app.test", &at_limit).await.0, StatusCode::OK);
+
+        let too_long = format!("/{}?q={}", "a".repeat(40), "b".repeat(21));
+        let (status, body) = proxy.get("app.test", &too_long).await;
+        assert_eq!((status, body.as_str()), (StatusCode::URI_TOO_LONG, "URI Too Long"));
+        assert_eq!(seen.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn oversized_request_headers_get_431() {
+        let (backend, seen) = echo_origin().await;
+        let proxy = start_proxy(&[("max_request_header_bytes", "1024")], &[domain("app.test", &backend)]).await;
+
+        let mut req = request(Method::GET, "app.test", "/small");
+        req.headers_mut().insert("x-padding", "a".repeat(200).parse().unwrap());
+        assert_eq!(proxy.send(req).await.0, StatusCode::OK);
+
+        let mut req = request(Method::GET, "app.test", "/large");
+        req.headers_mut().insert("x-padding", "a".repeat(2000).parse().unwrap());
+        let (status, headers, body) = proxy.send(req).await;
+        assert_eq!(
+            (status, body.as_str()),
+            (StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE, "Request Header Fields Too Large")
+        );
+        assert_eq!(*seen.lock().unwrap(), ["/small"]);
+
+        let logs = proxy.logs(headers["x-request-id"].to_str().unwrap()).await;
+        assert_eq!((logs[0]["path"].as_str(), logs[0]["status"].as_i64()), (Some("/large"), Some(431)));
+    }
+
+    //an origin streaming ten 100 byte chunks without a content-length
+    async fn chunked_origin() -> String {
+        origin(Router::new().fallback(|| async {
+            let chunks = (0..10).map(|_| Ok::<_, std::convert::Infallible>(bytes::Bytes::from(vec![b'x'; 100])));
+            Body::from_stream(futures::stream::iter(chunks))
+        }))
+        .await
+    }
+
+    #[tokio::test]
+    async fn an_oversized_upstream_body_is_refused_or_cut_short() {
+        let backend = chunked_origin().await;
+        let capped = |name: &str| DomainDto {
+            max_response_bytes: Some(300),
+            ..domain(name, &backend)
+        };
+
+        //a cacheable response is buffered, so it can still be replaced by a 502
+        let buffered = start_proxy(&[], &[capped("app.test")]).await;
+        let (status, body) = buffered.get("app.test", "/").await;
+        assert_eq!((status, body.as_str()), (StatusCode::BAD_GATEWAY, "Upstream response too large"));
+
+        //a streamed one has already started, it ends early instead
+        let streamed = start_proxy(&[("cache.enabled", "false")], &[capped("app.test")]).await;
+        let client: Client<HttpConnector, Body> = Client::builder(TokioExecutor::new()).build_http();
+        let mut req = request(Method::GET, "app.test", "/");
+        *req.uri_mut() = format!("http://{}/", streamed.addr).parse().unwrap();
+        let response = client.request(req).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let mut body = response.into_body();
+        let mut received = 0;
+        let truncated = loop {
+            match body.frame().await {
+                Some(Ok(frame)) => received += frame.data_ref().map(|data| data.len()).unwrap_or(0),
+                Some(Err(_)) => break true,
+                None => break false,
+            }
+        };
+        assert!(truncated);
+        assert!(received <= 300, "{} bytes relayed", received);
+    }
+
+    #[tokio::test]
+    async fn correlation_id_comes_back_unchanged() {
+        //the origin answers with its own value, the client's still wins
+        let backend = origin(Router::new().fallback(|| async { ([("x-correlation-id", "from-origin")], "ok") })).await;
+        let proxy = start_proxy(&[("echo_headers", "X-Correlation-Id")], &[domain("app.test", &backend)]).await;
+
+        for host in ["app.test", "unknown.test"] {
+            let mut req = request(Method::GET, host, "/");
+            req.headers_mut().insert("x-correlation-id", HeaderValue::from_static("corr-7f3a"));
+            let (_, headers, _) = proxy.send(req).await;
+            let values: Vec<_> = headers.get_all("x-correlation-id").iter().collect();
+            assert_eq!(values, ["corr-7f3a"], "{}", host);
+        }
+
+        //without one from the client nothing is made up, the origin's own header passes through
+        let (_, headers, _) = proxy.send(request(Method::GET, "app.test", "/uncached")).await;
+        assert_eq!(headers["x-correlation-id"], "from-origin");
+    }
+
+    #[tokio::test]
+    async fn trailing_slash_policies_settle_the_path() {
+        let (backend, _) = echo_origin().await;
+        let with_policy = |name: &str, policy| DomainDto {
+            trailing_slash: Some(policy),
+            ..domain(name, &backend)
+        };
+        let proxy = start_proxy(
+            &[("cache.enabled", "false")],
+            &[
+                with_policy("add.test", TrailingSlash::Add),
+                with_policy("remove.test", TrailingSlash::Remove),
+                with_policy("redirect.test", TrailingSlash::Redirect),
+            ],
+        )
+        .await;
+
+        //the origin answers with the path it was sent
+        for (host, path, forwarded) in [
+            ("add.test", "/docs", "/docs/"),
+            ("add.test", "/docs/", "/docs/"),
+            ("remove.test", "/docs/", "/docs"),
+            ("remove.test", "/docs", "/docs"),
+            ("add.test", "/", "/"),
+            ("remove.test", "/", "/"),
+            ("redirect.test", "/docs", "/docs"),
+        ] {
+            assert_eq!(proxy.get(host, path).await, (StatusCode::OK, forwarded.to_string()), "{}{}", host, path);
+        }
+
+        let (status, headers, _) = proxy.send(request(Method::GET, "redirect.test", "/docs/?page=2")).await;
+        assert_eq!(status, StatusCode::PERMANENT_REDIRECT);
+        assert_eq!(headers["location"], "/docs?page=2");
+    }
+
+    #[tokio::test]
+    async fn expect_continue_is_relayed_and_the_body_forwarded() {
+        let app = Router::new().fallback(|headers: HeaderMap, body: String| async move {
+            let expect = headers.get("expect").map(|v| v.to_str().unwrap().to_string()).unwrap_or_default();
+            format!("{} {}", expect, body)
+        });
+        let backend = origin(app).await;
+        let proxy = start_proxy(&[("expect_continue", "forward")], &[domain("app.test", &backend)]).await;
+
+        let mut client = tokio::net::TcpStream::connect(proxy.addr).await.unwrap();
+        client
+            .write_all(b"POST /upload HTTP/1.1\r\nHost: app.test\r\nContent-Length: 5\r\nExpect: 100-continue\r\n\r\n")
+            .await
+            .unwrap();
+
+        //nothing is uploaded until the proxy says go ahead
+        let mut interim = vec![0u8; 25];
+        tokio::time::timeout(Duration::from_secs(2), client.read_exact(&mut interim))
+            .await
+            .expect("no interim response")
+            .unwrap();
+        assert_eq!(&interim, b"HTTP/1.1 100 Continue\r\n\r\n");
+
+        client.write_all(b"hello").await.unwrap();
+        let mut response = Vec::new();
+        let mut buf = [0u8; 1024];
+        while !String::from_utf8_lossy(&response).ends_with("100-continue hello") {
+            let n = tokio::time::timeout(Duration::from_secs(2), client.read(&mut buf)).await.unwrap().unwrap();
+            assert!(n > 0, "connection closed: {}", String::from_utf8_lossy(&response));
+            response.extend_from_slice(&buf[..n]);
+        }
+        assert!(response.starts_with(b"HTTP/1.1 200 OK\r\n"));
+    }
+
+    #[tokio::test]
+    async fn selftest_passes_with_a_working_config_and_fails_with_a_broken_one() {
+        let db = database::test_db().await;
+        let state = test_state(&db).await;
+        let app = Router::new().fallback(proxy_handler).with_state(state.clone());
+        assert_eq!(selftest::run(state, app).await, Ok(()));
+
+        //maintenance mode answers every request with a 503 instead of proxying it
+        let db = database::test_db().await;
+        database::set_config(&db, "maintenance.enabled", "true").await.unwrap();
+        let state = test_state(&db).await;
+        let app = Router::new().fallback(proxy_handler).with_state(state.clone());
+        let err = selftest::run(state, app).await.unwrap_err();
+        assert!(err.contains("503"), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn repeated_vary_headers_are_joined_and_set_cookies_kept() {
+        let app = Router::new().fallback(|| async {
+            let mut headers = HeaderMap::new();
+            headers.append("vary", HeaderValue::from_static("Accept-Encoding"));
+            headers.append("vary", HeaderValue::from_static("Origin"));
+            headers.append("set-cookie", HeaderValue::from_static("a=1; Path=/"));
+            headers.append("set-cookie", HeaderValue::from_static("b=2; Expires=Wed, 21 Oct 2026 07:28:00 GMT"));
+            (headers, "ok")
+        });
+        let backend = origin(app).await;
+        let proxy = start_proxy(
+            &[("merge_response_headers", "Vary,Set-Cookie"), ("cache.enabled", "false")],
+            &[domain("app.test", &backend)],
+        )
+        .await;
+
+        let (status, headers, _) = proxy.send(request(Method::GET, "app.test", "/")).await;
+        assert_eq!(status, StatusCode::OK);
+        let vary: Vec<_> = headers.get_all("vary").iter().collect();
+        assert_eq!(vary, ["Accept-Encoding, Origin"]);
+        let cookies: Vec<_> = headers.get_all("set-cookie").iter().collect();
+        assert_eq!(cookies, ["a=1; Path=/", "b=2; Expires=Wed, 21 Oct 2026 07:28:00 GMT"]);
+    }
+
+    #[tokio::test]
+    async fn a_flooding_client_leaves_room_for_another() {
+        let app = Router::new().fallback(|| async {
+            tokio::time::sleep(Duration::from_millis(300)).await;
+            "ok"
+        });
+        let backend = origin(app).await;
+        let site = DomainDto { max_concurrency: Some(4), ..domain("app.test", &backend) };
+        let proxy = Arc::new(
+            start_proxy(
+                &[("trusted_proxies", "127.0.0.1/32"), ("concurrency.max_client_share", "0.5")],
+                &[site],
+            )
+            .await,
+        );
+        let from = |ip: &str| {
+            let mut req = request(Method::GET, "app.test", "/");
+            req.headers_mut().insert("x-forwarded-for", ip.parse().unwrap());
+            req
+        };
+
+        //the greedy client fires more requests than the whole domain allows
+        let flood: Vec<_> = (0..6)
+            .map(|_| {
+                let proxy = proxy.clone();
+                let req = from("203.0.113.1");
+                tokio::spawn(async move { proxy.send(req).await.0 })
+            })
+            .collect();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let (status, _, body) = proxy.send(from("203.0.113.2")).await;
+        assert_eq!((status, body.as_str()), (StatusCode::OK, "ok"));
+
+        let mut statuses = Vec::new();
+        for request in flood {
+            statuses.push(request.await.unwrap());
+        }
+        statuses.sort();
+        assert_eq!(
+            statuses,
+            [
+                StatusCode::OK,
+                StatusCode::OK,
+                StatusCode::TOO_MANY_REQUESTS,
+                StatusCode::TOO_MANY_REQUESTS,
+                StatusCode::TOO_MANY_REQUESTS,
+                StatusCode::TOO_MANY_REQUESTS,
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn a_transport_failure_is_an_opaque_or_diagnostic_502() {
+        let closed = format!("http://127.0.0.1:{}", free_port().await);
+
+        let proxy = start_proxy(&[], &[domain("app.test", &closed)]).await;
+        let (status, _, body) = proxy.send(request(Method::GET, "app.test", "/")).await;
+        assert_eq!((status, body.as_str()), (StatusCode::BAD_GATEWAY, ""));
+
+        let proxy = start_proxy(&[("upstream_error_body", "json")], &[domain("app.test", &closed)]).await;
+        let (status, headers, body) = proxy.send(request(Method::GET, "app.test", "/")).await;
+        assert_eq!(status, StatusCode::BAD_GATEWAY);
+        assert_eq!(headers["content-type"], "application/json");
+        let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(body["error"], "bad_gateway");
+        assert_eq!(body["code"], "connection_refused");
+        assert_eq!(body["status"], 502);
+        assert_eq!(body["request_id"], headers["x-request-id"].to_str().unwrap());
+    }
+
+    #[tokio::test]
+    async fn a_down_origin_is_covered_by_the_stale_entry() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let backend = format!("http://{}", listener.local_addr().unwrap());
+        let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+        let server = tokio::spawn(async move {
+            axum::serve(listener, Router::new().fallback(|| async { "fresh body" }))
+                .with_graceful_shutdown(async move {
+                    let _ = stopped.await;
+                })
+                .await
+        });
+        let config = [("cache.default_ttl_secs", "1"), ("cache.stale_if_error_secs", "60")];
+        let proxy = start_proxy(&config, &[domain("app.test", &backend)]).await;
+
+        assert_eq!(proxy.get("app.test", "/page").await, (StatusCode::OK, "fresh body".to_string()));
+        //the response is stored in the background
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        stop.send(()).unwrap();
+        server.await.unwrap().unwrap();
+        tokio::time::sleep(Duration::from_millis(1100)).await;
+
+        let (status, headers, body) = proxy.send(request(Method::GET, "app.test", "/page")).await;
+        assert_eq!((status, body.as_str()), (StatusCode::OK, "fresh body"));
+        assert_eq!(headers["x-cache"], "STALE-ERROR");
+
+        //nothing was cached for other paths, so they still fail
+        assert_eq!(proxy.get("app.test", "/other").await.0, StatusCode::BAD_GATEWAY);
+    }
+
+    //an origin noting when each request reached it
+    async fn timing_origin() -> (String, Arc<std::sync::Mutex<Vec<Instant>>>) {
+        let arrivals = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let app = Router::new().fallback({
+            let arrivals = arrivals.clone();
+            move || async move {
+                arrivals.lock().unwrap().push(Instant::now());
+                "ok"
+            }
+        });
+        (origin(app).await, arrivals)
+    }
+
+    #[tokio::test]
+    async fn paced_dispatches_are_spaced_by_the_interval() {
+        let (backend, arrivals) = timing_origin().await;
+        let pacing = pacing::Pacing { interval_ms: 100, max_queue: 10, max_wait_ms: 1000 };
+        let site = DomainDto { pacing: Some(pacing), ..domain("app.test", &backend) };
+        let proxy = Arc::new(start_proxy(&[("cache.enabled", "false")], &[site]).await);
+
+        let started = Instant::now();
+        let burst: Vec<_> = (0..4)
+            .map(|_| {
+                let proxy = proxy.clone();
+                tokio::spawn(async move { proxy.get("app.test", "/").await.0 })
+            })
+            .collect();
+        for request in burst {
+            assert_eq!(request.await.unwrap(), StatusCode::OK);
+        }
+
+        //each dispatch is held until its slot, one interval after the previous one's. measured
+        //from the burst rather than between arrivals, a late wakeup under load can't shrink it
+        let mut arrivals = arrivals.lock().unwrap().clone();
+        arrivals.sort();
+        assert_eq!(arrivals.len(), 4);
+        for (turn, arrived) in arrivals.iter().enumerate() {
+            let after = *arrived - started;
+            assert!(after >= Duration::from_millis(100) * turn as u32, "dispatch {} after {:?}", turn, after);
+        }
+    }
+
+    #[tokio::test]
+    async fn a_full_pacing_queue_sheds_with_503() {
+        let (backend, arrivals) = timing_origin().await;
+        let pacing = pacing::Pacing { interval_ms: 300, max_queue: 1, max_wait_ms: 1000 };
+        let site = DomainDto { pacing: Some(pacing), ..domain("app.test", &backend) };
+        let proxy = Arc::new(start_proxy(&[("cache.enabled", "false")], &[site]).await);
+
+        assert_eq!(proxy.get("app.test", "/").await.0, StatusCode::OK);
+        //the next request waits its turn, filling the queue
+        let queued = {
+            let proxy = proxy.clone();
+            tokio::spawn(async move { proxy.get("app.test", "/").await.0 })
+        };
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        let (status, body) = proxy.get("app.test", "/").await;
+        assert_eq!((status, body.as_str()), (StatusCode::SERVICE_UNAVAILABLE, "Origin pacing queue full"));
+        assert_eq!(queued.await.unwrap(), StatusCode::OK);
+        assert_eq!(arrivals.lock().unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn accept_routes_send_json_and_html_clients_to_their_own_origins() {
+        let api = origin(Router::new().fallback(|| async { "api" })).await;
+        let web = origin(Router::new().fallback(|| async { "web" })).await;
+        let default = origin(Router::new().fallback(|| async { "default" })).await;
+        let rule = |media_type: &str, origin: &str| routing::AcceptRoute {
+            media_type: media_type.to_string(),
+            origin: origin.to_string(),
+        };
+        let site = DomainDto {
+            accept_routes: vec![rule("application/json", &api), rule("text/html", &web)],
+            ..domain("app.test", &default)
+        };
+        let proxy = start_proxy(&[], &[site]).await;
+        let get = |accept: Option<&'static str>| {
+            let mut req = request(Method::GET, "app.test", "/items");
+            if let Some(accept) = accept {
+                req.headers_mut().insert("accept", HeaderValue::from_static(accept));
+            }
+            proxy.send(req)
+        };
+
+        assert_eq!(get(Some("application/json")).await.2, "api");
+        assert_eq!(get(Some("text/html,application/xhtml+xml;q=0.9")).await.2, "web");
+        //the most preferred type with a route wins
+        assert_eq!(get(Some("text/html;q=0.5, application/json")).await.2, "api");
+        assert_eq!(get(Some("image/png")).await.2, "default");
+        assert_eq!(get(None).await.2, "default");
+        //each representation is cached on its own, the json one is served again from cache
+        let (_, headers, body) = get(Some("application/json")).await;
+        assert_eq!((body.as_str(), &headers["x-cache"]), ("api", &HeaderValue::from_static("HIT")));
+    }
+
+    #[tokio::test]
+    async fn a_br_capable_client_gets_the_precompressed_variant() {
+        let dir = std::env::temp_dir().join(format!("proxynet-precompressed-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("app.js.br"), b"\x1b\x03brotli bytes").unwrap();
+
+        let (backend, seen) = echo_origin().await;
+        let site = DomainDto {
+            precompressed_dir: Some(dir.to_string_lossy().into_owned()),
+            ..domain("app.test", &backend)
+        };
+        let proxy = start_proxy(&[("cache.enabled", "false")], &[site]).await;
+        let get = |path: &str, accept_encoding: Option<&'static str>| {
+            let mut req = request(Method::GET, "app.test", path);
+            if let Some(accept_encoding) = accept_encoding {
+                req.headers_mut().insert("accept-encoding", HeaderValue::from_static(accept_encoding));
+            }
+            proxy.send(req)
+        };
+
+        let (status, headers, body) = get("/app.js", Some("gzip, deflate, br")).await;
+        assert_eq!((status, body.as_bytes()), (StatusCode::OK, &b"\x1b\x03brotli bytes"[..]));
+        assert_eq!(headers["content-encoding"], "br");
+        assert_eq!(headers["content-type"], "text/javascript; charset=utf-8");
+        assert_eq!(headers["vary"], "Accept-Encoding");
+        assert!(seen.lock().unwrap().is_empty());
+
+        //no usable variant: gzip-only clients, clients refusing br and files without a .br go to the origin
+        for (path, accept_encoding) in [
+            ("/app.js", Some("gzip")),
+            ("/app.js", Some("br;q=0, gzip")),
+            ("/app.js", None),
+            ("/style.css", Some("br")),
+        ] {
+            let (status, headers, body) = get(path, accept_encoding).await;
+            assert_eq!((status, body.as_str()), (StatusCode::OK, path), "{:?}", accept_encoding);
+            assert!(!headers.contains_key("content-encoding"));
         }
+        assert_eq!(seen.lock().unwrap().len(), 4);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn the_origin_guard_exempts_configured_origins_unless_told_not_to() {
+        let backend = origin(Router::new().fallback(|| async { "ok" })).await;
+
+        //a loopback origin from the domain config is trusted
+        let proxy = start_proxy(&[("ssrf_guard.enabled", "true")], &[domain("app.test", &backend)]).await;
+        assert_eq!(proxy.get("app.test", "/").await, (StatusCode::OK, "ok".to_string()));
+
+        let config = [("ssrf_guard.enabled", "true"), ("ssrf_guard.exempt_configured", "false")];
+        let proxy = start_proxy(&config, &[domain("app.test", &backend)]).await;
+        assert_eq!(proxy.get("app.test", "/").await.0, StatusCode::FORBIDDEN);
+        let denials = proxy.denials(1).await;
+        assert_eq!(denials[0].category, "origin_block");
     }
-}
\ No newline at end of file
+
+    //the whole of what the proxy sends back on a raw connection until it closes it,
+    //None if it's still open after `wait`
+    async fn read_until_closed(client: &mut tokio::net::TcpStream, wait: Duration) -> Option<String> {
+        let mut response = Vec::new();
+        tokio::time::timeout(wait, client.read_to_end(&mut response)).await.ok()?.unwrap();
+        Some(String::from_utf8_lossy(&response).into_owned())
+    }
+
+    #[tokio::test]
+    async fn connection_close_ends_the_client_connection_and_stays_off_the_upstream() {
+        let seen = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let app = Router::new().fallback({
+            let seen = seen.clone();
+            move |headers: HeaderMap| async move {
+                seen.lock().unwrap().push(headers);
+                "ok"
+            }
+        });
+        let backend = origin(app).await;
+        let proxy = start_proxy(&[("cache.enabled", "false")], &[domain("app.test", &backend)]).await;
+
+        let mut client = tokio::net::TcpStream::connect(proxy.addr).await.unwrap();
+        client
+            .write_all(b"GET / HTTP/1.1\r\nHost: app.test\r\nConnection: close, x-hop\r\nX-Hop: 1\r\n\r\n")
+            .await
+            .unwrap();
+        let response = read_until_closed(&mut client, Duration::from_secs(2)).await.expect("connection left open");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+        assert!(response.to_ascii_lowercase().contains("\r\nconnection: close\r\n"), "{}", response);
+        assert!(response.ends_with("ok"));
+
+        let upstream = seen.lock().unwrap()[0].clone();
+        //neither the client's Connection header nor the header it named reached the origin
+        assert!(!upstream.contains_key("connection"), "{:?}", upstream);
+        assert!(!upstream.contains_key("x-hop"));
+
+        //without it the connection is kept for the next request
+        let mut client = tokio::net::TcpStream::connect(proxy.addr).await.unwrap();
+        client.write_all(b"GET / HTTP/1.1\r\nHost: app.test\r\n\r\n").await.unwrap();
+        assert!(read_until_closed(&mut client, Duration::from_millis(300)).await.is_none());
+    }
+
+    //one keep-alive connection to the proxy, every request sent on it goes over the same socket
+    async fn client_connection(addr: SocketAddr) -> hyper::client::conn::http1::SendRequest<Body> {
+        let stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        let (sender, connection) = hyper::client::conn::http1::handshake(hyper_util::rt::TokioIo::new(stream))
+            .await
+            .unwrap();
+        tokio::spawn(connection);
+        sender
+    }
+
+    async fn send_on(sender: &mut hyper::client::conn::http1::SendRequest<Body>, path: &str) -> String {
+        sender.ready().await.unwrap();
+        let response = sender.send_request(request(Method::GET, "app.test", path)).await.unwrap();
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        String::from_utf8(body.to_vec()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn each_client_connection_keeps_its_own_upstream_connection() {
+        //the backend's state is its view of the connection: the proxy's address and port on it
+        let app = Router::new().fallback(|ConnectInfo(peer): ConnectInfo<SocketAddr>| async move { peer.to_string() });
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let backend = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>()).await
+        });
+        let site = DomainDto { connection_affinity: true, ..domain("app.test", &backend) };
+        let proxy = start_proxy(&[("cache.enabled", "false")], &[site]).await;
+
+        let mut first = client_connection(proxy.addr).await;
+        let mut second = client_connection(proxy.addr).await;
+        let mut first_seen = Vec::new();
+        let mut second_seen = Vec::new();
+        //interleaved, so a shared pool would hand the connections around
+        for i in 0..4 {
+            first_seen.push(send_on(&mut first, &format!("/a{}", i)).await);
+            second_seen.push(send_on(&mut second, &format!("/b{}", i)).await);
+        }
+
+        assert!(first_seen.iter().all(|conn| *conn == first_seen[0]), "{:?}", first_seen);
+        assert!(second_seen.iter().all(|conn| *conn == second_seen[0]), "{:?}", second_seen);
+        assert_ne!(first_seen[0], second_seen[0]);
+    }
+
+    #[tokio::test]
+    async fn the_banner_shows_resolved_values_and_hides_secrets() {
+        let db = database::test_db().await;
+        for (key, value) in [
+            ("rate_limit_per_minute", "120"),
+            ("max_upstream_connections", "64"),
+            ("acme.api_key", "sk-live-1234"),
+            ("admin.password", "hunter2"),
+            ("dns.token", "abcd"),
+        ] {
+            database::set_config(&db, key, value).await.unwrap();
+        }
+        let settings = ProxySettings::load(&db).await;
+
+        let banner = startup_banner(&db, &settings, "0.0.0.0:8080".to_string(), None, 3, "least_connections")
+            .await
+            .render();
+        let lines: Vec<&str> = banner.lines().map(str::trim).collect();
+        for expected in [
+            "listen = 0.0.0.0:8080",
+            "admin_listen = disabled",
+            "domains = 3",
+            "rate_limit_per_minute = 120",
+            "max_upstream_connections = 64",
+            "lb_strategy = least_connections",
+            "acme.api_key = [redacted]",
+            "admin.password = [redacted]",
+            "dns.token = [redacted]",
+        ] {
+            assert!(lines.contains(&expected), "{} missing from\n{}", expected, banner);
+        }
+        for secret in ["sk-live-1234", "hunter2", "abcd"] {
+            assert!(!banner.contains(secret), "{} leaked into\n{}", secret, banner);
+        }
+    }
+
+    #[tokio::test]
+    async fn streamed_responses_log_the_bytes_actually_relayed() {
+        let (sized, _) = sized_origin().await;
+        let chunked = chunked_origin().await;
+        let proxy = start_proxy(
+            &[("cache.enabled", "false")],
+            &[domain("sized.test", &sized), domain("chunked.test", &chunked)],
+        )
+        .await;
+        let bytes_logged = |headers: HeaderMap| {
+            let proxy = &proxy;
+            async move {
+                let logs = proxy.logs(headers["x-request-id"].to_str().unwrap()).await;
+                logs[0]["bytes_sent"].as_i64().unwrap()
+            }
+        };
+
+        let (_, headers, body) = proxy.send(request(Method::GET, "sized.test", "/1000")).await;
+        assert_eq!(body.len(), 1000);
+        assert_eq!(bytes_logged(headers).await, 1000);
+
+        let (_, headers, body) = proxy.send(request(Method::GET, "chunked.test", "/")).await;
+        assert_eq!(body.len(), 1000);
+        assert!(!headers.contains_key("content-length"));
+        assert_eq!(bytes_logged(headers).await, 1000);
+
+        let (_, headers, _) = proxy.send(request(Method::HEAD, "sized.test", "/1000")).await;
+        assert_eq!(headers["content-length"], "1000");
+        assert_eq!(bytes_logged(headers).await, 0);
+
+        //a client leaving early was sent less than the declared length
+        let declared = 16 * 1024 * 1024;
+        let mut client = tokio::net::TcpStream::connect(proxy.addr).await.unwrap();
+        let head = format!("GET /{} HTTP/1.1\r\nHost: sized.test\r\n\r\n", declared);
+        client.write_all(head.as_bytes()).await.unwrap();
+        let mut start = vec![0u8; 4096];
+        let n = client.read(&mut start).await.unwrap();
+        let start = String::from_utf8_lossy(&start[..n]).into_owned();
+        let request_id = start
+            .lines()
+            .find_map(|line| line.strip_prefix("x-request-id: "))
+            .unwrap()
+            .to_string();
+        drop(client);
+
+        let logs = proxy.logs(&request_id).await;
+        let sent = logs[0]["bytes_sent"].as_i64().unwrap();
+        assert!(sent < declared, "logged {} of {} bytes", sent, declared);
+    }
+
+    #[tokio::test]
+    async fn the_status_page_is_served_only_when_enabled() {
+        let (backend, seen) = echo_origin().await;
+        let domains = [domain("app.test", &backend), domain("www.test", &backend)];
+
+        //off by default: the path goes to the origin and unknown domains get the usual 404
+        let proxy = start_proxy(&[], &domains).await;
+        let (status, body) = proxy.get("app.test", "/_proxynet/status").await;
+        assert_eq!((status, body.as_str()), (StatusCode::OK, "/_proxynet/status"));
+        assert_eq!(proxy.get("nowhere.test", "/").await.0, StatusCode::NOT_FOUND);
+        assert_eq!(seen.lock().unwrap().len(), 1);
+
+        let config = [("status_page.enabled", "true"), ("status_page.unknown_domain", "true")];
+        let proxy = start_proxy(&config, &domains).await;
+        for host in ["app.test", "nowhere.test"] {
+            let path = if host == "app.test" { "/_proxynet/status" } else { "/" };
+            let (status, headers, body) = proxy.send(request(Method::GET, host, path)).await;
+            assert_eq!(status, StatusCode::OK, "{}", host);
+            assert_eq!(headers["content-type"], "application/json");
+            let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+            assert_eq!(body["version"], env!("CARGO_PKG_VERSION"));
+            assert!(body["uptime_secs"].is_u64());
+            assert_eq!(body["domains"], 2);
+            assert_eq!(body["healthy"], true);
+        }
+        assert_eq!(seen.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn images_and_html_pages_are_cached_for_their_own_ttls() {
+        let image = || async { ([(header::CONTENT_TYPE, "image/png")], "png") };
+        let page = || async { ([(header::CONTENT_TYPE, "text/html; charset=utf-8")], "<p>hi</p>") };
+        let app = Router::new()
+            .route("/logo.png", axum::routing::get(image))
+            .route("/", axum::routing::get(page));
+        let backend = origin(app).await;
+        let config = [
+            ("cache.inspect", "true"),
+            ("cache.default_ttl_secs", "300"),
+            ("cache.ttl_by_content_type", "image/*=86400,text/html=60"),
+        ];
+        let proxy = start_proxy(&config, &[domain("app.test", &backend)]).await;
+
+        assert_eq!(proxy.get("app.test", "/logo.png").await.0, StatusCode::OK);
+        assert_eq!(proxy.get("app.test", "/").await.0, StatusCode::OK);
+
+        let mut ttls = Vec::new();
+        for _ in 0..50 {
+            let keys = proxy.admin("/cache/keys").await;
+            ttls = keys["data"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|entry| (entry["key"].as_str().unwrap().to_string(), entry["ttl_secs"].as_i64().unwrap()))
+                .collect();
+            if ttls.len() == 2 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        ttls.sort();
+        assert_eq!(ttls.len(), 2, "{:?}", ttls);
+        let (page, image) = (&ttls[0], &ttls[1]);
+        assert!(page.0.ends_with(":/") && (59..=60).contains(&page.1), "{:?}", page);
+        assert!(image.0.ends_with(":/logo.png") && (86399..=86400).contains(&image.1), "{:?}", image);
+    }
+
+    //an origin that resets its first connection once the request has arrived, like a backend
+    //restarting mid-request, and answers "ok" on every later one
+    async fn resetting_origin() -> (String, Arc<AtomicUsize>) {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let backend = format!("http://{}", listener.local_addr().unwrap());
+        let connections = Arc::new(AtomicUsize::new(0));
+        let counter = connections.clone();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let first = counter.fetch_add(1, Ordering::SeqCst) == 0;
+                tokio::spawn(async move {
+                    let mut request = Vec::new();
+                    let mut buf = [0u8; 1024];
+                    while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+                        match stream.read(&mut buf).await {
+                            Ok(0) | Err(_) => return,
+                            Ok(n) => request.extend_from_slice(&buf[..n]),
+                        }
+                    }
+                    if first {
+                        stream.set_linger(Some(Duration::ZERO)).unwrap();
+                        return;
+                    }
+                    let _ = stream
+                        .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\nConnection: close\r\n\r\nok")
+                        .await;
+                });
+            }
+        });
+        (backend, connections)
+    }
+
+    #[tokio::test]
+    async fn an_idempotent_request_is_redispatched_after_a_reset() {
+        let config = [("redispatch_on_reset.attempts", "2"), ("cache.enabled", "false")];
+
+        let (backend, connections) = resetting_origin().await;
+        let proxy = start_proxy(&config, &[domain("app.test", &backend)]).await;
+        assert_eq!(proxy.get("app.test", "/").await, (StatusCode::OK, "ok".to_string()));
+        assert_eq!(connections.load(Ordering::SeqCst), 2);
+
+        //a POST may have had its effect before the reset, it's never sent twice
+        let (backend, connections) = resetting_origin().await;
+        let proxy = start_proxy(&config, &[domain("app.test", &backend)]).await;
+        assert_eq!(proxy.send(request(Method::POST, "app.test", "/")).await.0, StatusCode::BAD_GATEWAY);
+        assert_eq!(connections.load(Ordering::SeqCst), 1);
+
+        //and without the setting the reset reaches the client
+        let (backend, _) = resetting_origin().await;
+        let proxy = start_proxy(&[("cache.enabled", "false")], &[domain("app.test", &backend)]).await;
+        assert_eq!(proxy.get("app.test", "/").await.0, StatusCode::BAD_GATEWAY);
+    }
+
+    #[tokio::test]
+    async fn a_hung_origin_gets_a_logged_504_at_the_timeout() {
+        let backend = origin(Router::new().fallback(|| async {
+            tokio::time::sleep(Duration::from_secs(5)).await;
+            "too late"
+        }))
+        .await;
+        let proxy = start_proxy(
+            &[("upstream_timeout_ms", "200"), ("cache.enabled", "false")],
+            &[domain("slow.test", &backend)],
+        )
+        .await;
+
+        let started = Instant::now();
+        let (status, headers, _) = proxy.send(request(Method::GET, "slow.test", "/hung")).await;
+        assert_eq!(status, StatusCode::GATEWAY_TIMEOUT);
+        //the client isn't kept waiting for the origin
+        assert!(started.elapsed() < Duration::from_secs(2), "{:?}", started.elapsed());
+
+        let logs = proxy.logs(headers["x-request-id"].to_str().unwrap()).await;
+        assert_eq!((logs[0]["path"].as_str(), logs[0]["status"].as_i64()), (Some("/hung"), Some(504)));
+    }
+
+    //an origin whose accept queue is full until shortly after the first connect attempt
+    //timed out, so only a retry gets through. returns how many requests it served
+    async fn through_flaky_origin(config: &[(&str, &str)], method: Method) -> (StatusCode, usize) {
+        let socket = tokio::net::TcpSocket::new_v4().unwrap();
+        socket.bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        let addr = socket.local_addr().unwrap();
+        //a zero backlog holds one pending connection, further SYNs are dropped
+        let listener = socket.listen(0).unwrap();
+        let filler = tokio::net::TcpStream::connect(addr).await.unwrap();
+
+        let served = Arc::new(AtomicUsize::new(0));
+        let counter = served.clone();
+        let app = Router::new().fallback(move || {
+            counter.fetch_add(1, Ordering::SeqCst);
+            async { "recovered" }
+        });
+        let proxy = start_proxy(config, &[domain("flaky.test", &format!("http://{}", addr))]).await;
+        let up = tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(350)).await;
+            drop(filler);
+            axum::serve(listener, app).await.unwrap();
+        });
+        let status = proxy.send(request(method, "flaky.test", "/")).await.0;
+        up.abort();
+        (status, served.load(Ordering::SeqCst))
+    }
+    #[tokio::test]
+    async fn a_get_is_retried_past_a_failed_connect_but_a_post_is_not() {
+        let config = [
+            ("upstream_connect_timeout_ms", "200"),
+            ("retry.attempts", "2"),
+            ("retry.backoff_ms", "500"),
+            ("retry.jitter", "none"),
+            ("cache.enabled", "false"),
+        ];
+
+        assert_eq!(through_flaky_origin(&config, Method::GET).await, (StatusCode::OK, 1));
+
+        let (status, served) = through_flaky_origin(&config, Method::POST).await;
+        assert_eq!((status, served), (StatusCode::BAD_GATEWAY, 0));
+    }
+
+    //the key and accept value from the example handshake in RFC 6455
+    const WS_KEY: &str = "dGhlIHNhbXBsZSBub25jZQ==";
+    const WS_ACCEPT: &str = "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=";
+
+    //reads one masked client frame with a short payload and returns its unmasked payload
+    async fn read_ws_frame(io: &mut (impl tokio::io::AsyncRead + Unpin)) -> (u8, Vec<u8>) {
+        let mut head = [0u8; 2];
+        io.read_exact(&mut head).await.unwrap();
+        let mut mask = [0u8; 4];
+        if head[1] & 0x80 != 0 {
+            io.read_exact(&mut mask).await.unwrap();
+        }
+        let mut payload = vec![0u8; (head[1] & 0x7f) as usize];
+        io.read_exact(&mut payload).await.unwrap();
+        payload.iter_mut().enumerate().for_each(|(i, byte)| *byte ^= mask[i % 4]);
+        (head[0], payload)
+    }
+
+    #[tokio::test]
+    async fn a_websocket_frame_is_echoed_through_the_proxy() {
+        //a websocket origin sending every frame it gets back, unmasked as servers do
+        let seen = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let app = Router::new().fallback({
+            let seen = seen.clone();
+            move |mut req: Request| async move {
+                seen.lock().unwrap().push(req.uri().to_string());
+                assert_eq!(req.headers()["sec-websocket-key"], WS_KEY);
+                let upgrade = hyper::upgrade::on(&mut req);
+                tokio::spawn(async move {
+                    let mut io = hyper_util::rt::TokioIo::new(upgrade.await.unwrap());
+                    let (opcode, payload) = read_ws_frame(&mut io).await;
+                    let mut frame = vec![opcode, payload.len() as u8];
+                    frame.extend_from_slice(&payload);
+                    io.write_all(&frame).await.unwrap();
+                });
+                Response::builder()
+                    .status(StatusCode::SWITCHING_PROTOCOLS)
+                    .header(header::CONNECTION, "upgrade")
+                    .header(header::UPGRADE, "websocket")
+                    .header("sec-websocket-accept", WS_ACCEPT)
+                    .body(Body::empty())
+                    .unwrap()
+            }
+        });
+        let backend = origin(app).await;
+        let proxy = start_proxy(&[("cache.enabled", "false")], &[domain("ws.test", &backend)]).await;
+
+        let stream = tokio::net::TcpStream::connect(proxy.addr).await.unwrap();
+        let (mut sender, connection) = hyper::client::conn::http1::handshake(hyper_util::rt::TokioIo::new(stream))
+            .await
+            .unwrap();
+        tokio::spawn(connection.with_upgrades());
+
+        let mut req = request(Method::GET, "ws.test", "/chat?room=1");
+        let headers = req.headers_mut();
+        headers.insert(header::CONNECTION, HeaderValue::from_static("upgrade"));
+        headers.insert(header::UPGRADE, HeaderValue::from_static("websocket"));
+        headers.insert("sec-websocket-version", HeaderValue::from_static("13"));
+        headers.insert("sec-websocket-key", HeaderValue::from_static(WS_KEY));
+        let mut response = sender.send_request(req).await.unwrap();
+        assert_eq!(response.status(), StatusCode::SWITCHING_PROTOCOLS);
+        assert_eq!(response.headers()["sec-websocket-accept"], WS_ACCEPT);
+        assert_eq!(*seen.lock().unwrap(), ["/chat?room=1"]);
+
+        //a masked text frame, as a browser would send it
+        let mut io = hyper_util::rt::TokioIo::new(hyper::upgrade::on(&mut response).await.unwrap());
+        let mask = [0x37, 0xfa, 0x21, 0x3d];
+        let mut frame = vec![0x81, 0x80 | 5];
+        frame.extend_from_slice(&mask);
+        frame.extend(b"hello".iter().enumerate().map(|(i, byte)| byte ^ mask[i % 4]));
+        io.write_all(&frame).await.unwrap();
+
+        let echoed = tokio::time::timeout(Duration::from_secs(2), read_ws_frame(&mut io)).await.unwrap();
+        assert_eq!(echoed, (0x81, b"hello".to_vec()));
+    }
+
+    #[tokio::test]
+    async fn https_clients_are_proxied_with_their_scheme_forwarded() {
+        let backend = origin(Router::new().fallback(|headers: HeaderMap| async move {
+            headers.get("x-forwarded-proto").map(|proto| proto.to_str().unwrap().to_string()).unwrap_or_default()
+        }))
+        .await;
+        let db = database::test_db().await;
+        database::create_domain(&db, &domain("app.test", &backend)).await.unwrap();
+        let state = test_state(&db).await;
+
+        //the https listener as main wires it, with a self-signed certificate for the domain
+        let (cert, key) = tls::tests::self_signed("app.test");
+        let certs = CertStore::new(None);
+        certs.insert("app.test", &cert, &key).unwrap();
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let listener = TlsListener::new(listener, tls::server_config(certs).unwrap(), state.tls.clone()).unwrap();
+        let app = Router::new()
+            .fallback(proxy_handler)
+            .with_state(state)
+            .layer(Extension(TlsConnection))
+            .layer(middleware::map_request(proxy_protocol::restore_connect_info));
+        tokio::spawn(async move {
+            axum::serve(listener, app.into_make_service_with_connect_info::<ProxiedAddr>()).await
+        });
+
+        let tcp = tokio::net::TcpStream::connect(addr).await.unwrap();
+        let name = tokio_rustls::rustls::pki_types::ServerName::try_from("app.test").unwrap();
+        let stream = tls::tests::connector(&cert).connect(name, tcp).await.unwrap();
+        let (mut sender, connection) = hyper::client::conn::http1::handshake(hyper_util::rt::TokioIo::new(stream))
+            .await
+            .unwrap();
+        tokio::spawn(connection);
+
+        let response = sender.send_request(request(Method::GET, "app.test", "/")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(body, "https");
+    }
+
+    #[tokio::test]
+    async fn a_fresh_entry_is_served_when_no_origin_is_up() {
+        let backend = origin(Router::new().route("/page", axum::routing::get(|| async { "fresh" })).fallback(|| async {
+            StatusCode::INTERNAL_SERVER_ERROR
+        }))
+        .await;
+        let proxy = start_proxy(&[("circuit_breaker.failure_threshold", "1")], &[domain("app.test", &backend)]).await;
+
+        assert_eq!(proxy.get("app.test", "/page").await, (StatusCode::OK, "fresh".to_string()));
+        //one 5xx opens the breaker of the only origin
+        assert_eq!(proxy.get("app.test", "/boom").await.0, StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(proxy.get("app.test", "/other").await.0, StatusCode::BAD_GATEWAY);
+
+        let (status, headers, body) = proxy.send(request(Method::GET, "app.test", "/page")).await;
+        assert_eq!((status, body.as_str()), (StatusCode::OK, "fresh"));
+        assert_eq!(headers["x-cache"], "HIT");
+    }
+}
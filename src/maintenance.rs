@@ -0,0 +1,116 @@
+use axum::{
+    body::Body,
+    http::{header, StatusCode},
+    response::Response,
+};
+use std::path::{Component, Path, PathBuf};
+
+const UNAVAILABLE_BODY: &str = "Service under maintenance";
+
+//answers a request while in maintenance, from static_dir when configured.
+//anything that isn't a file there gets a plain 503. returns the body size for logging
+pub async fn respond(static_dir: Option<&Path>, request_path: &str) -> (Response, u64) {
+    let file = match static_dir.and_then(|dir| resolve(dir, request_path)) {
+        Some(mut file) => {
+            if tokio::fs::metadata(&file).await.map(|m| m.is_dir()).unwrap_or(false) {
+                file.push("index.html");
+            }
+            tokio::fs::read(&file).await.ok().map(|bytes| (file, bytes))
+        }
+        None => None,
+    };
+
+    match file {
+        Some((file, bytes)) => {
+            let len = bytes.len() as u64;
+            let response = Response::builder()
+                .status(StatusCode::OK)
+                .header(header::CONTENT_TYPE, content_type(&file))
+                .header(header::CACHE_CONTROL, "no-store")
+                .body(Body::from(bytes))
+                .unwrap();
+            (response, len)
+        }
+        None => {
+            let response = Response::builder()
+                .status(StatusCode::SERVICE_UNAVAILABLE)
+                .header(header::CACHE_CONTROL, "no-store")
+                .body(Body::from(UNAVAILABLE_BODY))
+                .unwrap();
+            (response, UNAVAILABLE_BODY.len() as u64)
+        }
+    }
+}
+
+//maps a request path into the directory, paths trying to leave it resolve to nothing
+pub fn resolve(dir: &Path, request_path: &str) -> Option<PathBuf> {
+    let mut file = dir.to_path_buf();
+    for component in Path::new(request_path.trim_start_matches('/')).components() {
+        match component {
+            Component::Normal(part) => file.push(part),
+            Component::CurDir => {}
+            _ => return None,
+        }
+    }
+    Some(file)
+}
+
+pub fn content_type(file: &Path) -> &'static str {
+    let extension = file
+        .extension()
+        .and_then(|e| e.to_str())
+        .map(|e| e.to_ascii_lowercase());
+
+    match extension.as_deref() {
+        Some("html") | Some("htm") => "text/html; charset=utf-8",
+        Some("css") => "text/css; charset=utf-8",
+        Some("js") => "text/javascript; charset=utf-8",
+        Some("json") => "application/json",
+        Some("txt") => "text/plain; charset=utf-8",
+        Some("svg") => "image/svg+xml",
+        Some("png") => "image/png",
+        Some("jpg") | Some("jpeg") => "image/jpeg",
+        Some("gif") => "image/gif",
+        Some("webp") => "image/webp",
+        Some("ico") => "image/x-icon",
+        Some("woff") => "font/woff",
+        Some("woff2") => "font/woff2",
+        _ => "application/octet-stream",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http_body_util::BodyExt;
+
+    async fn body(response: Response) -> String {
+        let bytes = response.into_body().collect().await.unwrap().to_bytes();
+        String::from_utf8(bytes.to_vec()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn static_dir_serves_its_index_and_503s_the_rest() {
+        let dir = std::env::temp_dir().join(format!("proxynet-maintenance-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("index.html"), "<h1>back soon</h1>").unwrap();
+
+        let (response, len) = respond(Some(&dir), "/").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "text/html; charset=utf-8");
+        assert_eq!(len, 18);
+        assert_eq!(body(response).await, "<h1>back soon</h1>");
+
+        for path in ["/missing.css", "/../index.html"] {
+            let (response, len) = respond(Some(&dir), path).await;
+            assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE, "{}", path);
+            assert_eq!(len, UNAVAILABLE_BODY.len() as u64);
+            assert_eq!(body(response).await, UNAVAILABLE_BODY);
+        }
+
+        let (response, _) = respond(None, "/").await;
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,99 @@
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+//spacing between dispatches to each of a domain's origins, requests arriving
+//faster wait in a short queue instead of reaching the backend as a burst
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Pacing {
+    pub interval_ms: u64,
+    //requests that may be waiting per origin, more get a 503
+    #[serde(default = "default_max_queue")]
+    pub max_queue: usize,
+    //the longest a request may wait for its turn, later turns get a 503
+    #[serde(default = "default_max_wait_ms")]
+    pub max_wait_ms: u64,
+}
+
+fn default_max_queue() -> usize {
+    100
+}
+
+fn default_max_wait_ms() -> u64 {
+    1000
+}
+
+struct Slot {
+    //the earliest the next request may be dispatched
+    next: Instant,
+    queued: usize,
+}
+
+impl Default for Slot {
+    fn default() -> Self {
+        Self {
+            next: Instant::now(),
+            queued: 0,
+        }
+    }
+}
+
+//leaky bucket per origin: each dispatch reserves the next free instant
+#[derive(Clone, Default)]
+pub struct OriginPacer {
+    slots: Arc<Mutex<HashMap<String, Slot>>>,
+}
+
+//a reserved dispatch time, counted as queued until it's dropped
+pub struct Turn {
+    pacer: OriginPacer,
+    origin: String,
+    at: Instant,
+}
+
+impl OriginPacer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    //None when the origin's queue is full or the wait would be too long
+    pub fn reserve(&self, origin: &str, pacing: &Pacing) -> Option<Turn> {
+        let now = Instant::now();
+        let mut slots = self.slots.lock().unwrap();
+        let slot = slots.entry(origin.to_string()).or_default();
+
+        let at = slot.next.max(now);
+        let wait = at - now;
+        if !wait.is_zero()
+            && (slot.queued >= pacing.max_queue || wait > Duration::from_millis(pacing.max_wait_ms))
+        {
+            return None;
+        }
+
+        slot.next = at + Duration::from_millis(pacing.interval_ms);
+        slot.queued += 1;
+        Some(Turn {
+            pacer: self.clone(),
+            origin: origin.to_string(),
+            at,
+        })
+    }
+}
+
+impl Turn {
+    pub async fn wait(self) {
+        tokio::time::sleep_until(self.at.into()).await;
+    }
+}
+
+impl Drop for Turn {
+    fn drop(&mut self) {
+        let mut slots = self.pacer.slots.lock().unwrap();
+        if let Some(slot) = slots.get_mut(&self.origin) {
+            slot.queued = slot.queued.saturating_sub(1);
+        }
+    }
+}
@@ -0,0 +1,64 @@
+use axum::{
+    body::Body,
+    http::{header, StatusCode},
+    response::Response,
+};
+use std::path::Path;
+use crate::maintenance;
+
+//variants looked for next to the requested file, best compression first
+const VARIANTS: [(&str, &str); 2] = [("br", "br"), ("gzip", "gz")];
+
+//whether the client's Accept-Encoding allows `coding`, q=0 refuses it
+fn accepts(accept_encoding: &str, coding: &str) -> bool {
+    let mut wildcard = false;
+    for part in accept_encoding.split(',') {
+        let mut params = part.split(';');
+        let name = params.next().unwrap_or("").trim();
+        let q = params
+            .filter_map(|param| param.trim().strip_prefix("q="))
+            .find_map(|q| q.trim().parse::<f32>().ok())
+            .unwrap_or(1.0);
+        if name.eq_ignore_ascii_case(coding) {
+            return q > 0.0;
+        }
+        if name == "*" {
+            wildcard = q > 0.0;
+        }
+    }
+    wildcard
+}
+
+//a pre-compressed copy of the requested file from `dir` (e.g. app.js.br for /app.js)
+//in an encoding the client accepts, None to proxy the request as usual.
+//returns the body size for logging
+pub async fn respond(dir: &Path, request_path: &str, accept_encoding: Option<&str>, is_head: bool) -> Option<(Response, u64)> {
+    let accept_encoding = accept_encoding?;
+    let file = maintenance::resolve(dir, request_path)?;
+    let name = file.file_name()?.to_str()?.to_string();
+
+    for (coding, extension) in VARIANTS {
+        if !accepts(accept_encoding, coding) {
+            continue;
+        }
+        let variant = file.with_file_name(format!("{}.{}", name, extension));
+        let bytes = match tokio::fs::read(&variant).await {
+            Ok(bytes) => bytes,
+            Err(_) => continue,
+        };
+
+        let len = bytes.len() as u64;
+        let body = if is_head { Body::empty() } else { Body::from(bytes) };
+        let response = Response::builder()
+            .status(StatusCode::OK)
+            .header(header::CONTENT_TYPE, maintenance::content_type(&file))
+            .header(header::CONTENT_ENCODING, coding)
+            .header(header::CONTENT_LENGTH, len)
+            .header(header::VARY, "Accept-Encoding")
+            .body(body)
+            .unwrap();
+        return Some((response, if is_head { 0 } else { len }));
+    }
+
+    None
+}
@@ -0,0 +1,292 @@
+use axum::{
+    extract::{connect_info::Connected, ConnectInfo, Request},
+    serve::{IncomingStream, Listener},
+};
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::Duration,
+};
+use tokio::{
+    io::AsyncReadExt,
+    net::{TcpListener, TcpStream},
+    sync::mpsc,
+};
+use tracing::warn;
+
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+//longest possible v1 line, CRLF included
+const V1_MAX_LEN: usize = 107;
+const HEADER_TIMEOUT: Duration = Duration::from_secs(5);
+
+//accepts connections that start with a PROXY protocol (v1 or v2) header and reports
+//the client address from it instead of the load balancer's. headers are read off the
+//accept loop so a slow peer can't hold up other connections
+pub struct ProxyProtocolListener {
+    local_addr: SocketAddr,
+    accepted: mpsc::Receiver<(TcpStream, SocketAddr)>,
+}
+
+impl ProxyProtocolListener {
+    pub fn new(listener: TcpListener) -> io::Result<Self> {
+        let local_addr = listener.local_addr()?;
+        let (tx, accepted) = mpsc::channel(1024);
+
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, peer) = match listener.accept().await {
+                    Ok(conn) => conn,
+                    Err(e) => {
+                        warn!("PROXY protocol accept failed: {}", e);
+                        tokio::time::sleep(Duration::from_millis(100)).await;
+                        continue;
+                    }
+                };
+
+                let tx = tx.clone();
+                tokio::spawn(async move {
+                    match tokio::time::timeout(HEADER_TIMEOUT, read_header(&mut stream)).await {
+                        //LOCAL connections (the balancer's own health checks) keep the peer address
+                        Ok(Ok(source)) => {
+                            let _ = tx.send((stream, source.unwrap_or(peer))).await;
+                        }
+                        Ok(Err(e)) => warn!("Dropping connection from {}: {}", peer, e),
+                        Err(_) => warn!("Dropping connection from {}: no PROXY header in time", peer),
+                    }
+                });
+            }
+        });
+
+        Ok(Self { local_addr, accepted })
+    }
+}
+
+impl Listener for ProxyProtocolListener {
+    type Io = TcpStream;
+    type Addr = SocketAddr;
+
+    async fn accept(&mut self) -> (Self::Io, Self::Addr) {
+        match self.accepted.recv().await {
+            Some(conn) => conn,
+            None => std::future::pending().await,
+        }
+    }
+
+    fn local_addr(&self) -> io::Result<Self::Addr> {
+        Ok(self.local_addr)
+    }
+}
+
+//the client address of a connection from one of our own listeners (PROXY protocol, TLS).
+//axum only takes a SocketAddr from a TcpListener, so it travels as this and
+//`restore_connect_info` hands it back to the handlers as ConnectInfo<SocketAddr>
+#[derive(Debug, Clone, Copy)]
+pub struct ProxiedAddr(pub SocketAddr);
+
+impl Connected<IncomingStream<'_, ProxyProtocolListener>> for ProxiedAddr {
+    fn connect_info(stream: IncomingStream<'_, ProxyProtocolListener>) -> Self {
+        ProxiedAddr(*stream.remote_addr())
+    }
+}
+
+pub async fn restore_connect_info(mut req: Request) -> Request {
+    if let Some(ConnectInfo(ProxiedAddr(addr))) = req.extensions().get::<ConnectInfo<ProxiedAddr>>().copied() {
+        req.extensions_mut().insert(ConnectInfo(addr));
+    }
+    req
+}
+
+//reads exactly the header off the stream, leaving the request bytes untouched
+async fn read_header(stream: &mut TcpStream) -> Result<Option<SocketAddr>, String> {
+    //the shortest v1 header ("PROXY UNKNOWN\r\n") is longer than the v2 signature
+    let mut header = vec![0u8; V2_SIGNATURE.len()];
+    stream.read_exact(&mut header).await.map_err(|e| e.to_string())?;
+
+    if header == V2_SIGNATURE {
+        let mut fixed = [0u8; 4];
+        stream.read_exact(&mut fixed).await.map_err(|e| e.to_string())?;
+        header.extend_from_slice(&fixed);
+        let len = u16::from_be_bytes([fixed[2], fixed[3]]) as usize;
+        let mut rest = vec![0u8; len];
+        stream.read_exact(&mut rest).await.map_err(|e| e.to_string())?;
+        header.extend_from_slice(&rest);
+        return parse_v2(&header);
+    }
+
+    if !header.starts_with(b"PROXY ") {
+        return Err("missing PROXY protocol header".to_string());
+    }
+
+    while !header.ends_with(b"\r\n") {
+        if header.len() >= V1_MAX_LEN {
+            return Err("PROXY v1 header too long".to_string());
+        }
+        let byte = stream.read_u8().await.map_err(|e| e.to_string())?;
+        header.push(byte);
+    }
+    parse_v1(&header)
+}
+
+//"PROXY TCP4 203.0.113.7 10.0.0.1 51234 443\r\n", None for UNKNOWN
+pub fn parse_v1(header: &[u8]) -> Result<Option<SocketAddr>, String> {
+    let line = std::str::from_utf8(header)
+        .map_err(|_| "PROXY v1 header is not text".to_string())?
+        .trim_end_matches("\r\n");
+    let parts: Vec<&str> = line.split(' ').collect();
+
+    match parts.as_slice() {
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        ["PROXY", "TCP4" | "TCP6", src, _dst, sport, _dport] => {
+            let ip = src
+                .parse::<IpAddr>()
+                .map_err(|_| format!("bad PROXY v1 source address '{}'", src))?;
+            let port = sport
+                .parse::<u16>()
+                .map_err(|_| format!("bad PROXY v1 source port '{}'", sport))?;
+            Ok(Some(SocketAddr::new(ip, port)))
+        }
+        _ => Err(format!("malformed PROXY v1 header '{}'", line)),
+    }
+}
+
+//signature, version/command, family, length, addresses. None for LOCAL and non-IP families
+pub fn parse_v2(header: &[u8]) -> Result<Option<SocketAddr>, String> {
+    if header.len() < 16 || header[..12] != V2_SIGNATURE {
+        return Err("malformed PROXY v2 header".to_string());
+    }
+
+    let version = header[12] >> 4;
+    let command = header[12] & 0x0f;
+    if version != 2 {
+        return Err(format!("unsupported PROXY protocol version {}", version));
+    }
+    if command == 0 {
+        return Ok(None);
+    }
+
+    let addresses = &header[16..];
+    match header[13] >> 4 {
+        //AF_INET: src, dst, src port, dst port
+        0x1 if addresses.len() >= 12 => {
+            let ip = Ipv4Addr::new(addresses[0], addresses[1], addresses[2], addresses[3]);
+            let port = u16::from_be_bytes([addresses[8], addresses[9]]);
+            Ok(Some(SocketAddr::new(IpAddr::V4(ip), port)))
+        }
+        //AF_INET6
+        0x2 if addresses.len() >= 36 => {
+            let mut octets = [0u8; 16];
+            octets.copy_from_slice(&addresses[..16]);
+            let port = u16::from_be_bytes([addresses[32], addresses[33]]);
+            Ok(Some(SocketAddr::new(IpAddr::V6(Ipv6Addr::from(octets)), port)))
+        }
+        0x1 | 0x2 => Err("truncated PROXY v2 address block".to_string()),
+        _ => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncWriteExt;
+
+    fn v2(command: u8, family: u8, addresses: &[u8]) -> Vec<u8> {
+        let mut header = V2_SIGNATURE.to_vec();
+        header.push(0x20 | command);
+        header.push(family);
+        header.extend_from_slice(&(addresses.len() as u16).to_be_bytes());
+        header.extend_from_slice(addresses);
+        header
+    }
+
+    //sends `bytes` over a real connection and reads the header off the accepted end
+    async fn read_sent(bytes: &[u8]) -> (Result<Option<SocketAddr>, String>, Vec<u8>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (mut server, _) = listener.accept().await.unwrap();
+        client.write_all(bytes).await.unwrap();
+        client.shutdown().await.unwrap();
+
+        let result = read_header(&mut server).await;
+        let mut rest = Vec::new();
+        if result.is_ok() {
+            server.read_to_end(&mut rest).await.unwrap();
+        }
+        (result, rest)
+    }
+
+    #[test]
+    fn parses_v1() {
+        assert_eq!(
+            parse_v1(b"PROXY TCP4 203.0.113.7 10.0.0.1 51234 443\r\n"),
+            Ok(Some("203.0.113.7:51234".parse().unwrap()))
+        );
+        assert_eq!(
+            parse_v1(b"PROXY TCP6 2001:db8::1 2001:db8::2 51234 443\r\n"),
+            Ok(Some("[2001:db8::1]:51234".parse().unwrap()))
+        );
+        assert_eq!(parse_v1(b"PROXY UNKNOWN\r\n"), Ok(None));
+    }
+
+    #[test]
+    fn rejects_malformed_v1() {
+        assert!(parse_v1(b"PROXY TCP4 203.0.113.7 10.0.0.1 51234\r\n").is_err());
+        assert!(parse_v1(b"PROXY TCP4 203.0.113.999 10.0.0.1 51234 443\r\n").is_err());
+        assert!(parse_v1(b"PROXY TCP4 203.0.113.7 10.0.0.1 70000 443\r\n").is_err());
+        assert!(parse_v1(b"PROXY UDP4 203.0.113.7 10.0.0.1 51234 443\r\n").is_err());
+        assert!(parse_v1(b"PROXY TCP4 \xff\xfe 10.0.0.1 51234 443\r\n").is_err());
+    }
+
+    #[test]
+    fn parses_v2() {
+        let ipv4 = [203, 0, 113, 7, 10, 0, 0, 1, 0xc8, 0x22, 0x01, 0xbb];
+        assert_eq!(parse_v2(&v2(1, 0x11, &ipv4)), Ok(Some("203.0.113.7:51234".parse().unwrap())));
+
+        let mut ipv6 = Vec::new();
+        ipv6.extend_from_slice(&"2001:db8::1".parse::<Ipv6Addr>().unwrap().octets());
+        ipv6.extend_from_slice(&"2001:db8::2".parse::<Ipv6Addr>().unwrap().octets());
+        ipv6.extend_from_slice(&[0xc8, 0x22, 0x01, 0xbb]);
+        assert_eq!(parse_v2(&v2(1, 0x21, &ipv6)), Ok(Some("[2001:db8::1]:51234".parse().unwrap())));
+
+        //LOCAL, and a unix socket family, keep the peer address
+        assert_eq!(parse_v2(&v2(0, 0x00, &[])), Ok(None));
+        assert_eq!(parse_v2(&v2(1, 0x31, &[0; 216])), Ok(None));
+    }
+
+    #[test]
+    fn rejects_malformed_and_truncated_v2() {
+        assert!(parse_v2(&v2(1, 0x11, &[203, 0, 113, 7, 10, 0, 0, 1])).is_err());
+        assert!(parse_v2(&v2(1, 0x21, &[0; 12])).is_err());
+        assert!(parse_v2(&V2_SIGNATURE).is_err());
+
+        let mut wrong_version = v2(1, 0x11, &[0; 12]);
+        wrong_version[12] = 0x11;
+        assert!(parse_v2(&wrong_version).is_err());
+
+        let mut wrong_signature = v2(1, 0x11, &[0; 12]);
+        wrong_signature[0] = b'X';
+        assert!(parse_v2(&wrong_signature).is_err());
+    }
+
+    #[tokio::test]
+    async fn reads_only_the_header() {
+        let (result, rest) = read_sent(b"PROXY TCP4 203.0.113.7 10.0.0.1 51234 443\r\nGET / HTTP/1.1\r\n\r\n").await;
+        assert_eq!(result, Ok(Some("203.0.113.7:51234".parse().unwrap())));
+        assert_eq!(rest, b"GET / HTTP/1.1\r\n\r\n");
+
+        let mut sent = v2(1, 0x11, &[203, 0, 113, 7, 10, 0, 0, 1, 0xc8, 0x22, 0x01, 0xbb]);
+        sent.extend_from_slice(b"GET / HTTP/1.1\r\n\r\n");
+        let (result, rest) = read_sent(&sent).await;
+        assert_eq!(result, Ok(Some("203.0.113.7:51234".parse().unwrap())));
+        assert_eq!(rest, b"GET / HTTP/1.1\r\n\r\n");
+    }
+
+    #[tokio::test]
+    async fn rejects_missing_overlong_and_cut_off_headers() {
+        assert!(read_sent(b"GET / HTTP/1.1\r\nHost: a\r\n\r\n").await.0.is_err());
+        assert!(read_sent(format!("PROXY TCP4 {}\r\n", "1".repeat(120)).as_bytes()).await.0.is_err());
+        assert!(read_sent(b"PROXY TCP4 203.0.113.7 10.0").await.0.is_err());
+
+        let full = v2(1, 0x11, &[203, 0, 113, 7, 10, 0, 0, 1, 0xc8, 0x22, 0x01, 0xbb]);
+        assert!(read_sent(&full[..20]).await.0.is_err());
+    }
+}
@@ -1,11 +1,119 @@
+use axum::http::{header, HeaderMap, HeaderValue};
 use std::{collections::HashMap, sync::Arc, time::{Duration, Instant}};
+use crate::routing::PathRateLimit;
 use tokio::sync::Mutex;
 
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Algorithm {
+    //at most `limit` requests per window, windows reset on a schedule so up to twice
+    //that can get through around a reset
+    FixedWindow,
+    //at most about `limit` requests in any window-long span: the previous window's count
+    //is weighed by how much of it still overlaps the span ending now
+    SlidingWindow,
+    //refills `limit` tokens per window, holding at most `burst`
+    TokenBucket { burst: u32 },
+}
+
+impl Algorithm {
+    pub fn from_config(name: &str, burst: Option<u32>, limit: u32) -> Self {
+        match name {
+            "token_bucket" => Algorithm::TokenBucket {
+                burst: burst.unwrap_or(limit).max(1),
+            },
+            "fixed_window" => Algorithm::FixedWindow,
+            _ => Algorithm::SlidingWindow,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Algorithm::FixedWindow => "fixed_window",
+            Algorithm::SlidingWindow => "sliding_window",
+            Algorithm::TokenBucket { .. } => "token_bucket",
+        }
+    }
+}
+
+//escalating cooldown for clients that keep hitting the limit: each consecutive
+//violation doubles the block, up to `max`, until the client stays quiet for `reset_after`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Penalty {
+    pub base: Duration,
+    pub max: Duration,
+    pub reset_after: Duration,
+}
+
+impl Penalty {
+    fn cooldown(&self, violations: u32) -> Duration {
+        let factor = 2u32.saturating_pow(violations.saturating_sub(1));
+        self.base.saturating_mul(factor).min(self.max)
+    }
+}
+
+//the global per-client limit from the rate_limit_* keys, compared on reload so an
+//unchanged limit keeps its clients' counts
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RateLimitConfig {
+    pub limit: u32,
+    pub algorithm: Algorithm,
+    pub penalty: Option<Penalty>,
+    pub max_entries: Option<usize>,
+}
+
+//the outcome of one request against a limiter, enough to tell the client where it stands
+#[derive(Debug, Clone, Copy)]
+pub struct RateLimitDecision {
+    pub allowed: bool,
+    pub remaining: u32,
+    pub limit: u32,
+    //how long until another request would be allowed, zero while some remain
+    pub reset_after: Duration,
+}
+
+impl RateLimitDecision {
+    //X-RateLimit-Limit/-Remaining always, Retry-After (whole seconds, rounded up) when refused
+    pub fn add_headers(&self, headers: &mut HeaderMap) {
+        headers.insert("x-ratelimit-limit", HeaderValue::from(self.limit));
+        headers.insert("x-ratelimit-remaining", HeaderValue::from(self.remaining));
+        if !self.allowed {
+            let secs = self.reset_after.as_secs_f64().ceil() as u64;
+            headers.insert(header::RETRY_AFTER, HeaderValue::from(secs.max(1)));
+        }
+    }
+}
+
+struct Entry {
+    count: u32,
+    //requests in the window before `start`, for the sliding window
+    previous: u32,
+    tokens: f64,
+    start: Instant,
+    violations: u32,
+    last_violation: Option<Instant>,
+    blocked_until: Option<Instant>,
+    last_seen: Instant,
+}
+
+impl Entry {
+    fn blocked(&self, now: Instant) -> bool {
+        self.blocked_until.map(|until| until > now).unwrap_or(false)
+    }
+}
+
+//rough memory held by one tracked key
+fn entry_bytes(key: &str) -> usize {
+    key.len() + std::mem::size_of::<String>() + std::mem::size_of::<Entry>()
+}
+
 #[derive(Clone)]
 pub struct RateLimiter {
-    inner: Arc<Mutex<HashMap<String, (u32, Instant)>>>,
+    inner: Arc<Mutex<HashMap<String, Entry>>>,
     window: Duration,
     limit: u32,
+    algorithm: Algorithm,
+    penalty: Option<Penalty>,
+    max_entries: Option<usize>,
 }
 
 impl RateLimiter {
@@ -14,46 +122,485 @@ impl RateLimiter {
             inner: Arc::new(Mutex::new(HashMap::new())),
             window: Duration::from_secs(window_seconds),
             limit,
+            algorithm: Algorithm::SlidingWindow,
+            penalty: None,
+            max_entries: None,
+        }
+    }
+
+    //a per-minute limiter for the global limit, cleaned up in the background
+    pub fn from_config(config: &RateLimitConfig) -> Self {
+        let mut limiter = Self::new(config.limit, 60).with_algorithm(config.algorithm);
+        if let Some(penalty) = config.penalty {
+            limiter = limiter.with_penalty(penalty);
+        }
+        if let Some(max) = config.max_entries {
+            limiter = limiter.with_max_entries(max);
         }
+        limiter.spawn_cleanup();
+        limiter
+    }
+
+    pub fn with_algorithm(mut self, algorithm: Algorithm) -> Self {
+        self.algorithm = algorithm;
+        self
     }
 
-    pub async fn allow(&self, key: &str) -> bool {
+    pub fn with_penalty(mut self, penalty: Penalty) -> Self {
+        self.penalty = Some(penalty);
+        self
+    }
+
+    //bounds the number of tracked keys so a spray of unique IPs can't grow the map between cleanups
+    pub fn with_max_entries(mut self, max_entries: usize) -> Self {
+        self.max_entries = Some(max_entries.max(1));
+        self
+    }
+
+    pub async fn check(&self, key: &str) -> RateLimitDecision {
         let mut map = self.inner.lock().await;
         let now = Instant::now();
 
-        match map.get_mut(key) {
-            Some((count, start)) => {
-                if now.duration_since(*start) > self.window {
-                    *count = 1;
-                    *start = now;
-                    true
+        if let Some(max) = self.max_entries
+            && map.len() >= max
+            && !map.contains_key(key)
+        {
+            evict_least_recent(&mut map, now);
+        }
+
+        //new clients start with a full bucket
+        let entry = map.entry(key.to_string()).or_insert(Entry {
+            count: 0,
+            previous: 0,
+            tokens: match self.algorithm {
+                Algorithm::TokenBucket { burst } => burst as f64,
+                Algorithm::FixedWindow | Algorithm::SlidingWindow => 0.0,
+            },
+            start: now,
+            violations: 0,
+            last_violation: None,
+            blocked_until: None,
+            last_seen: now,
+        });
+        entry.last_seen = now;
+
+        if let Some(penalty) = &self.penalty {
+            //clients serving a cooldown are refused without touching their counters
+            if entry.blocked_until.map(|until| now < until).unwrap_or(false) {
+                return self.decision(false, entry, now);
+            }
+
+            //a quiet period forgives earlier violations
+            if entry
+                .last_violation
+                .map(|last| now.duration_since(last) > penalty.reset_after)
+                .unwrap_or(false)
+            {
+                entry.violations = 0;
+                entry.last_violation = None;
+            }
+        }
+
+        let allowed = match self.algorithm {
+            Algorithm::FixedWindow => self.fixed_window(entry, now),
+            Algorithm::SlidingWindow => self.sliding_window(entry, now),
+            Algorithm::TokenBucket { burst } => self.token_bucket(entry, now, burst),
+        };
+
+        if !allowed
+            && let Some(penalty) = &self.penalty
+        {
+            entry.violations += 1;
+            entry.last_violation = Some(now);
+            entry.blocked_until = Some(now + penalty.cooldown(entry.violations));
+        }
+
+        self.decision(allowed, entry, now)
+    }
+
+    fn decision(&self, allowed: bool, entry: &Entry, now: Instant) -> RateLimitDecision {
+        let remaining = if entry.blocked(now) {
+            0
+        } else {
+            match self.algorithm {
+                Algorithm::FixedWindow => self.limit.saturating_sub(entry.count),
+                Algorithm::SlidingWindow => (self.limit as f64 - self.estimate(entry, now)).max(0.0).ceil() as u32,
+                Algorithm::TokenBucket { .. } => entry.tokens.max(0.0) as u32,
+            }
+        };
+        RateLimitDecision {
+            allowed,
+            remaining,
+            limit: self.limit,
+            reset_after: if remaining > 0 { Duration::ZERO } else { self.wait(entry, now) },
+        }
+    }
+
+    //how long until the entry's client may send again: its cooldown, the rest of its
+    //window or the time to its next token
+    fn wait(&self, entry: &Entry, now: Instant) -> Duration {
+        if let Some(until) = entry.blocked_until.filter(|until| *until > now) {
+            return until - now;
+        }
+        match self.algorithm {
+            Algorithm::FixedWindow => self.window.saturating_sub(now.duration_since(entry.start)),
+            Algorithm::SlidingWindow => {
+                let window = self.window.as_secs_f64().max(f64::EPSILON);
+                let into = now.duration_since(entry.start).as_secs_f64();
+                //the previous window has to fade until the estimate drops below the limit
+                let free = self.limit.saturating_sub(entry.count) as f64;
+                let until = if free > 0.0 && entry.previous > 0 {
+                    window * (1.0 - free / entry.previous as f64)
                 } else {
-                    if *count < self.limit {
-                        *count += 1;
-                        true
-                    } else {
-                        false
-                    }
-                }
+                    //a full window becomes the previous one and fades the same way
+                    window + window * (1.0 - self.limit as f64 / entry.count.max(1) as f64).max(0.0)
+                };
+                Duration::from_secs_f64((until - into).max(0.0))
+            }
+            Algorithm::TokenBucket { .. } => {
+                let rate = self.limit as f64 / self.window.as_secs_f64().max(f64::EPSILON);
+                let missing = (1.0 - entry.tokens).max(0.0);
+                Duration::from_secs_f64(missing / rate.max(f64::EPSILON)).min(self.window)
             }
-            None => {
-                map.insert(key.to_string(), (1, now));
-                true
+        }
+    }
+
+    //remaining cooldown for a penalized key, None when it isn't blocked
+    pub async fn blocked_for(&self, key: &str) -> Option<Duration> {
+        let map = self.inner.lock().await;
+        let now = Instant::now();
+        map.get(key)
+            .and_then(|entry| entry.blocked_until)
+            .filter(|until| *until > now)
+            .map(|until| until - now)
+    }
+
+    fn fixed_window(&self, entry: &mut Entry, now: Instant) -> bool {
+        if now.duration_since(entry.start) > self.window {
+            entry.count = 1;
+            entry.start = now;
+            true
+        } else if entry.count < self.limit {
+            entry.count += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    //`start` is the beginning of the current window, aligned to whole windows
+    fn sliding_window(&self, entry: &mut Entry, now: Instant) -> bool {
+        let window = self.window.as_secs_f64().max(f64::EPSILON);
+        let elapsed = now.duration_since(entry.start).as_secs_f64();
+        if elapsed >= window {
+            let passed = (elapsed / window) as u32;
+            //only the window just finished still overlaps the span
+            entry.previous = if passed == 1 { entry.count } else { 0 };
+            entry.count = 0;
+            entry.start += self.window * passed;
+        }
+
+        if self.estimate(entry, now) < self.limit as f64 {
+            entry.count += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    //requests in the window-long span ending now, counting the part of the previous
+    //window still inside it
+    fn estimate(&self, entry: &Entry, now: Instant) -> f64 {
+        let window = self.window.as_secs_f64().max(f64::EPSILON);
+        let overlap = (1.0 - now.duration_since(entry.start).as_secs_f64() / window).max(0.0);
+        entry.previous as f64 * overlap + entry.count as f64
+    }
+
+    //`start` tracks the last refill
+    fn token_bucket(&self, entry: &mut Entry, now: Instant, burst: u32) -> bool {
+        let rate = self.limit as f64 / self.window.as_secs_f64().max(f64::EPSILON);
+        let elapsed = now.duration_since(entry.start).as_secs_f64();
+        entry.tokens = (entry.tokens + elapsed * rate).min(burst as f64);
+        entry.start = now;
+
+        if entry.tokens >= 1.0 {
+            entry.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+
+    pub async fn estimated_bytes(&self) -> usize {
+        let map = self.inner.lock().await;
+        map.keys().map(|key| entry_bytes(key)).sum()
+    }
+
+    //forgets the clients seen longest ago until about `bytes` are freed, sparing clients
+    //serving a cooldown unless nothing else is left. returns the estimated bytes freed
+    pub async fn shrink(&self, bytes: usize) -> usize {
+        let mut map = self.inner.lock().await;
+        let now = Instant::now();
+
+        let mut keys: Vec<(bool, Instant, String)> = map
+            .iter()
+            .map(|(key, entry)| (entry.blocked(now), entry.last_seen, key.clone()))
+            .collect();
+        keys.sort();
+
+        let mut freed = 0;
+        for (_, _, key) in keys {
+            if freed >= bytes {
+                break;
             }
+            map.remove(&key);
+            freed += entry_bytes(&key);
         }
+        freed
     }
 
+    //runs until this task holds the last handle, e.g. once a reload replaced the limiter
     pub fn spawn_cleanup(&self) {
-        let inner = self.inner.clone();
-        let window = self.window;
+        let limiter = self.clone();
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(limiter.window).await;
+                if Arc::strong_count(&limiter.inner) == 1 {
+                    break;
+                }
+                limiter.cleanup().await;
+            }
+        });
+    }
+
+    //forgets clients whose window has passed
+    async fn cleanup(&self) {
+        let penalty = self.penalty;
+
+        //an idle bucket is only safe to drop once it would have refilled completely
+        let max_age = match self.algorithm {
+            Algorithm::TokenBucket { burst } if self.limit > 0 => {
+                let refill = self.window.mul_f64(burst as f64 / self.limit as f64);
+                refill.max(self.window + self.window)
+            }
+            _ => self.window + self.window,
+        };
+
+        let mut map = self.inner.lock().await;
+        let now = Instant::now();
+        map.retain(|_, entry| {
+            //keep penalized clients until their violations would be forgiven
+            let penalized = penalty
+                .zip(entry.last_violation)
+                .map(|(penalty, last)| now.duration_since(last) <= penalty.reset_after)
+                .unwrap_or(false);
+            penalized || entry.blocked(now) || now.duration_since(entry.start) <= max_age
+        });
+    }
+
+    async fn is_empty(&self) -> bool {
+        self.inner.lock().await.is_empty()
+    }
+}
+
+//drops the key seen longest ago, sparing clients serving a cooldown unless nothing else is left
+fn evict_least_recent(map: &mut HashMap<String, Entry>, now: Instant) {
+    let oldest = map
+        .iter()
+        .filter(|(_, entry)| !entry.blocked(now))
+        .min_by_key(|(_, entry)| entry.last_seen)
+        .or_else(|| map.iter().min_by_key(|(_, entry)| entry.last_seen))
+        .map(|(key, _)| key.clone());
+
+    if let Some(key) = oldest {
+        map.remove(&key);
+    }
+}
+
+//one sliding-window limiter per domain path rule, created the first time the rule is hit.
+//the rule's limit is part of the key so editing it starts a fresh limiter, and a limiter
+//left with no clients is dropped so removed or edited rules don't pile up
+#[derive(Clone, Default)]
+pub struct PathLimiters {
+    inner: Arc<std::sync::Mutex<HashMap<String, RateLimiter>>>,
+}
+
+impl PathLimiters {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn limiter(&self, domain: &str, rule: &PathRateLimit) -> RateLimiter {
+        let key = format!("{}|{}|{}/{}s", domain, rule.prefix, rule.limit, rule.window_secs);
+        let mut limiters = self.inner.lock().unwrap();
+        limiters
+            .entry(key)
+            .or_insert_with(|| RateLimiter::new(rule.limit, rule.window_secs.max(1)))
+            .clone()
+    }
+
+    pub fn all(&self) -> Vec<RateLimiter> {
+        self.inner.lock().unwrap().values().cloned().collect()
+    }
+
+    //one task cleans every limiter instead of a task per limiter living forever
+    pub fn spawn_cleanup(&self, interval: Duration) {
+        let limiters = self.clone();
         tokio::spawn(async move {
             loop {
-                tokio::time::sleep(window).await;
-                let mut map = inner.lock().await;
-                let now = Instant::now();
-                let max_age = window + window;
-                map.retain(|_, (_, start)| now.duration_since(*start) <= max_age);
+                tokio::time::sleep(interval).await;
+                limiters.cleanup().await;
             }
         });
     }
-}
\ No newline at end of file
+
+    async fn cleanup(&self) {
+        let limiters = self.inner.lock().unwrap().clone();
+        let mut idle = Vec::new();
+        for (key, limiter) in limiters {
+            limiter.cleanup().await;
+            if limiter.is_empty().await {
+                idle.push(key);
+            }
+        }
+
+        let mut limiters = self.inner.lock().unwrap();
+        for key in idle {
+            limiters.remove(&key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    //moves every timestamp of `key` back by `by`, as if that much time had passed
+    async fn rewind(limiter: &RateLimiter, key: &str, by: Duration) {
+        let mut map = limiter.inner.lock().await;
+        let entry = map.get_mut(key).unwrap();
+        entry.start -= by;
+        entry.last_violation = entry.last_violation.map(|at| at - by);
+        entry.blocked_until = entry.blocked_until.map(|until| until - by);
+    }
+
+    #[tokio::test]
+    async fn token_bucket_allows_a_burst_then_refills_at_the_rate() {
+        //10 tokens a second, at most 3 held
+        let limiter = RateLimiter::new(10, 1).with_algorithm(Algorithm::TokenBucket { burst: 3 });
+        for _ in 0..3 {
+            assert!(limiter.check("client").await.allowed);
+        }
+        assert!(!limiter.check("client").await.allowed);
+
+        rewind(&limiter, "client", Duration::from_millis(250)).await;
+        assert!(limiter.check("client").await.allowed);
+        assert!(limiter.check("client").await.allowed);
+        assert!(!limiter.check("client").await.allowed);
+
+        //a long pause refills no more than the burst
+        rewind(&limiter, "client", Duration::from_secs(10)).await;
+        for _ in 0..3 {
+            assert!(limiter.check("client").await.allowed);
+        }
+        assert!(!limiter.check("client").await.allowed);
+    }
+
+    //sends the limit at the end of one window and again just after the next one starts,
+    //returning how many of the second burst got through
+    async fn burst_across_a_boundary(limiter: &RateLimiter) -> usize {
+        for _ in 0..10 {
+            assert!(limiter.check("client").await.allowed);
+        }
+        rewind(limiter, "client", Duration::from_secs(59)).await;
+        assert!(!limiter.check("client").await.allowed);
+
+        rewind(limiter, "client", Duration::from_secs(2)).await;
+        let mut allowed = 0;
+        for _ in 0..10 {
+            allowed += limiter.check("client").await.allowed as usize;
+        }
+        allowed
+    }
+
+    #[tokio::test]
+    async fn sliding_window_rejects_a_burst_across_the_boundary() {
+        //the previous window still weighs almost fully a second into the next one
+        assert_eq!(burst_across_a_boundary(&RateLimiter::new(10, 60)).await, 1);
+
+        //a fixed window lets twice the limit through in those two seconds
+        let fixed = RateLimiter::new(10, 60).with_algorithm(Algorithm::FixedWindow);
+        assert_eq!(burst_across_a_boundary(&fixed).await, 10);
+    }
+
+    #[test]
+    fn penalty_doubles_up_to_max() {
+        let penalty = Penalty {
+            base: Duration::from_secs(1),
+            max: Duration::from_secs(4),
+            reset_after: Duration::from_secs(30),
+        };
+        let cooldowns: Vec<u64> = (1..=5).map(|violations| penalty.cooldown(violations).as_secs()).collect();
+        assert_eq!(cooldowns, vec![1, 2, 4, 4, 4]);
+    }
+
+    #[tokio::test]
+    async fn penalty_escalates_and_decays() {
+        let limiter = RateLimiter::new(1, 60).with_penalty(Penalty {
+            base: Duration::from_secs(1),
+            max: Duration::from_secs(4),
+            reset_after: Duration::from_secs(30),
+        });
+        let blocked_secs = |blocked: Option<Duration>| blocked.unwrap().as_secs_f64().ceil() as u64;
+        assert!(limiter.check("client").await.allowed);
+
+        //each violation after the previous cooldown ends doubles the next one
+        for expected in [1, 2, 4, 4] {
+            assert!(!limiter.check("client").await.allowed);
+            assert_eq!(blocked_secs(limiter.blocked_for("client").await), expected);
+            //refused without counting while the cooldown lasts
+            assert!(!limiter.check("client").await.allowed);
+            assert_eq!(blocked_secs(limiter.blocked_for("client").await), expected);
+            rewind(&limiter, "client", Duration::from_secs(expected)).await;
+        }
+
+        //quiet for longer than reset_after, the next violation starts over
+        rewind(&limiter, "client", Duration::from_secs(31)).await;
+        assert!(!limiter.check("client").await.allowed);
+        assert_eq!(blocked_secs(limiter.blocked_for("client").await), 1);
+    }
+
+    #[tokio::test]
+    async fn max_entries_bounds_the_map() {
+        let limiter = RateLimiter::new(10, 60).with_max_entries(3);
+        for i in 0..100 {
+            assert!(limiter.check(&format!("10.0.0.{}", i)).await.allowed);
+        }
+        let map = limiter.inner.lock().await;
+        assert_eq!(map.len(), 3);
+        assert!(map.contains_key("10.0.0.99"));
+    }
+
+    #[tokio::test]
+    async fn login_throttles_before_other_paths() {
+        let limiters = PathLimiters::new();
+        let login = PathRateLimit { prefix: "/login".to_string(), limit: 2, window_secs: 60 };
+        let rest = PathRateLimit { prefix: "/".to_string(), limit: 10, window_secs: 60 };
+
+        for _ in 0..2 {
+            assert!(limiters.limiter("app.local", &login).check("1.2.3.4").await.allowed);
+            assert!(limiters.limiter("app.local", &rest).check("1.2.3.4").await.allowed);
+        }
+        assert!(!limiters.limiter("app.local", &login).check("1.2.3.4").await.allowed);
+        assert!(limiters.limiter("app.local", &rest).check("1.2.3.4").await.allowed);
+    }
+
+    #[tokio::test]
+    async fn idle_path_limiters_are_dropped() {
+        let limiters = PathLimiters::new();
+        let rule = PathRateLimit { prefix: "/login".to_string(), limit: 2, window_secs: 60 };
+        limiters.limiter("app.local", &rule);
+        limiters.cleanup().await;
+        assert!(limiters.all().is_empty());
+    }
+}
@@ -0,0 +1,54 @@
+use axum::http::{header, HeaderMap, HeaderValue, Uri};
+
+//headers that can send a client to an absolute URL
+const REWRITTEN_HEADERS: [header::HeaderName; 3] = [header::LOCATION, header::CONTENT_LOCATION, header::REFRESH];
+
+//points absolute URLs on the origin back at the proxy so redirects don't bypass it,
+//URLs for any other host are left alone
+pub fn rewrite_headers(headers: &mut HeaderMap, origin: &str, public_scheme: &str, public_host: &str) {
+    let origin = match origin.parse::<Uri>() {
+        Ok(uri) => uri,
+        Err(_) => return,
+    };
+    let origin_authority = match origin.authority() {
+        Some(authority) => authority.as_str().to_ascii_lowercase(),
+        None => return,
+    };
+    let public_base = format!("{}://{}", public_scheme, public_host);
+
+    for name in REWRITTEN_HEADERS {
+        let value = match headers.get(&name).and_then(|v| v.to_str().ok()) {
+            Some(value) => value,
+            None => continue,
+        };
+
+        //Refresh looks like "5; url=http://origin/next", the URL follows the delay
+        let rewritten = if name == header::REFRESH {
+            match value.to_ascii_lowercase().find("url=") {
+                Some(at) => {
+                    let (delay, url) = value.split_at(at + 4);
+                    rewrite_url(url.trim_matches(|c| c == '\'' || c == '"'), &origin_authority, &public_base)
+                        .map(|url| format!("{}{}", delay, url))
+                }
+                None => None,
+            }
+        } else {
+            rewrite_url(value, &origin_authority, &public_base)
+        };
+
+        if let Some(value) = rewritten.and_then(|v| HeaderValue::from_str(&v).ok()) {
+            headers.insert(name, value);
+        }
+    }
+}
+
+fn rewrite_url(url: &str, origin_authority: &str, public_base: &str) -> Option<String> {
+    let uri = url.parse::<Uri>().ok()?;
+    uri.scheme_str()?;
+    if uri.authority()?.as_str().to_ascii_lowercase() != origin_authority {
+        return None;
+    }
+
+    let path = uri.path_and_query().map(|pq| pq.as_str()).unwrap_or("/");
+    Some(format!("{}{}", public_base, path))
+}
@@ -0,0 +1,277 @@
+use sqlx::SqlitePool;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tracing::{info, warn};
+use crate::config_file;
+use crate::database;
+use crate::rate_limiter::RateLimiter;
+use crate::routing::{self, RouteTable};
+use crate::settings::{ActiveConfig, ActiveConfigHandle, ProxySettings};
+use crate::validation;
+
+//config keys only read at startup, to bind listeners and build the upstream client,
+//the cache and background tasks. a key ending in '.' covers the whole group.
+//every other key is applied by a reload
+pub const RESTART_KEYS: &[&str] = &[
+    "host",
+    "port",
+    "config_file",
+    "api_port",
+    "admin.",
+    "lb_strategy",
+    "lb_hash_key",
+    "proxy_protocol",
+    "tls.",
+    "acme.",
+    "buffers.",
+    "upstream_connect_timeout_ms",
+    "max_upstream_connections",
+    "dns_cache.",
+    "health_check.",
+    "circuit_breaker.",
+    "slow_start.",
+    "cache.enabled",
+    "cache.capacity_bytes",
+    "cache.max_entries",
+    "cache.sweep_interval_secs",
+    "cache.stale_if_error_secs",
+    "cache.dedupe_bodies",
+    "cache.inspect",
+    "max_aux_memory_bytes",
+    "ip_filter.default",
+    "ip_filter.allow",
+    "connection_affinity.",
+    "log_queue.",
+    "tracing.",
+    "snapshot.",
+    "shutdown.",
+    "startup_banner",
+    "startup_probe",
+];
+
+pub fn needs_restart(key: &str) -> bool {
+    RESTART_KEYS.iter().any(|restart| match restart.strip_suffix('.') {
+        Some(group) => key.strip_prefix(group).is_some_and(|rest| rest.starts_with('.')),
+        None => key == *restart,
+    })
+}
+
+//names whose routes appeared, went away or differ after a reload
+#[derive(Debug, Default)]
+pub struct RouteDiff {
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+    pub changed: Vec<String>,
+}
+
+//rebuilds the route table from the domains table and the active config from the config
+//table. everything is loaded and checked before any lock is taken, so a failed reload
+//leaves both alone, and both are swapped under the routes lock so a request never sees
+//new routes with the old config. a global rate limit that didn't change keeps its counts
+pub async fn reload(db: &SqlitePool, routes: &RouteTable, active: &ActiveConfigHandle) -> Result<RouteDiff, String> {
+    let domains = database::load_domains(db).await.map_err(|e| e.to_string())?;
+    let config = database::get_all_config(db).await.map_err(|e| e.to_string())?;
+    let problems: Vec<String> = domains
+        .iter()
+        .flat_map(validation::validate_domain)
+        .chain(config.iter().filter_map(|(key, value)| validation::validate_config_value(key, value)))
+        .collect();
+    if !problems.is_empty() {
+        return Err(problems.join("; "));
+    }
+    let fresh = routing::table(&domains);
+    let settings = ProxySettings::load(db).await;
+
+    let current = active.read().unwrap().clone();
+    let rate_limiter = if settings.rate_limit == current.settings.rate_limit {
+        current.rate_limiter
+    } else {
+        info!("RELOAD: global rate limit is now {:?}", settings.rate_limit);
+        settings.rate_limit.as_ref().map(RateLimiter::from_config)
+    };
+
+    let mut routes = routes.write().await;
+    let mut diff = RouteDiff::default();
+    for (name, route) in &fresh {
+        match routes.get(name) {
+            None => diff.added.push(name.clone()),
+            Some(old) if old != route => diff.changed.push(name.clone()),
+            Some(_) => {}
+        }
+    }
+    diff.removed = routes.keys().filter(|name| !fresh.contains_key(*name)).cloned().collect();
+    *routes = fresh;
+    *active.write().unwrap() = ActiveConfig {
+        settings: Arc::new(settings),
+        rate_limiter,
+    };
+    drop(routes);
+
+    diff.added.sort();
+    diff.removed.sort();
+    diff.changed.sort();
+    Ok(diff)
+}
+
+//what SIGHUP and the config_file watcher run. the config_file, when set, is written to the
+//database first, and a file that doesn't apply stops the reload before anything changes
+pub async fn reload_from(
+    db: &SqlitePool,
+    config_file: Option<&Path>,
+    routes: &RouteTable,
+    active: &ActiveConfigHandle,
+) -> Result<RouteDiff, String> {
+    if let Some(path) = config_file {
+        config_file::apply(db, path).await.map_err(|e| format!("{}: {}", path.display(), e))?;
+    }
+    reload(db, routes, active).await
+}
+
+pub fn log_outcome(result: Result<RouteDiff, String>) {
+    match result {
+        Ok(diff) => info!(
+            "RELOAD: added [{}] removed [{}] changed [{}]",
+            diff.added.join(", "),
+            diff.removed.join(", "),
+            diff.changed.join(", ")
+        ),
+        Err(e) => warn!("RELOAD FAILED, keeping the running routes and config: {}", e),
+    }
+}
+
+//reloads the domains and config on SIGHUP, applying the config_file first when one is set.
+//keys in RESTART_KEYS changed since startup are only reported
+#[cfg(unix)]
+pub fn spawn_on_sighup(
+    db: SqlitePool,
+    config_file: Option<PathBuf>,
+    routes: RouteTable,
+    active: ActiveConfigHandle,
+    startup_config: Vec<(String, String)>,
+) {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    tokio::spawn(async move {
+        let mut hangup = match signal(SignalKind::hangup()) {
+            Ok(hangup) => hangup,
+            Err(e) => {
+                warn!("Can't listen for SIGHUP, reload disabled: {}", e);
+                return;
+            }
+        };
+        while hangup.recv().await.is_some() {
+            info!("SIGHUP received, reloading domains and config");
+            log_outcome(reload_from(&db, config_file.as_deref(), &routes, &active).await);
+
+            let current = database::get_all_config(&db).await.unwrap_or_default();
+            let mut pending: Vec<&str> = current
+                .iter()
+                .filter(|entry| !startup_config.contains(entry))
+                .map(|(key, _)| key.as_str())
+                .chain(
+                    startup_config
+                        .iter()
+                        .filter(|(key, _)| !current.iter().any(|(k, _)| k == key))
+                        .map(|(key, _)| key.as_str()),
+                )
+                .filter(|key| needs_restart(key))
+                .collect();
+            pending.sort();
+            if !pending.is_empty() {
+                warn!("RELOAD: config changed since startup, restart to apply: {}", pending.join(", "));
+            }
+        }
+    });
+}
+
+//there is no SIGHUP outside unix
+#[cfg(not(unix))]
+pub fn spawn_on_sighup(
+    _db: SqlitePool,
+    _config_file: Option<PathBuf>,
+    _routes: RouteTable,
+    _active: ActiveConfigHandle,
+    _startup_config: Vec<(String, String)>,
+) {
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::DomainDto;
+    use tokio::sync::RwLock;
+
+    fn domain(name: &str, origin: &str) -> DomainDto {
+        DomainDto {
+            domain: name.to_string(),
+            origin: origin.to_string(),
+            enabled: true,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn restart_keys_cover_whole_groups_only() {
+        assert!(needs_restart("port"));
+        assert!(needs_restart("tls.cert_path"));
+        assert!(needs_restart("cache.capacity_bytes"));
+        assert!(!needs_restart("tls"));
+        assert!(!needs_restart("tlsx.port"));
+        assert!(!needs_restart("cache.ttl_by_status"));
+        assert!(!needs_restart("rate_limit_per_minute"));
+    }
+
+    #[tokio::test]
+    async fn a_failed_reload_keeps_the_routes_and_config() {
+        let db = database::test_db().await;
+        database::create_domain(&db, &domain("app.test", "http://app")).await.unwrap();
+        let routes: RouteTable = Arc::new(RwLock::new(routing::table(&database::load_domains(&db).await.unwrap())));
+        let settings = Arc::new(ProxySettings::load(&db).await);
+        let active = Arc::new(std::sync::RwLock::new(ActiveConfig { settings: settings.clone(), rate_limiter: None }));
+
+        //a valid new domain alongside a config value that doesn't parse
+        database::create_domain(&db, &domain("new.test", "http://new")).await.unwrap();
+        database::set_config(&db, "upstream_timeout_ms", "soon").await.unwrap();
+        let error = reload(&db, &routes, &active).await.unwrap_err();
+        assert!(error.contains("upstream_timeout_ms"), "{}", error);
+
+        let names = |routes: &std::collections::HashMap<String, routing::Route>| {
+            let mut names: Vec<String> = routes.keys().cloned().collect();
+            names.sort();
+            names
+        };
+        assert_eq!(names(&*routes.read().await), ["app.test"]);
+        assert!(Arc::ptr_eq(&active.read().unwrap().settings, &settings));
+
+        //once fixed the same reload goes through
+        database::set_config(&db, "upstream_timeout_ms", "2500").await.unwrap();
+        let diff = reload(&db, &routes, &active).await.unwrap();
+        assert_eq!(diff.added, ["new.test"]);
+        assert_eq!(names(&*routes.read().await), ["app.test", "new.test"]);
+        assert_eq!(active.read().unwrap().settings.upstream_timeout_ms, Some(2500));
+
+        //a config_file that doesn't validate stops the reload before it writes anything
+        let dir = std::env::temp_dir().join(format!("proxynet-reload-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("config.toml");
+        let file = |timeout: &str| {
+            format!(
+                "[config]\nupstream_timeout_ms = \"{}\"\n\n[[domains]]\ndomain = \"file.test\"\norigin = \"http://file\"\nenabled = true\n",
+                timeout
+            )
+        };
+        std::fs::write(&path, file("soon")).unwrap();
+        let error = reload_from(&db, Some(&path), &routes, &active).await.unwrap_err();
+        assert!(error.contains("upstream_timeout_ms"), "{}", error);
+        assert_eq!(names(&*routes.read().await), ["app.test", "new.test"]);
+        assert_eq!(active.read().unwrap().settings.upstream_timeout_ms, Some(2500));
+        assert_eq!(database::get_all_domains(&db).await.unwrap().len(), 2);
+
+        std::fs::write(&path, file("1500")).unwrap();
+        let diff = reload_from(&db, Some(&path), &routes, &active).await.unwrap();
+        assert_eq!((diff.added, diff.removed), (vec!["file.test".to_string()], vec!["app.test".to_string(), "new.test".to_string()]));
+        assert_eq!(active.read().unwrap().settings.upstream_timeout_ms, Some(1500));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,36 @@
+use axum::http::HeaderMap;
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+pub const HEADER: &str = "x-request-id";
+
+static COUNTER: AtomicU64 = AtomicU64::new(0);
+
+//keeps the id a client or an upstream proxy already assigned, as long as it
+//is a sane header value, otherwise makes a new one
+pub fn for_request(headers: &HeaderMap) -> String {
+    headers
+        .get(HEADER)
+        .and_then(|h| h.to_str().ok())
+        .filter(|id| is_valid(id))
+        .map(String::from)
+        .unwrap_or_else(generate)
+}
+
+//unique within the process and practically unique across restarts
+pub fn generate() -> String {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos() as u64)
+        .unwrap_or(0);
+    let seq = COUNTER.fetch_add(1, Ordering::Relaxed);
+    format!("{:016x}{:08x}", nanos, seq as u32)
+}
+
+fn is_valid(id: &str) -> bool {
+    !id.is_empty()
+        && id.len() <= 128
+        && id.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.' | ':'))
+}
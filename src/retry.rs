@@ -0,0 +1,102 @@
+use rand::Rng;
+use std::time::Duration;
+
+//randomization applied to the backoff so clients that failed together don't retry together
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Jitter {
+    //plain exponential backoff
+    None,
+    //anywhere between zero and the exponential delay
+    Full,
+    //between the base delay and three times the previous delay
+    Decorrelated,
+}
+
+impl Jitter {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "none" => Some(Jitter::None),
+            "full" => Some(Jitter::Full),
+            "decorrelated" => Some(Jitter::Decorrelated),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct RetryPolicy {
+    //retries after the first try
+    pub attempts: u32,
+    pub base: Duration,
+    pub max: Duration,
+    pub jitter: Jitter,
+}
+
+impl RetryPolicy {
+    //delay before retry number `attempt` (starting at 1), `previous` is the last delay used
+    pub fn delay(&self, attempt: u32, previous: Duration) -> Duration {
+        let exponential = self
+            .base
+            .saturating_mul(2u32.saturating_pow(attempt.saturating_sub(1)))
+            .min(self.max);
+
+        match self.jitter {
+            Jitter::None => exponential,
+            Jitter::Full => random_between(Duration::ZERO, exponential),
+            Jitter::Decorrelated => {
+                let upper = previous.max(self.base).saturating_mul(3).min(self.max);
+                random_between(self.base.min(upper), upper)
+            }
+        }
+    }
+}
+
+fn random_between(low: Duration, high: Duration) -> Duration {
+    if high <= low {
+        return low;
+    }
+    let millis = rand::thread_rng().gen_range(low.as_millis() as u64..=high.as_millis() as u64);
+    Duration::from_millis(millis)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    fn policy(jitter: Jitter) -> RetryPolicy {
+        RetryPolicy {
+            attempts: 5,
+            base: Duration::from_millis(100),
+            max: Duration::from_millis(2000),
+            jitter,
+        }
+    }
+
+    #[test]
+    fn jittered_delays_vary_within_their_bounds() {
+        let none = policy(Jitter::None);
+        let plain: Vec<u128> = (1..=6).map(|attempt| none.delay(attempt, Duration::ZERO).as_millis()).collect();
+        assert_eq!(plain, [100, 200, 400, 800, 1600, 2000]);
+
+        //full jitter picks anywhere from zero to the exponential delay of that attempt
+        let full = policy(Jitter::Full);
+        let delays: Vec<Duration> = (0..200).map(|_| full.delay(3, Duration::ZERO)).collect();
+        assert!(delays.iter().all(|d| *d <= Duration::from_millis(400)));
+        assert!(delays.iter().collect::<HashSet<_>>().len() > 10);
+
+        //decorrelated stays between the base and three times the previous delay, capped at max
+        let decorrelated = policy(Jitter::Decorrelated);
+        let mut previous = Duration::ZERO;
+        let mut seen = HashSet::new();
+        for attempt in 1..=200 {
+            let delay = decorrelated.delay(attempt, previous);
+            let upper = previous.max(Duration::from_millis(100)) * 3;
+            assert!(delay >= Duration::from_millis(100), "{:?}", delay);
+            assert!(delay <= upper.min(Duration::from_millis(2000)), "{:?} after {:?}", delay, previous);
+            seen.insert(delay);
+            previous = delay;
+        }
+        assert!(seen.len() > 10);
+    }
+}
@@ -0,0 +1,483 @@
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, path::PathBuf, sync::Arc};
+use tokio::sync::RwLock;
+use crate::api::DomainDto;
+use crate::health::HealthCheckConfig;
+use crate::pacing::Pacing;
+
+//in-memory route entry for a single domain
+#[derive(Debug, Clone, PartialEq)]
+pub struct Route {
+    pub origins: Vec<String>,
+    pub upstream_timeout_ms: Option<u64>,
+    pub health_check: Option<HealthCheckConfig>,
+    pub priority: i64,
+    pub maintenance: bool,
+    pub weights: HashMap<String, u32>,
+    pub cache_paths: Vec<String>,
+    pub max_response_bytes: Option<u64>,
+    pub trailing_slash: Option<TrailingSlash>,
+    pub html_inject: Option<String>,
+    pub rate_limits: Vec<PathRateLimit>,
+    pub rate_limit_per_minute: Option<u32>,
+    pub max_concurrency: Option<usize>,
+    pub pacing: Option<Pacing>,
+    pub accept_routes: Vec<AcceptRoute>,
+    pub path_routes: Vec<PathRoute>,
+    pub precompressed_dir: Option<PathBuf>,
+    pub service: Option<String>,
+    pub connection_affinity: bool,
+}
+
+//how a domain treats "/path" vs "/path/" so both don't end up as separate cache entries
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TrailingSlash {
+    //forward "/path" as "/path/"
+    Add,
+    //forward "/path/" as "/path"
+    Remove,
+    //answer "/path/" with a 308 to "/path"
+    Redirect,
+}
+
+impl TrailingSlash {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TrailingSlash::Add => "add",
+            TrailingSlash::Remove => "remove",
+            TrailingSlash::Redirect => "redirect",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "add" => Some(TrailingSlash::Add),
+            "remove" => Some(TrailingSlash::Remove),
+            "redirect" => Some(TrailingSlash::Redirect),
+            _ => None,
+        }
+    }
+
+    //the canonical form of `path`, None when it already is. "/" is left alone
+    pub fn normalize(&self, path: &str) -> Option<String> {
+        if path == "/" {
+            return None;
+        }
+        match self {
+            TrailingSlash::Add if !path.ends_with('/') => Some(format!("{}/", path)),
+            TrailingSlash::Remove | TrailingSlash::Redirect if path.ends_with('/') => {
+                let trimmed = path.trim_end_matches('/');
+                Some(if trimmed.is_empty() { "/".to_string() } else { trimmed.to_string() })
+            }
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Color {
+    Blue,
+    Green,
+}
+
+impl Color {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Color::Blue => "blue",
+            Color::Green => "green",
+        }
+    }
+}
+
+//two deployments of a domain, all traffic goes to the active one
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BlueGreen {
+    pub blue: String,
+    pub green: String,
+    #[serde(default = "default_color")]
+    pub active: Color,
+}
+
+fn default_color() -> Color {
+    Color::Blue
+}
+
+impl BlueGreen {
+    pub fn active_origin(&self) -> &str {
+        match self.active {
+            Color::Blue => &self.blue,
+            Color::Green => &self.green,
+        }
+    }
+}
+
+//a tighter or looser limit for paths under `prefix`, per client
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PathRateLimit {
+    pub prefix: String,
+    pub limit: u32,
+    #[serde(default = "default_window_secs")]
+    pub window_secs: u64,
+}
+
+fn default_window_secs() -> u64 {
+    60
+}
+
+//sends requests preferring `media_type` (e.g. application/json) to `origin`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AcceptRoute {
+    pub media_type: String,
+    pub origin: String,
+}
+
+impl AcceptRoute {
+    //"text/*" covers any text type, "*/*" is left to the default origins
+    fn matches(&self, range: &str) -> bool {
+        if range.eq_ignore_ascii_case(&self.media_type) {
+            return true;
+        }
+        match (range.strip_suffix("/*"), self.media_type.split_once('/')) {
+            (Some(kind), Some((rule_kind, _))) => kind != "*" && kind.eq_ignore_ascii_case(rule_kind),
+            _ => false,
+        }
+    }
+}
+
+//sends requests under `prefix` (e.g. /api) to `origin`, optionally without the prefix
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PathRoute {
+    pub prefix: String,
+    pub origin: String,
+    #[serde(default)]
+    pub strip_prefix: bool,
+}
+
+impl PathRoute {
+    //whole segments only, "/api" covers "/api" and "/api/users" but not "/apis"
+    fn matches(&self, path: &str) -> bool {
+        matches_segments(&self.prefix, path)
+    }
+
+    //the path the origin sees, "/api/users" becomes "/users" when stripping
+    pub fn upstream_path(&self, path: &str) -> String {
+        if !self.strip_prefix {
+            return path.to_string();
+        }
+        match path.strip_prefix(self.prefix.trim_end_matches('/')) {
+            Some(rest) if !rest.is_empty() => rest.to_string(),
+            _ => "/".to_string(),
+        }
+    }
+}
+
+//media ranges of an Accept header, most preferred first, refused (q=0) ones dropped
+fn media_ranges(accept: &str) -> Vec<&str> {
+    let mut ranges: Vec<(&str, f32)> = accept
+        .split(',')
+        .filter_map(|part| {
+            let mut params = part.split(';');
+            let range = params.next()?.trim();
+            let q = params
+                .filter_map(|param| param.trim().strip_prefix("q="))
+                .find_map(|q| q.trim().parse::<f32>().ok())
+                .unwrap_or(1.0);
+            (!range.is_empty() && q > 0.0).then_some((range, q))
+        })
+        .collect();
+    //stable, so equal q keeps the client's order
+    ranges.sort_by(|a, b| b.1.total_cmp(&a.1));
+    ranges.into_iter().map(|(range, _)| range).collect()
+}
+
+impl Route {
+    //no cache_paths leaves every path cacheable, otherwise only the listed ones are
+    pub fn caches_path(&self, path: &str) -> bool {
+        self.cache_paths.is_empty() || self.cache_paths.iter().any(|pattern| path_matches(pattern, path))
+    }
+
+    //the accept route for the client's most preferred media type that has one
+    pub fn accept_route_for(&self, accept: Option<&str>) -> Option<&AcceptRoute> {
+        if self.accept_routes.is_empty() {
+            return None;
+        }
+        media_ranges(accept?)
+            .into_iter()
+            .find_map(|range| self.accept_routes.iter().find(|rule| rule.matches(range)))
+    }
+
+    //the longest prefix wins so "/api/v2" can go somewhere other than "/api"
+    pub fn path_route_for(&self, path: &str) -> Option<&PathRoute> {
+        self.path_routes
+            .iter()
+            .filter(|rule| rule.matches(path))
+            .max_by_key(|rule| rule.prefix.trim_end_matches('/').len())
+    }
+
+    //the longest prefix wins so "/api/login" can override "/api"
+    pub fn rate_limit_for(&self, path: &str) -> Option<&PathRateLimit> {
+        self.rate_limits
+            .iter()
+            .filter(|rule| matches_segments(&rule.prefix, path))
+            .max_by_key(|rule| rule.prefix.len())
+    }
+
+    //the domain's own per-minute limit as a rule covering every path
+    pub fn domain_rate_limit(&self) -> Option<PathRateLimit> {
+        self.rate_limit_per_minute.map(|limit| PathRateLimit {
+            prefix: "/".to_string(),
+            limit,
+            window_secs: 60,
+        })
+    }
+}
+
+//a pattern with `*` is a glob where `*` covers any run of characters, "/static/*" or
+//"/images/*.png"; one without is a prefix of whole segments, "/static" covers
+//"/static/app.js" but not "/staticky"
+fn path_matches(pattern: &str, path: &str) -> bool {
+    if !pattern.contains('*') {
+        return matches_segments(pattern, path);
+    }
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or("");
+    let Some(mut rest) = path.strip_prefix(first) else {
+        return false;
+    };
+    let parts: Vec<&str> = parts.collect();
+    for (i, part) in parts.iter().enumerate() {
+        if i == parts.len() - 1 {
+            return rest.ends_with(part);
+        }
+        match rest.find(part) {
+            Some(at) => rest = &rest[at + part.len()..],
+            None => return false,
+        }
+    }
+    true
+}
+
+//`prefix` followed by nothing or a new segment
+pub fn matches_segments(prefix: &str, path: &str) -> bool {
+    match path.strip_prefix(prefix.trim_end_matches('/')) {
+        Some(rest) => rest.is_empty() || rest.starts_with('/'),
+        None => false,
+    }
+}
+
+pub type RouteTable = Arc<RwLock<HashMap<String, Route>>>;
+
+impl From<&DomainDto> for Route {
+    fn from(domain: &DomainDto) -> Self {
+        Self {
+            origins: domain.origin_list(),
+            upstream_timeout_ms: domain.upstream_timeout_ms,
+            health_check: domain.health_check.clone(),
+            priority: domain.priority,
+            maintenance: domain.maintenance,
+            weights: domain.origin_weights.clone(),
+            cache_paths: domain.cache_paths.clone(),
+            max_response_bytes: domain.max_response_bytes,
+            trailing_slash: domain.trailing_slash,
+            html_inject: domain.html_inject.clone(),
+            rate_limits: domain.rate_limits.clone(),
+            rate_limit_per_minute: domain.rate_limit_per_minute,
+            max_concurrency: domain.max_concurrency,
+            pacing: domain.pacing.clone(),
+            accept_routes: domain.accept_routes.clone(),
+            path_routes: domain.path_routes.clone(),
+            precompressed_dir: domain.precompressed_dir.as_ref().map(PathBuf::from),
+            service: domain.service.clone(),
+            connection_affinity: domain.connection_affinity,
+        }
+    }
+}
+
+//a domain is routed under its own name and each of its aliases
+pub fn names(domain: &DomainDto) -> Vec<String> {
+    std::iter::once(domain.domain.clone())
+        .chain(domain.aliases.iter().cloned())
+        .collect()
+}
+
+//route map entries for a domain, built before taking the routes lock
+pub fn entries(domain: &DomainDto) -> Vec<(String, Route)> {
+    let route = Route::from(domain);
+    names(domain)
+        .into_iter()
+        .map(|name| (name, route.clone()))
+        .collect()
+}
+
+//adds a domain's entries to the route map. a name another domain already routes at a
+//higher priority stays with it, on equal priority the entry added last wins
+pub fn insert(routes: &mut HashMap<String, Route>, entries: Vec<(String, Route)>) {
+    for (name, route) in entries {
+        if routes.get(&name).is_some_and(|existing| existing.priority > route.priority) {
+            continue;
+        }
+        routes.insert(name, route);
+    }
+}
+
+//the route map for a set of domains, names claimed by several go by priority
+pub fn table(domains: &[DomainDto]) -> HashMap<String, Route> {
+    let mut routes = HashMap::new();
+    for domain in domains {
+        insert(&mut routes, entries(domain));
+    }
+    routes
+}
+
+//finds the route serving a host: its own entry whenever it has one, otherwise the
+//wildcard covering it
+pub fn resolve<'a>(routes: &'a HashMap<String, Route>, host: &str) -> Option<&'a Route> {
+    routes
+        .get(host)
+        .or_else(|| wildcard_for(host).and_then(|pattern| routes.get(&pattern)))
+}
+
+//the wildcard entry that would serve `host`: `*.example.com` covers exactly one more
+//label, so api.example.com but neither example.com nor a.b.example.com
+fn wildcard_for(host: &str) -> Option<String> {
+    let (label, parent) = host.split_once('.')?;
+    if label.is_empty() || parent.is_empty() {
+        return None;
+    }
+    Some(format!("*.{}", parent))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn route(origin: &str) -> Route {
+        Route::from(&DomainDto {
+            origin: origin.to_string(),
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn wildcard_covers_exactly_one_label() {
+        let mut routes = HashMap::new();
+        routes.insert("*.example.com".to_string(), route("http://wildcard"));
+
+        assert_eq!(resolve(&routes, "api.example.com").map(|r| r.origins[0].as_str()), Some("http://wildcard"));
+        assert!(resolve(&routes, "a.b.example.com").is_none());
+        assert!(resolve(&routes, "example.com").is_none());
+    }
+
+    #[test]
+    fn exact_entry_beats_wildcard_whatever_the_priority() {
+        let mut wildcard = route("http://wildcard");
+        wildcard.priority = 100;
+        let mut routes = HashMap::new();
+        routes.insert("*.example.com".to_string(), wildcard);
+        routes.insert("api.example.com".to_string(), route("http://exact"));
+
+        assert_eq!(resolve(&routes, "api.example.com").map(|r| r.origins[0].as_str()), Some("http://exact"));
+        assert_eq!(resolve(&routes, "www.example.com").map(|r| r.origins[0].as_str()), Some("http://wildcard"));
+    }
+
+    #[test]
+    fn higher_priority_keeps_a_name_claimed_twice() {
+        let shop = DomainDto {
+            domain: "shop.example.com".to_string(),
+            origin: "http://shop".to_string(),
+            ..Default::default()
+        };
+        let legacy = DomainDto {
+            domain: "legacy.example.com".to_string(),
+            origin: "http://legacy".to_string(),
+            aliases: vec!["shop.example.com".to_string()],
+            priority: 10,
+            ..Default::default()
+        };
+        let origin = |routes: &HashMap<String, Route>| {
+            resolve(routes, "shop.example.com").map(|r| r.origins[0].clone())
+        };
+
+        //whichever is added first, the exact name claimed at priority 10 wins
+        let routes = table(&[shop.clone(), legacy.clone()]);
+        assert_eq!(origin(&routes).as_deref(), Some("http://legacy"));
+        let routes = table(&[legacy.clone(), shop.clone()]);
+        assert_eq!(origin(&routes).as_deref(), Some("http://legacy"));
+
+        //on equal priority the domain added last takes it
+        let legacy = DomainDto { priority: 0, ..legacy };
+        let routes = table(&[legacy, shop]);
+        assert_eq!(origin(&routes).as_deref(), Some("http://shop"));
+    }
+
+    #[test]
+    fn aliases_follow_the_primary_origin() {
+        let primary = DomainDto {
+            domain: "example.com".to_string(),
+            origin: "http://shared".to_string(),
+            aliases: vec!["www.example.com".to_string(), "example.net".to_string()],
+            ..Default::default()
+        };
+        let origin = |routes: &HashMap<String, Route>, host| resolve(routes, host).map(|r| r.origins[0].clone());
+
+        let mut routes = table(std::slice::from_ref(&primary));
+        for host in ["example.com", "www.example.com", "example.net"] {
+            assert_eq!(origin(&routes, host).as_deref(), Some("http://shared"), "{}", host);
+        }
+
+        //an update swaps every name of the previous version for the new entries, as the api does
+        let updated = DomainDto {
+            origin: "http://moved".to_string(),
+            aliases: vec!["www.example.com".to_string()],
+            ..primary.clone()
+        };
+        for name in names(&primary) {
+            routes.remove(&name);
+        }
+        insert(&mut routes, entries(&updated));
+        assert_eq!(origin(&routes, "example.com").as_deref(), Some("http://moved"));
+        assert_eq!(origin(&routes, "www.example.com").as_deref(), Some("http://moved"));
+        assert!(origin(&routes, "example.net").is_none());
+    }
+
+    #[test]
+    fn login_rule_covers_its_segments_only() {
+        let mut route = route("http://app");
+        route.rate_limits = vec![
+            PathRateLimit { prefix: "/".to_string(), limit: 100, window_secs: 60 },
+            PathRateLimit { prefix: "/login".to_string(), limit: 5, window_secs: 60 },
+        ];
+
+        let prefix = |path| route.rate_limit_for(path).map(|rule| rule.prefix.as_str());
+        assert_eq!(prefix("/login"), Some("/login"));
+        assert_eq!(prefix("/login/reset"), Some("/login"));
+        assert_eq!(prefix("/loginhelp"), Some("/"));
+        assert_eq!(prefix("/dashboard"), Some("/"));
+    }
+
+    #[test]
+    fn the_longest_path_route_wins_and_strips_only_when_asked() {
+        let mut route = route("http://app");
+        let rule = |prefix: &str, origin: &str, strip_prefix| PathRoute {
+            prefix: prefix.to_string(),
+            origin: origin.to_string(),
+            strip_prefix,
+        };
+        route.path_routes = vec![
+            rule("/api", "http://api", true),
+            rule("/api/v2/", "http://api-v2", false),
+        ];
+
+        let upstream = |path| route.path_route_for(path).map(|rule| (rule.origin.as_str(), rule.upstream_path(path)));
+        assert_eq!(upstream("/api/users"), Some(("http://api", "/users".to_string())));
+        assert_eq!(upstream("/api"), Some(("http://api", "/".to_string())));
+        assert_eq!(upstream("/api/v2/users"), Some(("http://api-v2", "/api/v2/users".to_string())));
+        assert_eq!(upstream("/api/v2"), Some(("http://api-v2", "/api/v2".to_string())));
+        assert_eq!(upstream("/api/v20"), Some(("http://api", "/v20".to_string())));
+        assert_eq!(upstream("/apis"), None);
+        assert_eq!(upstream("/"), None);
+    }
+}
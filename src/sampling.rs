@@ -0,0 +1,84 @@
+use axum::http::HeaderMap;
+use rand::Rng;
+
+//head-based sampling: the decision is made once when the request arrives. a caller that
+//already decided (the sampled flag of a W3C traceparent) is followed, the rest are sampled at `rate`
+#[derive(Debug, Clone, Copy)]
+pub struct Sampler {
+    rate: f64,
+}
+
+impl Sampler {
+    pub fn new(rate: f64) -> Self {
+        Self {
+            rate: rate.clamp(0.0, 1.0),
+        }
+    }
+
+    pub fn sample(&self, headers: &HeaderMap) -> bool {
+        if let Some(sampled) = headers
+            .get("traceparent")
+            .and_then(|h| h.to_str().ok())
+            .and_then(parent_sampled)
+        {
+            return sampled;
+        }
+        rand::thread_rng().gen_bool(self.rate)
+    }
+}
+
+//"00-<trace id>-<parent id>-<flags>", None when the header is malformed
+pub fn parent_sampled(traceparent: &str) -> Option<bool> {
+    let parts: Vec<&str> = traceparent.trim().split('-').collect();
+    let [version, trace_id, parent_id, flags] = parts.as_slice() else {
+        return None;
+    };
+
+    let hex = |s: &str, len: usize| s.len() == len && s.bytes().all(|b| b.is_ascii_hexdigit());
+    if !hex(version, 2) || *version == "ff" || !hex(trace_id, 32) || !hex(parent_id, 16) || !hex(flags, 2) {
+        return None;
+    }
+    //all-zero ids are invalid
+    if trace_id.bytes().all(|b| b == b'0') || parent_id.bytes().all(|b| b == b'0') {
+        return None;
+    }
+
+    let flags = u8::from_str_radix(flags, 16).ok()?;
+    Some(flags & 0x01 == 0x01)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLED: &str = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+    const NOT_SAMPLED: &str = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-00";
+
+    fn traceparent(value: &'static str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert("traceparent", value.parse().unwrap());
+        headers
+    }
+
+    #[test]
+    fn rate_is_roughly_honoured() {
+        let sampler = Sampler::new(0.2);
+        let sampled = (0..10_000).filter(|_| sampler.sample(&HeaderMap::new())).count();
+        assert!((1700..=2300).contains(&sampled), "{} of 10000 sampled", sampled);
+
+        assert_eq!((0..1000).filter(|_| Sampler::new(0.0).sample(&HeaderMap::new())).count(), 0);
+        assert_eq!((0..1000).filter(|_| Sampler::new(1.0).sample(&HeaderMap::new())).count(), 1000);
+    }
+
+    #[test]
+    fn an_inbound_sampled_flag_decides() {
+        let never = Sampler::new(0.0);
+        assert!((0..100).all(|_| never.sample(&traceparent(SAMPLED))));
+
+        let always = Sampler::new(1.0);
+        assert!((0..100).all(|_| !always.sample(&traceparent(NOT_SAMPLED))));
+
+        //a malformed header is ignored and the rate applies
+        assert!(!never.sample(&traceparent("00-zz-00f067aa0ba902b7-01")));
+    }
+}
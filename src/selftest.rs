@@ -0,0 +1,82 @@
+use axum::{
+    body::Body,
+    http::{Request, StatusCode},
+    Router,
+};
+use http_body_util::BodyExt;
+use std::{net::SocketAddr, time::Duration};
+use tracing::info;
+use crate::api::DomainDto;
+use crate::routing;
+use crate::AppState;
+
+const DOMAIN: &str = "selftest.proxynet.invalid";
+const BODY: &str = "proxynet selftest ok";
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+//sends a request through the real proxy router, with its rate limiting, logging and
+//cache, to a throwaway loopback backend. nothing listens on the configured ports
+pub async fn run(state: AppState, proxy_app: Router) -> Result<(), String> {
+    let backend = tokio::net::TcpListener::bind("127.0.0.1:0").await.map_err(|e| e.to_string())?;
+    let backend_addr = backend.local_addr().map_err(|e| e.to_string())?;
+    tokio::spawn(async move {
+        let app = Router::new().fallback(|| async { BODY });
+        let _ = axum::serve(backend, app).await;
+    });
+
+    let domain = DomainDto {
+        domain: DOMAIN.to_string(),
+        origin: format!("http://{}", backend_addr),
+        enabled: true,
+        ..Default::default()
+    };
+    state.routes.write().await.extend(routing::entries(&domain));
+
+    let proxy = tokio::net::TcpListener::bind("127.0.0.1:0").await.map_err(|e| e.to_string())?;
+    let proxy_addr = proxy.local_addr().map_err(|e| e.to_string())?;
+    tokio::spawn(async move {
+        let service = proxy_app.into_make_service_with_connect_info::<SocketAddr>();
+        let _ = axum::serve(proxy, service).await;
+    });
+
+    let (status, _, body) = fetch(&state, proxy_addr).await?;
+    if status != StatusCode::OK || body != BODY {
+        return Err(format!("expected 200 '{}', got {} '{}'", BODY, status, body));
+    }
+    info!("SELFTEST: request proxied to loopback backend {}", backend_addr);
+
+    //a second request should come from the cache when 200s are cached
+    if state.cache.is_some() && state.settings.cache_ttl(200, None).is_some() {
+        //the first response is stored in the background
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let (_, cache_status, _) = fetch(&state, proxy_addr).await?;
+        if cache_status.as_deref() != Some("HIT") {
+            return Err(format!("expected a cache hit, got X-Cache {:?}", cache_status));
+        }
+        info!("SELFTEST: repeated request served from cache");
+    }
+
+    Ok(())
+}
+
+async fn fetch(state: &AppState, proxy_addr: SocketAddr) -> Result<(StatusCode, Option<String>, String), String> {
+    let req = Request::get(format!("http://{}/selftest", proxy_addr))
+        .header("host", DOMAIN)
+        .body(Body::empty())
+        .map_err(|e| e.to_string())?;
+
+    let response = tokio::time::timeout(TIMEOUT, state.client.request(req))
+        .await
+        .map_err(|_| "timed out waiting for the proxy".to_string())?
+        .map_err(|e| e.to_string())?;
+
+    let status = response.status();
+    let cache_status = response
+        .headers()
+        .get("x-cache")
+        .and_then(|h| h.to_str().ok())
+        .map(String::from);
+    let body = response.into_body().collect().await.map_err(|e| e.to_string())?.to_bytes();
+
+    Ok((status, cache_status, String::from_utf8_lossy(&body).into_owned()))
+}
@@ -0,0 +1,451 @@
+use axum::http::{HeaderName, StatusCode};
+use sqlx::SqlitePool;
+use std::{collections::HashMap, path::PathBuf, str::FromStr, sync::{Arc, RwLock}, time::Duration};
+use crate::database;
+use crate::routing;
+use crate::breaker::BreakerConfig;
+use crate::cidr::Cidr;
+use crate::acme::AcmeConfig;
+use crate::dns::DnsCacheConfig;
+use crate::expect::ExpectContinue;
+use crate::ip_filter::DefaultAction;
+use crate::ssrf::OriginGuard;
+use crate::status_page::StatusPage;
+use crate::log_writer::Overflow;
+use crate::upstream_error::ErrorBody;
+use crate::tls::UnknownSni;
+use crate::retry::{Jitter, RetryPolicy};
+use crate::rate_limiter::{Algorithm, Penalty, RateLimitConfig, RateLimiter};
+use crate::health::HealthCheckConfig;
+use crate::streaming::FlushPolicy;
+
+//runtime settings read by the proxy handler, loaded from the config table
+#[derive(Debug, Clone)]
+pub struct ProxySettings {
+    pub deny_status: StatusCode,
+    pub deny_body: String,
+    pub rate_limit: Option<RateLimitConfig>,
+    pub upstream_timeout_ms: Option<u64>,
+    pub upstream_connect_timeout_ms: Option<u64>,
+    pub retry: Option<RetryPolicy>,
+    pub redispatch_on_reset: Option<u32>,
+    pub dns_cache: Option<DnsCacheConfig>,
+    pub health_check: HealthCheckConfig,
+    pub max_upstream_connections: Option<usize>,
+    pub client_buffer_bytes: Option<u32>,
+    pub upstream_buffer_bytes: Option<usize>,
+    pub upstream_queue_timeout_ms: u64,
+    pub upstream_error_body: ErrorBody,
+    pub max_client_share: Option<f64>,
+    pub trusted_proxies: Vec<Cidr>,
+    pub ip_filter_default: DefaultAction,
+    pub ip_filter_allow: Vec<Cidr>,
+    pub ip_filter_deny: Vec<Cidr>,
+    pub forwarded_hops: usize,
+    pub forwarded_headers: bool,
+    pub proxy_protocol: bool,
+    //https is served on tls_port only when both a certificate and a key are configured
+    pub tls_cert_path: Option<PathBuf>,
+    pub tls_key_path: Option<PathBuf>,
+    pub tls_port: u16,
+    pub tls_log_handshake_failures: bool,
+    //None unless acme.enabled, certificates then come from the CA for every domain
+    pub acme: Option<AcmeConfig>,
+    //None keeps the default certificate for unknown names, read by the SNI resolver
+    pub tls_unknown_sni: Option<UnknownSni>,
+    pub reject_duplicate_host: bool,
+    pub echo_headers: Vec<HeaderName>,
+    pub merge_response_headers: Vec<HeaderName>,
+    pub max_uri_length: Option<usize>,
+    pub max_request_header_bytes: Option<usize>,
+    pub expect_continue: ExpectContinue,
+    pub origin_guard: Option<OriginGuard>,
+    pub strip_hop_by_hop: bool,
+    pub startup_banner: bool,
+    pub status_page: Option<StatusPage>,
+    pub connection_affinity_idle_secs: u64,
+    pub log_queue_capacity: usize,
+    pub log_queue_overflow: Overflow,
+    pub trace_sample_rate: Option<f64>,
+    pub stream_flush: FlushPolicy,
+    pub startup_probe: bool,
+    pub shutdown_grace: Duration,
+    pub min_cache_bytes: u64,
+    pub cache_enabled: bool,
+    pub max_aux_memory_bytes: Option<usize>,
+    pub max_cache_bytes: Option<u64>,
+    pub cache_capacity_bytes: Option<usize>,
+    pub cache_max_entries: Option<usize>,
+    pub cache_sweep_interval_secs: u64,
+    pub cache_stale_if_error_secs: Option<u64>,
+    pub cache_dedupe_bodies: bool,
+    pub cache_inspect: bool,
+    pub cache_default_ttl_secs: u64,
+    pub cache_ttl_by_status: HashMap<u16, u64>,
+    pub cache_ttl_by_content_type: Vec<(String, u64)>,
+    pub cache_respect_origin_ttl: bool,
+    pub cache_strip_set_cookie_paths: Vec<String>,
+    pub rewrite_redirects: bool,
+    pub circuit_breaker: Option<BreakerConfig>,
+    pub slow_start: Option<Duration>,
+    pub maintenance: bool,
+    pub maintenance_static_dir: Option<PathBuf>,
+    pub snapshot_dir: PathBuf,
+    pub snapshot_interval_secs: Option<u64>,
+}
+
+impl ProxySettings {
+    pub async fn load(db: &SqlitePool) -> Self {
+        Self {
+            deny_status: config_value::<u16>(db, "deny_status")
+                .await
+                .and_then(|s| StatusCode::from_u16(s).ok())
+                .unwrap_or(StatusCode::FORBIDDEN),
+            deny_body: config_value(db, "deny_body")
+                .await
+                .unwrap_or("Forbidden".to_string()),
+            rate_limit: rate_limit(db).await,
+            //a hung origin shouldn't hold a connection forever, 0 turns the limit off
+            upstream_timeout_ms: Some(config_value(db, "upstream_timeout_ms").await.unwrap_or(30_000))
+                .filter(|ms| *ms > 0),
+            upstream_connect_timeout_ms: config_value(db, "upstream_connect_timeout_ms").await,
+            retry: match config_value::<u32>(db, "retry.attempts").await.unwrap_or(2) {
+                attempts if attempts > 0 => Some(RetryPolicy {
+                    attempts,
+                    base: Duration::from_millis(config_value(db, "retry.backoff_ms").await.unwrap_or(100)),
+                    max: Duration::from_millis(config_value(db, "retry.max_backoff_ms").await.unwrap_or(2000)),
+                    jitter: config_value::<String>(db, "retry.jitter")
+                        .await
+                        .and_then(|j| Jitter::parse(&j))
+                        .unwrap_or(Jitter::Full),
+                }),
+                _ => None,
+            },
+            redispatch_on_reset: config_value::<u32>(db, "redispatch_on_reset.attempts")
+                .await
+                .filter(|attempts| *attempts > 0),
+            dns_cache: match config_value::<u64>(db, "dns_cache.ttl_secs").await {
+                Some(ttl) if ttl > 0 => Some(DnsCacheConfig {
+                    ttl: Duration::from_secs(ttl),
+                    refresh: config_value(db, "dns_cache.refresh").await.unwrap_or(false),
+                }),
+                _ => None,
+            },
+            health_check: HealthCheckConfig {
+                expected_status: config_value(db, "health_check.expected_status").await,
+                expect_body: config_value(db, "health_check.expect_body").await,
+                path: Some(
+                    config_value(db, "health_check.path")
+                        .await
+                        .unwrap_or("/".to_string()),
+                ),
+                interval_secs: config_value(db, "health_check.interval_secs").await,
+                healthy_threshold: config_value(db, "health_check.healthy_threshold").await,
+                unhealthy_threshold: config_value(db, "health_check.unhealthy_threshold").await,
+            },
+            max_upstream_connections: config_value(db, "max_upstream_connections").await,
+            client_buffer_bytes: config_value(db, "buffers.client_bytes").await,
+            upstream_buffer_bytes: config_value(db, "buffers.upstream_bytes").await,
+            upstream_queue_timeout_ms: config_value(db, "upstream_queue_timeout_ms")
+                .await
+                .unwrap_or(1000),
+            upstream_error_body: config_value::<String>(db, "upstream_error_body")
+                .await
+                .and_then(|value| ErrorBody::parse(&value))
+                .unwrap_or(ErrorBody::Opaque),
+            max_client_share: config_value::<f64>(db, "concurrency.max_client_share")
+                .await
+                .filter(|share| *share > 0.0 && *share <= 1.0),
+            trusted_proxies: config_value::<String>(db, "trusted_proxies")
+                .await
+                .map(|list| Cidr::parse_list(&list))
+                .unwrap_or_default(),
+            ip_filter_default: config_value::<String>(db, "ip_filter.default")
+                .await
+                .and_then(|value| DefaultAction::parse(&value))
+                .unwrap_or(DefaultAction::Allow),
+            ip_filter_allow: config_value::<String>(db, "ip_filter.allow")
+                .await
+                .map(|list| Cidr::parse_list(&list))
+                .unwrap_or_default(),
+            ip_filter_deny: config_value::<String>(db, "ip_filter.deny")
+                .await
+                .map(|list| Cidr::parse_list(&list))
+                .unwrap_or_default(),
+            forwarded_hops: config_value(db, "forwarded_hops").await.unwrap_or(0),
+            forwarded_headers: config_value(db, "forwarded_headers").await.unwrap_or(true),
+            proxy_protocol: config_value(db, "proxy_protocol").await.unwrap_or(false),
+            tls_cert_path: config_value(db, "tls.cert_path").await,
+            tls_key_path: config_value(db, "tls.key_path").await,
+            tls_port: config_value(db, "tls.port").await.unwrap_or(8443),
+            tls_log_handshake_failures: config_value(db, "tls.log_handshake_failures").await.unwrap_or(true),
+            acme: match config_value(db, "acme.enabled").await.unwrap_or(false) {
+                true => Some(AcmeConfig {
+                    directory: config_value(db, "acme.directory")
+                        .await
+                        .unwrap_or("https://acme-v02.api.letsencrypt.org/directory".to_string()),
+                    email: config_value(db, "acme.email").await,
+                    renew_before: Duration::from_secs(
+                        config_value::<u64>(db, "acme.renew_before_days").await.unwrap_or(30) * 24 * 60 * 60,
+                    ),
+                }),
+                false => None,
+            },
+            tls_unknown_sni: config_value::<String>(db, "tls.unknown_sni")
+                .await
+                .map(|value| UnknownSni::parse(&value)),
+            reject_duplicate_host: config_value(db, "reject_duplicate_host").await.unwrap_or(true),
+            echo_headers: config_value::<String>(db, "echo_headers")
+                .await
+                .map(|list| {
+                    list.split(',')
+                        .filter_map(|name| HeaderName::from_bytes(name.trim().as_bytes()).ok())
+                        .collect()
+                })
+                .unwrap_or_default(),
+            merge_response_headers: config_value::<String>(db, "merge_response_headers")
+                .await
+                .map(|list| {
+                    list.split(',')
+                        .filter_map(|name| HeaderName::from_bytes(name.trim().as_bytes()).ok())
+                        .collect()
+                })
+                .unwrap_or_default(),
+            max_uri_length: config_value(db, "max_uri_length").await,
+            max_request_header_bytes: config_value(db, "max_request_header_bytes").await,
+            expect_continue: config_value::<String>(db, "expect_continue")
+                .await
+                .and_then(|value| ExpectContinue::parse(&value))
+                .unwrap_or(ExpectContinue::Forward),
+            origin_guard: match config_value(db, "ssrf_guard.enabled").await.unwrap_or(false) {
+                true => Some(OriginGuard {
+                    exempt_configured: config_value(db, "ssrf_guard.exempt_configured").await.unwrap_or(true),
+                }),
+                false => None,
+            },
+            strip_hop_by_hop: config_value(db, "strip_hop_by_hop").await.unwrap_or(true),
+            startup_banner: config_value(db, "startup_banner").await.unwrap_or(false),
+            status_page: match config_value(db, "status_page.enabled").await.unwrap_or(false) {
+                true => Some(StatusPage {
+                    path: config_value(db, "status_page.path")
+                        .await
+                        .unwrap_or("/_proxynet/status".to_string()),
+                    unknown_domain: config_value(db, "status_page.unknown_domain").await.unwrap_or(false),
+                }),
+                false => None,
+            },
+            connection_affinity_idle_secs: config_value(db, "connection_affinity.idle_secs").await.unwrap_or(90),
+            log_queue_capacity: config_value(db, "log_queue.capacity").await.unwrap_or(10_000),
+            log_queue_overflow: config_value::<String>(db, "log_queue.overflow")
+                .await
+                .and_then(|value| Overflow::parse(&value))
+                .unwrap_or(Overflow::DropNewest),
+            trace_sample_rate: config_value(db, "tracing.sample_rate").await,
+            stream_flush: FlushPolicy {
+                min_bytes: config_value(db, "stream_flush_bytes").await.unwrap_or(16 * 1024),
+                max_delay: Duration::from_millis(config_value(db, "stream_flush_ms").await.unwrap_or(50)),
+            },
+            shutdown_grace: Duration::from_secs(config_value(db, "shutdown.grace_secs").await.unwrap_or(30)),
+            startup_probe: config_value(db, "startup_probe").await.unwrap_or(false),
+            min_cache_bytes: config_value(db, "min_cache_bytes").await.unwrap_or(0),
+            cache_enabled: config_value(db, "cache.enabled").await.unwrap_or(true),
+            max_aux_memory_bytes: config_value::<usize>(db, "max_aux_memory_bytes")
+                .await
+                .filter(|bytes| *bytes > 0),
+            max_cache_bytes: config_value(db, "max_cache_bytes").await,
+            cache_capacity_bytes: config_value(db, "cache.capacity_bytes").await,
+            cache_max_entries: config_value::<usize>(db, "cache.max_entries")
+                .await
+                .filter(|max| *max > 0),
+            cache_sweep_interval_secs: config_value(db, "cache.sweep_interval_secs").await.unwrap_or(60),
+            cache_stale_if_error_secs: config_value::<u64>(db, "cache.stale_if_error_secs")
+                .await
+                .filter(|secs| *secs > 0),
+            cache_dedupe_bodies: config_value(db, "cache.dedupe_bodies").await.unwrap_or(false),
+            cache_inspect: config_value(db, "cache.inspect").await.unwrap_or(false),
+            cache_default_ttl_secs: config_value(db, "cache.default_ttl_secs").await.unwrap_or(300),
+            cache_ttl_by_status: config_value::<String>(db, "cache.ttl_by_status")
+                .await
+                .map(|map| parse_ttl_map(&map))
+                .unwrap_or_default(),
+            cache_ttl_by_content_type: config_value::<String>(db, "cache.ttl_by_content_type")
+                .await
+                .map(|map| parse_content_type_ttls(&map))
+                .unwrap_or_default(),
+            cache_respect_origin_ttl: config_value(db, "cache.respect_origin_ttl").await.unwrap_or(true),
+            cache_strip_set_cookie_paths: config_value::<String>(db, "cache.strip_set_cookie_paths")
+                .await
+                .map(|list| {
+                    list.split(',')
+                        .map(|p| p.trim().to_string())
+                        .filter(|p| !p.is_empty())
+                        .collect()
+                })
+                .unwrap_or_default(),
+            rewrite_redirects: config_value(db, "rewrite_redirects").await.unwrap_or(false),
+            circuit_breaker: match config_value::<u32>(db, "circuit_breaker.failure_threshold").await {
+                Some(threshold) if threshold > 0 => Some(BreakerConfig {
+                    failure_threshold: threshold,
+                    open_for: Duration::from_secs(
+                        config_value(db, "circuit_breaker.open_secs").await.unwrap_or(30),
+                    ),
+                    idle_for: config_value::<u64>(db, "circuit_breaker.idle_secs")
+                        .await
+                        .filter(|secs| *secs > 0)
+                        .map(Duration::from_secs),
+                    max_entries: config_value(db, "circuit_breaker.max_entries").await,
+                }),
+                _ => None,
+            },
+            slow_start: config_value::<u64>(db, "slow_start.window_secs")
+                .await
+                .filter(|secs| *secs > 0)
+                .map(Duration::from_secs),
+            maintenance: config_value(db, "maintenance.enabled").await.unwrap_or(false),
+            maintenance_static_dir: config_value(db, "maintenance.static_dir").await,
+            snapshot_dir: snapshot_dir(db).await,
+            snapshot_interval_secs: config_value(db, "snapshot.interval_secs").await,
+        }
+    }
+
+    //how long a response with this status is cached, None when it isn't cached at all.
+    //200s use the default TTL unless the status map says otherwise, other statuses must be listed.
+    //for a cached status, a matching content type rule replaces that TTL
+    pub fn cache_ttl(&self, status: u16, content_type: Option<&str>) -> Option<u64> {
+        let ttl = match self.cache_ttl_by_status.get(&status) {
+            Some(ttl) => *ttl,
+            None if status == 200 => self.cache_default_ttl_secs,
+            None => return None,
+        };
+        if ttl == 0 {
+            return None;
+        }
+        let ttl = content_type.and_then(|ct| self.content_type_ttl(ct)).unwrap_or(ttl);
+        Some(ttl).filter(|ttl| *ttl > 0)
+    }
+
+    //the TTL of the most specific rule matching the media type, parameters like charset are ignored
+    fn content_type_ttl(&self, content_type: &str) -> Option<u64> {
+        let media_type = content_type.split(';').next().unwrap_or("").trim().to_ascii_lowercase();
+        self.cache_ttl_by_content_type
+            .iter()
+            .filter(|(pattern, _)| match pattern.strip_suffix('*') {
+                Some(prefix) => media_type.starts_with(prefix),
+                None => media_type == *pattern,
+            })
+            //an exact type beats any pattern, then longer patterns beat shorter ones
+            .max_by_key(|(pattern, _)| (!pattern.ends_with('*'), pattern.len()))
+            .map(|(_, ttl)| *ttl)
+    }
+
+    //responses setting cookies are only cached, without the cookie, under these path prefixes.
+    //prefixes cover whole segments, "/static" doesn't cover "/staticky"
+    pub fn strips_set_cookie(&self, path: &str) -> bool {
+        self.cache_strip_set_cookie_paths
+            .iter()
+            .any(|prefix| routing::matches_segments(prefix, path))
+    }
+}
+
+//the global limit, None unless rate_limit_per_minute is set
+async fn rate_limit(db: &SqlitePool) -> Option<RateLimitConfig> {
+    let limit = config_value::<u32>(db, "rate_limit_per_minute").await?;
+    let algorithm = config_value::<String>(db, "rate_limit_algorithm")
+        .await
+        .unwrap_or("sliding_window".to_string());
+    let burst = config_value(db, "rate_limit_burst").await;
+    let penalty = match config_value::<u64>(db, "rate_limit_penalty_secs").await.filter(|secs| *secs > 0) {
+        Some(base) => {
+            let max = config_value(db, "rate_limit_penalty_max_secs").await.unwrap_or(3600);
+            let reset_after = config_value(db, "rate_limit_penalty_reset_secs").await.unwrap_or(600);
+            Some(Penalty {
+                base: Duration::from_secs(base),
+                max: Duration::from_secs(max.max(base)),
+                reset_after: Duration::from_secs(reset_after),
+            })
+        }
+        None => None,
+    };
+    Some(RateLimitConfig {
+        limit,
+        algorithm: Algorithm::from_config(&algorithm, burst, limit),
+        penalty,
+        max_entries: config_value::<usize>(db, "rate_limit_max_entries").await.filter(|max| *max > 0),
+    })
+}
+
+//what requests run with, swapped as a whole by a reload. each request takes the current
+//one when it arrives and keeps it to the end
+#[derive(Clone)]
+pub struct ActiveConfig {
+    pub settings: Arc<ProxySettings>,
+    pub rate_limiter: Option<RateLimiter>,
+}
+
+pub type ActiveConfigHandle = Arc<RwLock<ActiveConfig>>;
+
+//where config snapshots are written, also read by the on-demand snapshot endpoint
+pub async fn snapshot_dir(db: &SqlitePool) -> PathBuf {
+    config_value(db, "snapshot.dir")
+        .await
+        .unwrap_or(PathBuf::from("snapshots"))
+}
+
+//"200=300,301=86400,404=10", invalid entries are skipped
+fn parse_ttl_map(value: &str) -> HashMap<u16, u64> {
+    value
+        .split(',')
+        .filter_map(|entry| {
+            let (status, ttl) = entry.split_once('=')?;
+            Some((status.trim().parse().ok()?, ttl.trim().parse().ok()?))
+        })
+        .collect()
+}
+
+//"image/*=86400,text/html=60,text/*=600", a trailing * matches any type with that prefix.
+//invalid entries are skipped
+fn parse_content_type_ttls(value: &str) -> Vec<(String, u64)> {
+    value
+        .split(',')
+        .filter_map(|entry| {
+            let (pattern, ttl) = entry.split_once('=')?;
+            let pattern = pattern.trim().to_ascii_lowercase();
+            if pattern.is_empty() {
+                return None;
+            }
+            Some((pattern, ttl.trim().parse().ok()?))
+        })
+        .collect()
+}
+
+//reads a config key and parses it, treating missing or invalid values as unset
+pub async fn config_value<T: FromStr>(db: &SqlitePool, key: &str) -> Option<T> {
+    database::get_config(db, key)
+        .await
+        .ok()
+        .flatten()
+        .and_then(|s| s.parse::<T>().ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn settings(config: &[(&str, &str)]) -> ProxySettings {
+        let db = database::test_db().await;
+        for (key, value) in config {
+            database::set_config(&db, key, value).await.unwrap();
+        }
+        ProxySettings::load(&db).await
+    }
+
+    #[tokio::test]
+    async fn redirects_and_pages_get_their_own_ttls() {
+        let settings = settings(&[("cache.ttl_by_status", "200=60,301=86400")]).await;
+        assert_eq!(settings.cache_ttl(200, Some("text/html")), Some(60));
+        assert_eq!(settings.cache_ttl(301, None), Some(86400));
+        //statuses missing from the map aren't cached
+        assert_eq!(settings.cache_ttl(302, None), None);
+        assert_eq!(settings.cache_ttl(404, None), None);
+    }
+}
@@ -0,0 +1,121 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use crate::balancer::weight_of;
+
+//weights are scaled up by this much while ramping so a fraction of weight 1 still counts
+const SCALE: u64 = 100;
+
+//origins coming back from a failed health check or an open circuit get their weight
+//ramped from almost nothing to full over `window`, so a cold backend isn't flooded
+#[derive(Clone)]
+pub struct SlowStart {
+    window: Option<Duration>,
+    recovered: Arc<Mutex<HashMap<String, Instant>>>,
+}
+
+impl SlowStart {
+    pub fn new(window: Option<Duration>) -> Self {
+        Self {
+            window: window.filter(|w| !w.is_zero()),
+            recovered: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    pub fn recovered(&self, origin: &str) {
+        if self.window.is_some() {
+            self.recovered.lock().unwrap().insert(origin.to_string(), Instant::now());
+        }
+    }
+
+    //how far `origin` is through its ramp, from 0 to 1, None when it isn't ramping
+    fn progress(&self, origin: &str) -> Option<f64> {
+        let window = self.window?;
+        let elapsed = self.recovered.lock().unwrap().get(origin)?.elapsed();
+        (elapsed < window).then(|| elapsed.as_secs_f64() / window.as_secs_f64())
+    }
+
+    //whether a pick of `origin` stands with a strategy that doesn't weigh origins: a
+    //ramping origin keeps a share of its picks that grows over the window
+    pub fn admits(&self, origin: &str) -> bool {
+        match self.progress(origin) {
+            Some(progress) => rand::random::<f64>() < progress,
+            None => true,
+        }
+    }
+
+    //the weights to select with, None when no candidate is ramping
+    pub fn weights(&self, origins: &[String], weights: &HashMap<String, u32>) -> Option<HashMap<String, u32>> {
+        let window = self.window?;
+        let mut recovered = self.recovered.lock().unwrap();
+        recovered.retain(|_, at| at.elapsed() < window);
+        if !origins.iter().any(|origin| recovered.contains_key(origin)) {
+            return None;
+        }
+
+        let ramped = origins
+            .iter()
+            .map(|origin| {
+                let full = weight_of(weights, origin) as u64 * SCALE;
+                let weight = match recovered.get(origin) {
+                    //weight 0 stays 0, anything else gets at least 1
+                    Some(at) if full > 0 => {
+                        let progress = at.elapsed().as_millis() as u64 * SCALE / window.as_millis().max(1) as u64;
+                        (full * progress / SCALE).max(1)
+                    }
+                    _ => full,
+                };
+                (origin.clone(), weight.min(u32::MAX as u64) as u32)
+            })
+            .collect();
+        Some(ramped)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn recovered_ago(slow_start: &SlowStart, origin: &str, ago: Duration) {
+        slow_start
+            .recovered
+            .lock()
+            .unwrap()
+            .insert(origin.to_string(), Instant::now() - ago);
+    }
+
+    #[test]
+    fn recovering_weight_grows_over_the_window() {
+        let slow_start = SlowStart::new(Some(Duration::from_secs(100)));
+        let origins = vec!["http://a".to_string(), "http://b".to_string()];
+        let weight_after = |secs| {
+            recovered_ago(&slow_start, "http://b", Duration::from_secs(secs));
+            let weights = slow_start.weights(&origins, &HashMap::new()).unwrap();
+            assert_eq!(weights["http://a"], 100);
+            weights["http://b"]
+        };
+
+        assert_eq!(weight_after(0), 1);
+        assert_eq!(weight_after(25), 25);
+        assert_eq!(weight_after(75), 75);
+        recovered_ago(&slow_start, "http://b", Duration::from_secs(100));
+        assert!(slow_start.weights(&origins, &HashMap::new()).is_none());
+    }
+
+    #[test]
+    fn recovering_origin_is_admitted_more_often_over_the_window() {
+        let slow_start = SlowStart::new(Some(Duration::from_secs(100)));
+        let admitted_after = |secs| {
+            recovered_ago(&slow_start, "http://b", Duration::from_secs(secs));
+            (0..1000).filter(|_| slow_start.admits("http://b")).count()
+        };
+
+        let early = admitted_after(10);
+        let late = admitted_after(90);
+        assert!(early < 200, "{} admitted at 10%", early);
+        assert!(late > 800, "{} admitted at 90%", late);
+        assert!(slow_start.admits("http://a"));
+    }
+}
@@ -0,0 +1,89 @@
+use serde::Serialize;
+use sqlx::SqlitePool;
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+use tracing::{info, warn};
+use crate::api::DomainDto;
+use crate::database;
+
+//same shape as a ConfigDocument, so a snapshot can be validated and restored as-is
+#[derive(Debug, Serialize)]
+pub struct Snapshot {
+    pub taken_at: i64,
+    pub config: BTreeMap<String, String>,
+    pub domains: Vec<DomainDto>,
+}
+
+//writes the domains and config table to <dir>/proxynet-<timestamp>.json
+pub async fn write(db: &SqlitePool, dir: &Path) -> Result<PathBuf, String> {
+    let config = database::get_all_config(db).await.map_err(|e| e.to_string())?;
+    let domains = database::get_all_domains(db).await.map_err(|e| e.to_string())?;
+
+    let now = chrono::Utc::now();
+    let snapshot = Snapshot {
+        taken_at: now.timestamp(),
+        config: config.into_iter().collect(),
+        domains,
+    };
+    let json = serde_json::to_string_pretty(&snapshot).map_err(|e| e.to_string())?;
+
+    tokio::fs::create_dir_all(dir).await.map_err(|e| e.to_string())?;
+    let file = dir.join(format!("proxynet-{}.json", now.format("%Y%m%dT%H%M%SZ")));
+    tokio::fs::write(&file, json).await.map_err(|e| e.to_string())?;
+
+    Ok(file)
+}
+
+pub fn spawn(db: SqlitePool, dir: PathBuf, interval: Duration) {
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(interval).await;
+            match write(&db, &dir).await {
+                Ok(file) => info!("SNAPSHOT: wrote {}", file.display()),
+                Err(e) => warn!("SNAPSHOT: failed to write to {}: {}", dir.display(), e),
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::validation::{self, ConfigDocument};
+
+    #[tokio::test]
+    async fn snapshot_holds_the_config_and_domains_and_validates() {
+        let db = database::test_db().await;
+        database::set_config(&db, "rate_limit_per_minute", "120").await.unwrap();
+        let domain = DomainDto {
+            domain: "app.test".to_string(),
+            origin: "http://127.0.0.1:3000".to_string(),
+            aliases: vec!["www.app.test".to_string()],
+            enabled: true,
+            ..Default::default()
+        };
+        database::create_domain(&db, &domain).await.unwrap();
+
+        let dir = std::env::temp_dir().join(format!("proxynet-snapshot-{}", std::process::id()));
+        let file = write(&db, &dir).await.unwrap();
+        assert_eq!(file.parent(), Some(dir.as_path()));
+        let name = file.file_name().unwrap().to_str().unwrap();
+        assert!(name.starts_with("proxynet-") && name.ends_with("Z.json"), "{}", name);
+
+        let contents = std::fs::read_to_string(&file).unwrap();
+        let json: serde_json::Value = serde_json::from_str(&contents).unwrap();
+        assert!(json["taken_at"].as_i64().unwrap() > 0);
+        assert_eq!(json["config"]["rate_limit_per_minute"], "120");
+        assert_eq!(json["domains"][0]["domain"], "app.test");
+        assert_eq!(json["domains"][0]["aliases"], serde_json::json!(["www.app.test"]));
+
+        //a snapshot can be fed straight back to validation
+        let doc = ConfigDocument::parse(&contents).unwrap();
+        assert_eq!(validation::validate_document(&doc), Vec::<String>::new());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
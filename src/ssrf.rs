@@ -0,0 +1,118 @@
+use axum::http::Uri;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+//cloud metadata services answer on these even though they aren't all in a private range
+const METADATA_ADDRS: [IpAddr; 2] = [
+    IpAddr::V4(Ipv4Addr::new(169, 254, 169, 254)),
+    IpAddr::V6(Ipv6Addr::new(0xfd00, 0xec2, 0, 0, 0, 0, 0, 0x254)),
+];
+
+//keeps the proxy from being pointed at itself, the local network or a metadata endpoint
+#[derive(Debug, Clone, Copy)]
+pub struct OriginGuard {
+    //origins from the domain config are trusted, only ones picked per request are checked
+    pub exempt_configured: bool,
+}
+
+impl OriginGuard {
+    //the reason `origin` may not be used, None when it's fine. `configured` is false for
+    //origins that came from the request rather than the domain config
+    pub async fn check(&self, origin: &str, configured: bool) -> Option<&'static str> {
+        if configured && self.exempt_configured {
+            return None;
+        }
+
+        let uri = origin.parse::<Uri>().ok()?;
+        let host = uri.host()?.trim_start_matches('[').trim_end_matches(']');
+        let port = uri.port_u16().unwrap_or(if uri.scheme_str() == Some("https") { 443 } else { 80 });
+
+        //every address the name resolves to has to be public, or a second lookup could pick a private one
+        let addrs: Vec<IpAddr> = match host.parse::<IpAddr>() {
+            Ok(ip) => vec![ip],
+            Err(_) => match tokio::net::lookup_host((host, port)).await {
+                Ok(addrs) => addrs.map(|addr| addr.ip()).collect(),
+                Err(_) => return Some("unresolvable"),
+            },
+        };
+
+        addrs.into_iter().find_map(classify)
+    }
+}
+
+//what kind of internal address `ip` is, None for public ones
+fn classify(ip: IpAddr) -> Option<&'static str> {
+    if METADATA_ADDRS.contains(&ip) {
+        return Some("metadata");
+    }
+    match ip {
+        IpAddr::V4(v4) => {
+            if v4.is_loopback() {
+                Some("loopback")
+            } else if v4.is_link_local() {
+                Some("link_local")
+            } else if v4.is_private() || is_shared(v4) {
+                Some("private")
+            } else if v4.is_unspecified() || v4.is_broadcast() {
+                Some("unspecified")
+            } else {
+                None
+            }
+        }
+        IpAddr::V6(v6) => {
+            if let Some(v4) = v6.to_ipv4_mapped() {
+                return classify(IpAddr::V4(v4));
+            }
+            let first = v6.segments()[0];
+            if v6.is_loopback() {
+                Some("loopback")
+            } else if first & 0xffc0 == 0xfe80 {
+                Some("link_local")
+            } else if first & 0xfe00 == 0xfc00 {
+                Some("private")
+            } else if v6.is_unspecified() {
+                Some("unspecified")
+            } else {
+                None
+            }
+        }
+    }
+}
+
+//100.64.0.0/10, carrier-grade NAT
+fn is_shared(ip: Ipv4Addr) -> bool {
+    let [a, b, ..] = ip.octets();
+    a == 100 && (b & 0xc0) == 64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn request_chosen_origins_to_internal_addresses_are_blocked() {
+        let guard = OriginGuard { exempt_configured: true };
+        for (origin, reason) in [
+            ("http://169.254.169.254/latest/meta-data", "metadata"),
+            ("http://[fd00:ec2::254]", "metadata"),
+            ("http://127.0.0.1:8080", "loopback"),
+            ("http://localhost:8080", "loopback"),
+            ("http://[::ffff:127.0.0.1]", "loopback"),
+            ("http://169.254.10.1", "link_local"),
+            ("http://10.0.0.5", "private"),
+            ("http://100.64.1.1", "private"),
+        ] {
+            assert_eq!(guard.check(origin, false).await, Some(reason), "{}", origin);
+        }
+        assert_eq!(guard.check("http://93.184.216.34", false).await, None);
+    }
+
+    #[tokio::test]
+    async fn configured_private_origins_are_exempt_unless_disabled() {
+        let exempt = OriginGuard { exempt_configured: true };
+        assert_eq!(exempt.check("http://10.0.0.5:3000", true).await, None);
+        assert_eq!(exempt.check("http://127.0.0.1:3000", true).await, None);
+
+        let strict = OriginGuard { exempt_configured: false };
+        assert_eq!(strict.check("http://10.0.0.5:3000", true).await, Some("private"));
+    }
+}
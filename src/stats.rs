@@ -0,0 +1,265 @@
+use serde::Serialize;
+use std::{
+    collections::HashMap,
+    fmt,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+//how often a domain's requests were refused by a limit
+#[derive(Debug, Default, Clone, Copy, Serialize)]
+pub struct LimitCounts {
+    //rejected by rate limiting
+    pub throttled: u64,
+    //dropped because the upstream concurrency ceiling was reached
+    pub shed: u64,
+}
+
+#[derive(Clone, Default)]
+pub struct LimitCounters {
+    inner: Arc<Mutex<HashMap<String, LimitCounts>>>,
+}
+
+impl LimitCounters {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn throttled(&self, domain: &str) {
+        let mut counts = self.inner.lock().unwrap();
+        counts.entry(domain.to_string()).or_default().throttled += 1;
+    }
+
+    pub fn shed(&self, domain: &str) {
+        let mut counts = self.inner.lock().unwrap();
+        counts.entry(domain.to_string()).or_default().shed += 1;
+    }
+
+    pub fn snapshot(&self) -> HashMap<String, LimitCounts> {
+        self.inner.lock().unwrap().clone()
+    }
+}
+
+//requests sent to each origin of each domain since startup, to check
+//how traffic actually spreads across weighted origins
+#[derive(Clone, Default)]
+pub struct OriginCounters {
+    inner: Arc<Mutex<HashMap<(String, String), u64>>>,
+}
+
+impl OriginCounters {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn record(&self, domain: &str, origin: &str) {
+        let mut counts = self.inner.lock().unwrap();
+        *counts.entry((domain.to_string(), origin.to_string())).or_default() += 1;
+    }
+
+    pub fn snapshot(&self) -> HashMap<(String, String), u64> {
+        self.inner.lock().unwrap().clone()
+    }
+}
+
+//requests each origin is serving right now, a guard is taken on dispatch
+//and the count drops when it's released
+#[derive(Clone, Default)]
+pub struct InFlight {
+    inner: Arc<Mutex<HashMap<String, usize>>>,
+}
+
+pub struct InFlightGuard {
+    in_flight: InFlight,
+    origin: String,
+}
+
+impl InFlight {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn start(&self, origin: &str) -> InFlightGuard {
+        *self.inner.lock().unwrap().entry(origin.to_string()).or_default() += 1;
+        InFlightGuard {
+            in_flight: self.clone(),
+            origin: origin.to_string(),
+        }
+    }
+
+    pub fn count(&self, origin: &str) -> usize {
+        self.inner.lock().unwrap().get(origin).copied().unwrap_or(0)
+    }
+
+    //across every origin
+    pub fn total(&self) -> usize {
+        self.inner.lock().unwrap().values().sum()
+    }
+}
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        let mut counts = self.in_flight.inner.lock().unwrap();
+        if let Some(count) = counts.get_mut(&self.origin) {
+            *count = count.saturating_sub(1);
+            if *count == 0 {
+                counts.remove(&self.origin);
+            }
+        }
+    }
+}
+
+//totals for the current run, kept in memory so they survive a failing db
+#[derive(Clone)]
+pub struct LiveCounters {
+    inner: Arc<LiveInner>,
+}
+
+struct LiveInner {
+    started_at: Instant,
+    requests: AtomicU64,
+    errors: AtomicU64,
+    bytes_sent: AtomicU64,
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct LiveSnapshot {
+    pub uptime: Duration,
+    pub requests: u64,
+    pub errors: u64,
+    pub bytes_sent: u64,
+}
+
+impl LiveCounters {
+    pub fn new() -> Self {
+        Self {
+            inner: Arc::new(LiveInner {
+                started_at: Instant::now(),
+                requests: AtomicU64::new(0),
+                errors: AtomicU64::new(0),
+                bytes_sent: AtomicU64::new(0),
+            }),
+        }
+    }
+
+    pub fn record(&self, status: u16, bytes: u64) {
+        self.inner.requests.fetch_add(1, Ordering::Relaxed);
+        self.inner.bytes_sent.fetch_add(bytes, Ordering::Relaxed);
+        if status >= 500 {
+            self.inner.errors.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn snapshot(&self) -> LiveSnapshot {
+        LiveSnapshot {
+            uptime: self.inner.started_at.elapsed(),
+            requests: self.inner.requests.load(Ordering::Relaxed),
+            errors: self.inner.errors.load(Ordering::Relaxed),
+            bytes_sent: self.inner.bytes_sent.load(Ordering::Relaxed),
+        }
+    }
+}
+
+//the line logged once the proxy has shut down
+impl fmt::Display for LiveSnapshot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "uptime={}s requests={} errors={} bytes_sent={}",
+            self.uptime.as_secs(),
+            self.requests,
+            self.errors,
+            self.bytes_sent
+        )
+    }
+}
+
+//upper bounds of the response time histogram buckets, in seconds
+pub const LATENCY_BUCKETS: [f64; 11] = [0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+//per domain totals for /metrics, kept in memory so a scrape never touches the db
+#[derive(Debug, Default, Clone)]
+pub struct DomainMetrics {
+    //by status class, "2xx" .. "5xx"
+    pub requests: HashMap<&'static str, u64>,
+    pub upstream_errors: u64,
+    pub cache_hits: u64,
+    pub cache_misses: u64,
+    //cumulative, one count per LATENCY_BUCKETS entry
+    pub latency_buckets: [u64; LATENCY_BUCKETS.len()],
+    pub latency_sum: f64,
+    pub latency_count: u64,
+}
+
+#[derive(Clone, Default)]
+pub struct RequestMetrics {
+    inner: Arc<Mutex<HashMap<String, DomainMetrics>>>,
+}
+
+impl RequestMetrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn record(&self, domain: &str, status: u16, response_time: Duration, upstream_error: bool) {
+        let seconds = response_time.as_secs_f64();
+        let mut domains = self.inner.lock().unwrap();
+        let metrics = domains.entry(domain.to_string()).or_default();
+        *metrics.requests.entry(status_class(status)).or_default() += 1;
+        if upstream_error {
+            metrics.upstream_errors += 1;
+        }
+        for (count, bound) in metrics.latency_buckets.iter_mut().zip(LATENCY_BUCKETS) {
+            if seconds <= bound {
+                *count += 1;
+            }
+        }
+        metrics.latency_sum += seconds;
+        metrics.latency_count += 1;
+    }
+
+    pub fn cache_lookup(&self, domain: &str, hit: bool) {
+        let mut domains = self.inner.lock().unwrap();
+        let metrics = domains.entry(domain.to_string()).or_default();
+        if hit {
+            metrics.cache_hits += 1;
+        } else {
+            metrics.cache_misses += 1;
+        }
+    }
+
+    pub fn snapshot(&self) -> HashMap<String, DomainMetrics> {
+        self.inner.lock().unwrap().clone()
+    }
+}
+
+fn status_class(status: u16) -> &'static str {
+    match status {
+        100..=199 => "1xx",
+        200..=299 => "2xx",
+        300..=399 => "3xx",
+        400..=499 => "4xx",
+        _ => "5xx",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shutdown_report_reflects_the_requests_served() {
+        let live = LiveCounters::new();
+        live.record(200, 120);
+        live.record(304, 0);
+        live.record(502, 30);
+        live.record(404, 50);
+
+        let report = live.snapshot();
+        assert_eq!((report.requests, report.errors, report.bytes_sent), (4, 1, 200));
+        assert_eq!(report.to_string(), "uptime=0s requests=4 errors=1 bytes_sent=200");
+    }
+}
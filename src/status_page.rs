@@ -0,0 +1,40 @@
+use axum::{
+    body::Body,
+    http::{header, StatusCode},
+    response::Response,
+};
+use serde::Serialize;
+
+//where the data plane answers with its own status, and whether that also replaces the unknown-domain 404
+#[derive(Debug, Clone)]
+pub struct StatusPage {
+    pub path: String,
+    pub unknown_domain: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Status {
+    pub version: &'static str,
+    pub uptime_secs: u64,
+    pub domains: usize,
+    pub healthy: bool,
+}
+
+impl Status {
+    //503 while unhealthy so a plain status check works without reading the body.
+    //returns the body size for logging
+    pub fn respond(&self, is_head: bool) -> (Response, u64) {
+        let json = serde_json::to_string(self).unwrap_or_default();
+        let len = json.len() as u64;
+        let status = if self.healthy { StatusCode::OK } else { StatusCode::SERVICE_UNAVAILABLE };
+        let body = if is_head { Body::empty() } else { Body::from(json) };
+        let response = Response::builder()
+            .status(status)
+            .header(header::CONTENT_TYPE, "application/json")
+            .header(header::CONTENT_LENGTH, len)
+            .header(header::CACHE_CONTROL, "no-store")
+            .body(body)
+            .unwrap();
+        (response, if is_head { 0 } else { len })
+    }
+}
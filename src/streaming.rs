@@ -0,0 +1,133 @@
+use axum::{body::{Body, HttpBody}, http::HeaderMap};
+use bytes::{Bytes, BytesMut};
+use futures::StreamExt;
+use http_body_util::BodyExt;
+use std::time::Duration;
+use tracing::warn;
+
+//how eagerly proxied body chunks are written to the client
+#[derive(Debug, Clone, Copy)]
+pub struct FlushPolicy {
+    //flush once this many bytes are buffered
+    pub min_bytes: usize,
+    //flush whatever is buffered after this long
+    pub max_delay: Duration,
+}
+
+impl FlushPolicy {
+    pub fn immediate() -> Self {
+        Self {
+            min_bytes: 0,
+            max_delay: Duration::ZERO,
+        }
+    }
+
+    //server-sent events always flush immediately, other bodies coalesce
+    pub fn for_response(headers: &HeaderMap, bulk: FlushPolicy) -> Self {
+        if is_event_stream(headers) {
+            Self::immediate()
+        } else {
+            bulk
+        }
+    }
+}
+
+pub fn is_event_stream(headers: &HeaderMap) -> bool {
+    headers
+        .get("content-type")
+        .and_then(|h| h.to_str().ok())
+        .map(|ct| ct.starts_with("text/event-stream"))
+        .unwrap_or(false)
+}
+
+//re-chunks an upstream body according to the flush policy
+pub fn coalesce<B>(body: B, policy: FlushPolicy) -> Body
+where
+    B: HttpBody<Data = Bytes> + Send + 'static,
+    B::Error: Into<axum::BoxError>,
+{
+    if policy.min_bytes <= 1 {
+        return Body::new(body);
+    }
+
+    let chunks = Box::pin(body.into_data_stream());
+    let stream = futures::stream::unfold((chunks, false), move |(mut chunks, done)| async move {
+        if done {
+            return None;
+        }
+
+        //wait as long as needed for the first chunk, then at most max_delay for more
+        let mut buffer = BytesMut::new();
+        match chunks.next().await {
+            Some(Ok(chunk)) => buffer.extend_from_slice(&chunk),
+            Some(Err(e)) => return Some((Err(e.into()), (chunks, true))),
+            None => return None,
+        }
+
+        let deadline = tokio::time::Instant::now() + policy.max_delay;
+        while buffer.len() < policy.min_bytes {
+            match tokio::time::timeout_at(deadline, chunks.next()).await {
+                Ok(Some(Ok(chunk))) => buffer.extend_from_slice(&chunk),
+                Ok(Some(Err(e))) => return Some((Err(e.into()), (chunks, true))),
+                Ok(None) => return Some((Ok(buffer.freeze()), (chunks, true))),
+                Err(_) => break,
+            }
+        }
+
+        Some((Ok::<Bytes, axum::BoxError>(buffer.freeze()), (chunks, false)))
+    });
+
+    Body::from_stream(stream)
+}
+
+//ends a streamed body with an error once it grows past max_bytes, so the client
+//sees a truncated response instead of an unbounded one
+pub fn cap<B>(body: B, max_bytes: u64, context: String) -> Body
+where
+    B: HttpBody<Data = Bytes> + Send + 'static,
+    B::Error: Into<axum::BoxError>,
+{
+    let mut sent = 0u64;
+    let stream = body.into_data_stream().map(move |chunk| {
+        let chunk = chunk.map_err(Into::into)?;
+        sent += chunk.len() as u64;
+        if sent > max_bytes {
+            warn!("RESPONSE TRUNCATED: {} exceeded {} bytes", context, max_bytes);
+            return Err::<Bytes, axum::BoxError>("response exceeded max_response_bytes".into());
+        }
+        Ok(chunk)
+    });
+
+    Body::from_stream(stream)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chunked(chunks: &[&'static str]) -> Body {
+        let chunks: Vec<Result<Bytes, std::convert::Infallible>> =
+            chunks.iter().map(|chunk| Ok(Bytes::from_static(chunk.as_bytes()))).collect();
+        Body::from_stream(futures::stream::iter(chunks))
+    }
+
+    async fn writes(body: Body) -> Vec<Bytes> {
+        body.into_data_stream().map(|chunk| chunk.unwrap()).collect().await
+    }
+
+    #[tokio::test]
+    async fn event_stream_flushes_each_chunk_while_bulk_coalesces() {
+        let bulk = FlushPolicy {
+            min_bytes: 1024,
+            max_delay: Duration::from_secs(1),
+        };
+        let mut sse = HeaderMap::new();
+        sse.insert("content-type", "text/event-stream".parse().unwrap());
+
+        let events = coalesce(chunked(&["data: 1\n\n", "data: 2\n\n", "data: 3\n\n"]), FlushPolicy::for_response(&sse, bulk));
+        assert_eq!(writes(events).await.len(), 3);
+
+        let bulk_body = coalesce(chunked(&["aaaa", "bbbb", "cccc"]), FlushPolicy::for_response(&HeaderMap::new(), bulk));
+        assert_eq!(writes(bulk_body).await, vec![Bytes::from_static(b"aaaabbbbcccc")]);
+    }
+}
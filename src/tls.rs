@@ -0,0 +1,399 @@
+use axum::{
+    extract::connect_info::Connected,
+    serve::{IncomingStream, Listener},
+};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::{
+    collections::HashMap,
+    io,
+    net::SocketAddr,
+    path::Path,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, RwLock,
+    },
+    time::Duration,
+};
+use tokio::{
+    net::{TcpListener, TcpStream},
+    sync::mpsc,
+};
+use tokio_rustls::{
+    rustls::{
+        crypto::ring,
+        pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer},
+        server::{Acceptor, ClientHello, ResolvesServerCert},
+        sign::CertifiedKey,
+        ServerConfig,
+    },
+    server::TlsStream,
+    LazyConfigAcceptor,
+};
+use tracing::warn;
+use crate::proxy_protocol::ProxiedAddr;
+
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+//marks requests that arrived over the TLS listener, so origins are told the client used https
+#[derive(Debug, Clone, Copy)]
+pub struct TlsConnection;
+
+//a certificate the TLS listener serves, as listed by the /certs endpoint
+#[derive(Debug, Clone, Serialize)]
+pub struct CertInfo {
+    pub domain: String,
+    pub expires_at: DateTime<Utc>,
+    //"file" for tls.cert_path, "acme" for certificates obtained automatically
+    pub source: &'static str,
+}
+
+#[derive(Debug, Default)]
+struct Certs {
+    by_name: HashMap<String, (Arc<CertifiedKey>, CertInfo)>,
+    default: Option<(Arc<CertifiedKey>, CertInfo)>,
+}
+
+//certificates by server name, picked per handshake from the client's SNI. names
+//without one get the default certificate, or whatever tls.unknown_sni says
+#[derive(Debug, Clone)]
+pub struct CertStore {
+    certs: Arc<RwLock<Certs>>,
+    unknown_sni: Option<UnknownSni>,
+}
+
+impl CertStore {
+    pub fn new(unknown_sni: Option<UnknownSni>) -> Self {
+        Self {
+            certs: Arc::new(RwLock::new(Certs::default())),
+            unknown_sni,
+        }
+    }
+
+    //the certificate for tls.cert_path/tls.key_path
+    pub fn load_default(&self, cert_path: &Path, key_path: &Path) -> Result<(), String> {
+        let cert_pem = std::fs::read_to_string(cert_path)
+            .map_err(|e| format!("can't read certificates from {}: {}", cert_path.display(), e))?;
+        let key_pem = std::fs::read_to_string(key_path)
+            .map_err(|e| format!("can't read private key from {}: {}", key_path.display(), e))?;
+        let (key, expires_at) = certified_key(&cert_pem, &key_pem)?;
+        let info = CertInfo {
+            domain: "default".to_string(),
+            expires_at,
+            source: "file",
+        };
+        self.certs.write().unwrap().default = Some((key, info));
+        Ok(())
+    }
+
+    //adds or replaces the certificate for one name, returning when it expires
+    pub fn insert(&self, domain: &str, cert_pem: &str, key_pem: &str) -> Result<DateTime<Utc>, String> {
+        let (key, expires_at) = certified_key(cert_pem, key_pem)?;
+        let info = CertInfo {
+            domain: domain.to_lowercase(),
+            expires_at,
+            source: "acme",
+        };
+        self.certs.write().unwrap().by_name.insert(info.domain.clone(), (key, info));
+        Ok(expires_at)
+    }
+
+    pub fn expires_at(&self, domain: &str) -> Option<DateTime<Utc>> {
+        let certs = self.certs.read().unwrap();
+        certs.by_name.get(domain).map(|(_, info)| info.expires_at)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        let certs = self.certs.read().unwrap();
+        certs.by_name.is_empty() && certs.default.is_none()
+    }
+
+    pub fn list(&self) -> Vec<CertInfo> {
+        let certs = self.certs.read().unwrap();
+        let mut list: Vec<CertInfo> = certs.by_name.values().map(|(_, info)| info.clone()).collect();
+        list.sort_by(|a, b| a.domain.cmp(&b.domain));
+        list.extend(certs.default.iter().map(|(_, info)| info.clone()));
+        list
+    }
+}
+
+impl ResolvesServerCert for CertStore {
+    fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        let certs = self.certs.read().unwrap();
+        let name = match &self.unknown_sni {
+            Some(unknown) => unknown.server_name(client_hello.server_name(), |name| certs.by_name.contains_key(name))?,
+            None => client_hello.server_name().unwrap_or_default().to_lowercase(),
+        };
+        certs
+            .by_name
+            .get(&name)
+            .or(certs.default.as_ref())
+            .map(|(key, _)| key.clone())
+    }
+}
+
+//a PEM certificate chain and its private key (PKCS#8, PKCS#1 or SEC1), with the
+//expiry of the leaf certificate
+fn certified_key(cert_pem: &str, key_pem: &str) -> Result<(Arc<CertifiedKey>, DateTime<Utc>), String> {
+    let certs = CertificateDer::pem_slice_iter(cert_pem.as_bytes())
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| format!("bad certificate: {}", e))?;
+    let leaf = certs.first().ok_or_else(|| "no certificate found".to_string())?;
+    let (_, parsed) = x509_parser::parse_x509_certificate(leaf).map_err(|e| format!("bad certificate: {}", e))?;
+    let expires_at = DateTime::from_timestamp(parsed.validity().not_after.timestamp(), 0)
+        .ok_or_else(|| "certificate expiry out of range".to_string())?;
+
+    let key = PrivateKeyDer::from_pem_slice(key_pem.as_bytes()).map_err(|e| format!("bad private key: {}", e))?;
+    let signing_key = ring::sign::any_supported_type(&key).map_err(|e| format!("unsupported private key: {}", e))?;
+    Ok((Arc::new(CertifiedKey::new(certs, signing_key)), expires_at))
+}
+
+//handshakes pick their certificate from the store
+pub fn server_config(certs: CertStore) -> Result<Arc<ServerConfig>, String> {
+    let mut config = ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .map_err(|e| e.to_string())?
+        .with_no_client_auth()
+        .with_cert_resolver(Arc::new(certs));
+    config.alpn_protocols = vec![b"http/1.1".to_vec()];
+    Ok(Arc::new(config))
+}
+
+//terminates TLS on accepted connections. handshakes run off the accept loop so a slow
+//or stalled client can't hold up other connections, failures go to the stats
+pub struct TlsListener {
+    local_addr: SocketAddr,
+    accepted: mpsc::Receiver<(TlsStream<TcpStream>, SocketAddr)>,
+}
+
+impl TlsListener {
+    pub fn new(listener: TcpListener, config: Arc<ServerConfig>, stats: TlsStats) -> io::Result<Self> {
+        let local_addr = listener.local_addr()?;
+        let (tx, accepted) = mpsc::channel(1024);
+
+        tokio::spawn(async move {
+            loop {
+                let (stream, peer) = match listener.accept().await {
+                    Ok(conn) => conn,
+                    Err(e) => {
+                        warn!("TLS accept failed: {}", e);
+                        tokio::time::sleep(Duration::from_millis(100)).await;
+                        continue;
+                    }
+                };
+
+                let tx = tx.clone();
+                let config = config.clone();
+                let stats = stats.clone();
+                tokio::spawn(async move {
+                    match tokio::time::timeout(HANDSHAKE_TIMEOUT, handshake(stream, config)).await {
+                        Ok(Ok(stream)) => {
+                            let _ = tx.send((stream, peer)).await;
+                        }
+                        Ok(Err((sni, reason))) => stats.handshake_failed(peer, sni.as_deref(), &reason),
+                        Err(_) => stats.handshake_failed(peer, None, "timed out"),
+                    }
+                });
+            }
+        });
+
+        Ok(Self { local_addr, accepted })
+    }
+}
+
+impl Listener for TlsListener {
+    type Io = TlsStream<TcpStream>;
+    type Addr = SocketAddr;
+
+    async fn accept(&mut self) -> (Self::Io, Self::Addr) {
+        match self.accepted.recv().await {
+            Some(conn) => conn,
+            None => std::future::pending().await,
+        }
+    }
+
+    fn local_addr(&self) -> io::Result<Self::Addr> {
+        Ok(self.local_addr)
+    }
+}
+
+impl Connected<IncomingStream<'_, TlsListener>> for ProxiedAddr {
+    fn connect_info(stream: IncomingStream<'_, TlsListener>) -> Self {
+        ProxiedAddr(*stream.remote_addr())
+    }
+}
+
+//reads the ClientHello first so a failure can be reported with the name the client asked for
+async fn handshake(
+    stream: TcpStream,
+    config: Arc<ServerConfig>,
+) -> Result<TlsStream<TcpStream>, (Option<String>, String)> {
+    let start = match LazyConfigAcceptor::new(Acceptor::default(), stream).await {
+        Ok(start) => start,
+        Err(e) => return Err((None, e.to_string())),
+    };
+    let sni = start.client_hello().server_name().map(String::from);
+    start.into_stream(config).await.map_err(|e| (sni, e.to_string()))
+}
+
+//handshake failures seen by the TLS listener, exported via /metrics
+#[derive(Clone)]
+pub struct TlsStats {
+    log_failures: bool,
+    handshake_failures: Arc<AtomicU64>,
+}
+
+impl TlsStats {
+    pub fn new(log_failures: bool) -> Self {
+        Self {
+            log_failures,
+            handshake_failures: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    //called from the TLS acceptor's error path, failures are always counted
+    pub fn handshake_failed(&self, peer: SocketAddr, sni: Option<&str>, reason: &str) {
+        self.handshake_failures.fetch_add(1, Ordering::Relaxed);
+        if self.log_failures {
+            warn!(
+                "TLS HANDSHAKE FAILED: peer={} sni={} reason={}",
+                peer,
+                sni.unwrap_or("none"),
+                reason
+            );
+        }
+    }
+
+    pub fn handshake_failures(&self) -> u64 {
+        self.handshake_failures.load(Ordering::Relaxed)
+    }
+}
+
+//what to do with a handshake whose SNI is missing or names no configured domain
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum UnknownSni {
+    //abort the handshake
+    Reject,
+    //serve the certificate of this domain instead
+    Fallback(String),
+}
+
+impl UnknownSni {
+    //"reject", or the domain to fall back to
+    pub fn parse(value: &str) -> Self {
+        match value.trim() {
+            "reject" => UnknownSni::Reject,
+            domain => UnknownSni::Fallback(domain.to_lowercase()),
+        }
+    }
+
+    //the domain whose certificate answers the handshake, None to abort it
+    pub fn server_name(&self, sni: Option<&str>, is_configured: impl Fn(&str) -> bool) -> Option<String> {
+        match sni.map(str::to_lowercase) {
+            Some(name) if is_configured(&name) => Some(name),
+            _ => match self {
+                UnknownSni::Reject => None,
+                UnknownSni::Fallback(domain) => Some(domain.clone()),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use tokio::io::AsyncWriteExt;
+    use tokio_rustls::{
+        rustls::{pki_types::ServerName, ClientConfig, RootCertStore},
+        TlsConnector,
+    };
+
+    //a self-signed certificate for `name`, PEM encoded with its key
+    pub(crate) fn self_signed(name: &str) -> (String, String) {
+        let rcgen::CertifiedKey { cert, key_pair } = rcgen::generate_simple_self_signed(vec![name.to_string()]).unwrap();
+        (cert.pem(), key_pair.serialize_pem())
+    }
+
+    async fn listen(certs: CertStore) -> (TlsListener, TlsStats) {
+        let stats = TlsStats::new(true);
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let listener = TlsListener::new(listener, server_config(certs).unwrap(), stats.clone()).unwrap();
+        (listener, stats)
+    }
+
+    //a client trusting only `cert_pem`
+    pub(crate) fn connector(cert_pem: &str) -> TlsConnector {
+        TlsConnector::from(Arc::new(client_config(cert_pem)))
+    }
+
+    fn client_config(cert_pem: &str) -> ClientConfig {
+        let mut roots = RootCertStore::empty();
+        for cert in CertificateDer::pem_slice_iter(cert_pem.as_bytes()) {
+            roots.add(cert.unwrap()).unwrap();
+        }
+        ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(roots)
+            .with_no_client_auth()
+    }
+
+    //waits for the listener's background handshakes to be counted
+    async fn failures(stats: &TlsStats, expected: u64) -> u64 {
+        for _ in 0..100 {
+            if stats.handshake_failures() >= expected {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        stats.handshake_failures()
+    }
+
+    #[tokio::test]
+    async fn a_failed_handshake_is_counted() {
+        let (cert, key) = self_signed("app.test");
+        let certs = CertStore::new(None);
+        certs.insert("app.test", &cert, &key).unwrap();
+        let (mut listener, stats) = listen(certs).await;
+        let addr = listener.local_addr().unwrap();
+
+        //plain http on the TLS port never gets through the handshake
+        let mut plain = TcpStream::connect(addr).await.unwrap();
+        plain.write_all(b"GET / HTTP/1.1\r\nHost: app.test\r\n\r\n").await.unwrap();
+        assert_eq!(failures(&stats, 1).await, 1);
+
+        let tcp = TcpStream::connect(addr).await.unwrap();
+        let name = ServerName::try_from("app.test").unwrap();
+        connector(&cert).connect(name, tcp).await.unwrap();
+        listener.accept().await;
+        assert_eq!(stats.handshake_failures(), 1);
+    }
+
+    #[tokio::test]
+    async fn handshakes_without_a_known_sni_are_rejected() {
+        let (cert, key) = self_signed("app.test");
+        let certs = CertStore::new(Some(UnknownSni::Reject));
+        certs.insert("app.test", &cert, &key).unwrap();
+        let (mut listener, stats) = listen(certs).await;
+        let addr = listener.local_addr().unwrap();
+        let name = ServerName::try_from("app.test").unwrap();
+
+        //no SNI at all
+        let mut no_sni = client_config(&cert);
+        no_sni.enable_sni = false;
+        let tcp = TcpStream::connect(addr).await.unwrap();
+        assert!(TlsConnector::from(Arc::new(no_sni)).connect(name.clone(), tcp).await.is_err());
+        assert_eq!(failures(&stats, 1).await, 1);
+
+        //an SNI naming no configured domain
+        let tcp = TcpStream::connect(addr).await.unwrap();
+        let other = ServerName::try_from("other.test").unwrap();
+        assert!(connector(&cert).connect(other, tcp).await.is_err());
+        assert_eq!(failures(&stats, 2).await, 2);
+
+        let tcp = TcpStream::connect(addr).await.unwrap();
+        connector(&cert).connect(name, tcp).await.unwrap();
+        listener.accept().await;
+        assert_eq!(stats.handshake_failures(), 2);
+    }
+}
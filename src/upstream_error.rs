@@ -0,0 +1,131 @@
+use std::{error::Error, fmt};
+
+//a failed exchange with an origin, over the shared pool or a connection pinned to one client
+#[derive(Debug)]
+pub enum SendError {
+    Pooled(hyper_util::client::legacy::Error),
+    Pinned {
+        //the connection itself couldn't be set up
+        connecting: bool,
+        error: Box<dyn Error + Send + Sync>,
+    },
+}
+
+impl fmt::Display for SendError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SendError::Pooled(error) => error.fmt(f),
+            SendError::Pinned { error, .. } => error.fmt(f),
+        }
+    }
+}
+
+//why a request to an origin failed before any response came back
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UpstreamFailure {
+    //the origin's hostname didn't resolve
+    Dns,
+    //nothing listens on the origin's port
+    Refused,
+    //the connection wasn't established within upstream_connect_timeout_ms
+    ConnectTimeout,
+    //any other failure while connecting
+    Connect,
+    //the origin closed or reset the connection before sending a response,
+    //typically a backend restarting with requests in flight
+    Reset,
+    //the connection was up but the exchange failed
+    Other,
+}
+
+impl UpstreamFailure {
+    pub fn classify(error: &SendError) -> Self {
+        match error {
+            SendError::Pooled(error) => Self::of(error, error.is_connect()),
+            SendError::Pinned { connecting, error } => Self::of(error.as_ref(), *connecting),
+        }
+    }
+
+    fn of(error: &(dyn Error + 'static), connecting: bool) -> Self {
+        //the connector wraps the io error, walk down to find what actually happened
+        let mut source = Some(error);
+        while let Some(err) = source {
+            if let Some(io) = err.downcast_ref::<std::io::Error>() {
+                match io.kind() {
+                    std::io::ErrorKind::ConnectionRefused => return UpstreamFailure::Refused,
+                    std::io::ErrorKind::TimedOut if connecting => return UpstreamFailure::ConnectTimeout,
+                    std::io::ErrorKind::ConnectionReset
+                    | std::io::ErrorKind::ConnectionAborted
+                    | std::io::ErrorKind::BrokenPipe
+                        if !connecting =>
+                    {
+                        return UpstreamFailure::Reset;
+                    }
+                    _ => {}
+                }
+            }
+            if let Some(hyper) = err.downcast_ref::<hyper::Error>()
+                && !connecting
+                && hyper.is_incomplete_message()
+            {
+                return UpstreamFailure::Reset;
+            }
+            if err.to_string().starts_with("dns error") {
+                return UpstreamFailure::Dns;
+            }
+            source = err.source();
+        }
+
+        if connecting {
+            UpstreamFailure::Connect
+        } else {
+            UpstreamFailure::Other
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            UpstreamFailure::Dns => "dns_failure",
+            UpstreamFailure::Refused => "connection_refused",
+            UpstreamFailure::ConnectTimeout => "connect_timeout",
+            UpstreamFailure::Connect => "connect_error",
+            UpstreamFailure::Reset => "connection_reset",
+            UpstreamFailure::Other => "upstream_error",
+        }
+    }
+
+    //the origin is unreachable, so retrying it right away would fail the same way
+    pub fn is_unreachable(&self) -> bool {
+        matches!(self, UpstreamFailure::Dns | UpstreamFailure::Refused)
+    }
+
+    //the request never reached the origin and the failure may be transient
+    pub fn is_retryable(&self) -> bool {
+        matches!(self, UpstreamFailure::ConnectTimeout | UpstreamFailure::Connect)
+    }
+
+    //the origin may have seen the request but never answered it, only safe to
+    //send again when repeating the request has no further effect
+    pub fn is_reset(&self) -> bool {
+        matches!(self, UpstreamFailure::Reset)
+    }
+}
+
+//how failures to get a response from an origin are reported to the client
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorBody {
+    //the bare status
+    Opaque,
+    //a JSON object with a machine-readable code
+    Json,
+}
+
+impl ErrorBody {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "opaque" => Some(ErrorBody::Opaque),
+            "json" => Some(ErrorBody::Json),
+            _ => None,
+        }
+    }
+}
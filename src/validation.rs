@@ -0,0 +1,359 @@
+use axum::http::{HeaderName, StatusCode, Uri};
+use serde::Deserialize;
+use std::collections::{HashMap, HashSet};
+use crate::api::DomainDto;
+use crate::cidr::Cidr;
+use crate::expect::ExpectContinue;
+use crate::ip_filter::DefaultAction;
+use crate::log_writer::Overflow;
+use crate::retry::Jitter;
+use crate::upstream_error::ErrorBody;
+use crate::routing;
+
+//a full proxy configuration as submitted for validation, in TOML or JSON:
+//a `config` table of key/values plus a list of `domains`
+#[derive(Debug, Deserialize)]
+pub struct ConfigDocument {
+    #[serde(default)]
+    pub config: HashMap<String, serde_json::Value>,
+    #[serde(default)]
+    pub domains: Vec<DomainDto>,
+}
+
+impl ConfigDocument {
+    //JSON is tried first since TOML would reject it anyway
+    pub fn parse(body: &str) -> Result<Self, String> {
+        match serde_json::from_str::<ConfigDocument>(body) {
+            Ok(doc) => Ok(doc),
+            Err(json_err) => toml::from_str::<ConfigDocument>(body).map_err(|toml_err| {
+                format!("not valid JSON ({}) or TOML ({})", json_err, toml_err)
+            }),
+        }
+    }
+
+    //the config table as key/value rows, sorted by key
+    pub fn entries(&self) -> Vec<(String, String)> {
+        let mut entries = Vec::new();
+        for (key, value) in &self.config {
+            flatten_value(key, value, &mut entries);
+        }
+        entries.sort();
+        entries
+    }
+}
+
+pub fn validate_document(doc: &ConfigDocument) -> Vec<String> {
+    let mut problems = Vec::new();
+
+    for (key, value) in doc.entries() {
+        if let Some(problem) = validate_config_value(&key, &value) {
+            problems.push(problem);
+        }
+    }
+
+    let mut seen = HashSet::new();
+    for domain in &doc.domains {
+        problems.extend(validate_domain(domain));
+        for name in routing::names(domain) {
+            if !seen.insert(name.to_lowercase()) {
+                problems.push(format!("domain '{}' is defined more than once", name));
+            }
+        }
+    }
+
+    problems
+}
+
+//nested tables (`[config.health_check]` in TOML) become dotted keys like the config table uses
+fn flatten_value(key: &str, value: &serde_json::Value, out: &mut Vec<(String, String)>) {
+    match value {
+        serde_json::Value::Object(map) => {
+            for (child, value) in map {
+                flatten_value(&format!("{}.{}", key, child), value, out);
+            }
+        }
+        serde_json::Value::String(s) => out.push((key.to_string(), s.clone())),
+        other => out.push((key.to_string(), other.to_string())),
+    }
+}
+
+pub fn validate_domain(domain: &DomainDto) -> Vec<String> {
+    let mut problems = Vec::new();
+
+    if !is_valid_route_host(&domain.domain) {
+        problems.push(format!("domain '{}' is not a valid hostname", domain.domain));
+    }
+
+    for alias in &domain.aliases {
+        if !is_valid_route_host(alias) {
+            problems.push(format!("domain '{}': alias '{}' is not a valid hostname", domain.domain, alias));
+        }
+    }
+
+    for origin in domain.origin_list() {
+        if let Some(problem) = validate_origin(&origin) {
+            problems.push(format!("domain '{}': {}", domain.domain, problem));
+        }
+    }
+
+    //the standby color has to be ready to take traffic too
+    if let Some(blue_green) = &domain.blue_green {
+        for (color, origin) in [("blue", &blue_green.blue), ("green", &blue_green.green)] {
+            if let Some(problem) = validate_origin(origin) {
+                problems.push(format!("domain '{}': blue_green.{}: {}", domain.domain, color, problem));
+            }
+        }
+    }
+
+    let origins = domain.origin_list();
+    for origin in domain.origin_weights.keys() {
+        if !origins.contains(origin) {
+            problems.push(format!("domain '{}': weight given for unknown origin '{}'", domain.domain, origin));
+        }
+    }
+
+    for rule in &domain.rate_limits {
+        if !rule.prefix.starts_with('/') {
+            problems.push(format!(
+                "domain '{}': rate limit prefix '{}' must start with /",
+                domain.domain, rule.prefix
+            ));
+        }
+        if rule.window_secs == 0 {
+            problems.push(format!(
+                "domain '{}': rate limit for '{}' needs a window of at least 1s",
+                domain.domain, rule.prefix
+            ));
+        }
+    }
+
+    if domain.rate_limit_per_minute == Some(0) {
+        problems.push(format!("domain '{}': rate_limit_per_minute must be greater than 0", domain.domain));
+    }
+
+    if domain.max_concurrency == Some(0) {
+        problems.push(format!("domain '{}': max_concurrency must be greater than 0", domain.domain));
+    }
+
+    for rule in &domain.accept_routes {
+        if rule.media_type.split_once('/').map(|(kind, sub)| kind.is_empty() || sub.is_empty()).unwrap_or(true) {
+            problems.push(format!(
+                "domain '{}': accept route media type '{}' must look like type/subtype",
+                domain.domain, rule.media_type
+            ));
+        }
+        if let Some(problem) = validate_origin(&rule.origin) {
+            problems.push(format!("domain '{}': accept route '{}': {}", domain.domain, rule.media_type, problem));
+        }
+    }
+
+    for rule in &domain.path_routes {
+        if !rule.prefix.starts_with('/') {
+            problems.push(format!(
+                "domain '{}': path route prefix '{}' must start with /",
+                domain.domain, rule.prefix
+            ));
+        }
+        if let Some(problem) = validate_origin(&rule.origin) {
+            problems.push(format!("domain '{}': path route '{}': {}", domain.domain, rule.prefix, problem));
+        }
+    }
+
+    if let Some(pacing) = &domain.pacing
+        && pacing.interval_ms == 0
+    {
+        problems.push(format!("domain '{}': pacing.interval_ms must be greater than 0", domain.domain));
+    }
+
+    if domain.max_response_bytes == Some(0) {
+        problems.push(format!("domain '{}': max_response_bytes must be greater than 0", domain.domain));
+    }
+
+    for pattern in &domain.cache_paths {
+        if !pattern.starts_with('/') && !pattern.starts_with('*') {
+            problems.push(format!(
+                "domain '{}': cache path '{}' must start with / or *",
+                domain.domain, pattern
+            ));
+        }
+    }
+
+    if domain.upstream_timeout_ms == Some(0) {
+        problems.push(format!("domain '{}': upstream_timeout_ms must be greater than 0", domain.domain));
+    }
+
+    if let Some(hc) = &domain.health_check {
+        if hc.interval_secs == Some(0) {
+            problems.push(format!("domain '{}': health_check.interval_secs must be greater than 0", domain.domain));
+        }
+        if hc.healthy_threshold == Some(0) || hc.unhealthy_threshold == Some(0) {
+            problems.push(format!("domain '{}': health_check thresholds must be greater than 0", domain.domain));
+        }
+    }
+
+    if let Some(expected) = domain.health_check.as_ref().and_then(|hc| hc.expected_status.as_deref())
+        && !is_valid_status_spec(expected)
+    {
+        problems.push(format!(
+            "domain '{}': health_check.expected_status '{}' is not a status range or list",
+            domain.domain, expected
+        ));
+    }
+
+    problems
+}
+
+pub fn validate_origin(origin: &str) -> Option<String> {
+    let uri = match origin.parse::<Uri>() {
+        Ok(uri) => uri,
+        Err(_) => return Some(format!("origin '{}' is not a valid URL", origin)),
+    };
+
+    match uri.scheme_str() {
+        Some("http") | Some("https") => {}
+        _ => return Some(format!("origin '{}' must start with http:// or https://", origin)),
+    }
+
+    if uri.host().map(|h| h.is_empty()).unwrap_or(true) {
+        return Some(format!("origin '{}' has no host", origin));
+    }
+
+    None
+}
+
+pub fn is_valid_host(host: &str) -> bool {
+    !host.is_empty()
+        && host.len() <= 253
+        && host.split('.').all(|label| {
+            !label.is_empty()
+                && label.len() <= 63
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+                && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+        })
+}
+
+//a hostname, or a wildcard like *.example.com standing for any one label in front of it
+fn is_valid_route_host(host: &str) -> bool {
+    match host.strip_prefix("*.") {
+        Some(parent) => is_valid_host(parent),
+        None => is_valid_host(host),
+    }
+}
+
+fn is_valid_status_spec(spec: &str) -> bool {
+    let valid_code = |code: &str| code.trim().parse::<u16>().map(|c| (100..=599).contains(&c)).unwrap_or(false);
+    match spec.split_once('-') {
+        Some((low, high)) => valid_code(low) && valid_code(high),
+        None => spec.split(',').all(valid_code),
+    }
+}
+
+//checks the value of a known config key, unknown keys are accepted as-is
+pub fn validate_config_value(key: &str, value: &str) -> Option<String> {
+    let ok = match key {
+        "port" | "api_port" | "admin.port" | "tls.port" => value.parse::<u16>().is_ok(),
+        "host" | "admin.host" => value.parse::<std::net::IpAddr>().is_ok() || is_valid_host(value),
+        "rate_limit_per_minute"
+        | "rate_limit_burst"
+        | "circuit_breaker.failure_threshold"
+        | "retry.attempts" => value.parse::<u32>().is_ok(),
+        "health_check.healthy_threshold" | "health_check.unhealthy_threshold" => {
+            value.parse::<u32>().map(|v| v > 0).unwrap_or(false)
+        }
+        "upstream_connect_timeout_ms"
+        | "health_check.interval_secs"
+        | "connection_affinity.idle_secs"
+        | "acme.renew_before_days" => value.parse::<u64>().map(|v| v > 0).unwrap_or(false),
+        "upstream_queue_timeout_ms"
+        | "upstream_timeout_ms"
+        | "stream_flush_ms"
+        | "min_cache_bytes"
+        | "max_cache_bytes"
+        | "cache.default_ttl_secs"
+        | "dns_cache.ttl_secs"
+        | "snapshot.interval_secs"
+        | "shutdown.grace_secs"
+        | "retry.backoff_ms"
+        | "retry.max_backoff_ms"
+        | "circuit_breaker.open_secs"
+        | "circuit_breaker.idle_secs"
+        | "slow_start.window_secs"
+        | "cache.stale_if_error_secs"
+        | "slo.window_secs"
+        | "rate_limit_penalty_secs"
+        | "rate_limit_penalty_max_secs"
+        | "rate_limit_penalty_reset_secs" => {
+            value.parse::<u64>().is_ok()
+        }
+        "max_upstream_connections"
+        | "max_uri_length"
+        | "max_request_header_bytes"
+        | "rate_limit_max_entries"
+        | "circuit_breaker.max_entries"
+        | "log_queue.capacity"
+        | "buffers.upstream_bytes"
+        | "cache.capacity_bytes" => value.parse::<usize>().map(|v| v > 0).unwrap_or(false),
+        "buffers.client_bytes" => value.parse::<u32>().map(|v| v > 0).unwrap_or(false),
+        "forwarded_hops" | "stream_flush_bytes" => value.parse::<usize>().is_ok(),
+        "startup_probe"
+        | "rewrite_redirects"
+        | "maintenance.enabled"
+        | "dns_cache.refresh"
+        | "admin.enabled"
+        | "cache.enabled"
+        | "cache.dedupe_bodies"
+        | "cache.inspect"
+        | "ssrf_guard.enabled"
+        | "ssrf_guard.exempt_configured"
+        | "strip_hop_by_hop"
+        | "forwarded_headers"
+        | "startup_banner"
+        | "proxy_protocol"
+        | "tls.log_handshake_failures"
+        | "acme.enabled"
+        | "reject_duplicate_host" => value.parse::<bool>().is_ok(),
+        "deny_status" => value
+            .parse::<u16>()
+            .ok()
+            .and_then(|s| StatusCode::from_u16(s).ok())
+            .is_some(),
+        "retry.jitter" => Jitter::parse(value).is_some(),
+        "rate_limit_algorithm" => matches!(value, "fixed_window" | "sliding_window" | "token_bucket"),
+        "concurrency.max_client_share" => value.parse::<f64>().map(|s| s > 0.0 && s <= 1.0).unwrap_or(false),
+        "slo.target" => value.parse::<f64>().map(|t| (0.0..=100.0).contains(&t)).unwrap_or(false),
+        "tracing.sample_rate" => value.parse::<f64>().map(|r| (0.0..=1.0).contains(&r)).unwrap_or(false),
+        "tls.unknown_sni" => value == "reject" || is_valid_host(value),
+        "upstream_error_body" => ErrorBody::parse(value).is_some(),
+        "expect_continue" => ExpectContinue::parse(value).is_some(),
+        "log_queue.overflow" => Overflow::parse(value).is_some(),
+        "lb_strategy" => matches!(value, "first" | "round_robin" | "consistent_hash" | "weighted_round_robin" | "least_connections"),
+        "health_check.expected_status" => is_valid_status_spec(value),
+        "cache.ttl_by_status" => value
+            .split(',')
+            .filter(|entry| !entry.trim().is_empty())
+            .all(|entry| match entry.split_once('=') {
+                Some((status, ttl)) => {
+                    status.trim().parse::<u16>().map(|s| (100..=599).contains(&s)).unwrap_or(false)
+                        && ttl.trim().parse::<u64>().is_ok()
+                }
+                None => false,
+            }),
+        "ip_filter.default" => DefaultAction::parse(value).is_some(),
+        "trusted_proxies" | "ip_filter.allow" | "ip_filter.deny" => value
+            .split(',')
+            .filter(|entry| !entry.trim().is_empty())
+            .all(|entry| Cidr::parse(entry).is_some()),
+        "echo_headers" | "merge_response_headers" => value
+            .split(',')
+            .filter(|name| !name.trim().is_empty())
+            .all(|name| HeaderName::from_bytes(name.trim().as_bytes()).is_ok()),
+        _ => true,
+    };
+
+    if ok {
+        None
+    } else {
+        Some(format!("config '{}' has invalid value '{}'", key, value))
+    }
+}